@@ -0,0 +1,469 @@
+//! `--dump-ast=json FILE`: the makefile's structure, for editors and
+//! other tools rather than for building.
+//!
+//! This is a syntax-only pass. Nothing is expanded, conditionals keep both
+//! branches and includes are listed rather than read, so the output is the
+//! same whatever the environment. Every node has the byte span of the
+//! text it came from (`end` is exclusive and a continued line's span
+//! covers all its physical lines) and the line it starts on.
+
+use crate::{directive, json_str, plan::json_list};
+
+#[derive(Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+}
+
+impl Span {
+    fn json(&self) -> String {
+        format!("{{\"start\":{},\"end\":{},\"line\":{}}}", self.start, self.end, self.line)
+    }
+}
+
+/// Fields are already JSON
+enum Node {
+    Plain(&'static str, Span, Vec<(&'static str, String)>),
+    /// An index into the rules, which collect their recipe as it comes
+    Rule(usize),
+    Conditional(Span, String, String, Vec<Node>, Vec<Node>),
+}
+
+struct Rule {
+    span: Span,
+    fields: Vec<(&'static str, String)>,
+    recipe: Vec<(Span, String)>,
+}
+
+/// An `if...` waiting for its `endif`. `else ifeq` opens another that
+/// the same `endif` closes.
+struct Frame {
+    span: Span,
+    directive: String,
+    args: String,
+    then: Vec<Node>,
+    otherwise: Option<Vec<Node>>,
+    chained: bool,
+}
+
+/// What a line that isn't a directive says
+enum Statement<'a> {
+    Assign(&'a str, &'a str, &'a str),
+    Rule(&'a str, bool, &'a str),
+    Other,
+}
+
+/// The first top level `:` or assignment operator decides
+fn statement(line: &str) -> Statement<'_> {
+    let bytes = line.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'$' if bytes.get(i + 1) == Some(&b'$') => i += 1,
+            b'$' if matches!(bytes.get(i + 1), Some(b'(' | b'{')) => {
+                depth += 1;
+                i += 1;
+            }
+            b')' | b'}' => depth = depth.saturating_sub(1),
+            b'=' if depth == 0 => {
+                let op = match i.checked_sub(1).map(|p| bytes[p]) {
+                    Some(b'+' | b'?' | b'!') => i - 1,
+                    _ => i,
+                };
+                return Statement::Assign(line[..op].trim(), &line[op..=i], line[i + 1..].trim_start());
+            }
+            b':' if depth == 0 => {
+                let rest = &line[i..];
+                for op in ["::=", ":="] {
+                    if let Some(value) = rest.strip_prefix(op) {
+                        return Statement::Assign(line[..i].trim(), op, value.trim_start());
+                    }
+                }
+                return match rest.strip_prefix("::") {
+                    Some(rest) => Statement::Rule(&line[..i], true, rest),
+                    None => Statement::Rule(&line[..i], false, &rest[1..]),
+                };
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Statement::Other
+}
+
+/// Everything before an unescaped `#`
+fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '#' if !escaped => return &line[..i],
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+/// `export` and `override` in front of an assignment, in either order
+fn modifiers(mut line: &str) -> (bool, bool, &str) {
+    let (mut export, mut overrides) = (false, false);
+    loop {
+        if let Some(rest) = directive(line, "export") {
+            export = true;
+            line = rest.trim();
+        } else if let Some(rest) = directive(line, "override") {
+            overrides = true;
+            line = rest.trim();
+        } else {
+            return (export, overrides, line);
+        }
+    }
+}
+
+fn words(s: &str) -> String {
+    json_list(&s.split_whitespace().map(String::from).collect::<Vec<_>>())
+}
+
+fn assignment(name: &str, op: &str, value: &str, export: bool, overrides: bool) -> Vec<(&'static str, String)> {
+    vec![
+        ("name", json_str(name)),
+        ("op", json_str(op)),
+        ("value", json_str(value)),
+        ("export", export.to_string()),
+        ("override", overrides.to_string()),
+    ]
+}
+
+struct Parser<'a> {
+    file: &'a str,
+    rules: Vec<Rule>,
+    root: Vec<Node>,
+    frames: Vec<Frame>,
+    /// The rule recipe lines belong to, until something ends it
+    rule: Option<usize>,
+}
+
+impl Parser<'_> {
+    fn error(&self, line: usize, msg: &str) -> String {
+        format!("{}:{}: *** {}.  Stop.", self.file, line, msg)
+    }
+
+    fn push(&mut self, node: Node) {
+        let nodes = match self.frames.last_mut() {
+            Some(Frame {
+                otherwise: Some(nodes), ..
+            }) => nodes,
+            Some(frame) => &mut frame.then,
+            None => &mut self.root,
+        };
+        nodes.push(node);
+    }
+
+    fn conditional(&mut self, span: Span, text: &str) -> Result<bool, String> {
+        for d in ["ifeq", "ifneq", "ifdef", "ifndef"] {
+            if let Some(args) = directive(text, d) {
+                self.frames.push(Frame {
+                    span,
+                    directive: d.to_string(),
+                    args: args.trim().to_string(),
+                    then: Vec::new(),
+                    otherwise: None,
+                    chained: false,
+                });
+                return Ok(true);
+            }
+        }
+
+        if let Some(rest) = directive(text, "else") {
+            let Some(frame) = self.frames.last_mut() else {
+                return Err(self.error(span.line, "extraneous 'else'"));
+            };
+            if frame.otherwise.is_some() {
+                return Err(self.error(span.line, "only one 'else' per conditional"));
+            }
+            frame.otherwise = Some(Vec::new());
+            let rest = rest.trim();
+            if !rest.is_empty() {
+                if !self.conditional(span, rest)? {
+                    return Err(self.error(span.line, "extraneous text after 'else' directive"));
+                }
+                self.frames.last_mut().unwrap().chained = true;
+            }
+            return Ok(true);
+        }
+
+        if directive(text, "endif").is_some() {
+            loop {
+                let Some(frame) = self.frames.pop() else {
+                    return Err(self.error(span.line, "extraneous 'endif'"));
+                };
+                let node = Node::Conditional(
+                    Span {
+                        end: span.end,
+                        ..frame.span
+                    },
+                    frame.directive,
+                    frame.args,
+                    frame.then,
+                    frame.otherwise.unwrap_or_default(),
+                );
+                self.push(node);
+                if !frame.chained {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn line(&mut self, span: Span, raw: &str) -> Result<(), String> {
+        if let Some(recipe) = raw.strip_prefix('\t') {
+            if let Some(rule) = self.rule {
+                self.rules[rule].recipe.push((span, recipe.to_string()));
+                return Ok(());
+            }
+            let rest = recipe.trim();
+            if rest.is_empty() || rest.starts_with('#') {
+                return Ok(());
+            }
+            return Err(self.error(span.line, "recipe commences before first target"));
+        }
+
+        // outside a recipe a continuation and the space around it is one space
+        let joined = raw.split("\\\n").map(str::trim).collect::<Vec<_>>().join(" ");
+        let text = strip_comment(&joined).trim().to_string();
+        if text.is_empty() || self.conditional(span, &text)? {
+            return Ok(());
+        }
+        self.rule = None;
+
+        for (d, optional) in [("include", false), ("-include", true), ("sinclude", true)] {
+            if let Some(files) = directive(&text, d) {
+                let fields = vec![("optional", optional.to_string()), ("files", words(files))];
+                self.push(Node::Plain("include", span, fields));
+                return Ok(());
+            }
+        }
+
+        let (export, overrides, rest) = modifiers(&text);
+        for d in ["unexport", "vpath"] {
+            if let Some(args) = directive(rest, d) {
+                let args = vec![("name", json_str(d)), ("args", words(args))];
+                self.push(Node::Plain("directive", span, args));
+                return Ok(());
+            }
+        }
+
+        match statement(rest) {
+            Statement::Assign(name, op, value) => {
+                self.push(Node::Plain("assignment", span, assignment(name, op, value, export, overrides)));
+            }
+            Statement::Rule(..) if export || overrides => {
+                return Err(self.error(span.line, "missing separator"));
+            }
+            Statement::Rule(targets, double_colon, rest) => {
+                let targets = ("targets", words(targets));
+                let double_colon = ("double_colon", double_colon.to_string());
+                let (rest, recipe) = match rest.split_once(';') {
+                    Some((rest, recipe)) => (rest, Some(recipe.trim_start())),
+                    None => (rest, None),
+                };
+                let (export, overrides, rest) = modifiers(rest.trim());
+                if let Statement::Assign(name, op, value) = statement(rest) {
+                    let mut fields = vec![targets];
+                    fields.extend(assignment(name, op, value, export, overrides));
+                    self.push(Node::Plain("target_variable", span, fields));
+                    return Ok(());
+                }
+
+                let (normal, order_only) = rest.split_once('|').unwrap_or((rest, ""));
+                let fields =
+                    vec![targets, double_colon, ("prerequisites", words(normal)), ("order_only", words(order_only))];
+                let mut rule = Rule {
+                    span,
+                    fields,
+                    recipe: Vec::new(),
+                };
+                if let Some(recipe) = recipe {
+                    rule.recipe.push((span, recipe.to_string()));
+                }
+                self.rules.push(rule);
+                self.rule = Some(self.rules.len() - 1);
+                self.push(Node::Rule(self.rules.len() - 1));
+            }
+            Statement::Other if export => {
+                let args = vec![("name", json_str("export")), ("args", words(rest))];
+                self.push(Node::Plain("directive", span, args));
+            }
+            Statement::Other => {
+                self.push(Node::Plain("unknown", span, vec![("text", json_str(&text))]));
+            }
+        }
+        Ok(())
+    }
+
+    fn json(&self, nodes: &[Node], indent: usize, out: &mut String) {
+        out.push('[');
+        for (i, node) in nodes.iter().enumerate() {
+            out.push_str(if i == 0 { "\n" } else { ",\n" });
+            out.push_str(&"  ".repeat(indent + 1));
+            let (kind, span, fields) = match node {
+                Node::Plain(kind, span, fields) => (*kind, span, fields),
+                Node::Rule(i) => ("rule", &self.rules[*i].span, &self.rules[*i].fields),
+                Node::Conditional(span, directive, args, then, otherwise) => {
+                    out.push_str(&format!(
+                        "{{\"kind\":\"conditional\",\"span\":{},\"directive\":{},\"args\":{},\"then\":",
+                        span.json(),
+                        json_str(directive),
+                        json_str(args)
+                    ));
+                    self.json(then, indent + 1, out);
+                    out.push_str(",\"else\":");
+                    self.json(otherwise, indent + 1, out);
+                    out.push('}');
+                    continue;
+                }
+            };
+            out.push_str(&format!("{{\"kind\":\"{}\",\"span\":{}", kind, span.json()));
+            for (name, value) in fields {
+                out.push_str(&format!(",\"{}\":{}", name, value));
+            }
+            if let Node::Rule(i) = node {
+                let recipe = self.rules[*i]
+                    .recipe
+                    .iter()
+                    .map(|(span, text)| format!("{{\"span\":{},\"text\":{}}}", span.json(), json_str(text)))
+                    .collect::<Vec<_>>();
+                out.push_str(&format!(",\"recipe\":[{}]", recipe.join(",")));
+            }
+            out.push('}');
+        }
+        if !nodes.is_empty() {
+            out.push('\n');
+            out.push_str(&"  ".repeat(indent));
+        }
+        out.push(']');
+    }
+}
+
+/// The makefile `src`, read from `file`, as JSON
+pub fn to_json(file: &str, src: &str) -> Result<String, String> {
+    let mut parser = Parser {
+        file,
+        rules: Vec::new(),
+        root: Vec::new(),
+        frames: Vec::new(),
+        rule: None,
+    };
+    // a define being collected: its span so far, name and operator
+    let mut define: Option<(Span, String, String, usize)> = None;
+    let mut offset = 0;
+    let mut line = 0;
+    let mut lines = src.split_inclusive('\n').peekable();
+    while let Some(first) = lines.next() {
+        line += 1;
+        let mut span = Span {
+            start: offset,
+            end: offset + first.trim_end_matches('\n').len(),
+            line,
+        };
+        offset += first.len();
+        let mut raw = first.trim_end_matches('\n').to_string();
+        // a define's body is kept as written
+        while define.is_none() && (raw.len() - raw.trim_end_matches('\\').len()) % 2 == 1 && lines.peek().is_some() {
+            let next = lines.next().unwrap();
+            line += 1;
+            raw.push('\n');
+            raw.push_str(next.trim_end_matches('\n'));
+            span.end = offset + next.trim_end_matches('\n').len();
+            offset += next.len();
+        }
+
+        if let Some((start, name, op, depth)) = &mut define {
+            if directive(&raw, "define").is_some() {
+                *depth += 1;
+            } else if directive(&raw, "endef").is_some() {
+                if *depth == 0 {
+                    let body = &src[start.end..span.start];
+                    let value = body.strip_prefix('\n').unwrap_or(body).strip_suffix('\n').unwrap_or_default();
+                    let mut fields = assignment(name, op, value, false, false);
+                    fields.push(("define", "true".into()));
+                    let span = Span { end: span.end, ..*start };
+                    parser.push(Node::Plain("assignment", span, fields));
+                    define = None;
+                    continue;
+                }
+                *depth -= 1;
+            }
+            continue;
+        }
+
+        if let Some(rest) = directive(&raw, "define") {
+            let rest = strip_comment(rest).trim();
+            let (name, op) = match ["::=", ":=", "+=", "?=", "!=", "="].iter().find(|op| rest.ends_with(*op)) {
+                Some(op) => (rest[..rest.len() - op.len()].trim(), *op),
+                None => (rest, "="),
+            };
+            parser.rule = None;
+            define = Some((span, name.to_string(), op.to_string(), 0));
+            continue;
+        }
+
+        parser.line(span, &raw)?;
+    }
+
+    if let Some((span, ..)) = define {
+        return Err(parser.error(span.line, "missing 'endef', unterminated 'define'"));
+    }
+    if let Some(frame) = parser.frames.last() {
+        return Err(parser.error(frame.span.line, "missing 'endif'"));
+    }
+
+    let mut out = format!("{{\"file\":{},\"nodes\":", json_str(file));
+    parser.json(&parser.root, 0, &mut out);
+    out.push('}');
+    Ok(out)
+}
+
+/// Run as `imake --dump-ast=FORMAT [FILE]`, returning the exit status
+pub fn dump(format: &str, args: &[String]) -> i32 {
+    if format != "json" {
+        eprintln!("imake: *** unknown --dump-ast format '{}', only 'json' is supported.  Stop.", format);
+        return 2;
+    }
+    let file = match args {
+        [file] => file.clone(),
+        [] => {
+            let found = ["GNUmakefile", "makefile", "Makefile"].into_iter().find(|f| std::path::Path::new(f).exists());
+            let Some(found) = found else {
+                eprintln!("imake: *** No makefile found.  Stop.");
+                return 2;
+            };
+            found.to_string()
+        }
+        _ => {
+            eprintln!("imake: *** --dump-ast takes one makefile.  Stop.");
+            return 2;
+        }
+    };
+
+    let src = match std::fs::read_to_string(&file) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("imake: *** {}: {}.  Stop.", file, e);
+            return 2;
+        }
+    };
+    match to_json(&file, &src) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        }
+        Err(e) => {
+            eprintln!("imake: {}", e);
+            2
+        }
+    }
+}
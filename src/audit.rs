@@ -0,0 +1,107 @@
+//! `--audit=FILE`: a manifest of every recipe that ran.
+//!
+//! Each line of the manifest is a JSON object for one target holding the
+//! commands that ran, the environment they could see, and hashes of the
+//! prerequisites before and the target after, and under `--shuffle` the
+//! order it was built in. Comparing the manifests of two builds shows
+//! whether a rebuild reproduced the same artifacts from the same inputs.
+
+use std::{collections::HashMap, fs::OpenOptions, io::prelude::*};
+
+use crate::{
+    cache::{fnv1a, FNV_OFFSET},
+    child_env, json_str, Origin, State, Var,
+};
+
+/// Environment variables from outside that routinely change build output
+const AUDIT_ENV: &[&str] = &[
+    "PATH",
+    "SHELL",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "SOURCE_DATE_EPOCH",
+];
+
+/// `fnv1a:<hex>` for a file or `null` if it can't be read
+fn hash_file(path: &str) -> String {
+    match std::fs::read(path) {
+        Ok(bytes) => format!("\"fnv1a:{:016x}\"", fnv1a(&bytes, FNV_OFFSET)),
+        Err(_) => "null".into(),
+    }
+}
+
+fn hashes<'a>(files: impl Iterator<Item = &'a String>) -> String {
+    let mut seen = Vec::new();
+    let mut out = String::new();
+    for f in files {
+        if seen.contains(&f) {
+            continue;
+        }
+        seen.push(f);
+        if !out.is_empty() {
+            out.push(',');
+        }
+        out.push_str(&format!("{}:{}", json_str(f), hash_file(f)));
+    }
+    format!("{{{}}}", out)
+}
+
+/// Start a fresh manifest
+pub fn start(path: &str) -> std::io::Result<()> {
+    std::fs::File::create(path).map(|_| ())
+}
+
+/// Append the entry for a target whose recipe just ran
+pub fn record(
+    state: &State,
+    path: &str,
+    vars: &HashMap<String, Var>,
+    target: &str,
+    commands: &[String],
+    prereqs: &[String],
+) {
+    // whatever we exported plus the usual suspects from outside
+    let mut env = child_env(state, vars)
+        .into_iter()
+        .filter(|v| !matches!(v.origin, Origin::Env) || AUDIT_ENV.contains(&v.name.as_str()))
+        .map(|v| (v.name.as_str(), v.value.clone()))
+        .collect::<Vec<_>>();
+    env.sort();
+    env.dedup();
+
+    let env = env
+        .iter()
+        .map(|(k, v)| format!("{}:{}", json_str(k), json_str(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let commands = commands.iter().map(|c| json_str(c)).collect::<Vec<_>>().join(",");
+
+    // a shuffled build says how to get the same order again
+    let shuffle = match state.shuffle.flag() {
+        Some(flag) => format!(",\"shuffle\":{}", json_str(&flag["--shuffle=".len()..])),
+        None => String::new(),
+    };
+
+    let target_file = target.to_string();
+    let line = format!(
+        "{{\"target\":{},\"commands\":[{}],\"env\":{{{}}},\"inputs\":{},\"outputs\":{}{}}}\n",
+        json_str(target),
+        commands,
+        env,
+        hashes(prereqs.iter()),
+        hashes(std::iter::once(&target_file)),
+        shuffle,
+    );
+
+    let written = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+
+    if let Err(e) = written {
+        eprintln!("{}: warning: can't write audit manifest {}: {}", state.basename, path, e);
+    }
+}
@@ -0,0 +1,434 @@
+//! `--parse-cache`: remember the parsed rules and variables in
+//! `.imake.cache` so a null build doesn't have to read the makefiles again.
+//!
+//! The cache is keyed on the command line, working directory and
+//! environment, and is thrown away when any makefile that was read (or an
+//! optional include that wasn't there) changes. `$(wildcard)` results seen
+//! while parsing are globbed again and compared, and what `$(info)` and
+//! `$(warning)` printed is printed again. If parsing ran `$(shell)`,
+//! `!=` or `$(file)` we can't know whether the output would still be the
+//! same so nothing is cached at all.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{prelude::*, BufReader, BufWriter},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    debug::{self, Category},
+    wildcard, Export, Flavor, Location, Origin, Rule, RuleData, State, Var, VarOp,
+};
+
+const CACHE_FILE: &str = ".imake.cache";
+const CACHE_VERSION: &str = "imake-cache 5";
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// 64 bit FNV-1a. Unlike `DefaultHasher` this is stable between builds.
+pub fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Everything outside the makefiles that parsing depends on
+pub fn cache_key() -> u64 {
+    let mut hash = fnv1a(env!("CARGO_PKG_VERSION").as_bytes(), FNV_OFFSET);
+
+    for arg in std::env::args() {
+        hash = fnv1a(arg.as_bytes(), hash);
+        hash = fnv1a(b"\0", hash);
+    }
+
+    if let Ok(dir) = std::env::current_dir() {
+        hash = fnv1a(dir.to_string_lossy().as_bytes(), hash);
+    }
+
+    let mut env = std::env::vars_os().collect::<Vec<_>>();
+    env.sort();
+    for (k, v) in env {
+        hash = fnv1a(k.to_string_lossy().as_bytes(), hash);
+        hash = fnv1a(b"=", hash);
+        hash = fnv1a(v.to_string_lossy().as_bytes(), hash);
+        hash = fnv1a(b"\0", hash);
+    }
+
+    hash
+}
+
+/// mtime and size of a makefile, or `missing` if it isn't there
+pub fn file_stamp(path: &str) -> String {
+    match Path::new(path).metadata() {
+        Ok(m) => {
+            let mtime = m
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or_default();
+            format!("{}:{}", mtime, m.len())
+        }
+        Err(_) => "missing".into(),
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            a => out.push(a),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(a) => out.push(a),
+                None => {}
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn flavor_str(flavor: Flavor) -> &'static str {
+    match flavor {
+        Flavor::Undefined => "undefined",
+        Flavor::Simple => "simple",
+        Flavor::Recursive => "recursive",
+    }
+}
+
+fn str_flavor(s: &str) -> Option<Flavor> {
+    Some(match s {
+        "undefined" => Flavor::Undefined,
+        "simple" => Flavor::Simple,
+        "recursive" => Flavor::Recursive,
+        _ => return None,
+    })
+}
+
+fn origin_str(origin: Origin) -> &'static str {
+    match origin {
+        Origin::Undefined => "undefined",
+        Origin::Default => "default",
+        Origin::Env => "env",
+        Origin::EnvOverride => "env-override",
+        Origin::File => "file",
+        Origin::CmdLine => "cmdline",
+        Origin::Override => "override",
+        Origin::Automatic => "automatic",
+    }
+}
+
+fn str_origin(s: &str) -> Option<Origin> {
+    Some(match s {
+        "undefined" => Origin::Undefined,
+        "default" => Origin::Default,
+        "env" => Origin::Env,
+        "env-override" => Origin::EnvOverride,
+        "file" => Origin::File,
+        "cmdline" => Origin::CmdLine,
+        "override" => Origin::Override,
+        "automatic" => Origin::Automatic,
+        _ => return None,
+    })
+}
+
+fn op_str(op: VarOp) -> &'static str {
+    match op {
+        VarOp::Store(false) => "store",
+        VarOp::Store(true) => "store-expand",
+        VarOp::Append => "append",
+        VarOp::StoreIfUndef => "if-undef",
+        VarOp::Shell => "shell",
+    }
+}
+
+fn str_op(s: &str) -> Option<VarOp> {
+    Some(match s {
+        "store" => VarOp::Store(false),
+        "store-expand" => VarOp::Store(true),
+        "append" => VarOp::Append,
+        "if-undef" => VarOp::StoreIfUndef,
+        "shell" => VarOp::Shell,
+        _ => return None,
+    })
+}
+
+fn export_str(export: Export) -> &'static str {
+    match export {
+        Export::Default => "default",
+        Export::Export => "export",
+        Export::Unexport => "unexport",
+    }
+}
+
+fn str_export(s: &str) -> Option<Export> {
+    Some(match s {
+        "default" => Export::Default,
+        "export" => Export::Export,
+        "unexport" => Export::Unexport,
+        _ => return None,
+    })
+}
+
+fn bool_str(b: bool) -> &'static str {
+    if b {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+fn write_cache(path: &str, state: &State, vars: &HashMap<String, Var>, key: u64) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+
+    writeln!(out, "{}", CACHE_VERSION)?;
+    writeln!(out, "key\t{:016x}", key)?;
+
+    for file in &state.makefiles {
+        writeln!(out, "file\t{}\t{}", file_stamp(file), escape(file))?;
+    }
+
+    if state.export_all {
+        writeln!(out, "export-all")?;
+    }
+
+    for (pattern, dirs) in &state.vpath.directives {
+        writeln!(out, "vpath\t{}\t{}", escape(pattern), escape(&dirs.join(" ")))?;
+    }
+
+    for (pattern, result) in state.parse_globs.borrow().iter() {
+        writeln!(out, "glob\t{}\t{}", escape(pattern), escape(result))?;
+    }
+
+    for (stderr, msg) in state.parse_messages.borrow().iter() {
+        writeln!(out, "message\t{}\t{}", if *stderr { "err" } else { "out" }, escape(msg))?;
+    }
+
+    for var in vars.values() {
+        let (loc_file, loc_line) = match &var.loc {
+            Some(loc) => (escape(&loc.file_name), loc.line.to_string()),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            out,
+            "var\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            flavor_str(var.flavor),
+            origin_str(var.origin),
+            export_str(var.export),
+            loc_file,
+            loc_line,
+            escape(&var.name),
+            escape(&var.value)
+        )?;
+    }
+
+    for rule in &state.rules {
+        write!(
+            out,
+            "rule\t{}\t{}\t{}\t",
+            escape(&rule.location.file_name),
+            rule.location.line,
+            escape(&rule.targets.join(" "))
+        )?;
+        match &rule.data {
+            RuleData::Prereq(double_colon, prereqs) => {
+                writeln!(out, "prereq\t{}\t{}", bool_str(*double_colon), escape(prereqs))?
+            }
+            RuleData::Recipie(recipie) => writeln!(out, "recipe\t{}", escape(recipie))?,
+            RuleData::Stem(stem) => writeln!(out, "stem\t{}", escape(stem))?,
+            RuleData::OrderOnly(prereqs) => writeln!(out, "order-only\t{}", escape(prereqs))?,
+            RuleData::Var(lhs, op, rhs, export, private) => writeln!(
+                out,
+                "var\t{}\t{}\t{}\t{}\t{}",
+                op_str(*op),
+                bool_str(*export),
+                bool_str(*private),
+                escape(lhs),
+                escape(rhs)
+            )?,
+        }
+    }
+
+    out.flush()?;
+    drop(out);
+    std::fs::rename(tmp, path)
+}
+
+/// Save the result of parsing unless something non-repeatable happened
+pub fn save(state: &State, vars: &HashMap<String, Var>, key: u64) {
+    save_as(CACHE_FILE, state, vars, key)
+}
+
+/// [`save`] to `path` rather than `.imake.cache`
+pub fn save_as(path: &str, state: &State, vars: &HashMap<String, Var>, key: u64) {
+    if state.parse_ran_shell.get() {
+        debug::log(state, Category::Makefiles, format_args!("parse cache: not saving, makefiles ran the shell"));
+        let _ = std::fs::remove_file(path);
+        return;
+    }
+
+    if let Err(e) = write_cache(path, state, vars, key) {
+        eprintln!("{}: warning: can't write {}: {}", state.basename, path, e);
+    }
+}
+
+/// Parsed makefile state read back from the cache
+struct Cached {
+    export_all: bool,
+    vpaths: Vec<(String, Vec<String>)>,
+    makefiles: Vec<String>,
+    /// `$(info)` and `$(warning)` output, in order
+    messages: Vec<(bool, String)>,
+    rules: Vec<Rule>,
+    vars: HashMap<String, Var>,
+}
+
+fn read_cache(path: &str, key: u64) -> Option<Cached> {
+    let file = BufReader::new(File::open(path).ok()?);
+    let mut lines = file.lines();
+
+    if lines.next()?.ok()? != CACHE_VERSION {
+        return None;
+    }
+
+    if lines.next()?.ok()? != format!("key\t{:016x}", key) {
+        return None;
+    }
+
+    let mut cached = Cached {
+        export_all: false,
+        vpaths: Vec::new(),
+        makefiles: Vec::new(),
+        messages: Vec::new(),
+        rules: Vec::new(),
+        vars: HashMap::new(),
+    };
+
+    for line in lines {
+        let line = line.ok()?;
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields.as_slice() {
+            ["file", stamp, path] => {
+                let path = unescape(path);
+                if file_stamp(&path) != *stamp {
+                    return None;
+                }
+                cached.makefiles.push(path);
+            }
+            ["export-all"] => cached.export_all = true,
+            ["vpath", pattern, dirs] => {
+                let dirs = unescape(dirs).split_whitespace().map(|d| d.to_string()).collect();
+                cached.vpaths.push((unescape(pattern), dirs));
+            }
+            ["glob", pattern, result] => {
+                if wildcard(&unescape(pattern)) != unescape(result) {
+                    return None;
+                }
+            }
+            ["message", to, msg] => cached.messages.push((*to == "err", unescape(msg))),
+            ["var", flavor, origin, export, loc_file, loc_line, name, value] => {
+                let loc = if loc_line.is_empty() {
+                    None
+                } else {
+                    Some(Location {
+                        file_name: unescape(loc_file),
+                        line: loc_line.parse().ok()?,
+                    })
+                };
+                let name = unescape(name);
+                cached.vars.insert(
+                    name.clone(),
+                    Var {
+                        flavor: str_flavor(flavor)?,
+                        origin: str_origin(origin)?,
+                        loc,
+                        name,
+                        value: unescape(value),
+                        export: str_export(export)?,
+                    },
+                );
+            }
+            ["rule", loc_file, loc_line, targets, data @ ..] => {
+                let data = match data {
+                    ["prereq", double_colon, prereqs] => {
+                        RuleData::Prereq(*double_colon == "1", unescape(prereqs))
+                    }
+                    ["recipe", recipie] => RuleData::Recipie(unescape(recipie)),
+                    ["stem", stem] => RuleData::Stem(unescape(stem)),
+                    ["order-only", prereqs] => RuleData::OrderOnly(unescape(prereqs)),
+                    ["var", op, export, private, lhs, rhs] => RuleData::Var(
+                        unescape(lhs),
+                        str_op(op)?,
+                        unescape(rhs),
+                        *export == "1",
+                        *private == "1",
+                    ),
+                    _ => return None,
+                };
+                cached.rules.push(Rule {
+                    location: Location {
+                        file_name: unescape(loc_file),
+                        line: loc_line.parse().ok()?,
+                    },
+                    targets: unescape(targets).split_whitespace().map(|x| x.to_string()).collect(),
+                    data,
+                });
+            }
+            _ => return None,
+        }
+    }
+
+    Some(cached)
+}
+
+/// Replace parsing with the cached result if it's still good. Returns
+/// whether the cache was used.
+pub fn load(state: &mut State, vars: &mut HashMap<String, Var>, key: u64) -> bool {
+    load_from(CACHE_FILE, state, vars, key)
+}
+
+/// [`load`] from `path` rather than `.imake.cache`
+pub fn load_from(path: &str, state: &mut State, vars: &mut HashMap<String, Var>, key: u64) -> bool {
+    let Some(cached) = read_cache(path, key) else {
+        debug::log(state, Category::Makefiles, format_args!("parse cache: miss"));
+        return false;
+    };
+
+    debug::log(state, Category::Makefiles, format_args!("parse cache: hit"));
+    for (stderr, msg) in &cached.messages {
+        if *stderr {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
+    }
+    *state.parse_messages.borrow_mut() = cached.messages;
+
+    state.export_all = cached.export_all;
+    state.vpath.directives = cached.vpaths;
+    state.makefiles = cached.makefiles;
+    state.rules = cached.rules;
+    *vars = cached.vars;
+    true
+}
@@ -0,0 +1,75 @@
+//! `--clean-outputs`: delete what the makefiles say gets built, instead of
+//! whatever a hand-written `clean` rule remembers to.
+//!
+//! An output is anything in the graph, a target or something a target
+//! needs, that isn't `.PHONY` and has a recipe, its own or a pattern
+//! rule's. Files no recipe makes are sources and are left alone, as are
+//! the makefiles and directories. Under `-n` it only says what it would
+//! delete.
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{prepare_target, State, Var};
+
+/// Every output, targets in the order they first appear and then what
+/// they need
+pub fn outputs(state: &mut State, vars: &HashMap<String, Var>) -> Vec<String> {
+    let mut seen: Vec<String> = Vec::new();
+    for rule in &state.rules {
+        for t in &rule.targets {
+            if !t.contains('%') && !t.starts_with('.') && !seen.contains(t) {
+                seen.push(t.clone());
+            }
+        }
+    }
+
+    let mut outputs = Vec::new();
+    let mut i = 0;
+    while i < seen.len() {
+        let t = seen[i].clone();
+        i += 1;
+        let node = prepare_target(state, vars, &t);
+        for p in node.waiting.iter().filter(|p| *p != ".WAIT") {
+            if !seen.contains(p) {
+                seen.push(p.clone());
+            }
+        }
+        if !node.recipies.is_empty() && !state.phony.contains(&t) && !state.makefiles.contains(&t) {
+            outputs.push(t);
+        }
+    }
+    outputs
+}
+
+pub fn run(state: &mut State, vars: &HashMap<String, Var>) -> Result<(), u32> {
+    let doomed = outputs(state, vars)
+        .into_iter()
+        .filter(|t| Path::new(t).symlink_metadata().is_ok_and(|m| !m.is_dir()))
+        .collect::<Vec<_>>();
+    if doomed.is_empty() {
+        if !state.silent {
+            eprintln!("{}: no build outputs to remove", state.basename);
+        }
+        return Ok(());
+    }
+
+    if !state.silent || state.dryrun {
+        println!("rm -f {}", doomed.join(" "));
+    }
+    if state.dryrun {
+        return Ok(());
+    }
+
+    let mut failed = false;
+    for t in &doomed {
+        if let Err(e) = std::fs::remove_file(t) {
+            eprintln!("{}: can't remove '{}': {}", state.basename, t, e);
+            failed = true;
+        }
+    }
+    if failed {
+        Err(1)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! `--diff-gnu`: compare what imake and GNU make would run.
+//!
+//! Both are run with `-n` and the same arguments. Their output is
+//! normalised (directory messages and "nothing to be done" chatter are
+//! dropped, the program name is made the same) and the planned command
+//! sequences are diffed line by line.
+
+use std::process::{Command, Stdio};
+
+/// Find a GNU make that isn't us. `$GNU_MAKE` wins if set.
+fn find_gnu_make() -> Option<String> {
+    let candidates = std::env::var("GNU_MAKE")
+        .ok()
+        .into_iter()
+        .chain(["gmake".to_string(), "make".to_string()]);
+
+    for candidate in candidates {
+        let Ok(out) = Command::new(&candidate)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+        else {
+            continue;
+        };
+        let version = String::from_utf8_lossy(&out.stdout);
+        if version.starts_with("GNU Make") && !version.contains("Iglunix") {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+fn plan(program: &str, args: &[String]) -> Result<String, std::io::Error> {
+    let out = Command::new(program)
+        .arg("-n")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Drop whatever isn't part of the plan itself
+pub fn normalize(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|l| l.trim_end())
+        .filter(|l| !l.is_empty())
+        .filter(|l| {
+            !(l.contains(": Entering directory '")
+                || l.contains(": Leaving directory '")
+                || l.contains(": Nothing to be done for '")
+                || l.ends_with("' is up to date."))
+        })
+        .map(|l| {
+            // `gmake[2]: ...` and `imake: ...` both become `make: ...`
+            match l.split_once(": ") {
+                Some((prog, rest))
+                    if !prog.contains(char::is_whitespace)
+                        && prog.trim_end_matches(|c: char| c.is_ascii_digit() || c == '[' || c == ']').ends_with("make") =>
+                {
+                    format!("make: {}", rest)
+                }
+                _ => l.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Longest common subsequence diff. Returns `(-|+| , line)` pairs.
+pub fn diff<'a>(a: &'a [String], b: &'a [String]) -> Vec<(char, &'a str)> {
+    let (n, m) = (a.len(), b.len());
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            out.push((' ', a[i].as_str()));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(('-', a[i].as_str()));
+            i += 1;
+        } else {
+            out.push(('+', b[j].as_str()));
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Run both planners and report where they disagree. Exits like `diff`:
+/// 0 for the same plan, 1 for differences, 2 for trouble.
+pub fn diff_gnu(args: &[String]) -> i32 {
+    let Some(gnu) = find_gnu_make() else {
+        eprintln!("imake: *** --diff-gnu needs GNU make installed (or $GNU_MAKE set).  Stop.");
+        return 2;
+    };
+
+    let me = match std::env::current_exe() {
+        Ok(me) => me.to_string_lossy().into_owned(),
+        Err(e) => {
+            eprintln!("imake: *** can't find our own executable: {}.  Stop.", e);
+            return 2;
+        }
+    };
+
+    let (gnu_plan, our_plan) = match (plan(&gnu, args), plan(&me, args)) {
+        (Ok(g), Ok(o)) => (normalize(&g), normalize(&o)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("imake: *** can't run planner: {}.  Stop.", e);
+            return 2;
+        }
+    };
+
+    let changes = diff(&gnu_plan, &our_plan);
+    let divergences = changes.iter().filter(|(c, _)| *c != ' ').count();
+
+    if divergences == 0 {
+        println!("imake: plans match ({} commands)", our_plan.len());
+        return 0;
+    }
+
+    println!("--- {} -n", gnu);
+    println!("+++ imake -n");
+    for (c, line) in changes {
+        println!("{}{}", c, line);
+    }
+    println!("imake: {} line(s) differ", divergences);
+    1
+}
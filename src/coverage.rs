@@ -0,0 +1,68 @@
+//! `--coverage`: which parts of the makefiles a build actually used.
+//!
+//! After the build it lists the targets whose recipes never ran, and how
+//! many targets each pattern rule was used for. Suffix rules count as
+//! pattern rules and built-in rules are only listed if they were used.
+//! Recipes of special targets like `.DEFAULT` aren't listed.
+
+use std::collections::HashMap;
+
+use crate::{implicit, RuleData, State};
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Targets whose recipe ran, or would have under `-n`
+    ran: Vec<String>,
+    /// Pattern rules, as [`implicit::Match::rule`] names them, and how many
+    /// targets they were used for
+    matches: HashMap<String, usize>,
+}
+
+impl Coverage {
+    pub fn ran(&mut self, target: &str) {
+        if !self.ran.iter().any(|t| t == target) {
+            self.ran.push(target.to_string());
+        }
+    }
+
+    pub fn matched(&mut self, rule: &str) {
+        *self.matches.entry(rule.to_string()).or_default() += 1;
+    }
+
+    pub fn report(&self, state: &State) {
+        // where each target's recipe starts
+        let mut unused: Vec<(String, &String)> = Vec::new();
+        for rule in &state.rules {
+            let RuleData::Recipie(_) = rule.data else { continue };
+            for t in &rule.targets {
+                if t.contains('%') || t.starts_with('.') || self.ran.contains(t) || unused.iter().any(|(_, u)| *u == t) {
+                    continue;
+                }
+                unused.push((rule.location.to_string(), t));
+            }
+        }
+
+        // the makefile's own in the order written, then built-ins that
+        // were used
+        let mut rules = implicit::written_rules(state);
+        let mut builtin = self
+            .matches
+            .keys()
+            .filter(|r| !rules.contains(r))
+            .cloned()
+            .collect::<Vec<_>>();
+        builtin.sort();
+        rules.extend(builtin);
+
+        eprintln!("{}: coverage report", state.basename);
+        eprintln!("  recipes that didn't run: {}", unused.len());
+        for (at, target) in &unused {
+            eprintln!("    {}: {}", at, target);
+        }
+        eprintln!("  pattern rules:");
+        for rule in &rules {
+            let n = self.matches.get(rule).copied().unwrap_or(0);
+            eprintln!("    {:>5}  {}", n, rule);
+        }
+    }
+}
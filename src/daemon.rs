@@ -0,0 +1,299 @@
+//! `--daemon`: keep the parsed makefiles resident and serve builds over a
+//! unix socket.
+//!
+//! The daemon parses once and then, for every request, forks a child that
+//! inherits the already parsed rules and variables. The child points its
+//! stdout and stderr at the connection and builds the requested goals. Once
+//! it exits the daemon sends a trailing `\0` and the exit status so the
+//! client can exit with it. Makefiles are checked before each request and
+//! parsed again if any of them changed, or if a `$(wildcard)` seen while
+//! parsing would now find something else, like the parse cache does. When
+//! parsing ran `$(shell)` or `$(file)` there's no telling whether it'd come
+//! out the same, so the makefiles are parsed again for every request. File
+//! times aren't kept between
+//! requests, each build looks at the files afresh since nothing would tell
+//! the daemon a source changed.
+//!
+//! Requests are served one at a time: the daemon waits for each build to
+//! finish before it takes the next connection, so a slow build holds up
+//! every other client until it's done.
+//!
+//! Plain `imake` invocations forward to the daemon when `.imake.sock`
+//! exists in the current directory and every argument is a goal or one of
+//! the flags that only matter at build time. Anything else (`-f`, `-C`,
+//! variable overrides, ...) would change the parse so those runs don't
+//! forward. Neither do ones from a different environment than the
+//! daemon's, since the parse (and any `$(shell)` it ran) might have turned
+//! out differently. The daemon turns those away and the client builds by
+//! itself.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{prelude::*, BufReader},
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use crate::{
+    cache::file_stamp,
+    debug::{self, Category},
+    make_goals,
+    options::{self, Arg, Opt},
+    parse_makefiles,
+    sys::{dup2, fork, waitpid},
+    wildcard, State, Var,
+};
+
+pub const SOCKET: &str = ".imake.sock";
+const PROTOCOL: &str = "imake-daemon 2";
+/// Sent instead of a status when the client's environment isn't ours
+const REFUSED: &str = "refused";
+
+/// Flags that can be applied to an already parsed makefile
+fn apply_flag(state: &mut State, flag: Opt) -> bool {
+    match flag {
+        Opt::DryRun => state.dryrun = true,
+        Opt::KeepGoing => state.keep_going = true,
+        Opt::Silent => state.silent = true,
+        Opt::IgnoreErrors => state.ignore_errors = true,
+        Opt::AlwaysMake => state.always_make = true,
+        _ => return false,
+    }
+    true
+}
+
+/// Whether the daemon can run this command line without parsing again
+pub fn forwardable(args: &[String]) -> bool {
+    let Ok(args) = options::parse(args.iter().cloned()) else {
+        return false;
+    };
+    args.into_iter().all(|arg| match arg {
+        Arg::Goal(_) => true,
+        // `--dry-run=safe` isn't just `-n`
+        Arg::Option(flag, None) => apply_flag(&mut State::default(), flag),
+        _ => false,
+    })
+}
+
+/// Variables a shell keeps changing that a build has no business with
+const SHELL_NOISE: &[&str] = &["_", "SHLVL", "OLDPWD", "PWD", "COLUMNS", "LINES"];
+
+/// The environment (ours, usually) the makefiles are parsed in, sorted so
+/// the order it was set up in doesn't matter. What differs from one shell
+/// to the next is left out or hardly any client would be served.
+pub fn env_hash(env: impl Iterator<Item = (OsString, OsString)>) -> u64 {
+    let mut env = env
+        .filter(|(name, _)| !SHELL_NOISE.iter().any(|noise| name == noise))
+        .collect::<Vec<_>>();
+    env.sort();
+    let mut hasher = DefaultHasher::new();
+    env.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What's sent to the daemon to build `args`
+pub fn request(args: &[String]) -> String {
+    let mut request = format!("{}\n{}\n", PROTOCOL, env_hash(std::env::vars_os()));
+    for arg in args {
+        request.push_str(arg);
+        request.push('\n');
+    }
+    request.push('\n');
+    request
+}
+
+/// Hand the build to a running daemon. `None` if there's nobody listening
+/// or it won't take the build.
+pub fn forward(args: &[String]) -> Option<i32> {
+    let mut stream = UnixStream::connect(SOCKET).ok()?;
+    stream.write_all(request(args).as_bytes()).ok()?;
+
+    // output is passed straight through but anything from the last `\0`
+    // on might be the status so hold it back until we know
+    let mut out = std::io::stdout();
+    let mut held: Option<Vec<u8>> = None;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let mut pass = Vec::with_capacity(n);
+        for &b in &buf[..n] {
+            if b == 0 {
+                if let Some(h) = held.replace(Vec::new()) {
+                    pass.push(0);
+                    pass.extend(h);
+                }
+            } else if let Some(h) = &mut held {
+                h.push(b);
+            } else {
+                pass.push(b);
+            }
+        }
+        let _ = out.write_all(&pass);
+    }
+    let _ = out.flush();
+
+    let Some(status) = held else {
+        eprintln!("imake: lost connection to the daemon");
+        return Some(2);
+    };
+
+    let status = String::from_utf8_lossy(&status);
+    if status == REFUSED {
+        return None;
+    }
+    Some(status.trim().parse().unwrap_or(2))
+}
+
+/// Ask a running daemon to exit
+pub fn stop() -> i32 {
+    match UnixStream::connect(SOCKET) {
+        Ok(mut stream) => {
+            let _ = stream.write_all(request(&["--daemon-stop".to_string()]).as_bytes());
+            0
+        }
+        Err(e) => {
+            eprintln!("imake: no daemon running: {}", e);
+            1
+        }
+    }
+}
+
+/// The client's environment hash and its arguments
+pub fn read_request(stream: &UnixStream) -> Option<(u64, Vec<String>)> {
+    let mut lines = BufReader::new(stream).lines();
+    if lines.next()?.ok()? != PROTOCOL {
+        return None;
+    }
+    let env = lines.next()?.ok()?.parse().ok()?;
+
+    let mut args = Vec::new();
+    for line in lines {
+        let line = line.ok()?;
+        if line.is_empty() {
+            break;
+        }
+        args.push(line);
+    }
+    Some((env, args))
+}
+
+/// Build the requested goals in a forked child and report how it went.
+/// Nothing else is served until the child exits.
+fn serve_one(state: &State, vars: &HashMap<String, Var>, mut stream: UnixStream, args: Vec<String>) {
+    match unsafe { fork() } {
+        -1 => {
+            let _ = writeln!(stream, "imake: daemon can't fork");
+            let _ = stream.write_all(b"\x002");
+        }
+        0 => {
+            let fd = stream.as_raw_fd();
+            unsafe {
+                dup2(fd, 1);
+                dup2(fd, 2);
+            }
+
+            let mut state = state.clone();
+            state.targets_to_make.clear();
+            // the client checked they're all ones we can apply
+            for arg in options::parse(args).unwrap_or_default() {
+                match arg {
+                    Arg::Option(flag, _) => {
+                        apply_flag(&mut state, flag);
+                    }
+                    Arg::Goal(goal) => state.targets_to_make.push(goal),
+                    Arg::Assignment(_) => {}
+                }
+            }
+
+            let code = match make_goals(state, vars.clone()) {
+                Ok(()) => 0,
+                Err(e) => e as i32,
+            };
+            let _ = std::io::stdout().flush();
+            std::process::exit(code)
+        }
+        pid => {
+            let mut status = 0;
+            unsafe {
+                waitpid(pid, &mut status, 0);
+            }
+            let code = if status & 0x7f == 0 {
+                (status >> 8) & 0xff
+            } else {
+                128 + (status & 0x7f)
+            };
+            let _ = write!(stream, "\0{}", code);
+        }
+    }
+}
+
+/// Whether what was parsed might not be what parsing again would give.
+/// `stamps` are the makefiles' as they were read.
+pub fn stale(state: &State, stamps: &[String]) -> bool {
+    if state.parse_ran_shell.get() {
+        return true;
+    }
+    let changed = state.makefiles.iter().zip(stamps).any(|(f, stamp)| file_stamp(f) != *stamp);
+    changed || state.parse_globs.borrow().iter().any(|(pattern, result)| wildcard(pattern) != *result)
+}
+
+pub fn serve(state: State, vars: HashMap<String, Var>, makefile: &str) -> Result<(), u32> {
+    if Path::new(SOCKET).exists() {
+        if UnixStream::connect(SOCKET).is_ok() {
+            eprintln!("{}: *** a daemon is already running here.  Stop.", state.basename);
+            return Err(2);
+        }
+        let _ = std::fs::remove_file(SOCKET);
+    }
+
+    let listener = UnixListener::bind(SOCKET).map_err(|e| {
+        eprintln!("{}: *** can't listen on {}: {}.  Stop.", state.basename, SOCKET, e);
+        2u32
+    })?;
+
+    let pristine = (state, vars);
+
+    let parse = || {
+        let (mut state, mut vars) = pristine.clone();
+        parse_makefiles(&mut state, &mut vars, makefile);
+        let stamps = state.makefiles.iter().map(|f| file_stamp(f)).collect::<Vec<_>>();
+        (state, vars, stamps)
+    };
+
+    let (mut state, mut vars, mut stamps) = parse();
+    let env = env_hash(std::env::vars_os());
+    eprintln!("{}: daemon listening on {}", state.basename, SOCKET);
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        let Some((client_env, args)) = read_request(&stream) else { continue };
+
+        if args.first().map(|x| x.as_str()) == Some("--daemon-stop") {
+            break;
+        }
+        if client_env != env {
+            debug::log(&state, Category::Basic, format_args!("daemon: client's environment differs, not serving"));
+            let _ = write!(stream, "\0{}", REFUSED);
+            continue;
+        }
+
+        if stale(&state, &stamps) {
+            let msg = "daemon: makefiles or what they read changed, parsing again";
+            debug::log(&state, Category::Makefiles, format_args!("{}", msg));
+            (state, vars, stamps) = parse();
+        }
+
+        serve_one(&state, &vars, stream, args);
+    }
+
+    let _ = std::fs::remove_file(SOCKET);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! `-d` and `--debug=FLAGS`: why make does what it does, a category at a
+//! time.
+//!
+//! The categories are GNU make's, by letter or by name: `basic` says what's
+//! remade and why, `verbose` adds every target considered and every
+//! makefile read, `implicit` the pattern rule search, `jobs` the children
+//! started and reaped and `makefile` the parse cache and daemon reparsing.
+//! `all` is all of those and `none` clears them. On top there's `env`,
+//! where a recipe's environment differs from ours, and `internal`, which
+//! dumps every logical line and the rule graph and is only for working on
+//! make itself. Like GNU make's, the output goes to stdout between the
+//! recipes' own.
+
+use std::fmt;
+
+use crate::State;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Basic = 1,
+    Verbose = 2,
+    Implicit = 4,
+    Jobs = 8,
+    Makefiles = 16,
+    Env = 32,
+    Internal = 64,
+}
+
+/// The categories turned on
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Categories(u8);
+
+impl Categories {
+    /// What `-d` and `--debug=a` turn on
+    pub const ALL: Categories = Categories(1 | 2 | 4 | 8 | 16);
+
+    pub fn has(self, category: Category) -> bool {
+        self.0 & category as u8 != 0
+    }
+
+    /// Add the comma separated categories in `spec`, or say which one
+    /// isn't known
+    pub fn parse(mut self, spec: &str) -> Result<Categories, String> {
+        for word in spec.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+            let bits = match word {
+                "a" | "all" => Self::ALL.0,
+                "n" | "none" => {
+                    self = Categories(0);
+                    continue;
+                }
+                "b" | "basic" => Category::Basic as u8,
+                // GNU make's verbose is basic with more
+                "v" | "verbose" => Category::Basic as u8 | Category::Verbose as u8,
+                "i" | "implicit" => Category::Implicit as u8,
+                "j" | "jobs" => Category::Jobs as u8,
+                "m" | "makefile" => Category::Makefiles as u8,
+                "env" => Category::Env as u8,
+                "internal" => Category::Internal as u8,
+                _ => return Err(word.to_string()),
+            };
+            self.0 |= bits;
+        }
+        Ok(self)
+    }
+}
+
+/// Print `msg` if `category` is on
+pub fn log(state: &State, category: Category, msg: fmt::Arguments) {
+    if state.debug.has(category) {
+        println!("{}", msg);
+    }
+}
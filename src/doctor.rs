@@ -0,0 +1,144 @@
+//! `--doctor`: what the build would run with, and what about it is known
+//! to cause trouble.
+//!
+//! The shell and flags are the ones recipes would get, after the makefile
+//! (if there is one) has been read. The build directory is probed by
+//! writing a scratch file there and reading its modification time back the
+//! way the up-to-date check does, so a filesystem that rounds mtimes shows
+//! up as it would in a build.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use crate::{jobs, sys, Location, State, Var};
+
+/// How finely the filesystem keeps modification times, if it could be
+/// probed
+fn mtime_resolution(probe: &Path) -> Option<Duration> {
+    let file = File::options().write(true).open(probe).ok()?;
+    // an odd second and every digit of the nanoseconds
+    let written = Duration::new(1_000_000_001, 123_456_789);
+    file.set_modified(SystemTime::UNIX_EPOCH + written).ok()?;
+    let read = probe.metadata().and_then(|m| m.modified()).ok()?;
+    let read = read.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+
+    let resolution = [1, 1_000, 1_000_000, 1_000_000_000]
+        .into_iter()
+        .map(Duration::from_nanos)
+        .find(|r| written.as_nanos() - written.as_nanos() % r.as_nanos() == read.as_nanos())
+        .unwrap_or(Duration::from_secs(2));
+    Some(resolution)
+}
+
+fn describe(resolution: Duration) -> String {
+    match resolution.as_nanos() {
+        1 => "1ns".to_string(),
+        1_000 => "1us".to_string(),
+        1_000_000 => "1ms".to_string(),
+        n => format!("{}s", n / 1_000_000_000),
+    }
+}
+
+/// Whether `shell` names something we could run
+fn runnable(shell: &str) -> bool {
+    let executable = |path: &Path| path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+    if shell.contains('/') {
+        return executable(Path::new(shell));
+    }
+    std::env::var("PATH")
+        .unwrap_or_default()
+        .split(':')
+        .any(|dir| executable(&Path::new(dir).join(shell)))
+}
+
+pub fn report(state: &State, vars: &mut HashMap<String, Var>, makefile: Option<&str>) -> Result<(), u32> {
+    let loc = Location::default();
+    let mut value = |name: &str| vars.get(name).cloned().map(|v| v.eval(state, &loc, vars)).unwrap_or_default();
+    let shell = value("SHELL");
+    let shell_flags = value(".SHELLFLAGS");
+    let makeflags = value("MAKEFLAGS");
+    let mut problems = Vec::new();
+
+    println!("{} doctor", state.basename);
+    println!("  version:     {}", env!("CARGO_PKG_VERSION"));
+    println!("  directory:   {}", state.curdir);
+    println!("  makefile:    {}", makefile.unwrap_or("none"));
+    println!("  shell:       {} {}", shell, shell_flags);
+    if !runnable(&shell) {
+        problems.push(format!("the shell '{}' can't be run, so no recipe can", shell));
+    }
+    println!("  MAKEFLAGS:   {}", makeflags);
+    println!("  MAKELEVEL:   {}", state.level);
+
+    let inherited = std::env::var("MAKEFLAGS").unwrap_or_default();
+    let whose = if inherited.contains("--jobserver-auth") { "the parent make's" } else { "ours" };
+    let jobserver = match (&state.jobserver_fifo, &state.jobserver_fds[..]) {
+        (Some(path), _) => format!("{}, fifo {}", whose, path),
+        (None, [r, w]) => format!("{}, fds {},{}", whose, r, w),
+        _ => "none".to_string(),
+    };
+    let jobs = match state.jobs {
+        jobs::UNLIMITED => "unlimited".to_string(),
+        n => n.max(1).to_string(),
+    };
+    println!("  jobs:        {} (jobserver: {})", jobs, jobserver);
+    if inherited.contains("--jobserver-auth=") && !inherited.contains("fifo:") && state.jobserver_fds.is_empty() {
+        problems.push("the parent make's jobserver isn't open here; add '+' to the recipe that runs us".to_string());
+    }
+
+    let umask = sys::get_umask();
+    println!("  umask:       {:04o}", umask);
+    if umask & 0o400 != 0 {
+        problems.push(format!("umask {:04o} makes files their owner can't read", umask));
+    }
+
+    let probe = Path::new(&state.curdir).join(format!(".imake-doctor-{}.probe", std::process::id()));
+    let resolution = File::create(&probe).ok().and_then(|_| mtime_resolution(&probe));
+    let upper = Path::new(&state.curdir).join(format!(".IMAKE-DOCTOR-{}.PROBE", std::process::id()));
+    let case_sensitive = !upper.exists();
+    let _ = std::fs::remove_file(&probe);
+
+    match resolution {
+        Some(resolution) => {
+            println!("  timestamps:  {}", describe(resolution));
+            if resolution >= Duration::from_secs(1) {
+                problems.push(format!(
+                    "modification times are only good to {}: a prerequisite changed in the same {} as its target \
+                     was made isn't seen as newer, so edit-and-rebuild loops can miss changes",
+                    describe(resolution),
+                    if resolution == Duration::from_secs(1) { "second" } else { "interval" },
+                ));
+            }
+        }
+        None => {
+            println!("  timestamps:  unknown");
+            problems.push(format!("can't write to {} to probe it", state.curdir));
+        }
+    }
+    println!("  filenames:   {}", if case_sensitive { "case-sensitive" } else { "case-insensitive" });
+    if !case_sensitive {
+        let mut targets = state.rules.iter().flat_map(|r| &r.targets).collect::<Vec<_>>();
+        targets.sort();
+        targets.dedup();
+        for (i, a) in targets.iter().enumerate() {
+            if let Some(b) = targets[i + 1..].iter().find(|b| b.eq_ignore_ascii_case(a)) {
+                problems.push(format!("targets '{}' and '{}' are the same file here", a, b));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("no problems found");
+        return Ok(());
+    }
+    println!("problems:");
+    for problem in &problems {
+        println!("  - {}", problem);
+    }
+    Err(1)
+}
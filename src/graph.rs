@@ -0,0 +1,299 @@
+//! `--serve-graph`: answer questions about the build graph over a unix
+//! socket, for editors and dashboards that want to know about a build
+//! without running it.
+//!
+//! The makefiles are parsed once, and again whenever one of them changes.
+//! A client sends one JSON object per line and gets one back for each:
+//!
+//! - `{"op":"targets"}`: every target with an explicit rule
+//! - `{"op":"deps","target":"x"}`: its prerequisites and order-only ones,
+//!   including any a pattern rule would give it
+//! - `{"op":"rdeps","target":"x"}`: the targets that have it as a
+//!   prerequisite
+//! - `{"op":"outdated","target":"x"}`: whether it would be remade, and why
+//! - `{"op":"stop"}`: shut the server down
+//!
+//! Replies have `"ok":true` and the answer, or `"ok":false` and an
+//! `"error"`. Nothing is ever built.
+
+use std::{
+    collections::HashMap,
+    io::{prelude::*, BufReader},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{
+    cache::file_stamp, json_str, newer_than, parse_makefiles, plan::json_list, plan::Reason, prepare_target, State, Var,
+};
+
+pub const SOCKET: &str = ".imake-graph.sock";
+
+/// A flat JSON object of strings, which is all a request ever is
+fn parse_request(line: &str) -> Option<HashMap<String, String>> {
+    fn string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+        let mut out = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => match chars.next()? {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    'u' => {
+                        let hex = (0..4).map(|_| chars.next()).collect::<Option<String>>()?;
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+    fn skip_blanks(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    let mut chars = line.chars().peekable();
+    let mut fields = HashMap::new();
+    skip_blanks(&mut chars);
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        skip_blanks(&mut chars);
+        match chars.next()? {
+            '}' => break,
+            ',' => continue,
+            '"' => {}
+            _ => return None,
+        }
+        let key = string(&mut chars)?;
+        skip_blanks(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_blanks(&mut chars);
+        if chars.next()? != '"' {
+            return None;
+        }
+        fields.insert(key, string(&mut chars)?);
+    }
+    skip_blanks(&mut chars);
+    chars.next().is_none().then_some(fields)
+}
+
+/// Targets with explicit rules, in the order they first appear
+fn targets(state: &State) -> Vec<String> {
+    let mut targets: Vec<String> = Vec::new();
+    for rule in &state.rules {
+        for t in &rule.targets {
+            if !t.contains('%') && !t.starts_with('.') && !targets.contains(t) {
+                targets.push(t.clone());
+            }
+        }
+    }
+    targets
+}
+
+/// Prerequisites of `name`, and its order-only ones
+fn deps(state: &mut State, vars: &HashMap<String, Var>, name: &str) -> (Vec<String>, Vec<String>) {
+    let node = prepare_target(state, vars, name);
+    let mut normal = Vec::new();
+    for p in &node.prerequisites {
+        if !normal.contains(p) {
+            normal.push(p.clone());
+        }
+    }
+    let order_only = node
+        .waiting
+        .into_iter()
+        .filter(|w| !normal.contains(w) && w != ".WAIT")
+        .collect();
+    (normal, order_only)
+}
+
+/// Everything with `name` as a prerequisite, out of all the targets and
+/// whatever they need in turn
+fn rdeps(state: &mut State, vars: &HashMap<String, Var>, name: &str) -> Vec<String> {
+    let mut seen = targets(state);
+    let mut needing = Vec::new();
+    let mut i = 0;
+    while i < seen.len() {
+        let t = seen[i].clone();
+        let (normal, order_only) = deps(state, vars, &t);
+        if normal.iter().chain(&order_only).any(|p| p == name) {
+            needing.push(t);
+        }
+        for p in normal.into_iter().chain(order_only) {
+            if !seen.contains(&p) {
+                seen.push(p);
+            }
+        }
+        i += 1;
+    }
+    needing
+}
+
+fn mtime(name: &str) -> Option<SystemTime> {
+    Path::new(name).metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Why `name` would be remade, if it would. A prerequisite that would be
+/// remade counts as newer whatever its mtime is now.
+fn outdated(
+    state: &mut State,
+    vars: &HashMap<String, Var>,
+    name: &str,
+    memo: &mut HashMap<String, Option<Reason>>,
+    path: &mut Vec<String>,
+) -> Option<Reason> {
+    if let Some(known) = memo.get(name) {
+        return known.clone();
+    }
+    // a cycle is dropped like make drops it
+    if path.iter().any(|p| p == name) {
+        return None;
+    }
+
+    let node = prepare_target(state, vars, name);
+    path.push(name.to_string());
+    let mut stale = Vec::new();
+    for p in &node.prerequisites {
+        if !stale.contains(p) && outdated(state, vars, p, memo, path).is_some() {
+            stale.push(p.clone());
+        }
+    }
+    path.pop();
+
+    let reason = if state.phony.iter().any(|p| p == name) {
+        Some(Reason::Phony)
+    } else if state.assume_old.iter().chain(&state.assume_new).any(|f| f == name) {
+        // neither is ever remade itself
+        None
+    } else if let Some(time) = mtime(name) {
+        let mut newer = Vec::new();
+        for p in &node.prerequisites {
+            if newer.contains(p) || state.assume_old.contains(p) {
+                continue;
+            }
+            let new = stale.contains(p) || state.assume_new.contains(p);
+            if new || mtime(p).is_none_or(|t| newer_than(state, name, t, time)) {
+                newer.push(p.clone());
+            }
+        }
+        (!newer.is_empty()).then_some(Reason::Newer(newer))
+    } else {
+        Some(Reason::Missing)
+    };
+    memo.insert(name.to_string(), reason.clone());
+    reason
+}
+
+fn error(msg: &str) -> String {
+    format!("{{\"ok\":false,\"error\":{}}}", json_str(msg))
+}
+
+/// The reply to one request line, and whether it asked us to stop
+pub fn answer(state: &mut State, vars: &HashMap<String, Var>, line: &str) -> (String, bool) {
+    let Some(request) = parse_request(line) else {
+        return (error("bad request"), false);
+    };
+    let target = request.get("target").map(|t| crate::normalize_path(t));
+    let reply = match (request.get("op").map(|op| op.as_str()), target) {
+        (Some("targets"), _) => format!("{{\"ok\":true,\"targets\":{}}}", json_list(&targets(state))),
+        (Some("deps"), Some(t)) => {
+            let (normal, order_only) = deps(state, vars, &t);
+            format!(
+                "{{\"ok\":true,\"target\":{},\"deps\":{},\"order_only\":{}}}",
+                json_str(&t),
+                json_list(&normal),
+                json_list(&order_only)
+            )
+        }
+        (Some("rdeps"), Some(t)) => format!(
+            "{{\"ok\":true,\"target\":{},\"rdeps\":{}}}",
+            json_str(&t),
+            json_list(&rdeps(state, vars, &t))
+        ),
+        (Some("outdated"), Some(t)) => match outdated(state, vars, &t, &mut HashMap::new(), &mut Vec::new()) {
+            Some(reason) => format!(
+                "{{\"ok\":true,\"target\":{},\"outdated\":true,\"reason\":{}}}",
+                json_str(&t),
+                json_str(&reason.to_string())
+            ),
+            None => format!("{{\"ok\":true,\"target\":{},\"outdated\":false}}", json_str(&t)),
+        },
+        (Some("stop"), _) => return ("{\"ok\":true}".into(), true),
+        (Some("deps" | "rdeps" | "outdated"), None) => error("no target given"),
+        (Some(op), _) => error(&format!("unknown op '{}'", op)),
+        (None, _) => error("no op given"),
+    };
+    (reply, false)
+}
+
+/// Answer requests on `stream` until it's closed. Returns whether we were
+/// asked to stop.
+fn serve_one(state: &mut State, vars: &HashMap<String, Var>, stream: UnixStream) -> bool {
+    let Ok(mut out) = stream.try_clone() else { return false };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply, stop) = answer(state, vars, &line);
+        if writeln!(out, "{}", reply).is_err() || stop {
+            return stop;
+        }
+    }
+    false
+}
+
+pub fn serve(state: State, vars: HashMap<String, Var>, makefile: &str) -> Result<(), u32> {
+    if Path::new(SOCKET).exists() {
+        if UnixStream::connect(SOCKET).is_ok() {
+            eprintln!("{}: *** a graph server is already running here.  Stop.", state.basename);
+            return Err(2);
+        }
+        let _ = std::fs::remove_file(SOCKET);
+    }
+
+    let listener = UnixListener::bind(SOCKET).map_err(|e| {
+        eprintln!("{}: *** can't listen on {}: {}.  Stop.", state.basename, SOCKET, e);
+        2u32
+    })?;
+
+    let pristine = (state, vars);
+
+    let parse = || {
+        let (mut state, mut vars) = pristine.clone();
+        parse_makefiles(&mut state, &mut vars, makefile);
+        crate::process_specials(&mut state, &mut vars);
+        let stamps = state.makefiles.iter().map(|f| file_stamp(f)).collect::<Vec<_>>();
+        (state, vars, stamps)
+    };
+
+    let (mut state, mut vars, mut stamps) = parse();
+    eprintln!("{}: serving the build graph on {}", state.basename, SOCKET);
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+
+        let changed = state
+            .makefiles
+            .iter()
+            .zip(&stamps)
+            .any(|(f, stamp)| file_stamp(f) != *stamp);
+        if changed {
+            (state, vars, stamps) = parse();
+        }
+
+        if serve_one(&mut state, &vars, stream) {
+            break;
+        }
+    }
+
+    let _ = std::fs::remove_file(SOCKET);
+    Ok(())
+}
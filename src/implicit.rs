@@ -0,0 +1,298 @@
+//! Pattern rules and GNU make's catalogue of built-in ones.
+//!
+//! A target with no recipe of its own is matched against the makefile's
+//! `%` rules in the order they were written, then its suffix rules, then
+//! the built-in rules unless `-r` was given. A rule applies when each of
+//! its prerequisites, with the stem filled in, exists or is the target of
+//! an explicit rule. Failing that, a prerequisite can be an intermediate
+//! file that another pattern rule would make, as long as no rule is used
+//! twice in the chain and a match-anything rule (`%: ...`) is only used for
+//! the target itself.
+//!
+//! Suffix rules are the old way of writing pattern rules: `.c.o:` is
+//! `%.o: %.c` and `.sh:` is `%: %.sh`, as long as the suffixes are in
+//! `.SUFFIXES`.
+
+use std::path::Path;
+
+use crate::{
+    debug::{self, Category},
+    pattern_stem, Location, RuleData, State,
+};
+
+/// GNU make's built-in pattern rules: target, prerequisite and recipe
+const BUILTIN_RULES: &[(&str, &str, &[&str])] = &[
+    ("%.o", "%.c", &["$(COMPILE.c) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.cc", &["$(COMPILE.cc) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.cpp", &["$(COMPILE.cpp) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.C", &["$(COMPILE.C) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.s", &["$(COMPILE.s) -o $@ $<"]),
+    ("%.o", "%.S", &["$(COMPILE.S) -o $@ $<"]),
+    ("%.o", "%.f", &["$(COMPILE.f) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.F", &["$(COMPILE.F) $(OUTPUT_OPTION) $<"]),
+    ("%.o", "%.p", &["$(COMPILE.p) $(OUTPUT_OPTION) $<"]),
+    ("%.s", "%.S", &["$(PREPROCESS.S) $< > $@"]),
+    ("%", "%.o", &["$(LINK.o) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.c", &["$(LINK.c) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.cc", &["$(LINK.cc) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.cpp", &["$(LINK.cpp) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.C", &["$(LINK.C) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.s", &["$(LINK.s) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.S", &["$(LINK.S) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.f", &["$(LINK.f) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.p", &["$(LINK.p) $^ $(LOADLIBES) $(LDLIBS) -o $@"]),
+    ("%", "%.sh", &["cat $< >$@", "chmod a+x $@"]),
+    ("%.c", "%.y", &["$(YACC.y) $<", "mv -f y.tab.c $@"]),
+    ("%.c", "%.l", &["@$(RM) $@", "$(LEX.l) $< > $@"]),
+    ("%.ln", "%.c", &["$(LINT.c) -C$* $<"]),
+];
+
+/// GNU make's default `.SUFFIXES`
+const DEFAULT_SUFFIXES: &[&str] = &[
+    ".out", ".a", ".ln", ".o", ".c", ".cc", ".C", ".cpp", ".p", ".f", ".F", ".m", ".r", ".y", ".l", ".ym", ".yl", ".s",
+    ".S", ".mod", ".sym", ".def", ".h", ".info", ".dvi", ".tex", ".texinfo", ".texi", ".txinfo", ".w", ".ch", ".web",
+    ".sh", ".elc", ".el",
+];
+
+#[derive(Debug, Clone)]
+struct PatternRule {
+    /// Where it was written, `None` for a built-in one
+    location: Option<Location>,
+    /// The targets as written, to find its recipe lines by
+    written: Vec<String>,
+    targets: Vec<String>,
+    prereqs: Vec<String>,
+    order_only: Vec<String>,
+    recipe: Vec<(Location, String)>,
+}
+
+/// How a pattern rule would make a target
+#[derive(Debug)]
+pub struct Match {
+    /// What `%` stood for, `$*`
+    pub stem: String,
+    /// Which rule it was, as [`PatternRule::describe`] puts it
+    pub rule: String,
+    pub prereqs: Vec<String>,
+    pub order_only: Vec<String>,
+    pub recipe: Vec<(Location, String)>,
+    /// Prerequisites that don't exist and will be made by another pattern
+    /// rule just for this
+    pub intermediate: Vec<String>,
+}
+
+impl PatternRule {
+    /// `Makefile:3: %.o: %.c` or `<builtin>: %.o: %.c`
+    fn describe(&self) -> String {
+        let at = match &self.location {
+            Some(loc) => loc.to_string(),
+            None => "<builtin>".to_string(),
+        };
+        format!("{}: {}: {}", at, self.targets.join(" "), self.prereqs.join(" "))
+    }
+}
+
+/// The known suffixes in order. `.SUFFIXES:` with nothing after it
+/// forgets them all, `-r` starts with none.
+pub fn suffixes(state: &State) -> Vec<String> {
+    let mut suffixes = Vec::new();
+    if !state.no_builtin_rules {
+        suffixes.extend(DEFAULT_SUFFIXES.iter().map(|s| s.to_string()));
+    }
+
+    for rule in &state.rules {
+        let RuleData::Prereq(_, prereqs) = &rule.data else { continue };
+        if rule.targets.first().is_some_and(|t| t == ".SUFFIXES") {
+            if prereqs.trim().is_empty() {
+                suffixes.clear();
+            }
+            suffixes.extend(prereqs.split_whitespace().map(|s| s.to_string()));
+        }
+    }
+
+    suffixes
+}
+
+/// The pattern rule `.c.o` or `.c` stands for. Suffix rules can't have
+/// prerequisites of their own.
+fn suffix_rule(suffixes: &[String], target: &str, prereqs: &str) -> Option<(String, String)> {
+    if !prereqs.trim().is_empty() {
+        return None;
+    }
+
+    suffixes.iter().filter(|s| target.starts_with(s.as_str())).find_map(|from| {
+        let to = &target[from.len()..];
+        if to.is_empty() {
+            Some(("%".to_string(), format!("%{}", from)))
+        } else if suffixes.iter().any(|s| s == to) {
+            Some((format!("%{}", to), format!("%{}", from)))
+        } else {
+            None
+        }
+    })
+}
+
+/// The makefile's pattern rules, then its suffix rules, then the built-in
+/// ones. A pattern rule with no recipe cancels any built-in one with the
+/// same target and prerequisites.
+fn pattern_rules(state: &State) -> Vec<PatternRule> {
+    let suffixes = suffixes(state);
+    let mut rules: Vec<PatternRule> = Vec::new();
+    let mut suffix_rules: Vec<PatternRule> = Vec::new();
+    let mut last_suffix_rule = false;
+    // a rule's `OrderOnly` comes just before its `Prereq`
+    let mut order_only = Vec::new();
+
+    for rule in &state.rules {
+        match &rule.data {
+            RuleData::Prereq(_, prereqs) if rule.targets.iter().any(|t| t.contains('%')) => {
+                last_suffix_rule = false;
+                rules.push(PatternRule {
+                    location: Some(rule.location.clone()),
+                    written: rule.targets.clone(),
+                    targets: rule.targets.clone(),
+                    prereqs: prereqs.split_whitespace().map(|s| s.to_string()).collect(),
+                    order_only: std::mem::take(&mut order_only),
+                    recipe: Vec::new(),
+                });
+            }
+            RuleData::Prereq(_, prereqs) => {
+                let [target] = &rule.targets[..] else { continue };
+                if let Some((to, from)) = suffix_rule(&suffixes, target, prereqs) {
+                    last_suffix_rule = true;
+                    suffix_rules.push(PatternRule {
+                        location: Some(rule.location.clone()),
+                        written: rule.targets.clone(),
+                        targets: vec![to],
+                        prereqs: vec![from],
+                        order_only: Vec::new(),
+                        recipe: Vec::new(),
+                    });
+                }
+            }
+            RuleData::Recipie(r) => {
+                let last = if last_suffix_rule {
+                    suffix_rules.last_mut()
+                } else {
+                    rules.last_mut()
+                };
+                if let Some(last) = last.filter(|last| last.written == rule.targets) {
+                    last.recipe.push((rule.location.clone(), r.clone()));
+                }
+            }
+            RuleData::OrderOnly(prereqs) if rule.targets.iter().any(|t| t.contains('%')) => {
+                order_only = prereqs.split_whitespace().map(|s| s.to_string()).collect();
+            }
+            RuleData::Var(..) | RuleData::Stem(..) | RuleData::OrderOnly(..) => {}
+        }
+    }
+
+    rules.extend(suffix_rules);
+    let (mut rules, cancelled): (Vec<_>, Vec<_>) = rules.into_iter().partition(|r| !r.recipe.is_empty());
+
+    if !state.no_builtin_rules {
+        let builtin = Location {
+            file_name: "<builtin>".into(),
+            line: 0,
+        };
+        for (target, prereq, recipe) in BUILTIN_RULES {
+            if cancelled.iter().any(|c| c.targets == [*target] && c.prereqs == [*prereq]) {
+                continue;
+            }
+            rules.push(PatternRule {
+                location: None,
+                written: Vec::new(),
+                targets: vec![target.to_string()],
+                prereqs: vec![prereq.to_string()],
+                order_only: Vec::new(),
+                recipe: recipe.iter().map(|r| (builtin.clone(), r.to_string())).collect(),
+            });
+        }
+    }
+
+    rules
+}
+
+/// The makefile's own pattern and suffix rules, as [`Match::rule`] names
+/// them
+pub fn written_rules(state: &State) -> Vec<String> {
+    pattern_rules(state)
+        .iter()
+        .filter(|r| r.location.is_some())
+        .map(|r| r.describe())
+        .collect()
+}
+
+/// Whether `name` exists, here or along the vpath, or there's an explicit
+/// rule for it
+fn ought_to_exist(state: &State, name: &str) -> bool {
+    Path::new(name).exists()
+        || state.rules.iter().any(|r| r.targets.iter().any(|t| t == name))
+        || state.vpath.search(name).is_some()
+}
+
+fn with_stem(prereqs: &[String], stem: &str) -> Vec<String> {
+    prereqs.iter().map(|p| p.replacen('%', stem, 1)).collect()
+}
+
+/// Whether some chain of the pattern rules not in `used` could make `name`
+/// out of files that ought to exist
+fn can_make(state: &State, rules: &[PatternRule], name: &str, used: &mut Vec<usize>) -> bool {
+    for (i, rule) in rules.iter().enumerate() {
+        if used.contains(&i) || rule.targets.iter().any(|t| t == "%") {
+            continue;
+        }
+        let Some(stem) = rule.targets.iter().find_map(|t| pattern_stem(t, name)) else {
+            continue;
+        };
+        used.push(i);
+        let ok = with_stem(&rule.prereqs, stem)
+            .iter()
+            .all(|p| ought_to_exist(state, p) || can_make(state, rules, p, used));
+        used.pop();
+        if ok {
+            return true;
+        }
+    }
+    false
+}
+
+/// The first pattern rule that can make `name`. Rules whose prerequisites
+/// are all there already win over ones that need intermediate files.
+pub fn find(state: &State, name: &str) -> Option<Match> {
+    let rules = pattern_rules(state);
+    debug::log(state, Category::Implicit, format_args!("Looking for an implicit rule for '{}'.", name));
+    for chaining in [false, true] {
+        for (i, rule) in rules.iter().enumerate() {
+            let Some(stem) = rule.targets.iter().find_map(|t| pattern_stem(t, name)) else {
+                continue;
+            };
+            let how = if chaining { ", allowing intermediates" } else { "" };
+            let msg = format!("Trying pattern rule with stem '{}'{} ({}).", stem, how, rule.describe());
+            debug::log(state, Category::Implicit, format_args!("{}", msg));
+
+            let prereqs = with_stem(&rule.prereqs, stem);
+            let intermediate = prereqs
+                .iter()
+                .filter(|p| !ought_to_exist(state, p))
+                .cloned()
+                .collect::<Vec<_>>();
+            let usable = intermediate.is_empty()
+                || chaining && intermediate.iter().all(|p| can_make(state, &rules, p, &mut vec![i]));
+            if usable {
+                debug::log(state, Category::Implicit, format_args!("Found an implicit rule for '{}'.", name));
+                return Some(Match {
+                    stem: stem.to_string(),
+                    rule: rule.describe(),
+                    prereqs,
+                    order_only: with_stem(&rule.order_only, stem),
+                    recipe: rule.recipe.clone(),
+                    intermediate,
+                });
+            }
+        }
+    }
+
+    debug::log(state, Category::Implicit, format_args!("No implicit rule found for '{}'.", name));
+    None
+}
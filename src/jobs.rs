@@ -0,0 +1,163 @@
+//! Job slots for `-j`, shared with recursive makes through a jobserver.
+//!
+//! Every make has one implicit slot of its own. Each job beyond that needs
+//! a token, a single byte read from the jobserver pipe, which is written
+//! back once the job is done. The top make creates the pipe holding one
+//! token less than its `-j` and hands it down through MAKEFLAGS as
+//! `--jobserver-auth=R,W` so sub-makes draw from the same pool. With
+//! `--jobserver-style=fifo` it's a named pipe instead, passed on as
+//! `--jobserver-auth=fifo:PATH` for each make to open for itself, which is
+//! what GNU make 4.4 does by default and doesn't depend on descriptors
+//! surviving whatever runs between the makes.
+//!
+//! Tokens are all alike, so the slots we hold are numbered as they're
+//! taken: 0 for the implicit one, then the lowest number not in use.
+//!
+//! A bare `-j` has no limit, so there's no jobserver and a slot is always
+//! there. Sub-makes are passed the bare `-j` and have no limit either.
+
+use std::{fs::File, os::fd::IntoRawFd};
+
+use crate::{sys, temp};
+
+/// `-j` on its own
+pub const UNLIMITED: usize = usize::MAX;
+
+/// `--jobserver-style`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Style {
+    #[default]
+    Pipe,
+    Fifo,
+}
+
+impl Style {
+    pub fn parse(style: &str) -> Option<Style> {
+        match style {
+            "pipe" => Some(Style::Pipe),
+            "fifo" => Some(Style::Fifo),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Slot {
+    Implicit,
+    /// The byte read and our number for it
+    Token(u8, usize),
+}
+
+impl Slot {
+    pub fn id(&self) -> usize {
+        match self {
+            Slot::Implicit => 0,
+            Slot::Token(_, id) => *id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Slots {
+    implicit_taken: bool,
+    /// Read and write ends of the jobserver pipe
+    jobserver: Option<(i32, i32)>,
+    /// There's a slot for every job, with no jobserver needed
+    unlimited: bool,
+    /// Numbers of the tokens we hold
+    token_ids: Vec<usize>,
+}
+
+impl Slots {
+    pub fn new(fds: &[i32]) -> Self {
+        Self {
+            implicit_taken: false,
+            jobserver: match fds {
+                [r, w] => Some((*r, *w)),
+                _ => None,
+            },
+            token_ids: Vec::new(),
+            unlimited: false,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self {
+            unlimited: true,
+            ..Self::new(&[])
+        }
+    }
+
+    /// Make a jobserver for `jobs` slots, one of which is our implicit one
+    pub fn create(jobs: usize) -> Option<(i32, i32)> {
+        let (r, w) = sys::make_pipe()?;
+        for _ in 1..jobs {
+            sys::write_byte(w, b'+');
+        }
+        Some((r, w))
+    }
+
+    /// [`create`](Self::create) with a named pipe. Its path and the one
+    /// descriptor we use for both ends.
+    pub fn create_fifo(jobs: usize) -> Option<(String, i32)> {
+        let path = temp::fifo("fifo").ok()?.to_str()?.to_string();
+        let fd = open_fifo(&path)?;
+        for _ in 1..jobs {
+            sys::write_byte(fd, b'+');
+        }
+        Some((path, fd))
+    }
+
+    pub fn has_jobserver(&self) -> bool {
+        self.jobserver.is_some()
+    }
+
+    pub fn acquire(&mut self) -> Option<Slot> {
+        if !self.implicit_taken {
+            self.implicit_taken = true;
+            return Some(Slot::Implicit);
+        }
+
+        let b = if self.unlimited {
+            b'+'
+        } else {
+            // another make can beat us to the token between the poll and
+            // the read. then we just block until someone gives one back.
+            let (r, _) = self.jobserver?;
+            if !sys::readable(r, 0) {
+                return None;
+            }
+            sys::read_byte(r)?
+        };
+        let id = (1..).find(|id| !self.token_ids.contains(id)).unwrap();
+        self.token_ids.push(id);
+        Some(Slot::Token(b, id))
+    }
+
+    pub fn release(&mut self, slot: Slot) {
+        match slot {
+            Slot::Implicit => self.implicit_taken = false,
+            Slot::Token(b, id) => {
+                self.token_ids.retain(|i| *i != id);
+                if let Some((_, w)) = self.jobserver {
+                    sys::write_byte(w, b);
+                }
+            }
+        }
+    }
+
+    /// Wait up to `timeout` milliseconds for a token to turn up
+    pub fn wait_token(&self, timeout: i32) -> bool {
+        match self.jobserver {
+            Some((r, _)) => sys::readable(r, timeout),
+            None => false,
+        }
+    }
+}
+
+/// Open a jobserver fifo another make made. Opened for reading and writing
+/// so it's never left without a writer.
+pub fn open_fifo(path: &str) -> Option<i32> {
+    let file = File::options().read(true).write(true).open(path).ok()?;
+    Some(file.into_raw_fd())
+}
@@ -2,80 +2,354 @@
 #![feature(array_from_fn)]
 
 use std::{
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
     fs::File,
     io::{prelude::*, BufReader},
     iter::Peekable,
-    os::unix::process::CommandExt,
+    os::unix::process::{CommandExt, ExitStatusExt},
     path::Path,
     process::{Command, Stdio},
+    time::{Duration, Instant, SystemTime},
 };
 
 use glob;
+use options::Opt;
+
+mod ast;
+mod audit;
+mod cache;
+mod clean;
+mod compat;
+mod coverage;
+mod daemon;
+mod debug;
+mod doctor;
+mod graph;
+mod implicit;
+mod jobs;
+mod options;
+mod output;
+mod overlay;
+mod phony;
+mod plan;
+mod shuffle;
+mod strict;
+mod suggest;
+mod sys;
+mod temp;
+mod timing;
+mod vpath;
 
 // Global makefile state
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 struct State {
-    debug: bool,
+    /// `-d` and `--debug=`: what to explain along the way
+    debug: debug::Categories,
     fullname: String,
     basename: String,
     dirname: String,
     curdir: String,
     // vars: HashMap<String, Var>,
+    /// `-B`: remake every target that has a rule, whatever the mtimes say
     always_make: bool,
+    /// `-t`: mark targets up to date instead of running their recipes
+    touch: bool,
+    /// `-q`: run nothing and say with the exit status whether anything is
+    /// out of date
+    question: bool,
+    /// Under `-q`, whether a recipe would have been run
+    out_of_date: bool,
     targets_to_make: Vec<String>,
     silent: bool,
     rules: Vec<Rule>,
     in_rule: bool,
     ignore_errors: bool,
     dryrun: bool,
+    /// `--dry-run=safe`: nothing runs at all, not `$(shell)`, `!=`, `+`
+    /// lines or recursive makes, so it's fit for makefiles we don't trust
+    safe_dryrun: bool,
+    /// `--no-env-vars`: the environment isn't read into variables
+    no_env_vars: bool,
+    /// What we started with under `--no-env-vars`, given to children as it
+    /// was unless a variable of the same name says otherwise
+    passed_env: Vec<Var>,
     keep_going: bool,
+    /// `--halt=`: what happens to running jobs when one fails without `-k`
+    halt: Halt,
     /// List of phony target names
     phony: Vec<String>,
+    /// Targets and `%` patterns from `.SILENT`
     silent_targets: Vec<String>,
-    processed: Vec<String>,
+    /// Targets from `.IGNORE` whose recipe errors are ignored
+    ignore_targets: Vec<String>,
+    /// Targets and `%` patterns from `.PRECIOUS` that are kept when their
+    /// recipe is interrupted
+    precious: Vec<String>,
+    /// A bare `.PRECIOUS:` makes everything precious
+    precious_all: bool,
+    /// Files a pattern rule needs that another one makes on the way, and
+    /// those from `.INTERMEDIATE`
+    intermediates: Vec<String>,
+    /// Targets and `%` patterns from `.NOTINTERMEDIATE`, which are kept
+    /// like any other target however they're made
+    not_intermediate: Vec<String>,
+    /// A bare `.NOTINTERMEDIATE:` means nothing is intermediate
+    not_intermediate_all: bool,
+    /// Targets and `%` patterns from `.INTERACTIVE`, whose recipes get our
+    /// stdin instead of `/dev/null`
+    interactive: Vec<String>,
+    /// A bare `.INTERACTIVE:` gives every recipe our stdin
+    interactive_all: bool,
+    /// Targets from `.ATOMIC`, whose recipes write a temporary file that's
+    /// renamed to the target once they succeed
+    atomic: Vec<String>,
+    /// A bare `.ATOMIC:` does it for every target
+    atomic_all: bool,
+    /// Directories from `.SERIAL_DIRS`, where only one recipe runs at a time
+    serial_dirs: Vec<String>,
+    /// `--serialize-dir` or a bare `.SERIAL_DIRS:` does it for every directory
+    serial_dirs_all: bool,
+    /// `-E`/`--eval`: makefile text read before the makefile
+    evals: Vec<String>,
+    /// `--toolchain=`: a makefile of variables read before the real one,
+    /// absolute so sub-makes can find it wherever they run
+    toolchain: Option<String>,
+    /// `-W`: files to treat as just modified, without touching them
+    assume_new: Vec<String>,
+    /// `-o`: files never remade and never reason to remake anything else
+    assume_old: Vec<String>,
+    /// Targets from `.LOW_RESOLUTION_TIME`, whose mtimes are only good to
+    /// the second
+    low_resolution: Vec<String>,
+    /// Targets from `.SECONDARY`, intermediate but never deleted
+    secondary: Vec<String>,
+    /// A bare `.SECONDARY:` keeps every intermediate file
+    secondary_all: bool,
+    /// Intermediate files that weren't there and are being made, to delete
+    /// at the end
+    made_intermediates: Vec<String>,
+    /// Missing intermediate files that didn't need making because what
+    /// needs them is newer than what they're made from
+    skipped_intermediates: Vec<String>,
+    /// Where each target we've looked at has got to
+    processed: HashMap<String, Progress>,
+    /// Targets whose recipes ran (or would have run under `-n`)
+    remade: Vec<String>,
+    /// Leave exports that don't fit in a child's environment out of it
+    skip_oversized_exports: bool,
+    /// Exported variables we've already complained about being too big
+    oversized_warned: RefCell<Vec<String>>,
+    /// How many recursive variables may be mid-expansion at once. 0 means
+    /// [`DEFAULT_MAX_EXPANSION_DEPTH`].
+    max_expansion_depth: usize,
+    /// How deep `MAKELEVEL` may go before we decide a recipe is running
+    /// `$(MAKE)` without end. 0 means [`DEFAULT_MAX_RECURSION`].
+    max_recursion: u32,
+    /// Names of the recursive variables currently being expanded
+    expansion_stack: RefCell<Vec<String>>,
+    /// `-R`: don't define the built-in variables
+    no_builtin_variables: bool,
+    /// `-r`: only the makefile's own pattern rules
+    no_builtin_rules: bool,
+    /// A bare `export` or `.EXPORT_ALL_VARIABLES` is in effect
+    export_all: bool,
+    /// `.NOTPARALLEL`: run one job at a time and don't overlap goals
+    not_parallel: bool,
+    /// Use `.imake.cache` instead of parsing when nothing has changed
+    parse_cache: bool,
+    /// Every makefile we've tried to read
+    makefiles: Vec<String>,
+    /// `$(wildcard)` patterns and their results
+    parse_globs: RefCell<Vec<(String, String)>>,
+    /// Whether `$(shell)` or `$(file)` has been run
+    parse_ran_shell: Cell<bool>,
+    /// What `$(info)` and `$(warning)` printed, and whether it went to
+    /// stderr, so a parse cache hit can print it again
+    parse_messages: RefCell<Vec<(bool, String)>>,
+    /// Stay resident and serve builds over `.imake.sock`
+    daemon: bool,
+    /// Stay resident and answer questions about the graph over
+    /// `.imake-graph.sock`
+    serve_graph: bool,
+    /// `--shuffle`: the order prerequisites are made in
+    shuffle: shuffle::Shuffle,
+    /// Where to write the `--audit` manifest
+    audit: Option<String>,
+    /// `--only`: run the goals' recipes and nothing else, taking their
+    /// prerequisites as done
+    only: bool,
+    /// `--missing`: list the prerequisites that can't be had instead of
+    /// building
+    missing_report: bool,
+    /// `--clean-outputs`: delete the targets recipes make instead of
+    /// building
+    clean_outputs: bool,
+    /// `--infer-phony`: targets found not to make their file
+    infer_phony: Option<phony::Inferred>,
+    /// `--doctor`: report on the environment instead of building
+    doctor: bool,
+    /// `--coverage`: what the build has used of the makefiles
+    coverage: Option<coverage::Coverage>,
+    /// `--strict`: the checks in [`strict`] are errors
+    strict: bool,
+    /// A recipe is being expanded, which is when `--strict` minds
+    /// undefined variables
+    in_recipe: Cell<bool>,
+    /// `--plan-json`: the targets that would be remade so far
+    plan: Option<Vec<plan::Step>>,
+    /// Where to look for prerequisites that aren't here
+    vpath: vpath::Vpath,
+    /// `.ALIAS: name = targets...` goal aliases
+    aliases: HashMap<String, Vec<String>>,
+    /// Targets whose recipes failed. Only ever filled in under `-k`.
+    failed: Vec<String>,
+    /// Jobserver descriptors from a parent make. Only `+` and `$(MAKE)`
+    /// lines get these.
+    jobserver_fds: Vec<i32>,
+    /// `-I`: where else to look for an included makefile
+    include_dirs: Vec<String>,
+    /// `--jobserver-style`: what kind of jobserver we'd make
+    jobserver_style: jobs::Style,
+    /// Where the jobserver is when it's a named pipe. Both descriptors are
+    /// then the one we opened it with and aren't passed on.
+    jobserver_fifo: Option<String>,
+    /// `IMAKE_JOB_ID` of the recipe that ran us, if it was given one
+    parent_job_id: Option<String>,
+    /// What `--time-report` has measured so far
+    timings: timing::Timings,
+    /// `MAKELEVEL`: how many makes there are above us
+    level: u32,
+    /// The umask we started with, which every command gets. `None` leaves
+    /// them with whatever ours is at the time.
+    umask: Option<u32>,
+    /// `-w`: print Entering and Leaving directory messages
+    print_directory: bool,
+    /// Leaving directory messages owed for the Entering ones we printed,
+    /// innermost last
+    leaving: RefCell<Vec<String>>,
+    /// How many recipes `-j` lets run at once. 0 if it wasn't given, which
+    /// is the same as 1, and [`jobs::UNLIMITED`] for a bare `-j`.
+    jobs: usize,
+    /// `-l`: don't start another job while the load average is this high
+    max_load: Option<f64>,
+    /// `-O`: how recipe output is kept together. Always `None` without
+    /// parallel jobs.
+    output_sync: output::Mode,
+    /// Our implicit job slot and the jobserver's tokens
+    slots: jobs::Slots,
+}
+
+/// What to do with the other running jobs once one has failed
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Halt {
+    /// Let them finish, like make always has
+    #[default]
+    Drain,
+    /// Kill them
+    Now,
+}
+
+/// The Entering and Leaving directory messages for a make `level` deep
+/// working in `dir`. Tools reading the log go by these to know what the
+/// file names in the output between them are relative to, so they're
+/// worded exactly like GNU make's.
+fn directory_messages(state: &State, level: u32, dir: &str) -> (String, String) {
+    let name = match level {
+        0 => state.basename.clone(),
+        n => format!("{}[{}]", state.basename, n),
+    };
+    (
+        format!("{}: Entering directory '{}'", name, dir),
+        format!("{}: Leaving directory '{}'", name, dir),
+    )
+}
+
+/// Close every directory we're still in
+fn leave_all(state: &State) {
+    for msg in state.leaving.take().iter().rev() {
+        println!("{}", msg);
+    }
+}
+
+/// Exit without leaving unbalanced directory messages behind
+fn leave_and_exit(state: &State, code: i32) -> ! {
+    leave_all(state);
+    temp::remove_all();
+    std::process::exit(code)
 }
 
-fn fatal_double_and_single(loc: &Location, target: &str) -> ! {
+fn fatal_double_and_single(state: &State, loc: &Location, target: &str) -> ! {
     println!("{}:{}: *** target file '{}' has both : and :: entries.  Stop", loc.file_name, loc.line, target);
-    std::process::exit(2)
+    leave_and_exit(state, 2)
 }
 
-fn fatal_arg_count(loc: &Location, given: usize, func: &str) -> ! {
+fn fatal_arg_count(state: &State, loc: &Location, given: usize, func: &str) -> ! {
     println!(
         "{}:{}: *** insufficient number of arguments ({}) to function '{}'.  Stop.",
         loc.file_name, loc.line, given, func
     );
-    std::process::exit(2)
+    leave_and_exit(state, 2)
+}
+
+/// `arg` split at its commas, stopping make if that's fewer arguments than
+/// `func` needs
+fn split_args<'a>(state: &State, loc: &Location, func: &str, arg: &'a str, needed: usize) -> Vec<&'a str> {
+    let args = arg.split(',').collect::<Vec<_>>();
+    if args.len() < needed {
+        fatal_arg_count(state, loc, args.len(), func);
+    }
+    args
 }
 
-fn fatal_unterm_var(loc: &Location) -> ! {
+fn fatal_unterm_var(state: &State, loc: &Location) -> ! {
     println!(
         "{}:{}: *** unterminated variable reference.  Stop.",
         loc.file_name, loc.line
     );
-    std::process::exit(2)
+    leave_and_exit(state, 2)
+}
+
+fn fatal_recipe_outside_rule(state: &State, loc: &Location) -> ! {
+    eprintln!("{}:{}: *** recipe commences before first target.  Stop.", loc.file_name, loc.line);
+    leave_and_exit(state, 2)
+}
+
+fn fatal_expansion_depth(state: &State, loc: &Location, max: usize, chain: &[String]) -> ! {
+    // if we're going round in circles only show the loop
+    let start = chain
+        .split_last()
+        .and_then(|(last, rest)| rest.iter().rposition(|x| x == last))
+        .unwrap_or(0);
+    eprintln!(
+        "{}:{}: *** expansion too deep (more than {} levels): {}.  Stop.",
+        loc.file_name,
+        loc.line,
+        max,
+        chain[start..].join(" -> ")
+    );
+    leave_and_exit(state, 2)
 }
 
-fn get_all_args(loc: &Location, func: &str, src: &str) -> Vec<String> {
+fn get_all_args(state: &State, loc: &Location, func: &str, src: &str) -> Vec<String> {
     let mut args = Vec::new();
     let mut buf = String::new();
     let mut delim_stack = String::new();
     let mut src = src.chars();
 
     while match src.next() {
-        Some(')') if delim_stack.chars().last().unwrap() == '(' => {
+        Some(')') if delim_stack.ends_with('(') => {
             delim_stack.pop();
             buf.push(')');
             true
         }
-        Some('}') if delim_stack.chars().last().unwrap() == '{' => {
+        Some('}') if delim_stack.ends_with('{') => {
             delim_stack.pop();
             buf.push('}');
             true
         }
-        Some('}') if delim_stack.chars().last().unwrap() == '(' => fatal_unterm_var(loc),
-        Some(')') if delim_stack.chars().last().unwrap() == '{' => fatal_unterm_var(loc),
+        Some('}') if delim_stack.ends_with('(') => fatal_unterm_var(state, loc),
+        Some(')') if delim_stack.ends_with('{') => fatal_unterm_var(state, loc),
         Some('(') => {
             delim_stack.push('(');
             buf.push('(');
@@ -101,18 +375,46 @@ fn get_all_args(loc: &Location, func: &str, src: &str) -> Vec<String> {
     args
 }
 
-fn get_args<const ARG_COUNT: usize>(loc: &Location, func: &str, src: &str) -> [String; ARG_COUNT] {
-    let mut args = get_all_args(loc, func, src).into_iter();
+fn get_args<const ARG_COUNT: usize>(state: &State, loc: &Location, func: &str, src: &str) -> [String; ARG_COUNT] {
+    let mut args = get_all_args(state, loc, func, src).into_iter();
 
     core::array::from_fn(|i| {
         args.next()
-            .unwrap_or_else(|| fatal_arg_count(loc, i, func))
+            .unwrap_or_else(|| fatal_arg_count(state, loc, i, func))
             .to_string()
     })
 }
 
-fn main() -> Result<(), u32> {
-    let mut args = std::env::args();
+fn main() {
+    let r = run();
+    temp::remove_all();
+    if let Err(code) = r {
+        std::process::exit(code as i32);
+    }
+}
+
+fn run() -> Result<(), u32> {
+    let started = Instant::now();
+    sys::cloexec_inherited();
+
+    let argv = std::env::args().skip(1).collect::<Vec<_>>();
+    if argv.iter().any(|a| a == "--diff-gnu") {
+        let rest = argv.into_iter().filter(|a| a != "--diff-gnu").collect::<Vec<_>>();
+        std::process::exit(compat::diff_gnu(&rest));
+    }
+
+    if let Some(format) = argv.iter().find_map(|a| a.strip_prefix("--dump-ast=")) {
+        let rest = argv.iter().filter(|a| !a.starts_with("--dump-ast=")).cloned().collect::<Vec<_>>();
+        std::process::exit(ast::dump(format, &rest));
+    }
+
+    if daemon::forwardable(&argv) && Path::new(daemon::SOCKET).exists() {
+        if let Some(code) = daemon::forward(&argv) {
+            std::process::exit(code);
+        }
+    }
+
+    let mut argv = shebang_args(std::env::args().collect()).into_iter();
 
     let mut makefile_names = vec![
         "GNUmakefile".to_owned(),
@@ -121,29 +423,20 @@ fn main() -> Result<(), u32> {
     ];
 
     let mut state = State::default();
-    state.debug = matches!(std::env::var("IMAKE_DEBUG").as_ref().map(|x| x.as_str()), Ok("1"));
     
     let mut vars = HashMap::new();
 
-    let mpath: String = args.next().unwrap().trim().into();
+    // we might have been run with nothing at all in argv
+    let mpath: String = argv.next().unwrap_or_default().trim().into();
     state.basename = Path::new(&mpath)
         .file_name()
-        .unwrap()
-        .to_owned()
-        .into_string()
-        .unwrap();
+        .map_or_else(|| "imake".into(), |name| name.to_string_lossy().into_owned());
 
-    state.dirname = Path::new(&mpath).parent().unwrap().to_str().unwrap().into();
+    state.dirname = Path::new(&mpath).parent().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
 
-    let olddir: String = std::env::current_dir().unwrap().to_str().unwrap().into();
+    let olddir = current_dir(&state)?;
     state.curdir = olddir.clone();
-
-    for (a, b) in std::env::vars() {
-        vars.insert(
-            a.clone(),
-            Var::new(Flavor::Simple, Origin::Env, None, a, b, true),
-        );
-    }
+    state.umask = Some(sys::get_umask());
 
     state.fullname = mpath.clone();
     let name: String = "MAKE".into();
@@ -172,171 +465,781 @@ fn main() -> Result<(), u32> {
         Var::new(Flavor::Simple, Origin::Env, None, n, "-c".into(), true),
     );
 
-    let n = "CC".to_string();
-    vars.insert(
-        n.clone(),
-        Var::new(Flavor::Simple, Origin::Default, None, n, "cc".into(), true),
-    );
-
     let level = std::env::var("MAKELEVEL")
         .ok()
         .unwrap_or_default()
         .parse::<u32>()
-        .map_or(0, |x| x + 1)
-        .to_string();
+        .map_or(0, |x| x + 1);
+    state.level = level;
 
-    let n = "MAKELEVELS".to_string();
+    let n = "MAKELEVEL".to_string();
     vars.insert(
         n.clone(),
-        Var::new(Flavor::Simple, Origin::Env, None, n, level, true),
+        Var::new(Flavor::Simple, Origin::Env, None, n, level.to_string(), true),
     );
 
     let mut makeflags = String::new();
+    let mut long_flags = Vec::new();
+    let mut overrides = Vec::new();
 
     let mut dashC = false;
+    let mut print_directory = None;
+    let mut environment_overrides = false;
+
+    // flags from a parent make go first so our own command line wins
+    let inherited = std::env::var("MAKEFLAGS")
+        .map(|f| makeflags_args(&f))
+        .unwrap_or_default();
+    let args = match options::parse(inherited.into_iter().chain(argv)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}: {}", state.basename, e);
+            eprint!("{}", options::usage(&state.basename));
+            return Err(2);
+        }
+    };
 
-    while let Some(arg) = args.next() {
-        let mut sargs = vec![];
-        if arg.starts_with("--") {
-            sargs.push(arg);
-        } else if arg.starts_with("-") {
-            let mut chars = arg.chars();
-            chars.next(); // skip `-`
-            for a in chars {
-                sargs.push(String::from(a));
+    for arg in args {
+        let (opt, value) = match arg {
+            options::Arg::Option(opt, value) => (opt, value),
+            options::Arg::Assignment(arg) => {
+                let (l, v) = arg.split_once('=').unwrap_or_default();
+                vars.insert(
+                    l.to_string(),
+                    Var::new(Flavor::Simple, Origin::CmdLine, None, l.to_string(), v.to_string(), false),
+                );
+                overrides.push(makeflags_quote(&arg));
+                continue;
             }
-        } else {
-            sargs.push(arg);
-        }
-        let mut sargs = sargs.into_iter().peekable();
-        while let Some(arg) = sargs.next() {
-            match arg.as_str() {
-                "b" | "m" => {
-                    // Ignored for compatibilty.
-                }
-                "B" | "--always-make" => {
-                    state.always_make = true;
-                    makeflags.push('B');
-                }
-                "i" | "--ignore-errors" => {
-                    state.ignore_errors = true;
-                }
-                s if s.starts_with("--directory=") => {}
-                "C" => {
-                    let dir = args.next().expect("no dir provided");
-                    std::env::set_current_dir(Path::new(&dir)).unwrap();
-                    state.curdir = std::env::current_dir().unwrap().to_str().unwrap().into();
-                    dashC = true;
-                }
-                "v" | "--version" => {
-                    println!("GNU Make 4.3 Compatible Iglunix Make");
-                    return Ok(());
+            options::Arg::Goal(goal) => {
+                state.targets_to_make.push(goal);
+                continue;
+            }
+        };
+        // only ever `None` for options that can go without
+        let required = || value.clone().unwrap_or_default();
+        match opt {
+            Opt::Ignored => {}
+            Opt::AlwaysMake => {
+                state.always_make = true;
+                makeflags.push('B');
+            }
+            Opt::Touch => {
+                state.touch = true;
+                makeflags.push('t');
+            }
+            Opt::Question => {
+                state.question = true;
+                state.dryrun = true;
+                makeflags.push('q');
+            }
+            // accepted so GNU make's `MAKEFLAGS` don't stop us, but there's
+            // nothing behind them yet
+            Opt::PrintDataBase => makeflags.push('p'),
+            Opt::CheckSymlinkTimes => makeflags.push('L'),
+            Opt::Trace => long_flags.push("--trace".to_string()),
+            Opt::WarnUndefinedVariables => long_flags.push("--warn-undefined-variables".to_string()),
+            Opt::Eval => {
+                let text = required();
+                long_flags.push(format!("--eval={}", makeflags_quote(&text)));
+                state.evals.push(text);
+            }
+            Opt::IgnoreErrors => {
+                state.ignore_errors = true;
+                makeflags.push('i');
+            }
+            Opt::NoBuiltinRules => {
+                state.no_builtin_rules = true;
+                makeflags.push('r');
+            }
+            Opt::NoBuiltinVariables => {
+                // the built-in rules are no use without their variables
+                state.no_builtin_variables = true;
+                state.no_builtin_rules = true;
+                makeflags.push('R');
+                makeflags.push('r');
+            }
+            Opt::Directory => {
+                let dir = required();
+                if let Err(e) = std::env::set_current_dir(Path::new(&dir)) {
+                    eprintln!("{}: *** {}: {}.  Stop.", state.basename, dir, e);
+                    return Err(2);
                 }
-                "f" => {
-                    let n = args.next().expect("");
-                    makefile_names = vec![n]
+                state.curdir = current_dir(&state)?;
+                dashC = true;
+            }
+            Opt::Version => {
+                println!("GNU Make 4.3 Compatible Iglunix Make");
+                return Ok(());
+            }
+            Opt::Help => {
+                print!("{}", options::usage(&state.basename));
+                return Ok(());
+            }
+            Opt::File => {
+                let mut n = required();
+                if n == "-" {
+                    // like GNU the makefile is known by the copy's name
+                    let copied = temp::create("stdin")
+                        .and_then(|(path, mut file)| std::io::copy(&mut std::io::stdin(), &mut file).map(|_| path));
+                    match copied {
+                        Ok(path) => n = path.to_string_lossy().into_owned(),
+                        Err(e) => {
+                            eprintln!("{}: *** can't read makefile from stdin: {}.  Stop.", state.basename, e);
+                            return Err(2);
+                        }
+                    }
                 }
-                "s" | "--silent" | "--quiet" => {
-                    state.silent = true;
-                    makeflags.push('s');
+                makefile_names = vec![n]
+            }
+            Opt::IncludeDir => {
+                let dir = required();
+                long_flags.push(format!("-I{}", dir));
+                state.include_dirs.push(dir);
+            }
+            Opt::Silent => {
+                state.silent = true;
+                makeflags.push('s');
+            }
+            Opt::DryRun => match value.as_deref() {
+                None => {
+                    state.dryrun = true;
+                    makeflags.push('n');
                 }
-                "n" | "--just-print" | "--dry-run" | "--recon" => {
+                Some("safe") => {
                     state.dryrun = true;
+                    state.safe_dryrun = true;
+                    makeflags.push('n');
+                    long_flags.push("--dry-run=safe".to_string());
                 }
-                "k" | "--keep-going" => {
-                    state.keep_going = true;
+                Some(mode) => {
+                    eprintln!("{}: *** unknown dry run mode '{}'.  Stop.", state.basename, mode);
+                    return Err(2);
                 }
-                "--no-silent" => {
-                    state.silent = false;
+            },
+            Opt::KeepGoing => {
+                state.keep_going = true;
+                makeflags.push('k');
+            }
+            Opt::Stop => {
+                // most likely undoing a `-k` from a parent's MAKEFLAGS
+                state.keep_going = false;
+                makeflags.retain(|c| c != 'k');
+            }
+            Opt::JobserverAuth => {
+                let auth = required();
+                if let Some(path) = auth.strip_prefix("fifo:") {
+                    state.jobserver_fifo = Some(path.to_string());
+                    state.jobserver_fds.clear();
+                } else {
+                    state.jobserver_fifo = None;
+                    state.jobserver_fds = auth.split(',').filter_map(|fd| fd.parse().ok()).collect();
                 }
-                "--no-print-directory" => {
-                    // TODO:
+            }
+            Opt::JobserverStyle => {
+                let style = required();
+                let Some(style) = jobs::Style::parse(&style) else {
+                    eprintln!("{}: *** unknown jobserver auth style '{}'.  Stop.", state.basename, style);
+                    return Err(2);
+                };
+                state.jobserver_style = style;
+            }
+            Opt::TimeReport => {
+                state.timings = timing::Timings::enabled();
+            }
+            Opt::ParseCache => {
+                state.parse_cache = true;
+            }
+            Opt::Audit => {
+                let path = required();
+                if let Err(e) = audit::start(&path) {
+                    eprintln!("{}: *** can't create audit manifest {}: {}.  Stop.", state.basename, path, e);
+                    return Err(2);
                 }
-                "j" => {
-                    let mut n = String::new();
-                    while match sargs.peek() {
-                        Some(d) if d.parse::<usize>().is_ok() => {
-                            n.extend(sargs.next().unwrap().chars());
-                            true
-                        }
-                        _ => false,
-                    } {}
-                }
-                "e" | "--environment-override" => {
-                    // TODO:
-                    // need some logic for var stuff to implement this
-                    // sometimes we should store sometimes not
-                }
-                "" => {}
-                a if !a.starts_with('-') => {
-                    let mut l = String::new();
-                    let mut is_var = false;
-                    let mut v = String::new();
-
-                    for c in a.chars() {
-                        match c {
-                            '=' => is_var = true,
-                            a => {
-                                if is_var {
-                                    v.push(a)
-                                } else {
-                                    l.push(a)
-                                }
-                            }
-                        }
+                state.audit = Some(path);
+            }
+            Opt::Only => {
+                state.only = true;
+                state.targets_to_make.push(required());
+            }
+            Opt::Missing => {
+                state.missing_report = true;
+            }
+            Opt::CleanOutputs => {
+                state.clean_outputs = true;
+            }
+            Opt::InferPhony => {
+                state.infer_phony = Some(Default::default());
+                long_flags.push("--infer-phony".to_string());
+            }
+            Opt::Doctor => {
+                state.doctor = true;
+            }
+            Opt::Coverage => {
+                state.coverage = Some(Default::default());
+            }
+            Opt::DebugAll => {
+                state.debug = debug::Categories::ALL;
+                makeflags.push('d');
+            }
+            Opt::Debug => {
+                let spec = value.unwrap_or_else(|| "basic".to_string());
+                match state.debug.parse(&spec) {
+                    Ok(debug) => {
+                        state.debug = debug;
+                        long_flags.push(format!("--debug={}", spec));
                     }
-
-                    if is_var {
-                        vars.insert(
-                            l.clone(),
-                            Var::new(Flavor::Simple, Origin::CmdLine, None, l, v, false),
-                        );
-                    } else {
-                        state.targets_to_make.push(l);
+                    Err(level) => {
+                        eprintln!("{}: *** unknown debug level specification '{}'.  Stop.", state.basename, level);
+                        return Err(2);
+                    }
+                }
+            }
+            Opt::Shuffle => {
+                let mode = value.unwrap_or_else(|| "random".to_string());
+                match shuffle::Shuffle::parse(&mode) {
+                    Some(mode) => state.shuffle = mode,
+                    None => {
+                        eprintln!("{}: *** unknown shuffle mode '{}'.  Stop.", state.basename, mode);
+                        return Err(2);
                     }
                 }
-                _ => return Err(1),
             }
+            Opt::Strict => {
+                state.strict = true;
+                long_flags.push("--strict".to_string());
+            }
+            Opt::PlanJson => {
+                state.dryrun = true;
+                state.plan = Some(Vec::new());
+            }
+            Opt::Daemon => {
+                state.daemon = true;
+            }
+            Opt::ServeGraph => {
+                state.serve_graph = true;
+            }
+            Opt::DaemonStop => {
+                std::process::exit(daemon::stop());
+            }
+            Opt::SkipOversizedExports => {
+                state.skip_oversized_exports = true;
+            }
+            Opt::MaxExpansionDepth => {
+                let Ok(depth) = required().parse() else {
+                    eprintln!("{}: invalid expansion depth: '{}'", state.basename, required());
+                    return Err(2);
+                };
+                state.max_expansion_depth = depth;
+            }
+            Opt::MaxRecursion => {
+                let Ok(depth) = required().parse() else {
+                    eprintln!("{}: invalid recursion depth: '{}'", state.basename, required());
+                    return Err(2);
+                };
+                state.max_recursion = depth;
+                // so sub-makes stop at the same depth
+                long_flags.push(format!("--max-recursion={}", depth));
+            }
+            Opt::Halt => {
+                state.halt = match required().as_str() {
+                    "drain" => Halt::Drain,
+                    "now" => Halt::Now,
+                    how => {
+                        eprintln!("{}: *** unknown halt mode '{}'.  Stop.", state.basename, how);
+                        return Err(2);
+                    }
+                };
+                long_flags.push(format!("--halt={}", required()));
+            }
+            Opt::NoSilent => {
+                state.silent = false;
+            }
+            Opt::PrintDirectory => {
+                print_directory = Some(true);
+            }
+            Opt::NoPrintDirectory => {
+                print_directory = Some(false);
+                long_flags.push("--no-print-directory".to_string());
+            }
+            Opt::Jobs => {
+                state.jobs = match value.as_deref().map(str::parse) {
+                    None => jobs::UNLIMITED,
+                    Some(Ok(n)) if n > 0 => n,
+                    Some(_) => {
+                        eprintln!("{}: the '-j' option requires a positive integer argument", state.basename);
+                        return Err(2);
+                    }
+                };
+            }
+            Opt::OutputSync => {
+                let kind = value.unwrap_or_else(|| "target".to_string());
+                let Some(mode) = output::Mode::parse(&kind) else {
+                    eprintln!("{}: *** unknown output-sync type '{}'.  Stop.", state.basename, kind);
+                    return Err(2);
+                };
+                state.output_sync = mode;
+            }
+            Opt::LoadAverage => {
+                // on its own it takes the limit away again
+                state.max_load = match value.as_deref().map(str::parse::<f64>) {
+                    None => None,
+                    Some(Ok(n)) => Some(n).filter(|n| *n > 0.0),
+                    Some(Err(_)) => {
+                        eprintln!("{}: *** invalid load average '{}'.  Stop.", state.basename, required());
+                        return Err(2);
+                    }
+                };
+            }
+            Opt::EnvironmentOverrides => {
+                environment_overrides = true;
+                makeflags.push('e');
+            }
+            Opt::SerializeDir => {
+                state.serial_dirs_all = true;
+                long_flags.push("--serialize-dir".to_string());
+            }
+            Opt::NoEnvVars => {
+                state.no_env_vars = true;
+            }
+            Opt::NewFile => {
+                let file = required();
+                long_flags.push(format!("--assume-new={}", file));
+                state.assume_new.push(normalize_path(&file));
+            }
+            Opt::OldFile => {
+                let file = required();
+                long_flags.push(format!("--assume-old={}", file));
+                state.assume_old.push(normalize_path(&file));
+            }
+            Opt::Toolchain => {
+                let file = required();
+                // sub-makes may be somewhere else
+                let Ok(path) = std::fs::canonicalize(&file) else {
+                    eprintln!("{}: *** toolchain file '{}' not found.  Stop.", state.basename, file);
+                    return Err(2);
+                };
+                let path = path.to_string_lossy().into_owned();
+                long_flags.push(format!("--toolchain={}", path));
+                state.toolchain = Some(path);
+            }
+        }
+    }
+    let max_recursion = match state.max_recursion {
+        0 => DEFAULT_MAX_RECURSION,
+        max => max,
+    };
+    if level > max_recursion {
+        eprintln!(
+            "{}: *** MAKELEVEL {} is over --max-recursion={}; does a recipe run $(MAKE) unconditionally?  Stop.",
+            state.basename, level, max_recursion
+        );
+        return Err(2);
+    }
+
+    if let Some(flag) = state.shuffle.flag() {
+        long_flags.push(flag);
+    }
+    // sub-makes are handed the seed, the top one tells people what it is
+    if let shuffle::Shuffle::Random(seed) = state.shuffle {
+        if level == 0 && !state.silent {
+            eprintln!("{}: shuffling prerequisites with seed {}", state.basename, seed);
+        }
+    }
+
+    if state.no_env_vars && environment_overrides {
+        eprintln!("{}: *** -e has no environment to override with under --no-env-vars.  Stop.", state.basename);
+        return Err(2);
+    }
+
+    // the command line wins over the environment, and the environment over
+    // the built-in variables. under `-e` it wins over the makefiles too.
+    let origin = if environment_overrides { Origin::EnvOverride } else { Origin::Env };
+    for (a, b) in std::env::vars() {
+        let var = Var::new(Flavor::Simple, origin, None, a.clone(), b, true);
+        if state.no_env_vars {
+            state.passed_env.push(var);
+        } else {
+            vars.entry(a).or_insert(var);
+        }
+    }
+
+    if !state.no_builtin_variables {
+        // anything from the environment or the command line wins
+        for (name, value) in BUILTIN_VARS {
+            vars.entry(name.to_string()).or_insert_with(|| {
+                Var::new(
+                    Flavor::Recursive,
+                    Origin::Default,
+                    None,
+                    name.to_string(),
+                    value.to_string(),
+                    false,
+                )
+            });
+        }
+    }
+
+    if let Some(path) = &state.jobserver_fifo {
+        match jobs::open_fifo(path) {
+            Some(fd) => state.jobserver_fds = vec![fd, fd],
+            None => {
+                eprintln!("{}: warning: jobserver fifo '{}' unavailable: using -j1.", state.basename, path);
+                state.jobserver_fifo = None;
+                state.jobs = 1;
+            }
+        }
+    }
+
+    if !state.jobserver_fds.is_empty() && !state.jobserver_fds.iter().all(|fd| sys::is_open(*fd)) {
+        eprintln!(
+            "{}: warning: jobserver unavailable: using -j1.  Add '+' to parent make rule.",
+            state.basename
+        );
+        state.jobserver_fds.clear();
+        state.jobs = 1;
+    }
+
+    if state.jobserver_fds.is_empty() && state.jobs > 1 && state.jobs != jobs::UNLIMITED {
+        match state.jobserver_style {
+            jobs::Style::Pipe => match jobs::Slots::create(state.jobs) {
+                Some((r, w)) => state.jobserver_fds = vec![r, w],
+                None => {
+                    eprintln!("{}: warning: can't create jobserver pipe: using -j1", state.basename);
+                    state.jobs = 1;
+                }
+            },
+            jobs::Style::Fifo => match jobs::Slots::create_fifo(state.jobs) {
+                Some((path, fd)) => {
+                    state.jobserver_fds = vec![fd, fd];
+                    state.jobserver_fifo = Some(path);
+                }
+                None => {
+                    eprintln!("{}: warning: can't create jobserver fifo: using -j1", state.basename);
+                    state.jobs = 1;
+                }
+            },
+        }
+    }
+
+    state.slots = if state.jobserver_fds.is_empty() && state.jobs == jobs::UNLIMITED {
+        jobs::Slots::unlimited()
+    } else {
+        jobs::Slots::new(&state.jobserver_fds)
+    };
+    state.parent_job_id = std::env::var("IMAKE_JOB_ID").ok().filter(|_| state.slots.has_jobserver());
+    if let [r, w] = state.jobserver_fds[..] {
+        long_flags.push(format!("-j{}", state.jobs.max(2)));
+        match &state.jobserver_fifo {
+            Some(path) => long_flags.push(format!("--jobserver-auth=fifo:{}", path)),
+            None => long_flags.push(format!("--jobserver-auth={},{}", r, w)),
         }
+    } else if state.jobs == jobs::UNLIMITED {
+        long_flags.push("-j".to_string());
     }
+    if let Some(max_load) = state.max_load {
+        long_flags.push(format!("-l{}", max_load));
+    }
+    long_flags.extend(state.output_sync.flag());
+    // one job at a time can't interleave
+    if state.jobserver_fds.is_empty() && state.jobs != jobs::UNLIMITED {
+        state.output_sync = output::Mode::None;
+    }
+
+    // on by default for `-C` and sub-makes, unless `-s`
+    let print_directory = print_directory.unwrap_or(!state.silent && (dashC || state.level > 0));
+    long_flags.dedup();
+    overrides.dedup();
+    let makeflags = makeflags_value(&makeflags, print_directory, long_flags, &overrides);
+
+    for (name, value) in [(".FEATURES", features()), (".VARIABLES", String::new())] {
+        vars.insert(
+            name.to_string(),
+            Var::new(Flavor::Simple, Origin::Default, None, name.to_string(), value, false),
+        );
+    }
+
+    let name = "MAKEOVERRIDES".to_string();
+    vars.insert(
+        name.clone(),
+        Var::new(
+            Flavor::Simple,
+            Origin::Default,
+            None,
+            name,
+            overrides.join(" "),
+            false,
+        ),
+    );
+
+    // not `CmdLine`, a makefile may add to it
     let name = "MAKEFLAGS".to_string();
     vars.insert(
         name.clone(),
         Var::new(
             Flavor::Simple,
-            Origin::CmdLine,
+            Origin::Default,
             None,
             name,
             makeflags,
-            false,
+            true,
         ),
     );
 
-    let makefile = makefile_names
-        .into_iter()
-        .find(|name| Path::new(&name).exists())
-        .expect("No makefiles found")
-        .clone();
+    if state.doctor {
+        let makefile = makefile_names.iter().find(|name| Path::new(name).exists());
+        if let Some(makefile) = makefile {
+            parse_makefiles(&mut state, &mut vars, makefile);
+        }
+        return doctor::report(&state, &mut vars, makefile.map(|m| m.as_str()));
+    }
+
+    let Some(makefile) = makefile_names.iter().find(|name| Path::new(name).exists()).cloned() else {
+        // `-f` leaves just the one name
+        if let [named] = &makefile_names[..] {
+            eprintln!("{}: {}: No such file or directory", state.basename, named);
+            eprintln!("{}: *** No rule to make target '{}'.  Stop.", state.basename, named);
+        } else if let Some(goal) = state.targets_to_make.first() {
+            eprintln!("{}: *** No rule to make target '{}'.  Stop.", state.basename, goal);
+        } else {
+            eprintln!("{}: *** No targets specified and no makefile found.  Stop.", state.basename);
+        }
+        return Err(2);
+    };
 
     let mut leaving = None;
 
-    if !state.silent && dashC {
-        println!("{}: Entering directory '{}'", state.basename, state.curdir);
-        leaving = Some(format!(
-            "{}: Leaving directory '{}'",
-            state.basename, state.curdir
-        ));
+    // synced output has them around each piece instead
+    state.print_directory = print_directory;
+    if state.print_directory && state.output_sync == output::Mode::None {
+        let (entering, msg) = directory_messages(&state, state.level, &state.curdir);
+        println!("{}", entering);
+        // our copy is for when make returns, the state's for when it exits
+        state.leaving.borrow_mut().push(msg.clone());
+        leaving = Some(msg);
     }
 
-    let r = state_machine(state, vars, &makefile);
+    let (timings, basename) = (state.timings.clone(), state.basename.clone());
+    let r = if state.daemon {
+        daemon::serve(state, vars, &makefile)
+    } else if state.serve_graph {
+        graph::serve(state, vars, &makefile)
+    } else {
+        state_machine(state, vars, &makefile)
+    };
 
     if let Some(l) = leaving {
-        eprintln!("{}", l);
+        println!("{}", l);
     }
 
+    timings.report(&basename, started.elapsed());
     r
 }
 
+/// Where we are, for `$(CURDIR)`. It's gone if it was removed from under us.
+fn current_dir(state: &State) -> Result<String, u32> {
+    std::env::current_dir().map(|dir| dir.to_string_lossy().into_owned()).map_err(|e| {
+        eprintln!("{}: *** getcwd: {}.  Stop.", state.basename, e);
+        2
+    })
+}
+
+/// Escape whitespace and backslashes so a word survives being packed into
+/// `MAKEFLAGS`
+fn makeflags_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || c.is_whitespace() {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// `MAKEFLAGS` in the same layout as GNU make: `ks -- VAR=a\ b`. Printing
+/// directories is passed down as `w` whether it was asked for or not.
+fn makeflags_value(flags: &str, print_directory: bool, long_flags: Vec<String>, overrides: &[String]) -> String {
+    let mut letters = String::new();
+    for c in flags.chars().chain(print_directory.then_some('w')) {
+        if !letters.contains(c) {
+            letters.push(c);
+        }
+    }
+    let mut makeflags = vec![letters];
+    makeflags.extend(long_flags);
+    if !overrides.is_empty() {
+        makeflags.push("--".into());
+        makeflags.extend(overrides.iter().cloned());
+    }
+    makeflags.join(" ").trim().to_string()
+}
+
+/// Split `MAKEFLAGS` back into arguments. A leading word without a `-` is
+/// a cluster of single letter flags and everything after `--` is a
+/// variable override.
+fn makeflags_args(flags: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = flags.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+                in_word = true;
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    let mut args = Vec::new();
+    let mut overrides = false;
+    for (i, word) in words.into_iter().enumerate() {
+        if word == "--" {
+            overrides = true;
+        } else if overrides || word.starts_with('-') {
+            args.push(word);
+        } else if i == 0 && !word.contains('=') {
+            args.push(format!("-{}", word));
+        } else {
+            args.push(word);
+        }
+    }
+    args
+}
+
+/// The interpreter and optional argument on `path`'s `#!` line
+fn shebang(path: &str) -> Option<(String, Option<String>)> {
+    let file = File::open(path).ok()?;
+    let mut first = String::new();
+    let _ = BufReader::new(file).take(256).read_line(&mut first);
+    let line = first.strip_prefix("#!")?.trim();
+    // like the kernel, everything after the interpreter is one argument
+    match line.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((interp, arg)) => Some((interp.to_string(), Some(arg.trim().to_string()))),
+        None if !line.is_empty() => Some((line.to_string(), None)),
+        None => None,
+    }
+}
+
+/// Untangle the argv we get when running as a script interpreter.
+///
+/// `#!/usr/bin/imake -s -f` hands us `-s -f` as a single argument followed
+/// by the script, and `#!/usr/bin/env imake` just gives us the script. The
+/// `#!` line itself is a comment as far as parsing is concerned. Only an
+/// argv shaped like the kernel's, with the script's interpreter as our own
+/// name, counts; a goal that happens to be a script is left alone.
+fn shebang_args(mut argv: Vec<String>) -> Vec<String> {
+    let named_us = |(interp, arg): &(String, Option<String>)| match arg {
+        Some(arg) if Path::new(interp).file_name().is_some_and(|n| n == "env") => *arg == argv[0],
+        _ => *interp == argv[0],
+    };
+    if argv.len() > 2
+        && argv[1].starts_with('-')
+        && shebang(&argv[2]).is_some_and(|s| named_us(&s) && s.1.as_ref() == Some(&argv[1]))
+    {
+        let opts = argv.remove(1);
+        for (i, opt) in opts.split_whitespace().enumerate() {
+            argv.insert(1 + i, opt.to_string());
+        }
+    } else if argv.len() > 1 && !argv[1].starts_with('-') && shebang(&argv[1]).is_some_and(|s| named_us(&s)) {
+        argv.insert(1, "-f".into());
+    }
+    argv
+}
+
+/// GNU make's built-in variables. These are what the built-in implicit
+/// rules are written in terms of.
+const BUILTIN_VARS: &[(&str, &str)] = &[
+    ("AR", "ar"),
+    ("ARFLAGS", "rv"),
+    ("AS", "as"),
+    ("CC", "cc"),
+    ("CXX", "g++"),
+    ("OBJC", "cc"),
+    ("CPP", "$(CC) -E"),
+    ("FC", "f77"),
+    ("F77", "$(FC)"),
+    ("F77FLAGS", "$(FFLAGS)"),
+    ("LD", "ld"),
+    ("LEX", "lex"),
+    ("YACC", "yacc"),
+    ("M2C", "m2c"),
+    ("PC", "pc"),
+    ("CO", "co"),
+    ("COFLAGS", ""),
+    ("GET", "get"),
+    ("LINT", "lint"),
+    ("MAKEINFO", "makeinfo"),
+    ("TEX", "tex"),
+    ("TEXI2DVI", "texi2dvi"),
+    ("WEAVE", "weave"),
+    ("CWEAVE", "cweave"),
+    ("TANGLE", "tangle"),
+    ("CTANGLE", "ctangle"),
+    ("RM", "rm -f"),
+    ("OUTPUT_OPTION", "-o $@"),
+    ("COMPILE.c", "$(CC) $(CFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.cc", "$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.C", "$(COMPILE.cc)"),
+    ("COMPILE.cpp", "$(COMPILE.cc)"),
+    ("COMPILE.m", "$(OBJC) $(OBJCFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.s", "$(AS) $(ASFLAGS) $(TARGET_MACH)"),
+    ("COMPILE.S", "$(CC) $(ASFLAGS) $(CPPFLAGS) $(TARGET_MACH) -c"),
+    ("COMPILE.f", "$(FC) $(FFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.F", "$(FC) $(FFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.p", "$(PC) $(PFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.r", "$(FC) $(FFLAGS) $(RFLAGS) $(TARGET_ARCH) -c"),
+    ("COMPILE.def", "$(M2C) $(M2FLAGS) $(DEFFLAGS) $(TARGET_ARCH)"),
+    ("COMPILE.mod", "$(M2C) $(M2FLAGS) $(MODFLAGS) $(TARGET_ARCH)"),
+    ("LINK.c", "$(CC) $(CFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.cc", "$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.C", "$(LINK.cc)"),
+    ("LINK.cpp", "$(LINK.cc)"),
+    ("LINK.m", "$(OBJC) $(OBJCFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.o", "$(CC) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.s", "$(CC) $(ASFLAGS) $(LDFLAGS) $(TARGET_MACH)"),
+    ("LINK.S", "$(CC) $(ASFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_MACH)"),
+    ("LINK.f", "$(FC) $(FFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.F", "$(FC) $(FFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.p", "$(PC) $(PFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINK.r", "$(FC) $(FFLAGS) $(RFLAGS) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LINT.c", "$(LINT) $(LINTFLAGS) $(CPPFLAGS) $(TARGET_ARCH)"),
+    ("LEX.l", "$(LEX) $(LFLAGS) -t"),
+    ("LEX.m", "$(LEX) $(LFLAGS) -t"),
+    ("YACC.y", "$(YACC) $(YFLAGS)"),
+    ("YACC.m", "$(YACC) $(YFLAGS)"),
+    ("PREPROCESS.S", "$(CC) -E $(CPPFLAGS)"),
+    ("PREPROCESS.F", "$(FC) $(FFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -F"),
+    ("PREPROCESS.r", "$(FC) $(FFLAGS) $(RFLAGS) $(TARGET_ARCH) -F"),
+    ("CHECKOUT,v", "+$(if $(wildcard $@),,$(CO) $(COFLAGS) $< $@)"),
+    (".LIBPATTERNS", "lib%.so lib%.a"),
+];
+
+/// Deep enough for any sane makefile while staying well clear of the
+/// native stack limit
+const DEFAULT_MAX_EXPANSION_DEPTH: usize = 200;
+
+/// Far deeper than any real tree of sub-makes, but stops a runaway one
+/// before the process table fills up
+const DEFAULT_MAX_RECURSION: u32 = 100;
+
 #[derive(Default)]
 struct ShellState {
     in_string: Option<char>,
@@ -369,7 +1272,7 @@ fn process_for_shell(src: &str) -> String {
 }
 
 /// Read a logical makefile line and discard after comment
-fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool, line_no: &mut usize) -> String {
+fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool, location: &mut Location) -> String {
     let mut line: String = String::new();
 
     let mut needs_line = true;
@@ -382,7 +1285,7 @@ fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool,
         needs_line = false;
         // Handle end of file gracefully
         if matches!(file.read_line(&mut tmp_line), Ok(x) if x > 0) {
-            *line_no += 1;
+            location.line += 1;
 
             if tmp_line.starts_with('#') {
                 continue;
@@ -398,8 +1301,17 @@ fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool,
             }
 
             // we accept ' \t' gmake doesn't
+            let mut spaces = false;
             while just_spaces && matches!(chars.peek(), Some(' ')) {
                 chars.next();
+                spaces = true;
+            }
+            if spaces && state.strict && state.in_rule && matches!(chars.peek(), Some('\t')) {
+                eprintln!(
+                    "{}:{}: *** recipe line indented with spaces before a tab (--strict).  Stop.",
+                    location.file_name, location.line
+                );
+                leave_and_exit(state, 2);
             }
             just_spaces = false;
 
@@ -442,19 +1354,23 @@ fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool,
                         line.push('"');
                     }
                     (a, b, _, '\\') if a | b => {
-                        match chars.peek() {
-                            Some('\n') => {
-                                needs_line = true;
-                            },
-                            _ => {}
-                        }
                         line.push('\\');
-                        line.push(chars.next().unwrap());
+                        // at the very end of the file there's nothing to escape
+                        if let Some(c) = chars.next() {
+                            needs_line |= c == '\n';
+                            line.push(c);
+                        }
                     }
                     (false, false, _, '\\') => match chars.next() {
                         Some('\\') => line.push('\\'),
                         Some('\n') => needs_line = true,
-                        _ => {}
+                        Some('#') => line.push('#'),
+                        // anything else keeps its backslash, like `\include`
+                        Some(c) => {
+                            line.push('\\');
+                            line.push(c);
+                        }
+                        None => line.push('\\'),
                     },
                     (_, _, _, a) => {
                         //if !discard {
@@ -468,20 +1384,27 @@ fn read_logical_line(state: &State, file: &mut BufReader<File>, eof: &mut bool,
         }
     }
 
-    if state.debug {
-        eprintln!("logical line: {}", line);
-    }
+    debug::log(state, debug::Category::Internal, format_args!("logical line: {}", line));
 
     line
 }
 
 fn process_specials(state: &mut State, vars: &mut HashMap<String, Var>) {
+    if let Some(var) = vars.get("VPATH") {
+        let dirs = var.clone().eval(state, &Location::default(), vars);
+        state.vpath.set_general(&dirs);
+    }
+
     for t in &state.rules.clone() {
         if let Some(first_target) = t.targets.get(0) {
             match first_target.as_str() {
+                // with no prerequisites these apply to every target
                 ".SILENT" => {
                     if let RuleData::Prereq(_, prereqs) = &t.data {
                         let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.silent = true;
+                        }
                         state
                             .silent_targets
                             .extend(prereqs.split_whitespace().map(|s| s.to_string()));
@@ -490,79 +1413,396 @@ fn process_specials(state: &mut State, vars: &mut HashMap<String, Var>) {
                     }
                 }
 
-                ".PHONY" => {
+                ".IGNORE" => {
                     if let RuleData::Prereq(_, prereqs) = &t.data {
                         let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.ignore_errors = true;
+                        }
                         state
-                            .phony
+                            .ignore_targets
                             .extend(prereqs.split_whitespace().map(|s| s.to_string()));
                     }
                 }
-                _ => {}
-            }
-        }
-    }
-}
-
-/// setsup some options aswell
-fn select_targets(state: &mut State, vars: &mut HashMap<String, Var>) -> Vec<String> {
-    let mut best_matches = Vec::new();
-    for t in &state.rules.clone() {
-        let first_target = t.targets.get(0).map(|x| x.clone());
-        let first_target = first_target.unwrap_or_default();
-        match t {
-            Rule {
-                data: RuleData::Prereq(_, prereqs),
-                ..
-            } if first_target == ".DEFAULT" => {
-                let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
-                best_matches = prereqs.split_whitespace().map(|s| s.to_string()).collect();
-            }
 
-            Rule { .. } if first_target.starts_with('.') => {}
-            _ => {
-                if best_matches.is_empty() {
-                    best_matches.push(first_target);
+                ".PRECIOUS" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.precious_all = true;
+                        }
+                        state
+                            .precious
+                            .extend(prereqs.split_whitespace().map(|s| s.to_string()));
+                    }
+                }
+
+                ".SECONDARY" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.secondary_all = true;
+                        }
+                        state
+                            .secondary
+                            .extend(prereqs.split_whitespace().map(normalize_path));
+                    }
+                }
+
+                ".INTERMEDIATE" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        for p in prereqs.split_whitespace().map(normalize_path) {
+                            if !state.intermediates.contains(&p) {
+                                state.intermediates.push(p);
+                            }
+                        }
+                    }
+                }
+
+                ".NOTINTERMEDIATE" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.not_intermediate_all = true;
+                        }
+                        state
+                            .not_intermediate
+                            .extend(prereqs.split_whitespace().map(|s| s.to_string()));
+                    }
+                }
+
+                ".INTERACTIVE" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.interactive_all = true;
+                        }
+                        state
+                            .interactive
+                            .extend(prereqs.split_whitespace().map(|s| s.to_string()));
+                    }
+                }
+
+                ".LOW_RESOLUTION_TIME" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        state
+                            .low_resolution
+                            .extend(prereqs.split_whitespace().map(normalize_path));
+                    }
+                }
+
+                ".ATOMIC" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.atomic_all = true;
+                        }
+                        state.atomic.extend(prereqs.split_whitespace().map(normalize_path));
+                    }
+                }
+
+                ".SERIAL_DIRS" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        if prereqs.trim().is_empty() {
+                            state.serial_dirs_all = true;
+                        }
+                        state.serial_dirs.extend(prereqs.split_whitespace().map(dir_name));
+                    }
+                }
+
+                ".EXPORT_ALL_VARIABLES" => state.export_all = true,
+                ".NOTPARALLEL" => state.not_parallel = true,
+
+                ".PHONY" => {
+                    if let RuleData::Prereq(_, prereqs) = &t.data {
+                        let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                        state
+                            .phony
+                            .extend(prereqs.split_whitespace().map(|s| s.to_string()));
+                    }
+                }
+
+                // extension: `.ALIAS: check = test-unit test-integration`
+                // parses as a target specific variable on `.ALIAS`
+                #[cfg(feature = "extensions")]
+                ".ALIAS" => {
+                    if let RuleData::Var(names, _, real, ..) = &t.data {
+                        let real = expand_simple_ng(state, vars, &t.location, real)
+                            .split_whitespace()
+                            .map(|s| s.to_string())
+                            .collect::<Vec<_>>();
+                        for name in names.split_whitespace() {
+                            let shadowed = state.rules.iter().any(|r| {
+                                matches!(r.data, RuleData::Prereq(..) | RuleData::Recipie(_))
+                                    && r.targets.iter().any(|t| t == name)
+                            });
+                            if shadowed {
+                                eprintln!(
+                                    "{}: warning: '{}' is both a target and an alias; the alias wins",
+                                    t.location, name
+                                );
+                            }
+                            state.aliases.insert(name.to_string(), real.clone());
+                        }
+                    }
                 }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// `--missing`: print every prerequisite anywhere in the makefiles that
+/// isn't there and can't be made, with the first rule that wants it. Fails
+/// if there are any.
+fn report_missing(state: &State) -> Result<(), u32> {
+    let mut seen = Vec::new();
+    let mut missing = false;
+    for rule in &state.rules {
+        let (RuleData::Prereq(_, prereqs) | RuleData::OrderOnly(prereqs)) = &rule.data else {
+            continue;
+        };
+        // pattern rules are only templates and special targets' lists
+        // aren't prerequisites
+        if rule.targets.iter().any(|t| t.contains('%') || t.starts_with('.')) {
+            continue;
+        }
+
+        for p in prereqs.split_whitespace().filter(|p| *p != ".WAIT") {
+            if seen.iter().any(|s| s == p) {
+                continue;
+            }
+            seen.push(p.to_string());
+
+            let has_rule = state.rules.iter().any(|r| r.targets.iter().any(|t| t == p));
+            let found = Path::new(p).exists() || state.vpath.search(p).is_some();
+            if found || has_rule || state.phony.iter().any(|t| t == p) {
+                continue;
+            }
+            if implicit::find(state, p).is_some() {
+                continue;
+            }
+            println!("{}: {}: missing, needed by '{}'", rule.location, p, rule.targets.join(" "));
+            missing = true;
+        }
+    }
+
+    if missing {
+        Err(1)
+    } else {
+        Ok(())
+    }
+}
+
+/// While `.DEFAULT_GOAL` is empty the first target of each rule read is a
+/// candidate for it, so it ends up as the makefile's first target unless
+/// the makefile says otherwise. Special targets and pattern rules don't
+/// count.
+fn note_default_goal(vars: &mut HashMap<String, Var>, targets: &[String]) {
+    let Some(first) = targets.first() else { return };
+    if first.starts_with('.') && !first.contains('/') || first.contains('%') {
+        return;
+    }
+    if vars.get(".DEFAULT_GOAL").is_some_and(|v| !v.value.trim().is_empty()) {
+        return;
+    }
+    let name: String = ".DEFAULT_GOAL".into();
+    vars.insert(
+        name.clone(),
+        Var::new(Flavor::Simple, Origin::Default, None, name, first.clone(), false),
+    );
+}
+
+/// The goal when none were given: `.DEFAULT_GOAL`. If the makefile didn't
+/// set that itself, a `.DEFAULT:` rule's prerequisites are still taken
+/// ahead of the first target, like imake always has.
+fn select_targets(state: &mut State, vars: &mut HashMap<String, Var>) -> Vec<String> {
+    let goal = vars.get(".DEFAULT_GOAL").cloned();
+    let assigned = goal.as_ref().is_some_and(|v| !matches!(v.origin, Origin::Default));
+    if !assigned {
+        for t in &state.rules.clone() {
+            if let (Some(".DEFAULT"), RuleData::Prereq(_, prereqs)) = (t.targets.first().map(|t| t.as_str()), &t.data) {
+                let prereqs = expand_simple_ng(state, vars, &t.location, prereqs);
+                return prereqs.split_whitespace().map(|s| s.to_string()).collect();
             }
         }
     }
-    best_matches
+
+    let Some(goal) = goal else { return Vec::new() };
+    let loc = goal.loc.clone().unwrap_or_default();
+    let goals = goal.eval(state, &loc, vars).split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>();
+    if goals.len() > 1 {
+        eprintln!("{}: *** .DEFAULT_GOAL contains more than one target.  Stop.", state.basename);
+        leave_and_exit(state, 2);
+    }
+    goals
 }
 
 fn state_machine(mut state: State, mut vars: HashMap<String, Var>, file: &str) -> Result<(), u32> {
-    process_lines(&mut state, &mut vars, file);
+    parse_makefiles(&mut state, &mut vars, file);
+    make_goals(state, vars)
+}
+
+fn parse_makefiles(state: &mut State, vars: &mut HashMap<String, Var>, file: &str) {
+    if state.parse_cache {
+        let key = cache::cache_key();
+        if !cache::load(state, vars, key) {
+            load_toolchain(state, vars);
+            read_evals(state, vars);
+            process_lines(state, vars, file);
+            cache::save(state, vars, key);
+        }
+    } else {
+        load_toolchain(state, vars);
+        read_evals(state, vars);
+        process_lines(state, vars, file);
+    }
+}
 
+/// `--eval`: read each one like a makefile of its own. They aren't
+/// makefiles as far as `--parse-cache` is concerned.
+fn read_evals(state: &mut State, vars: &mut HashMap<String, Var>) {
+    for text in state.evals.clone() {
+        let written = temp::create("eval").and_then(|(path, mut file)| writeln!(file, "{}", text).map(|_| path));
+        let path = match written {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(e) => {
+                eprintln!("{}: *** can't write --eval text: {}.  Stop.", state.basename, e);
+                leave_and_exit(state, 2)
+            }
+        };
+        process_lines(state, vars, &path);
+        state.makefiles.retain(|m| *m != path);
+    }
+}
+
+/// `--toolchain=FILE`: read FILE ahead of the makefile. What it sets counts
+/// as set with `override`, so the makefile's own `CC = gcc` doesn't undo
+/// choosing a cross compiler.
+fn load_toolchain(state: &mut State, vars: &mut HashMap<String, Var>) {
+    let Some(file) = state.toolchain.clone() else { return };
+    let before = vars.clone();
+    process_lines(state, vars, &file);
+    for (name, var) in vars.iter_mut() {
+        let changed = before.get(name).is_none_or(|old| old.value != var.value || old.loc != var.loc);
+        if changed && !var.overrides_file() {
+            var.origin = Origin::Override;
+        }
+    }
+}
+
+/// Everything after parsing: work out the goals and build them
+fn make_goals(mut state: State, mut vars: HashMap<String, Var>) -> Result<(), u32> {
     process_specials(&mut state, &mut vars);
+    phony::load(&mut state);
 
     build_graph(&mut state, &mut vars);
 
+    if state.missing_report {
+        return report_missing(&state);
+    }
+
+    if state.clean_outputs {
+        return clean::run(&mut state, &vars);
+    }
+
     let mut targets_to_make = state.targets_to_make.clone();
 
     if targets_to_make.is_empty() {
-        targets_to_make = select_targets(&mut state, &mut vars)
+        targets_to_make = select_targets(&mut state, &mut vars);
+        if targets_to_make.is_empty() {
+            eprintln!("{}: *** No targets.  Stop.", state.basename);
+            return Err(2);
+        }
     }
 
-    for t in targets_to_make {
-        // TODO:is here place to push var stack?
-        let vars = vars.clone();
-        if let Some((done_smth, has_recipies)) = process_target(&mut state, &vars, &t) {
-            if !state.silent && !done_smth {
-                if state.phony.contains(&t) || !has_recipies {
-                    eprintln!("{}: Nothing to be done for '{}'.", state.basename, t);
-                } else {
-                    eprintln!("{}: '{}' is up to date.", state.basename, t);
+    let targets_to_make = targets_to_make
+        .into_iter()
+        .flat_map(|t| state.aliases.get(&t).cloned().unwrap_or_else(|| vec![t]))
+        .map(|t| normalize_path(&t))
+        .collect::<Vec<_>>();
+
+    if state.strict {
+        let mut requested = targets_to_make.clone();
+        requested.extend(state.aliases.values().flatten().cloned());
+        if !state.targets_to_make.is_empty() {
+            requested.extend(select_targets(&mut state, &mut vars));
+        }
+        let unrequested = strict::unrequested(&state, &requested);
+        for (loc, target) in &unrequested {
+            eprintln!("{}: *** recipe for '{}' that nothing asks for (--strict)", loc, target);
+        }
+        if !unrequested.is_empty() {
+            return Err(2);
+        }
+    }
+
+    // one job at a time whatever `-j` said
+    if state.not_parallel {
+        state.slots = jobs::Slots::new(&[]);
+    }
+
+    let mut no_rule = false;
+    process_goals(&mut state, &vars, &targets_to_make, |state, t, progress| {
+        let t = t.to_string();
+        match progress {
+            Progress::Done(Some((done_smth, has_recipies))) => {
+                if !state.silent && !state.question && !done_smth {
+                    if state.phony.contains(&t) || !has_recipies {
+                        eprintln!("{}: Nothing to be done for '{}'.", state.basename, t);
+                    } else {
+                        eprintln!("{}: '{}' is up to date.", state.basename, t);
+                    }
                 }
             }
-        } else {
-            eprintln!(
-                "{}: *** No rule to make target '{}'.  Stop.",
-                state.basename, t
-            );
+            Progress::Done(None) => {
+                eprintln!(
+                    "{}: *** No rule to make target '{}'.{}",
+                    state.basename,
+                    t,
+                    if state.keep_going { "" } else { "  Stop." }
+                );
+                if let Some(close) = suggest::closest(state, &t) {
+                    eprintln!("{}: did you mean '{}'?", state.basename, close);
+                }
+                if !state.keep_going {
+                    no_rule = true;
+                    return true;
+                }
+                state.failed.push(t);
+            }
+            // its own recipe failing has been reported already
+            Progress::Failed if !state.failed.contains(&t) && !state.dryrun => {
+                eprintln!("{}: Target '{}' not remade because of errors.", state.basename, t);
+            }
+            _ => {}
         }
+        false
+    });
+    remove_intermediates(&mut state);
+    phony::save(&state);
+
+    if let Some(plan) = &state.plan {
+        println!("{}", plan::to_json(plan));
+    }
+
+    if let Some(coverage) = &state.coverage {
+        coverage.report(&state);
     }
 
-    Ok(())
+    if no_rule {
+        Err(2)
+    } else if state.question && state.out_of_date && state.failed.is_empty() {
+        Err(1)
+    } else if state.failed.is_empty() {
+        Ok(())
+    } else {
+        shuffle::report_failure(&state);
+        Err(2)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -584,6 +1824,17 @@ pub enum Origin {
     Automatic,
 }
 
+/// What `export` and `unexport` said about a variable
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Export {
+    /// Nothing. Passed on if it came from the environment or the command
+    /// line, or everything is being exported.
+    #[default]
+    Default,
+    Export,
+    Unexport,
+}
+
 #[derive(Debug, Clone)]
 pub struct Var {
     flavor: Flavor,
@@ -591,9 +1842,7 @@ pub struct Var {
     loc: Option<Location>,
     name: String,
     value: String,
-    exported: bool,
-    unexported: bool,
-    ex_exported: bool
+    export: Export,
 }
 
 impl Var {
@@ -605,73 +1854,148 @@ impl Var {
         value: String,
         exported: bool,
     ) -> Self {
-        let ret = Self {
+        Self {
             flavor,
             origin,
             loc,
             name,
             value,
-            exported,
-            unexported: false,
-            ex_exported: false
-        };
-        ret.sync_env();
-        ret
+            export: if exported { Export::Export } else { Export::Default },
+        }
     }
 
     pub fn export(&mut self) {
-        self.exported = true;
-        self.ex_exported = true;
-        self.sync_env();
+        self.export = Export::Export;
     }
 
     pub fn unexport(&mut self) {
-        self.exported = false;
-        self.unexported = true;
-        std::env::remove_var(&self.name);
+        self.export = Export::Unexport;
+    }
+
+    /// Set on the command line, with `override` or from the environment
+    /// under `-e`, which a plain assignment in a makefile doesn't change
+    fn overrides_file(&self) -> bool {
+        matches!(self.origin, Origin::CmdLine | Origin::Override | Origin::EnvOverride)
     }
 
-    fn sync_env(&self) {
-        if self.exported {
-            std::env::set_var(&self.name, &self.value);
+    /// Whether recipes get this in their environment. `all` is whether a
+    /// bare `export` is in effect, which leaves out the built-in and
+    /// automatic variables and names the shell couldn't use.
+    pub fn exported(&self, all: bool) -> bool {
+        match self.export {
+            Export::Export => true,
+            Export::Unexport => false,
+            Export::Default => match self.origin {
+                Origin::Env | Origin::EnvOverride | Origin::CmdLine => true,
+                Origin::Default | Origin::Automatic => false,
+                _ => all && is_shell_name(&self.name),
+            },
         }
     }
 
+    /// Children's environments are built from the variables by
+    /// [`guard_env`] when something is about to run
     pub fn store(&mut self, value: String) {
         self.value = value;
-        self.sync_env();
     }
 
     pub fn append(&mut self, value: &str) {
         self.value.push(' ');
-        self.value.extend(value.trim().chars());
-        self.sync_env();
+        self.value.push_str(value.trim());
     }
 
     fn eval(&self, state: &State, location: &Location, vars: &mut HashMap<String, Var>) -> String {
         // TODO: expand if recursive
         match self.flavor {
-            Flavor::Recursive => expand_simple_ng(
-                state,
-                vars,
-                self.loc.as_ref().unwrap_or(location),
-                // TODO: errors should not use the var location but instead should use the line location
-                // for errors
-                //
-                // location,
-                &self.value,
-            ),
+            Flavor::Recursive => {
+                {
+                    let mut stack = state.expansion_stack.borrow_mut();
+                    stack.push(self.name.clone());
+                    let max = match state.max_expansion_depth {
+                        0 => DEFAULT_MAX_EXPANSION_DEPTH,
+                        max => max,
+                    };
+                    if stack.len() > max {
+                        fatal_expansion_depth(state, location, max, &stack);
+                    }
+                }
+
+                // like GNU make, `$(warning)` and `$(error)` inside report
+                // where the variable was used, not where it was defined
+                let out = expand_simple_ng(state, vars, location, &self.value);
+
+                state.expansion_stack.borrow_mut().pop();
+                out
+            }
+            // the built-in `.VARIABLES` is worked out whenever it's used
+            Flavor::Simple if self.name == ".VARIABLES" && matches!(self.origin, Origin::Default) => {
+                let mut names = vars
+                    .iter()
+                    .filter(|(_, v)| !matches!(v.flavor, Flavor::Undefined))
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>();
+                names.sort_unstable();
+                names.join(" ")
+            }
             Flavor::Undefined | Flavor::Simple => self.value.clone(),
         }
     }
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Location {
     file_name: String,
     line: usize,
 }
 
+/// `file:line` as GNU make shows it in job errors. Built-in rules have no
+/// line.
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.file_name),
+            line => write!(f, "{}:{}", self.file_name, line),
+        }
+    }
+}
+
+/// What `ifeq` and `ifneq` compare, either `(a,b)` or two words that are
+/// usually quoted
+fn conditional_args<'a>(state: &State, loc: &Location, args: &'a str) -> (&'a str, &'a str) {
+    let args = args.trim();
+    let split = match args.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+        // the comma that isn't inside a reference of one of them
+        Some(inner) => {
+            let mut depth = 0usize;
+            inner
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '(' | '{' => depth += 1,
+                        ')' | '}' => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    c == ',' && depth == 0
+                })
+                .map(|(i, _)| (&inner[..i], &inner[i + 1..]))
+        }
+        None => {
+            let mut words = args.split_whitespace();
+            words.next().zip(words.next())
+        }
+    };
+    split.unwrap_or_else(|| {
+        eprintln!("{}:{}: *** invalid syntax in conditional.  Stop.", loc.file_name, loc.line);
+        leave_and_exit(state, 2)
+    })
+}
+
+/// `ifdef` is only true when the variable's (unexpanded) value isn't empty,
+/// a plain `X =` doesn't count
+fn is_defined(vars: &HashMap<String, Var>, name: &str) -> bool {
+    vars.get(name.trim()).is_some_and(|v| !v.value.is_empty())
+}
+
 fn process_lines(state: &mut State, vars: &mut HashMap<String, Var>, file_name: &str) {
     #[derive(Debug, Clone, Copy)]
     enum VarOp {
@@ -686,7 +2010,13 @@ fn process_lines(state: &mut State, vars: &mut HashMap<String, Var>, file_name:
         Var(VarOp, String),
     }
 
-    let file = File::open(file_name).expect("can't find file");
+    debug::log(state, debug::Category::Verbose, format_args!("Reading makefile '{}'...", file_name));
+    state.makefiles.push(file_name.to_string());
+    state.timings.enter_makefile(file_name);
+    let file = File::open(file_name).unwrap_or_else(|e| {
+        eprintln!("{}: *** {}: {}.  Stop.", state.basename, file_name, e);
+        leave_and_exit(state, 2)
+    });
     let mut file = BufReader::new(file);
     let mut eof = false;
 
@@ -710,115 +2040,84 @@ fn process_lines(state: &mut State, vars: &mut HashMap<String, Var>, file_name:
     // TODO: .RECIPIEPREFIX
     let recipie_prefix = '\t';
     while !eof {
-        let line = read_logical_line(state, &mut file, &mut eof, &mut location.line);
+        let line = read_logical_line(state, &mut file, &mut eof, &mut location);
         // eprintln!("processing logical line: {}: in rule: {}", line.trim(), state.in_rule);
         //
         if let Some((v_name, op, buf)) = &mut in_define {
-            if line.trim().starts_with("endef") {
-                let v = vars.get(&v_name.to_string());
-                if let Some(v) = v {
-                    match op.as_ref().map(|x| x.as_str()) {
-                        None | Some("=") => {
-                            let v = vars.get_mut(v_name).unwrap();
-                            v.store(buf.to_string());
-                        }
-                        Some(":=") | Some("::=") => {
-                            let buf = expand_simple_ng(state, vars, &location, buf);
-                            let v = vars.get_mut(&v_name.to_string()).unwrap();
-                            v.store(buf.to_string());
-                        }
-                        Some("+=") => {
-                            let buf = if matches!(v.flavor, Flavor::Simple) {
-                                expand_simple_ng(state, vars, &location, buf)
-                            } else {
-                                buf.to_string()
-                            };
-                            let v = vars.get_mut(&v_name.to_string()).unwrap();
-                            v.store(buf.to_string());
-
-                        }
-                        Some(_) => panic!()
+            if directive(&line, "endef").is_some() {
+                // the newline before `endef` isn't part of the value
+                if buf.ends_with('\n') {
+                    buf.pop();
+                }
+                let v = vars.get(v_name.as_str());
+                if v.is_some_and(|v| v.overrides_file()) {
+                    // like any other assignment in a makefile
+                } else if let Some(simple) = v.map(|v| matches!(v.flavor, Flavor::Simple)) {
+                    let value = match op.as_deref() {
+                        Some("?=") => None,
+                        Some(":=") | Some("::=") => Some(expand_simple_ng(state, vars, &location, buf)),
+                        Some("+=") if simple => Some(expand_simple_ng(state, vars, &location, buf)),
+                        _ => Some(buf.to_string()),
+                    };
+                    if let (Some(value), Some(v)) = (value, vars.get_mut(v_name.as_str())) {
+                        v.store(value);
                     }
                 } else {
-                    match op.as_ref().map(|x| x.as_str()) {
-                        None | Some("=") | Some("+=") => {
-                            vars.insert(v_name.clone(), Var::new(Flavor::Recursive, Origin::File, Some(location.clone()), v_name.clone(), buf.to_string(), false));
-                        }
-                        Some(":=") | Some("::=") => {
-                            let buf = expand_simple_ng(state, vars, &location, buf);
-                            vars.insert(v_name.clone(), Var::new(Flavor::Simple, Origin::File, Some(location.clone()), v_name.clone(), buf.to_string(), false));
-                        }
-                        Some(_) => panic!()
-                    }
-
+                    let (flavor, value) = match op.as_deref() {
+                        Some(":=") | Some("::=") => (Flavor::Simple, expand_simple_ng(state, vars, &location, buf)),
+                        _ => (Flavor::Recursive, buf.to_string()),
+                    };
+                    vars.insert(
+                        v_name.clone(),
+                        Var::new(flavor, Origin::File, Some(location.clone()), v_name.clone(), value, false),
+                    );
                 }
-                
+
                 in_define = None;
             } else {
-                buf.extend(line.chars());
+                buf.push_str(&line);
             }
         } else if in_false > 0 {
-            if line.trim().starts_with("ifdef ")
-                || line.trim().starts_with("ifndef ")
-                || line.trim().starts_with("ifeq ")
-                || line.trim().starts_with("ifneq ")
-            {
+            if ["ifdef", "ifndef", "ifeq", "ifneq"].iter().any(|d| directive(&line, d).is_some()) {
                 in_false += 1;
-            } else if line.trim().starts_with("endif") {
+            } else if directive(&line, "endif").is_some() {
                 in_false -= 1;
 
 
                 
-            } else if in_false == 1 && !found_true && line.trim().starts_with("else") {
-                let line = line.trim()[4..].trim();
-                if line.len() == 0 {
+            } else if in_false == 1 && !found_true && directive(&line, "else").is_some() {
+                // `else ifeq ...` and friends take the same arguments as
+                // the conditional on its own line
+                let rest = directive(&line, "else").unwrap_or_default().trim();
+                if rest.is_empty() {
                     in_false = 0;
-                } else if line.trim().starts_with("ifeq ") {
-                    let s_args = line.trim()[5..].trim().to_string();
-                    let len = s_args.len();
-                    let mut args = s_args.chars().peekable();
-                    let mut args: Box<dyn Iterator<Item = _>> = if *args.peek().unwrap() == '(' {
-                        Box::new(s_args[1..(len - 1)].split(','))
-                    } else {
-                        Box::new(s_args.split_whitespace())
-                    };
-                    let a1 = args.next().unwrap();
-                    let a2 = args.next().unwrap();
-                    let a1 = expand_simple_ng(state, vars, &location, &a1).replace(['"', '\''], "");
-                    let a2 = expand_simple_ng(state, vars, &location, &a2).replace(['"', '\''], "");
+                } else if let Some(args) = directive(rest, "ifeq") {
+                    let (a1, a2) = conditional_args(state, &location, args);
+                    let a1 = expand_simple_ng(state, vars, &location, a1).replace(['"', '\''], "");
+                    let a2 = expand_simple_ng(state, vars, &location, a2).replace(['"', '\''], "");
                     if a1.trim() == a2.trim() {
                         in_false = 0;
                     }
-                } else if line.trim().starts_with("ifneq ") {
-                    let s_args = line.trim()[6..].trim().to_string();
-                    let len = s_args.len();
-                    let mut args = s_args.chars().peekable();
-                    let mut args: Box<dyn Iterator<Item = _>> = if *args.peek().unwrap() == '(' {
-                        Box::new(s_args[1..(len - 1)].split(','))
-                    } else {
-                        Box::new(s_args.split_whitespace())
-                    };
-                    let a1 = args.next().unwrap();
-                    let a2 = args.next().unwrap();
-                    let a1 = expand_simple_ng(state, vars, &location, &a1).replace(['"', '\''], "");
-                    let a2 = expand_simple_ng(state, vars, &location, &a2).replace(['"', '\''], "");
+                } else if let Some(args) = directive(rest, "ifneq") {
+                    let (a1, a2) = conditional_args(state, &location, args);
+                    let a1 = expand_simple_ng(state, vars, &location, a1).replace(['"', '\''], "");
+                    let a2 = expand_simple_ng(state, vars, &location, a2).replace(['"', '\''], "");
                     if a1.trim() != a2.trim() {
                         in_false = 0;
                     }
-                } else if line.trim().starts_with("ifdef") {
-                    let var = line.trim()[6..].trim();
-                    let var = expand_simple_ng(state, vars, &location, &var);
-
-                    if vars.contains_key(&var) {
+                } else if let Some(var) = directive(rest, "ifdef") {
+                    let var = expand_simple_ng(state, vars, &location, var.trim());
+                    if is_defined(vars, &var) {
                         in_false = 0;
                     }
-                } else if line.trim().starts_with("ifndef ") {
-                    let var = line.trim()[7..].trim();
-                    let var = expand_simple_ng(state, vars, &location, &var);
-
-                    if !vars.contains_key(&var) {
+                } else if let Some(var) = directive(rest, "ifndef") {
+                    let var = expand_simple_ng(state, vars, &location, var.trim());
+                    if !is_defined(vars, &var) {
                         in_false = 0;
                     }
+                } else {
+                    eprintln!("{}: extraneous text after 'else' directive", location);
+                    in_false = 0;
                 }
             }
         } else {
@@ -840,95 +2139,181 @@ fn process_lines(state: &mut State, vars: &mut HashMap<String, Var>, file_name:
                             data: RuleData::Recipie(l),
                         },
 
-                        t => panic!("{:#?}:{}", t, l),
+                        _ => fatal_recipe_outside_rule(state, &location),
                     };
                     state.rules.push(r);
                 }
-                l if l.starts_with(recipie_prefix) && !state.in_rule => {
-                    panic!("Not currently within a rule {}", l);
+                // a rule's recipe runs until the next line that isn't part
+                // of it. blank lines, comments and conditionals don't end
+                // it, anything else (include, define, assignments) does.
+                // outside a rule only blank and comment lines may start
+                // with the prefix.
+                l if l.starts_with(recipie_prefix) => {
+                    let rest = l[recipie_prefix.len_utf8()..].trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        fatal_recipe_outside_rule(state, &location);
+                    }
                 }
                 l if l.trim().is_empty() => {
                     // do nothing on empty lines that don't start with rule prefix
                     // state.in_rule = false;
                 }
-                l if l.starts_with("include ") => {
+                // `\include foo: x` is a rule for targets `include` and `foo`
+                l if l
+                    .strip_prefix('\\')
+                    .and_then(|rest| rest.split(|c: char| c.is_ascii_whitespace() || c == ':').next())
+                    .is_some_and(|word| DIRECTIVES.contains(&word)) =>
+                {
+                    parse_line(state, vars, &location, &l[1..])
+                }
+                #[cfg(feature = "extensions")]
+                l if l.starts_with("include --scoped ") => {
+                    state.in_rule = false;
+                    include_scoped(state, vars, l["include --scoped ".len()..].trim());
+                    state.in_rule = false;
+                }
+                l if directive(&l, "include").is_some() => {
                     state.in_rule = false;
 
-                    process_lines(state, vars, &l[8..].trim());
+                    // a bare `include` names nothing to read
+                    let file = directive(&l, "include").unwrap_or_default().trim();
+                    let file = &find_include(state, file);
+                    if !file.is_empty() && !Path::new(file).exists() {
+                        eprintln!("{}: {}: No such file or directory", location, file);
+                        eprintln!("{}: *** No rule to make target '{}'.  Stop.", state.basename, file);
+                        leave_and_exit(state, 2);
+                    }
+                    if !file.is_empty() {
+                        process_lines(state, vars, file);
+                    }
+                    // a rule at the end of the included file doesn't carry on here
+                    state.in_rule = false;
                 }
-                l if l.trim().starts_with("ifeq ") => {
-                    let s_args = l.trim()[5..].trim().to_string();
-                    let len = s_args.len();
-                    let mut args = s_args.chars().peekable();
-                    let mut args: Box<dyn Iterator<Item = _>> = if *args.peek().unwrap() == '(' {
-                        Box::new(s_args[1..(len - 1)].split(','))
-                    } else {
-                        Box::new(s_args.split_whitespace())
-                    };
-                    let a1 = args.next().unwrap();
-                    let a2 = args.next().unwrap();
+                l if directive(&l, "ifeq").is_some() => {
+                    let (a1, a2) = conditional_args(state, &location, directive(&l, "ifeq").unwrap_or_default());
                     let a1 = expand_simple_ng(state, vars, &location, &a1).replace(['"', '\''], "");
                     let a2 = expand_simple_ng(state, vars, &location, &a2).replace(['"', '\''], "");
                     if a1.trim() != a2.trim() {
                         in_false += 1
                     }
                 }
-                l if l.trim().starts_with("ifneq ") => {
-                    let s_args = l.trim()[5..].trim().to_string();
-                    let len = s_args.len();
-                    let mut args = s_args.chars().peekable();
-                    let mut args: Box<dyn Iterator<Item = _>> = if *args.peek().unwrap() == '(' {
-                        Box::new(s_args[1..(len - 1)].split(','))
-                    } else {
-                        Box::new(s_args.split_whitespace())
-                    };
-                    let a1 = args.next().unwrap();
-                    let a2 = args.next().unwrap();
+                l if directive(&l, "ifneq").is_some() => {
+                    let (a1, a2) = conditional_args(state, &location, directive(&l, "ifneq").unwrap_or_default());
                     let a1 = expand_simple_ng(state, vars, &location, &a1).replace(['"', '\''], "");
                     let a2 = expand_simple_ng(state, vars, &location, &a2).replace(['"', '\''], "");
                     if a1.trim() == a2.trim() {
                         in_false += 1
                     }
                 }
-                l if l.trim().starts_with("ifdef ") => {
-                    let var = l.trim()[6..].trim();
+                l if directive(&l, "ifdef").is_some() => {
+                    let var = directive(&l, "ifdef").unwrap_or_default().trim();
                     let var = expand_simple_ng(state, vars, &location, &var);
-                    if !vars.contains_key(&var) {
+                    if !is_defined(vars, &var) {
                         in_false += 1
                     }
                 }
-                l if l.trim().starts_with("ifndef ") => {
-                    let var = l.trim()[7..].trim();
+                l if directive(&l, "ifndef").is_some() => {
+                    let var = directive(&l, "ifndef").unwrap_or_default().trim();
                     let var = expand_simple_ng(state, vars, &location, &var);
-                    if vars.contains_key(&var) {
+                    if is_defined(vars, &var) {
                         in_false += 1
                     }
                 }
-                l if l.trim().starts_with("endif") => {
+                l if directive(&l, "endif").is_some() => {
                     // TODO: in_true?
                 }
-                l if l.trim().starts_with("else") => {
+                l if directive(&l, "else").is_some() => {
                     found_true = true;
                     in_false += 1;
                 }
-                l if l.starts_with("-include ") | l.starts_with("sinclude ") => {
+                l if directive(&l, "vpath").is_some() => {
+                    state.in_rule = false;
+                    let args = expand_simple_ng(state, vars, &location, directive(&l, "vpath").unwrap_or_default());
+                    state.vpath.directive(&args);
+                }
+                l if directive(&l, "-include").is_some() || directive(&l, "sinclude").is_some() => {
                     state.in_rule = false;
-                    if Path::new(l[8..].trim()).exists() {
-                        process_lines(state, vars, &l[8..].trim());
+                    let file = directive(&l, "-include").or(directive(&l, "sinclude")).unwrap_or_default().trim();
+                    let file = &find_include(state, file);
+                    if Path::new(file).exists() {
+                        process_lines(state, vars, file);
+                        state.in_rule = false;
+                    } else {
+                        // so the parse cache notices when it turns up
+                        state.makefiles.push(file.to_string());
                     }
                 }
-                l if l.trim().starts_with("define ") => {
-                    let mut args = l.split_whitespace();
-                    let _define = args.next().unwrap();
-                    let v_name = args.next().unwrap();
-                    let op = args.next();
+                l if directive(&l, "define").is_some() => {
+                    // the name is everything up to the optional operator
+                    // and is expanded like any other variable name
+                    let rest = directive(&l, "define").unwrap_or_default().trim();
+                    let (v_name, op) = match ["::=", ":=", "+=", "?=", "="]
+                        .iter()
+                        .find(|op| rest.ends_with(*op))
+                    {
+                        Some(op) => (&rest[..rest.len() - op.len()], Some(op.to_string())),
+                        None => (rest, None),
+                    };
+                    let v_name = expand_simple_ng(state, vars, &location, v_name).trim().to_string();
 
-                    in_define = Some((v_name.into(), op.map(|x| x.into()), String::new()));
+                    state.in_rule = false;
+                    in_define = Some((v_name, op, String::new()));
                 }
                 l => parse_line(state, vars, &location, &l),
             }
         }
     }
+
+    state.timings.leave_makefile();
+}
+
+/// Words that start a directive rather than a rule or an assignment
+const DIRECTIVES: &[&str] = &[
+    "include", "-include", "sinclude", "ifeq", "ifneq", "ifdef", "ifndef", "else", "endif", "define", "endef",
+    "export", "unexport", "vpath", "override",
+];
+
+/// The rest of `line` if it's the directive `keyword`: the keyword is the
+/// first word, and isn't followed by `:` or `=` as in a rule for a target
+/// of the same name or an assignment to a variable of that name
+fn directive<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = line.trim_start().strip_prefix(keyword)?;
+    if !rest.is_empty() && !rest.starts_with(|c: char| c.is_ascii_whitespace()) {
+        return None;
+    }
+    let after = rest.trim_start();
+    let assigns = ["=", ":", "+=", "?=", "!="].iter().any(|op| after.starts_with(op));
+    (!assigns).then_some(rest)
+}
+
+/// `include --scoped dir/foo.mk`: read it like `include`, but the
+/// variables it sets are only kept as `foo.NAME`, leaving ours alone. Ones
+/// it exports are kept under their own names. While the file is being read
+/// they go by their plain names; a recursive variable is expanded after
+/// that, so one referring to another from the same file should use the
+/// `foo.` name.
+#[cfg(feature = "extensions")]
+fn include_scoped(state: &mut State, vars: &mut HashMap<String, Var>, file_name: &str) {
+    let path = Path::new(file_name);
+    let scope = path.file_stem().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+
+    let mut inner = vars.clone();
+    process_lines(state, &mut inner, file_name);
+    for (name, mut var) in inner {
+        let changed = vars
+            .get(&name)
+            .is_none_or(|old| old.value != var.value || old.loc != var.loc || old.export != var.export);
+        if !changed {
+            continue;
+        }
+        // special variables like `.DEFAULT_GOAL` are make's, not the file's
+        if var.export == Export::Export || name.starts_with('.') {
+            vars.insert(name, var);
+        } else {
+            var.name = format!("{}.{}", scope, name);
+            vars.insert(var.name.clone(), var);
+        }
+    }
 }
 
 // TODO: rule execution handling
@@ -964,8 +2349,28 @@ enum VarOp {
 #[derive(Debug, Clone)]
 enum RuleData {
     Prereq(bool, String),
-    Var(String, VarOp, String),
+    /// Target-specific assignment, whether it's also exported and whether
+    /// it's `private` to the target, not inherited by its prerequisites
+    Var(String, VarOp, String, bool, bool),
     Recipie(String),
+    /// `$*` for a target of a static pattern rule
+    Stem(String),
+    /// Prerequisites after a `|`, made first but not compared against.
+    /// Comes just before the `Prereq` of the same rule.
+    OrderOnly(String),
+}
+
+/// Latch making sure a target is only ever made once per run
+#[derive(Debug, Clone)]
+enum Progress {
+    /// Its prerequisites or recipe are being made right now
+    InFlight,
+    /// Made or already up to date: whether anything was done and whether
+    /// it has a recipe. `None` if there's no rule to make it.
+    Done(Option<(bool, bool)>),
+    /// Its recipe or one of its prerequisites failed under `-k`. Anything
+    /// depending on it isn't attempted.
+    Failed,
 }
 
 /// All the rules for a single target bundled together for processing
@@ -973,8 +2378,11 @@ enum RuleData {
 #[derive(Debug, Clone, Default)]
 struct TargetRule {
     target: String,
-    vars: HashMap<String, String>,
+    /// Target-specific assignments in the order they were read, with
+    /// whether they're exported and whether they're private
+    vars: Vec<(Location, String, VarOp, String, bool, bool)>,
     prerequisites: Vec<String>,
+    order_only: Vec<String>,
 }
 
 fn build_graph(state: &mut State, vars: &HashMap<String, Var>) {
@@ -1035,79 +2443,250 @@ fn build_graph(state: &mut State, vars: &HashMap<String, Var>) {
                 }
             }
             Rule { targets, data: RuleData::Recipie(recipie), .. } => {
-                for target in targets {
-                    match str_lut.get_mut(target) {
-                        Some(target) => {
-                            graph[target[target.len() - 1]].recipies.push(recipie.to_string());
-                        }
-                        None => {
-                            panic!();
-                            // TODO: unreachable!()
-                        }
-                    }
-                }
-            }
-            Rule { targets, data: RuleData::Var(lhs, op, rhs), .. } => {
-                for target in targets {
-                    match str_lut.get_mut(target) {
-                        Some(target) => {
-
-                        }
-                        None => {}
-                    }
+                // a recipe always follows its rule's prerequisites
+                for ids in targets.iter().filter_map(|target| str_lut.get(target)) {
+                    graph[ids[ids.len() - 1]].recipies.push(recipie.to_string());
                 }
             }
+            // target-specific variables aren't part of the graph yet
+            Rule { data: RuleData::Var(..), .. } => {}
             _ => ()
         }
     }
 
-    if state.debug {
-        eprintln!("{:#?}", graph);
-    }
+    debug::log(state, debug::Category::Internal, format_args!("{:#?}", graph));
 }
 
-fn process_target(
+/// Whether goals `a` and `b` may be worked on at the same time. Goals are
+/// started left to right, and without `-j` each is finished before the
+/// next starts. Under `-j` the next goal is started while earlier ones are
+/// still going, unless either is phony: what `clean` or `install` does to
+/// the tree isn't in the graph, so `make -j clean all` finishes `clean`
+/// before starting on `all`. `.NOTPARALLEL` never overlaps them.
+fn goals_overlap(state: &State, a: &str, b: &str) -> bool {
+    state.jobs > 1
+        && !state.not_parallel
+        && !state.phony.iter().any(|p| p == a || p == b)
+}
+
+/// The order to go round the goals being worked on in for the `turn`th
+/// time. Each goal gets to start what it can before the others in turn, so
+/// one with plenty ready to run can't hold every job slot until it's done.
+fn take_turns(active: &[String], turn: usize) -> Vec<String> {
+    let mut order = active.to_vec();
+    if !active.is_empty() {
+        order.rotate_left(turn % active.len());
+    }
+    order
+}
+
+/// Make `goals`, calling `done` as each one finishes. `done` returns true
+/// to stop starting anything new, in which case this returns once the
+/// running jobs are finished.
+fn process_goals(
     state: &mut State,
     vars: &HashMap<String, Var>,
-    name: &str,
-) -> Option<(bool, bool)> {
-    let mut done_smth = false;
-    let mut vars = vars.clone();
-    vars.insert(
-        "@".into(),
-        Var::new(
-            Flavor::Simple,
-            Origin::Automatic,
-            None,
-            "@".into(),
-            name.into(),
-            false,
-        ),
-    );
+    goals: &[String],
+    mut done: impl FnMut(&mut State, &str, Progress) -> bool,
+) {
+    let mut sched = Schedule {
+        vars: vars.clone(),
+        ..Default::default()
+    };
+    let mut pending = goals.iter().cloned().collect::<VecDeque<_>>();
+    let mut active: Vec<String> = Vec::new();
+    let mut halted = false;
+    let mut turn = 0;
+
+    loop {
+        if sched.stopping && sched.running == 0 {
+            if let Some(sig) = sched.interrupted {
+                leave_all(state);
+                temp::remove_all();
+                sys::die_of(sig);
+            }
+            if halted {
+                return;
+            }
+            // with more than one job going the first error may have
+            // scrolled away by now
+            if let Some(first) = sched.first_failure.as_ref().filter(|_| sched.casualties > 0) {
+                eprintln!("{}: *** The first failure was {}", state.basename, first);
+            }
+            remove_intermediates(state);
+            shuffle::report_failure(state);
+            leave_and_exit(state, 2);
+        }
 
-    if state.processed.contains(&name.to_string()) {
-        return Some((false, false));
-    } else {
-        state.processed.push(name.to_string());
+        while !sched.stopping {
+            let Some(next) = pending.front() else { break };
+            if !active.iter().all(|a| goals_overlap(state, a, next)) {
+                break;
+            }
+
+            let goal = pending.pop_front().unwrap();
+            match state.processed.get(&goal) {
+                // named twice, or made already as part of an earlier goal
+                Some(Progress::Done(result)) => {
+                    let progress = Progress::Done(result.map(|_| (false, false)));
+                    if done(state, &goal, progress) {
+                        sched.stopping = true;
+                        halted = true;
+                    }
+                    continue;
+                }
+                Some(Progress::Failed) => {
+                    if done(state, &goal, Progress::Failed) {
+                        sched.stopping = true;
+                        halted = true;
+                    }
+                    continue;
+                }
+                Some(Progress::InFlight) => {}
+                None => {
+                    debug::log(state, debug::Category::Verbose, format_args!("Considering target file '{}'.", goal));
+                    let node = prepare_target(state, vars, &goal);
+                    state.processed.insert(goal.clone(), Progress::InFlight);
+                    sched.nodes.insert(goal.clone(), node);
+                }
+            }
+            active.push(goal);
+        }
+
+        if active.is_empty() && (pending.is_empty() || sched.stopping) {
+            if sched.running == 0 {
+                return;
+            }
+        } else if active.is_empty() {
+            continue;
+        }
+
+        sched.starved = false;
+        for goal in take_turns(&active, turn) {
+            if sched.stopping {
+                break;
+            }
+            if let Some(progress) = advance(state, &mut sched, &goal, &mut Vec::new()) {
+                if let Some(i) = active.iter().position(|a| *a == goal) {
+                    active.remove(i);
+                }
+                if done(state, &goal, progress) {
+                    sched.stopping = true;
+                    halted = true;
+                }
+            }
+        }
+        turn += 1;
+
+        if !active.is_empty() || sched.running > 0 {
+            reap(state, &mut sched);
+        }
     }
+}
+
+/// Everything going on while a goal is being made
+#[derive(Default)]
+struct Schedule {
+    /// The global variables, which each node's overlay is applied to
+    vars: HashMap<String, Var>,
+    /// Targets that have been started on but aren't finished
+    nodes: HashMap<String, Node>,
+    /// How many recipe lines are running right now
+    running: usize,
+    /// Serialized directories with a recipe in them holding a job slot
+    busy_dirs: Vec<String>,
+    /// Interrupt handlers to put back once nothing is running
+    interrupts: Option<[usize; 3]>,
+    /// Something is ready to run but there's no job slot for it
+    starved: bool,
+    /// When recent jobs were started, which the load average won't show
+    /// for a while yet
+    started: VecDeque<Instant>,
+    /// `-O` scratch files no recipe is using right now
+    spare_output: Vec<output::Captured>,
+    /// A recipe failed (without `-k`) or we were interrupted. Nothing new
+    /// is started and we exit once the running jobs are done.
+    stopping: bool,
+    interrupted: Option<i32>,
+    /// `[location: target] Error n` of the recipe that stopped us
+    first_failure: Option<String>,
+    /// Jobs that failed or were killed after that one
+    casualties: usize,
+}
+
+/// A target the scheduler has started on
+struct Node {
+    /// The variables it sees that aren't the global ones
+    vars: overlay::Overlay,
+    /// What the target's private variables were before it set them, which
+    /// is what its prerequisites get
+    hidden: Vec<(String, Option<Var>)>,
+    /// Every prerequisite as written
+    prerequisites: Vec<String>,
+    /// The target that first needed this one, what needed that and so on
+    /// up to the goal
+    needed_by: Vec<String>,
+    /// Prerequisites not yet seen to finish, first mention of each only
+    waiting: Vec<String>,
+    recipies: Vec<(Location, String)>,
+    found_rules: bool,
+    done_smth: bool,
+    prereq_failed: bool,
+    /// Set once the prerequisites are done and it needs remaking
+    run: Option<Run>,
+}
+
+/// A recipe being run
+struct Run {
+    /// Modification time before the recipe started
+    mtime: Option<SystemTime>,
+    /// Why it's being remade
+    reason: plan::Reason,
+    /// The job slot, taken when the first line is about to run and kept
+    /// until the last is done
+    slot: Option<jobs::Slot>,
+    /// `.ATOMIC`: what `$@` names instead of the target until it's done
+    temp: Option<String>,
+    /// `-O`: where its output is kept until it can be shown
+    output: Option<output::Captured>,
+    /// Expanded lines still to run, with whether they're recursive makes
+    lines: VecDeque<(Location, String, bool)>,
+    has_recipies: bool,
+    executed: Vec<String>,
+    job: Option<Job>,
+    failed: bool,
+}
+
+/// A recipe line running in the background
+struct Job {
+    pid: i32,
+    loc: Location,
+    cmd: String,
+    ignore_errors: bool,
+    since: Option<Instant>,
+    /// We sent it SIGTERM because of `--halt=now`
+    killed: bool,
+}
+
+/// Gather the rules for `name` and set up its automatic variables
+fn prepare_target(state: &mut State, vars: &HashMap<String, Var>, name: &str) -> Node {
+    prepare_inherited(state, vars, &overlay::Overlay::default(), name)
+}
+
+/// [`prepare_target`] for a prerequisite of something with `inherited`
+/// variables of its own
+fn prepare_inherited(state: &mut State, globals: &HashMap<String, Var>, inherited: &overlay::Overlay, name: &str) -> Node {
+    let mut vars = inherited.clone();
+    set_automatic(&mut vars, "@", &[name.to_string()]);
 
     let mut target_rule = TargetRule::default();
     target_rule.target = name.to_owned();
 
     let mut recipies = Vec::new();
 
-    let mut prereqs_var = Var::new(
-        Flavor::Simple,
-        Origin::Automatic,
-        None,
-        "?".into(),
-        "".into(),
-        false,
-    );
-
-    let mut was_prereq = false;
     let mut was_recipies = false;
     let mut found_rules = false;
+    let mut stem = None;
 
     let mut was_single = false;
     let mut was_double = false;
@@ -1116,1573 +2695,4843 @@ fn process_target(
         if rule.targets.contains(&name.to_owned()) {
             found_rules |= true;
             match &rule.data {
-                RuleData::Var(a, _op, b) => {
-                    target_rule.vars.insert(a.into(), b.into());
-                    was_prereq = false;
+                RuleData::Var(a, op, b, export, private) => {
+                    target_rule
+                        .vars
+                        .push((rule.location.clone(), a.clone(), *op, b.clone(), *export, *private));
                     was_recipies = false;
                 }
                 RuleData::Prereq(a, prereqs) => {
                     // let prereqs = expand_simple_ng(state, &mut vars, &rule.location, prereqs);
                     if *a && was_single {
-                        fatal_double_and_single(&rule.location, name);
+                        fatal_double_and_single(state, &rule.location, name);
                     } else if !*a && was_double {
-                        fatal_double_and_single(&rule.location, name);
+                        fatal_double_and_single(state, &rule.location, name);
                     } else if *a {
                         was_double = true;
                     } else {
                         was_single = true;
                     }
 
-                    prereqs_var.append(&prereqs);
-
                     target_rule
                         .prerequisites
                         .extend(prereqs.split_whitespace().map(|s| s.to_string()));
-                    was_prereq = true;
                     was_recipies = false;
                 }
                 RuleData::Recipie(r) => {
-                    if !recipies.is_empty() && !was_recipies {
-                        if !was_prereq {
-                            panic!();
-                        } else if !was_double {
-                            recipies = Vec::new();
-                        }
+                    // a later recipe replaces the earlier one unless they're `::`
+                    if !recipies.is_empty() && !was_recipies && !was_double {
+                        recipies = Vec::new();
                     }
                     was_recipies = true;
-                    was_prereq = false;
                     recipies.push((rule.location.clone(), r.clone()));
                 }
+                RuleData::Stem(s) => stem = Some(s.clone()),
+                RuleData::OrderOnly(prereqs) => {
+                    target_rule
+                        .order_only
+                        .extend(prereqs.split_whitespace().map(|s| s.to_string()));
+                }
+            }
+        }
+    }
+
+    // no recipe of its own so maybe a pattern rule has one. what it needs
+    // comes before any other prerequisites so it's what `$<` names.
+    if recipies.is_empty() && !state.phony.iter().any(|p| p == name) {
+        if let Some(implicit) = implicit::find(state, name) {
+            if let Some(coverage) = &mut state.coverage {
+                coverage.matched(&implicit.rule);
+            }
+            found_rules = true;
+            for i in &implicit.intermediate {
+                let i = normalize_path(i);
+                if !state.intermediates.contains(&i) {
+                    state.intermediates.push(i);
+                }
             }
+            target_rule.prerequisites.splice(0..0, implicit.prereqs);
+            target_rule.order_only.extend(implicit.order_only);
+            recipies = implicit.recipe;
+            stem = Some(implicit.stem);
         }
     }
 
-    vars.insert("?".into(), prereqs_var.clone());
-    prereqs_var.name = "<".into();
-    vars.insert("<".into(), prereqs_var);
+    // an explicit rule's `$*` is the target without its suffix, if it has
+    // one we know about
+    let stem = stem.unwrap_or_else(|| {
+        implicit::suffixes(state)
+            .iter()
+            .find_map(|s| name.strip_suffix(s.as_str()))
+            .unwrap_or_default()
+            .to_string()
+    });
+    set_automatic(&mut vars, "*", &[stem]);
 
-    for t in &target_rule.prerequisites {
-        if let Some((a, ..)) = process_target(state, &vars, t) {
-            done_smth |= a;
-        } else if !state.phony.contains(&t.trim().to_string()) {
-            println!(
-                "{}: *** No rule to make target '{}', needed by '{}'. Stop",
-                state.basename, t, name
-            );
-            std::process::exit(130);
+    // pattern rules can make names that aren't in shape
+    for p in target_rule.prerequisites.iter_mut().chain(&mut target_rule.order_only) {
+        *p = vpath::resolve(state, &normalize_path(p));
+    }
+
+    // order-only prerequisites are made all the same, only their mtimes
+    // are ignored. `.WAIT` isn't a prerequisite at all, it only holds back
+    // the ones after it until the ones before it are made.
+    let mut waiting: Vec<String> = Vec::new();
+    for t in target_rule.prerequisites.iter().chain(&target_rule.order_only) {
+        if t == ".WAIT" {
+            if waiting.last().is_some_and(|w| w != ".WAIT") {
+                waiting.push(t.clone());
+            }
+        } else if !waiting.contains(t) {
+            waiting.push(t.clone());
         }
     }
+    if waiting.last().is_some_and(|w| w == ".WAIT") {
+        waiting.pop();
+    }
+    target_rule.prerequisites.retain(|p| p != ".WAIT");
+    target_rule.order_only.retain(|p| p != ".WAIT");
 
-    let path = Path::new(name);
-    let mut needs_updating = false;
-    if state.phony.contains(&name.to_string()) {
-        needs_updating = true;
-    } else if let Ok(Ok(time)) = path.metadata().map(|m| m.modified()) {
-        for p in &target_rule.prerequisites {
-            if state.phony.contains(p) {
-                needs_updating = true;
-                // phony targets always exist
-                found_rules = true;
-            } else {
-                let ptime = Path::new(&p).metadata().map(|m| m.modified());
+    // `$+` keeps every mention, `$^` only the first of each. `$?` can't be
+    // worked out until the prerequisites are made so for now it's all of
+    // them.
+    let mut unique = Vec::new();
+    for t in &target_rule.prerequisites {
+        if !unique.contains(t) {
+            unique.push(t.clone());
+        }
+    }
+    let first = unique.iter().take(1).cloned().collect::<Vec<_>>();
+    // being a normal prerequisite anywhere wins over being order-only
+    let mut order_only = Vec::new();
+    for t in &target_rule.order_only {
+        if !unique.contains(t) && !order_only.contains(t) {
+            order_only.push(t.clone());
+        }
+    }
+    for (var, list) in [
+        ("+", &target_rule.prerequisites),
+        ("^", &unique),
+        ("?", &unique),
+        ("<", &first),
+        ("|", &order_only),
+    ] {
+        set_automatic(&mut vars, var, list);
+    }
 
-                if let Ok(Ok(ptime)) = ptime {
-                    if ptime > time {
-                        needs_updating = true;
-                    }
-                } else {
-                    needs_updating = true;
+    // after the automatic variables so a recursive `X = $@` sees this
+    // target. `:=` was expanded when it was read, before there was one.
+    // prerequisites are prepared from these vars so they inherit them,
+    // except for the private ones which they see as they were before.
+    let mut hidden: Vec<(String, Option<Var>)> = Vec::new();
+    for (loc, lhs, op, rhs, export, private) in target_rule.vars {
+        let old = vars.get(globals, &lhs).cloned();
+        if private && !hidden.iter().any(|(name, _)| *name == lhs) {
+            hidden.push((lhs.clone(), old.clone()));
+        }
+        let flavor = match op {
+            VarOp::Store(true) => Flavor::Simple,
+            VarOp::Store(false) => Flavor::Recursive,
+            VarOp::Append if old.is_some() => {
+                let mut var = old.unwrap();
+                var.append(&rhs);
+                if export {
+                    var.export();
                 }
+                vars.set(lhs, var);
+                continue;
+            }
+            VarOp::Append => Flavor::Recursive,
+            VarOp::StoreIfUndef if old.is_some() => continue,
+            VarOp::StoreIfUndef => Flavor::Recursive,
+            VarOp::Shell => continue,
+        };
+        let mut var = Var::new(flavor, Origin::File, Some(loc), lhs.clone(), rhs.trim().to_string(), export);
+        if !export {
+            // `t: X = 1` doesn't change whether X is exported
+            if let Some(old) = old {
+                var.export = old.export;
             }
         }
-    } else {
-        needs_updating = true;
+        vars.set(lhs, var);
     }
 
-    if !found_rules && needs_updating {
-        return None;
+    // `.NOTPARALLEL` asked for them one after the other as written
+    if !state.not_parallel {
+        for between_waits in waiting.split_mut(|w| w == ".WAIT") {
+            shuffle::apply(state.shuffle, name, between_waits);
+        }
     }
 
-    let mut has_recipies = false;
+    Node {
+        vars,
+        hidden,
+        waiting,
+        prerequisites: target_rule.prerequisites,
+        needed_by: Vec::new(),
+        recipies,
+        found_rules,
+        done_smth: false,
+        prereq_failed: false,
+        run: None,
+    }
+}
 
-    if needs_updating {
-        let mut expanded = Vec::new();
+/// Set automatic variable `name` to `words`, along with its `D` and `F`
+/// forms: the directory part of each word and the file name in it
+fn set_automatic(vars: &mut overlay::Overlay, name: &str, words: &[String]) {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for word in words {
+        let (dir, file) = match word.rsplit_once('/') {
+            Some(("", file)) => ("/", file),
+            Some((dir, file)) => (dir, file),
+            None => (".", word.as_str()),
+        };
+        dirs.push(dir);
+        files.push(file);
+    }
 
-        for (loc, r) in &recipies {
-            let cmd = expand_simple_ng(state, &mut vars, loc, r);
+    for (name, value) in [
+        (name.to_string(), words.join(" ")),
+        (format!("{}D", name), dirs.join(" ")),
+        (format!("{}F", name), files.join(" ")),
+    ] {
+        vars.set(name.clone(), Var::new(Flavor::Simple, Origin::Automatic, None, name, value, false));
+    }
+}
 
-            let cmd = cmd.trim();
+/// Get `name` as far along as it can go without waiting. Returns how it
+/// went once it's finished.
+fn advance(state: &mut State, sched: &mut Schedule, name: &str, path: &mut Vec<String>) -> Option<Progress> {
+    match state.processed.get(name) {
+        Some(Progress::InFlight) => {}
+        Some(progress) => return Some(progress.clone()),
+        None => return None,
+    }
 
-            if !cmd.is_empty() {
-                expanded.push((loc.clone(), cmd.to_string()));
-            }
+    // out of the table while we work on it so prerequisites can be advanced
+    // at the same time. `path` stops us coming back round to it.
+    let mut node = sched.nodes.remove(name)?;
+    path.push(name.to_string());
+    let result = step(state, sched, &mut node, name, path);
+    path.pop();
+
+    match result {
+        Some(progress) => {
+            state.processed.insert(name.to_string(), progress.clone());
+            Some(progress)
         }
+        None => {
+            sched.nodes.insert(name.to_string(), node);
+            None
+        }
+    }
+}
 
-        has_recipies = !expanded.is_empty();
-
-        for (loc, cmd) in &expanded {
-            done_smth = true;
-
-            let mut cmd = cmd.as_str();
-            let ignore_errors = if cmd.starts_with('-') {
-                cmd = &cmd[1..];
-                true
-            } else {
-                // TODO: state.ignore errors
-                state.ignore_errors
-            };
+fn step(
+    state: &mut State,
+    sched: &mut Schedule,
+    node: &mut Node,
+    name: &str,
+    path: &mut Vec<String>,
+) -> Option<Progress> {
+    if state.only {
+        node.waiting.clear();
+    }
 
-            let mut silent = state.silent_targets.contains(&name.to_string());
+    for t in node.waiting.clone() {
+        if sched.stopping {
+            return None;
+        }
 
-            if cmd.starts_with('@') {
-                cmd = &cmd[1..];
-                silent = true;
+        if t == ".WAIT" {
+            if node.waiting[0] != ".WAIT" {
+                break;
             }
+            node.waiting.remove(0);
+            continue;
+        }
 
-            if (!silent || state.dryrun) && !state.silent {
-                println!("{}", cmd);
-            }
+        if path.contains(&t) {
+            eprintln!("{}: Circular {} <- {} dependency dropped.", state.basename, name, t);
+            node.waiting.retain(|w| *w != t);
+            continue;
+        }
 
-            // TODO: a dirty state tracker
-            let shell = if let Some(v) = vars.get("SHELL") {
-                v.clone().eval(state, loc, &mut vars)
+        if !state.processed.contains_key(&t) {
+            debug::log(state, debug::Category::Verbose, format_args!("Considering target file '{}'.", t));
+            let mut prereq = if node.hidden.is_empty() {
+                prepare_inherited(state, &sched.vars, &node.vars, &t)
             } else {
-                String::new()
+                let mut vars = node.vars.clone();
+                for (name, old) in &node.hidden {
+                    match old {
+                        Some(old) => vars.set(name.clone(), old.clone()),
+                        None => vars.remove(name),
+                    };
+                }
+                prepare_inherited(state, &sched.vars, &vars, &t)
             };
+            prereq.needed_by = path.iter().rev().cloned().collect();
+            state.processed.insert(t.clone(), Progress::InFlight);
+            sched.nodes.insert(t.clone(), prereq);
+        }
 
-            let shell_flags = if let Some(v) = vars.get(".SHELLFLAGS") {
-                v.clone().eval(state, loc, &mut vars)
-            } else {
-                String::new()
-            };
+        let Some(progress) = advance(state, sched, &t, path) else {
+            continue;
+        };
+        node.waiting.retain(|w| *w != t);
 
-            let cmd_name = cmd.trim().split_ascii_whitespace().next().unwrap();
-            // WONTFIX: we will not check if a program we're executing exists before
-            // hand. we will not do a special printy thing.
-            //
-            // WONTFIX: gmake and bmake do internal processing if the shell is `/bin/sh` we will not
-
-            let mut leaving = None;
-
-            // std::env::set_var(
-            //     "MAKELEVEL",
-            //     (vars.get("MAKELEVEL")
-            //         .unwrap_or_default()
-            //         .value
-            //         .parse::<u32>()
-            //         .unwrap()
-            //         + 1)
-            //     .to_string(),
-            // );
-
-            if !silent && cmd_name == state.fullname {
-                println!(
-                    "{}[1]: Entering directory '{}'",
-                    state.basename, state.curdir
+        match progress {
+            Progress::Done(Some((a, ..))) => node.done_smth |= a,
+            Progress::Failed => node.prereq_failed = true,
+            _ if state.phony.contains(&t.trim().to_string()) => {}
+            _ => {
+                eprintln!(
+                    "{}: *** No rule to make target '{}', needed by '{}'.{}",
+                    state.basename,
+                    t,
+                    name,
+                    if state.keep_going { "" } else { "  Stop." }
                 );
-                leaving = Some(format!(
-                    "{}[1]: Leaving directory '{}'",
-                    state.basename, state.curdir
-                ));
-            } else {
+                if !state.keep_going {
+                    halt_jobs(state, sched);
+                    return None;
+                }
+                state.failed.push(t.clone());
+                node.prereq_failed = true;
             }
+        }
+    }
 
-            let status = Command::new(shell)
-                .arg0(&state.basename)
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .arg(shell_flags)
-                .arg(cmd)
-                .status()
-                .expect("command failed");
-            if !status.success() {
-                if ignore_errors {
-                    eprintln!(
-                        "{}: [{}:{}: {}] Error {} (ignored)",
-                        state.basename,
-                        loc.file_name,
-                        loc.line,
-                        name,
-                        status.code().unwrap_or_default()
-                    );
+    if !node.waiting.is_empty() {
+        return None;
+    }
+
+    // `-k` carries on with other prerequisites but whatever needed the
+    // failed ones can't be made from stale inputs
+    if node.prereq_failed {
+        return Some(Progress::Failed);
+    }
+
+    if node.run.is_none() {
+        debug::log(state, debug::Category::Verbose, format_args!("Finished prerequisites of target file '{}'.", name));
+        let path = Path::new(name);
+        let since = state.timings.start();
+        let mtime = path.metadata().and_then(|m| m.modified()).ok();
+        state.timings.stat(since);
+
+        let needs_updating;
+        let reason;
+        if state.only {
+            // whatever the mtimes say
+            needs_updating = true;
+            reason = plan::Reason::Only;
+        } else if state.phony.contains(&name.to_string()) {
+            needs_updating = true;
+            reason = plan::Reason::Phony;
+        } else if state.assume_old.contains(&name.to_string()) {
+            // whether or not it's there, and even under `-B`
+            needs_updating = false;
+            reason = plan::Reason::Newer(Vec::new());
+        } else if state.always_make && node.found_rules {
+            // a source file with no rule is still just there
+            needs_updating = true;
+            reason = plan::Reason::Always;
+        } else if state.assume_new.contains(&name.to_string()) {
+            // as new as it gets, so nothing it needs can be newer
+            needs_updating = false;
+            reason = plan::Reason::Newer(Vec::new());
+        } else if let Some(time) = mtime {
+            // what ends up in `$?`. for a target that isn't there it's
+            // every prerequisite, which is what it already says.
+            let mut newer = Vec::new();
+            for p in &node.prerequisites {
+                if newer.contains(p) || state.assume_old.contains(p) {
+                    continue;
+                } else if state.phony.contains(p) {
+                    newer.push(p.clone());
+                    // phony targets always exist
+                    node.found_rules = true;
+                } else if state.dryrun && state.remade.contains(p) {
+                    // under `-n` nothing is actually rebuilt so mtimes can't
+                    // tell us anything. treat the prereq as brand new.
+                    newer.push(p.clone());
+                } else if state.assume_new.contains(p) {
+                    newer.push(p.clone());
+                } else if state.skipped_intermediates.contains(p) {
+                    // missing, but nothing it's made from is newer than us
                 } else {
-                    eprintln!(
-                        "{}: *** [{}:{}: {}] Error {}",
-                        state.basename,
-                        loc.file_name,
-                        loc.line,
-                        name,
-                        status.code().unwrap_or_default()
-                    );
-                    if !state.keep_going {
-                        std::process::exit(2);
+                    let since = state.timings.start();
+                    let ptime = Path::new(&p).metadata().map(|m| m.modified());
+                    state.timings.stat(since);
+
+                    if let Ok(Ok(ptime)) = ptime {
+                        if newer_than(state, name, ptime, time) {
+                            newer.push(p.clone());
+                        }
+                    } else {
+                        newer.push(p.clone());
                     }
                 }
-            } else if let Some(s) = leaving {
-                println!("{}", s);
             }
+
+            needs_updating = !newer.is_empty();
+            set_automatic(&mut node.vars, "?", &newer);
+            reason = plan::Reason::Newer(newer);
+        } else if intermediate_unneeded(state, node, name) {
+            state.skipped_intermediates.push(name.to_string());
+            needs_updating = false;
+            reason = plan::Reason::Missing;
+        } else {
+            needs_updating = true;
+            reason = plan::Reason::Missing;
         }
-    }
 
-    Some((done_smth, has_recipies))
-}
+        if !node.found_rules && needs_updating {
+            return Some(Progress::Done(None));
+        }
 
-// TODO: symbol table
-// Need a proper symbol table that keeps track of variable flavors, expands only when needed,
-// and updates the environment.
-//
-// Perhaps scopes are needed
-//
-// TODO: process launching utilities
+        match &reason {
+            _ if !needs_updating => {
+                debug::log(state, debug::Category::Verbose, format_args!("No need to remake target '{}'.", name));
+            }
+            plan::Reason::Missing => {
+                debug::log(state, debug::Category::Basic, format_args!("File '{}' does not exist.", name));
+            }
+            plan::Reason::Newer(newer) => {
+                for p in newer {
+                    let msg = format!("Prerequisite '{}' is newer than target '{}'.", p, name);
+                    debug::log(state, debug::Category::Basic, format_args!("{}", msg));
+                }
+            }
+            _ => {}
+        }
+        if needs_updating {
+            debug::log(state, debug::Category::Basic, format_args!("Must remake target '{}'.", name));
+        }
 
-/// Keep track of defined variables
-struct SymbolTable {}
+        if !needs_updating {
+            return Some(Progress::Done(Some((node.done_smth, false))));
+        }
 
-impl SymbolTable {
-    pub fn new() -> Self {
-        Self {}
+        let temp = (atomic(state, name) && !state.touch).then(|| format!("{}.tmp", name));
+        if let Some(temp) = &temp {
+            set_automatic(&mut node.vars, "@", std::slice::from_ref(temp));
+        }
+        node.run = Some(Run {
+            mtime,
+            reason,
+            temp,
+            output: None,
+            slot: None,
+            lines: VecDeque::new(),
+            has_recipies: false,
+            executed: Vec::new(),
+            job: None,
+            failed: false,
+        });
+        if mtime.is_none() && is_intermediate(state, name) {
+            state.made_intermediates.push(name.to_string());
+        }
     }
 
-    pub fn set(name: &str, value: &str) {
-        std::env::set_var(name, value)
+    let run = node.run.as_mut()?;
+    if run.job.is_some() || sched.stopping {
+        return None;
     }
 
-    pub fn get(name: &str) -> String {
-        std::env::var(name).unwrap_or_default()
-    }
-}
+    if run.slot.is_none() && !run.failed {
+        let serial = serial_dir(state, name);
+        if serial.as_ref().is_some_and(|dir| sched.busy_dirs.contains(dir)) {
+            return None;
+        }
+        if !load_allows(state, sched) {
+            return None;
+        }
+        // like GNU make the recipe isn't expanded until it can run
+        let Some(slot) = state.slots.acquire() else {
+            sched.starved = true;
+            return None;
+        };
+        run.slot = Some(slot);
+        sched.busy_dirs.extend(serial);
 
-fn expand_ng(
-    state: &State,
-    vars: &mut HashMap<String, Var>,
-    loc: &Location,
-    src: &mut String,
-) -> String {
-    #[derive(Debug)]
-    enum SubType {
-        Var,
-        Info,
-        Shell,
-        Subst,
-        Warn,
-        BaseName,
-        AddPrefix,
-        AddSuffix,
-        Sort,
-        FirstWord,
-        LastWord,
-        Words,
-        Suffix,
-        Join,
-        Dir,
-        NotDir,
-        AbsPath,
-        FindString,
-        Error,
-        Call,
-        Flavor,
-        Origin,
-        ForEach,
-        Word,
-        WordList,
-        PatSubst,
-        SubstRef,
-        Strip,
-        WildCard,
-        Value
-    }
+        state.in_recipe.set(true);
+        node.vars.with(&mut sched.vars, |vars| {
+            for (loc, r) in &node.recipies {
+                let cmd = expand_simple_ng(state, vars, loc, r);
 
-    #[cfg(debug_assertions)]
-    let esrc = Some(src.clone());
+                let cmd = cmd.trim();
 
-    #[cfg(not(debug_assertions))]
-    let esrc = None;
+                if !cmd.is_empty() {
+                    let recursive = r.contains("$(MAKE)") || r.contains("${MAKE}");
+                    run.lines.push_back((loc.clone(), cmd.to_string(), recursive));
+                }
+            }
+        });
+        state.in_recipe.set(false);
 
-    // `$` should have already been consumed
-    let x = src.pop();
-    match x {
-        Some(b) if (b == '(') || (b == '{') => {
-            let mut arg = String::new();
-            let mut func = SubType::Var;
-            let mut had_space = false;
+        run.has_recipies = !run.lines.is_empty();
 
-            let mut delim_stack = b.to_string();
+        if state.output_sync != output::Mode::None && run.has_recipies && !interactive(state, name) {
+            let output = match sched.spare_output.pop() {
+                Some(output) => Ok(output),
+                None => output::Captured::new(),
+            };
+            match output {
+                Ok(output) => run.output = Some(output),
+                Err(e) => eprintln!("{}: warning: can't hold back the output of '{}': {}", state.basename, name, e),
+            }
+        }
 
-            // keep track if we hit delimiters for substitutions X:a=b
-            let mut hit_colon = true;
-            let mut defo_subst = false;
-            while !delim_stack.is_empty() {
-                let c = src.pop().expect(&format!(
-                    "aaaa should handle this $(... without the ): {}: {}: {}",
-                    arg,
-                    src,
-                    esrc.clone().unwrap_or_default()
-                ));
-                arg.push(c);
-                match c {
-                    ')' if delim_stack.chars().last().unwrap() == '(' => {
-                        delim_stack.pop();
-                    }
-                    '}' if delim_stack.chars().last().unwrap() == '{' => {
-                        delim_stack.pop();
-                    }
-                    '}' if delim_stack.chars().last().unwrap() == '(' => fatal_unterm_var(loc),
-                    ')' if delim_stack.chars().last().unwrap() == '{' => fatal_unterm_var(loc),
-                    '(' => delim_stack.push('('),
-                    '{' => delim_stack.push('{'),
-                    ':' if delim_stack.len() == 1 => {
-                        hit_colon = true;
-                    }
-                    '=' if delim_stack.len() == 1 && hit_colon => {
-                        defo_subst = true;
-                    }
+        if state.debug.has(debug::Category::Env) && run.has_recipies && !state.dryrun {
+            let diff = node.vars.with(&mut sched.vars, |vars| env_diff(state, vars));
+            if !diff.is_empty() {
+                println!("Environment of '{}' differs from {}'s:", name, state.basename);
+                for line in diff {
+                    println!("  {}", line);
+                }
+            }
+        }
 
-                    ' ' if delim_stack.len() == 1 && !had_space => {
-                        had_space = true;
-                        func = match arg.trim() {
-                            "info" => {
-                                arg = String::new();
-                                SubType::Info
-                            }
-                            "shell" => {
-                                arg = String::new();
-                                SubType::Shell
-                            }
-                            "subst" => {
-                                arg = String::new();
-                                SubType::Subst
-                            }
-                            "warning" => {
-                                arg = String::new();
-                                SubType::Warn
-                            }
-                            "basename" => {
-                                arg = String::new();
-                                SubType::BaseName
-                            }
-                            "addprefix" => {
-                                arg = String::new();
-                                SubType::AddPrefix
-                            }
-                            "addsuffix" => {
-                                arg = String::new();
-                                SubType::AddSuffix
-                            }
-                            "sort" => {
-                                arg = String::new();
-                                SubType::Sort
-                            }
-                            "firstword" => {
-                                arg = String::new();
-                                SubType::FirstWord
-                            }
-                            "lastword" => {
-                                arg = String::new();
-                                SubType::LastWord
-                            }
-                            "words" => {
-                                arg = String::new();
-                                SubType::Words
-                            }
-                            "word" => {
-                                arg = String::new();
-                                SubType::Word
-                            }
-                            "wordlist" => {
-                                arg = String::new();
-                                SubType::WordList
-                            }
-                            "suffix" => {
-                                arg = String::new();
-                                SubType::Suffix
-                            }
-                            "join" => {
-                                arg = String::new();
-                                SubType::Join
-                            }
-                            "notdir" => {
-                                arg = String::new();
-                                SubType::NotDir
-                            }
-                            "dir" => {
-                                arg = String::new();
-                                SubType::Dir
-                            }
-                            "abspath" => {
-                                arg = String::new();
-                                SubType::AbsPath
-                            }
-                            "findstring" => {
-                                arg = String::new();
-                                SubType::FindString
-                            }
-                            "error" => {
-                                arg = String::new();
-                                SubType::Error
-                            }
-                            "call" => {
-                                arg = String::new();
-                                SubType::Call
-                            }
-                            "flavor" => {
-                                arg = String::new();
-                                SubType::Flavor
-                            }
-                            "origin" => {
-                                arg = String::new();
-                                SubType::Origin
-                            }
-                            "foreach" => {
-                                arg = String::new();
-                                SubType::ForEach
-                            }
-                            "patsubst" => {
-                                arg = String::new();
-                                SubType::PatSubst
-                            }
-                            "strip" => {
-                                arg = String::new();
-                                SubType::Strip
-                            }
-                            "wildcard" => {
-                                arg = String::new();
-                                SubType::WildCard
-                            }
-                            "value" => {
-                                arg = String::new();
-                                SubType::Value
-                            }
-                            _ => SubType::Var,
-                        };
-                    }
-                    _ => {}
+        // planning only: say what would run instead of running it
+        if let Some(plan) = &mut state.plan {
+            let mut prerequisites = Vec::new();
+            for p in &node.prerequisites {
+                if !prerequisites.contains(p) {
+                    prerequisites.push(p.clone());
                 }
             }
-            arg.pop(); // drop last `)` or `}`
+            plan.push(plan::Step {
+                target: name.to_string(),
+                reason: run.reason.clone(),
+                prerequisites,
+                recipe: run.lines.drain(..).map(|(_, cmd, _)| parse_recipe_prefix(&cmd).1.to_string()).collect(),
+            });
+            node.done_smth |= run.has_recipies;
+        }
+    }
 
-            if matches!(func, SubType::Var) && defo_subst {
-                func = SubType::SubstRef
+    while let Some((loc, cmd, recursive)) = run.lines.pop_front() {
+        node.done_smth = true;
+
+        let (prefix, cmd) = parse_recipe_prefix(&cmd);
+        let ignore_errors =
+            prefix.ignore_errors || state.ignore_errors || state.ignore_targets.contains(&name.to_string());
+
+        // under `-t` only what has to run anyway does
+        if state.touch && !prefix.always && !recursive {
+            continue;
+        }
+
+        let cmd_name = cmd.trim().split_ascii_whitespace().next().unwrap_or_default();
+        // WONTFIX: we will not check if a program we're executing exists before
+        // hand. we will not do a special printy thing.
+        //
+        // WONTFIX: gmake and bmake do internal processing if the shell is `/bin/sh` we will not
+
+        let recursive = recursive || cmd_name == state.fullname;
+
+        let captured = run.output.is_some() && state.output_sync.captures(recursive);
+        if let Some(output) = run.output.as_mut().filter(|_| !captured) {
+            // whatever came before has to come out first
+            output.flush(state);
+        }
+        if echoes(state, name, prefix) {
+            match &mut run.output {
+                Some(output) if captured => output.echo(cmd),
+                _ => println!("{}", cmd),
             }
+        }
 
-            // TODO: fill in expand stuff
-            match func {
-                SubType::Var => {
-                    let name = expand_simple_ng(state, vars, loc, arg.trim());
-                    if let Some(v) = vars.get(&name) {
-                        v.clone().eval(state, loc, vars)
-                    } else {
-                        String::new()
-                    }
-                }
-                SubType::Shell => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let cmd = process_for_shell(&arg);
+        // TODO: a dirty state tracker
+        let undo = node.vars.apply(&mut sched.vars);
+        let vars = &mut sched.vars;
+        let shell = if let Some(v) = vars.get("SHELL") {
+            v.clone().eval(state, &loc, vars)
+        } else {
+            String::new()
+        };
 
-                    let cmd_name = cmd.split_whitespace().next().unwrap();
+        let shell_flags = if let Some(v) = vars.get(".SHELLFLAGS") {
+            v.clone().eval(state, &loc, vars)
+        } else {
+            String::new()
+        };
 
-                    // WONTFIX: gnu make does internal interpreting of shell
-                    // we will not do this and let the shell handle everything
-                    //
-                    // let cnf_status = Command::new("/bin/sh")
-                    //     .arg0(&state.basename)
-                    //     .stdout(Stdio::null())
-                    //     .stderr(Stdio::null())
-                    //     .arg("-c")
-                    //     .arg(format!("command -V {}", cmd_name))
-                    //     .status()
-                    //     .expect("command failed");
-                    // if !cnf_status.success() {
-                    //     eprintln!(
-                    //         "{}: {}: No such file or directory",
-                    //         state.basename, cmd_name
-                    //     );
-                    //     let name: String = ".SHELLSTATUS".into();
-                    //     // TODO: move vars out of state
-                    //     // vars.insert(
-                    //     //     name.clone(),
-                    //     //     Var::new(Flavor::Simple, Origin::Env, name, "127".into(), false),
-                    //     // );
-                    //     String::new()
-                    // } else {
-                    // }
-                    let shell = vars
-                        .get("SHELL")
-                        .expect("shell must be defined to execute stuff");
-                    let shell = shell.clone().eval(state, loc, vars);
-
-                    let shell_flags = vars.get(".SHELLFLAGS").unwrap();
-                    let shell_flags = shell_flags.clone().eval(state, loc, vars);
-
-                    let out = Command::new(shell)
-                        .arg0(&state.basename)
-                        .args(shell_flags.split_ascii_whitespace())
-                        .arg(cmd)
-                        .output()
-                        .expect("Command failed to execute");
-                    let s = String::from_utf8(out.stdout).unwrap();
-
-                    let name: String = ".SHELLSTATUS".into();
-                    vars.insert(
-                        name.clone(),
-                        Var::new(
-                            Flavor::Simple,
-                            Origin::Env,
-                            Some(loc.clone()),
-                            name,
-                            format!("{}", out.status.code().unwrap_or_default()),
-                            false,
-                        ),
-                    );
-                    s
-                }
-                SubType::Info => {
-                    println!("{}", expand_simple_ng(state, vars, loc, &arg));
-                    String::new()
-                }
+        // only recursive makes and `+` lines get to run under `-n`
+        if state.dryrun && (state.safe_dryrun || !prefix.always && !recursive) {
+            undo.rollback(vars);
+            continue;
+        }
 
-                SubType::Subst => {
-                    let mut args = arg.split(",");
-                    let from = args.next().unwrap();
-                    let from = expand_simple_ng(state, vars, loc, &from);
-                    let to = args.next().unwrap();
-                    let to = expand_simple_ng(state, vars, loc, &to);
-                    let text = args.next().unwrap();
-                    let text = expand_simple_ng(state, vars, loc, &text);
-                    text.replace(&from, &to)
-                }
-                SubType::Warn => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    eprintln!("{}:{}: {}", loc.file_name, loc.line, arg);
-                    String::new()
-                }
-                SubType::BaseName => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let names = arg.split_whitespace().rev();
-                    let mut out = String::new();
-                    for name in names {
-                        let mut rev = name.chars().rev().peekable();
-                        let mut purged = String::new();
-                        let mut no_dot = false;
-                        while match rev.peek() {
-                            Some('.') => {
-                                rev.next();
-                                false
-                            }
-                            Some('/') => {
-                                no_dot = true;
-                                false
-                            }
-                            Some(_) => {
-                                purged.push(rev.next().unwrap_or_else(|| unreachable!()));
-                                true
-                            }
-                            None => {
-                                no_dot = true;
-                                false
-                            }
-                        } {}
-                        if no_dot {
-                            out.extend(purged.chars());
-                        }
-                        out.extend(rev);
-                        out.push(' ');
-                    }
-                    out.chars().rev().collect()
-                }
-                SubType::Suffix => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let names = arg.split_whitespace().rev();
-                    let mut out = String::new();
-                    for name in names {
-                        let mut rev = name.chars().rev().peekable();
-                        let mut purged = String::new();
-                        let mut no_dot = false;
-                        while match rev.peek() {
-                            Some('/') => {
-                                no_dot = true;
-                                false
-                            }
-                            Some(&a) => {
-                                purged.push(rev.next().unwrap_or_else(|| unreachable!()));
-                                a != '.'
-                            }
-                            None => {
-                                no_dot = true;
-                                false
-                            }
-                        } {}
-                        if !no_dot {
-                            out.extend(purged.chars());
-                        }
-                        out.push(' ');
-                    }
-                    out.chars().rev().collect()
-                }
-                SubType::AddPrefix => {
-                    let mut args = arg.split(",");
-                    let prefix = args.next().unwrap();
-                    let prefix = expand_simple_ng(state, vars, loc, &prefix);
-                    let args = args.next().unwrap();
-                    let args = expand_simple_ng(state, vars, loc, &args);
-                    args.split_whitespace()
-                        .map(|x| format!("{}{}", prefix, x))
-                        .fold(String::new(), |s, x| format!("{} {}", s, x))
-                }
-                SubType::AddSuffix => {
-                    let mut args = arg.split(",");
-                    let suffix = args.next().unwrap();
-                    let suffix = expand_simple_ng(state, vars, loc, &suffix);
-                    let args = args.next().unwrap();
-                    let args = expand_simple_ng(state, vars, loc, &args);
-                    args.split_whitespace()
-                        .map(|x| format!("{}{}", x, suffix))
-                        .fold(String::new(), |s, x| format!("{} {}", s, x))
-                }
-                SubType::Sort => {
+        // a sub-make decides for its own recipes
+        let stdin = if recursive || interactive(state, name) {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        };
+        let mut command = Command::new(shell);
+        command
+            .arg0(&state.basename)
+            .stdin(stdin)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .arg(shell_flags)
+            .arg(cmd);
+        if let Some(output) = run.output.as_ref().filter(|_| captured) {
+            if let Err(e) = output.redirect(&mut command) {
+                eprintln!("{}: warning: can't hold back the output of '{}': {}", state.basename, name, e);
+            }
+        }
+        guard_env(state, vars, &mut command);
+        undo.rollback(vars);
+        child_context(state, &mut command);
+        if let Some(slot) = run.slot.filter(|_| state.slots.has_jobserver()) {
+            command.env("IMAKE_JOB_ID", job_id(state, slot));
+        }
+        command.env("IMAKE_REBUILD_REASON", run.reason.to_string());
+        if let Some(temp) = &run.temp {
+            command.env("IMAKE_ATOMIC_TMP", temp);
+        }
+        if (prefix.always || recursive) && state.jobserver_fifo.is_none() {
+            sys::inherit_fds(&mut command, state.jobserver_fds.clone());
+        }
+
+        let interrupts = *sched.interrupts.get_or_insert_with(sys::ignore_interrupts);
+        sys::child_interrupts(&mut command, interrupts);
+
+        let since = state.timings.start();
+        let pid = sys::spawn(&mut command).unwrap_or_else(|e| {
+            eprintln!("{}: *** [{}: {}] {}", state.basename, loc, name, e);
+            leave_and_exit(state, 2)
+        });
+
+        debug::log(state, debug::Category::Jobs, format_args!("Putting child '{}' PID {} on the chain.", name, pid));
+        sched.running += 1;
+        if state.max_load.is_some() {
+            sched.started.push_back(Instant::now());
+        }
+        run.job = Some(Job {
+            pid,
+            loc,
+            cmd: cmd.to_string(),
+            ignore_errors,
+            since,
+            killed: false,
+        });
+        return None;
+    }
+
+    if let Some(mut output) = run.output.take() {
+        output.flush(state);
+        sched.spare_output.push(output);
+    }
+    if let Some(slot) = run.slot.take() {
+        state.slots.release(slot);
+        if let Some(dir) = serial_dir(state, name) {
+            sched.busy_dirs.retain(|d| *d != dir);
+        }
+    }
+
+    if run.failed {
+        discard_temp(run);
+        state.failed.push(name.to_string());
+        return Some(Progress::Failed);
+    }
+
+    // the recipe may have written the target itself after all
+    if let Some(temp) = run.temp.as_ref().filter(|t| !state.dryrun && Path::new(t).exists()) {
+        if let Err(e) = std::fs::rename(temp, name) {
+            let loc = node.recipies.first().map(|(loc, _)| loc.clone()).unwrap_or_default();
+            eprintln!("{}: *** [{}: {}] can't rename {}: {}", state.basename, loc, name, temp, e);
+            discard_temp(run);
+            state.failed.push(name.to_string());
+            return Some(Progress::Failed);
+        }
+    }
+
+    let made = state.dryrun || state.touch || state.phony.iter().any(|p| p == name) || Path::new(name).exists();
+    if state.strict && run.has_recipies && !made {
+        let loc = node.recipies.first().map(|(loc, _)| loc.clone()).unwrap_or_default();
+        eprintln!("{}: *** [{}: {}] Recipe didn't create it and it isn't .PHONY (--strict)", state.basename, loc, name);
+        if !state.keep_going {
+            halt_jobs(state, sched);
+            return None;
+        }
+        state.failed.push(name.to_string());
+        return Some(Progress::Failed);
+    }
+
+    if state.touch && run.has_recipies && !state.phony.iter().any(|p| p == name) {
+        if !state.silent {
+            println!("touch {}", name);
+        }
+        if !state.dryrun {
+            let touched = File::options()
+                .create(true)
+                .append(true)
+                .open(name)
+                .and_then(|f| f.set_modified(build_time(&node.prerequisites)));
+            if let Err(e) = touched {
+                eprintln!("{}: *** touch: {}: {}", state.basename, name, e);
+                state.failed.push(name.to_string());
+                return Some(Progress::Failed);
+            }
+        }
+    }
+
+    if let Some(path) = &state.audit {
+        if !run.executed.is_empty() {
+            node.vars.with(&mut sched.vars, |vars| {
+                audit::record(state, path, vars, name, &run.executed, &node.prerequisites)
+            });
+        }
+    }
+
+    if run.has_recipies && !state.dryrun && !state.touch {
+        phony::observe(state, name, run.mtime);
+    }
+
+    if run.has_recipies {
+        debug::log(state, debug::Category::Basic, format_args!("Successfully remade target file '{}'.", name));
+        state.out_of_date |= state.question;
+    }
+    state.remade.push(name.to_string());
+    if let Some(coverage) = &mut state.coverage {
+        if run.has_recipies {
+            coverage.ran(name);
+        }
+    }
+
+    Some(Progress::Done(Some((node.done_smth, run.has_recipies))))
+}
+
+/// Whether `-l` leaves room for another job. There's always room for one,
+/// or nothing would ever run.
+fn load_allows(state: &State, sched: &mut Schedule) -> bool {
+    let Some(max_load) = state.max_load.filter(|_| sched.running > 0) else {
+        return true;
+    };
+    let Some(load) = sys::load_average() else {
+        return true;
+    };
+    // the kernel averages over a minute, so anything started in the last
+    // second counts in full on top of what it says
+    while sched.started.front().is_some_and(|t| t.elapsed() >= Duration::from_secs(1)) {
+        sched.started.pop_front();
+    }
+    let load = load + sched.started.len() as f64;
+    if load < max_load {
+        return true;
+    }
+    debug::log(state, debug::Category::Jobs, format_args!("Load average {:.2} is over -l {}.", load, max_load));
+    false
+}
+
+/// Wait for a recipe line to finish and deal with how it went
+fn reap(state: &mut State, sched: &mut Schedule) {
+    if sched.running == 0 {
+        return;
+    }
+
+    // if something is waiting on a token from another make keep an eye on
+    // the jobserver as well as our own children
+    let reaped = if sched.starved && state.slots.has_jobserver() {
+        loop {
+            if let Some(reaped) = sys::wait_child(false) {
+                break Some(reaped);
+            }
+            if state.slots.wait_token(50) {
+                break None;
+            }
+        }
+    } else {
+        sys::wait_child(true)
+    };
+
+    let Some((pid, status)) = reaped else { return };
+    let Some((name, node)) = sched
+        .nodes
+        .iter_mut()
+        .find(|(_, n)| n.run.as_ref().and_then(|r| r.job.as_ref()).map(|j| j.pid) == Some(pid))
+    else {
+        return;
+    };
+    let run = node.run.as_mut().unwrap();
+    let job = run.job.take().unwrap();
+    let outcome = if status.success() { "winning" } else { "losing" };
+    debug::log(state, debug::Category::Jobs, format_args!("Reaping {} child '{}' PID {}", outcome, name, job.pid));
+    // out before anything we have to say about it
+    if let Some(output) = &mut run.output {
+        if state.output_sync == output::Mode::Line || !status.success() {
+            output.flush(state);
+        }
+    }
+
+    sched.running -= 1;
+    if sched.running == 0 {
+        if let Some(old) = sched.interrupts.take() {
+            sys::restore_interrupts(old);
+        }
+    }
+
+    state.timings.recipe(job.since);
+
+    let loc = &job.loc;
+    let interrupt = status.signal().filter(|sig| sys::INTERRUPTS.contains(sig));
+    if job.killed && sched.interrupted.is_none() {
+        if interrupt.is_some() {
+            eprintln!("{}: *** [{}: {}] Terminated", state.basename, loc, name);
+            remove_unfinished(state, name, run.mtime);
+            discard_temp(run);
+        } else {
+            // it was done anyway by the time the signal got there
+            eprintln!("{}: *** [{}: {}] Error {}", state.basename, loc, name, status.code().unwrap_or_default());
+        }
+        run.lines.clear();
+        run.failed = true;
+        sched.casualties += 1;
+        return;
+    }
+    if let Some(sig) = interrupt {
+        let why = match sig {
+            1 => "Hangup",
+            2 => "Interrupt",
+            _ => "Terminated",
+        };
+        eprintln!("{}: *** [{}: {}] {}", state.basename, loc, name, why);
+        sched.interrupted.get_or_insert(sig);
+        sched.stopping = true;
+    }
+
+    if sched.interrupted.is_some() {
+        // everything else running got the signal too
+        remove_unfinished(state, name, run.mtime);
+        discard_temp(run);
+        return;
+    }
+
+    if status.success() {
+        run.executed.push(job.cmd);
+    } else if job.ignore_errors {
+        eprintln!(
+            "{}: [{}: {}] Error {} (ignored)",
+            state.basename,
+            loc,
+            name,
+            status.code().unwrap_or_default()
+        );
+        run.executed.push(job.cmd);
+    } else {
+        let failure = format!("[{}: {}] Error {}", loc, name, status.code().unwrap_or_default());
+        eprintln!("{}: *** {}", state.basename, failure);
+        // deep in the graph the target alone doesn't say much
+        if !node.needed_by.is_empty() {
+            let chain = node.needed_by.iter().map(|t| format!("needed by '{}'", t)).collect::<Vec<_>>();
+            eprintln!("{}: *** '{}' was {}", state.basename, name, chain.join(", "));
+        }
+        // the rest of this recipe doesn't run
+        run.lines.clear();
+        run.failed = true;
+        if !state.keep_going {
+            if sched.first_failure.is_none() {
+                sched.first_failure = Some(failure);
+            } else {
+                sched.casualties += 1;
+            }
+            halt_jobs(state, sched);
+        }
+    }
+}
+
+/// Stop starting new jobs after a failure without `-k`, and deal with the
+/// ones still running as `--halt` says
+fn halt_jobs(state: &State, sched: &mut Schedule) {
+    if sched.stopping {
+        return;
+    }
+    sched.stopping = true;
+    if sched.running == 0 {
+        return;
+    }
+
+    match state.halt {
+        // without `-j` the one that's left is what we were about to
+        // wait for anyway
+        Halt::Drain if state.jobs > 1 => eprintln!("{}: *** Waiting for unfinished jobs....", state.basename),
+        Halt::Drain => {}
+        Halt::Now => {
+            eprintln!("{}: *** Terminating unfinished jobs....", state.basename);
+            for node in sched.nodes.values_mut() {
+                if let Some(job) = node.run.as_mut().and_then(|r| r.job.as_mut()) {
+                    sys::terminate(job.pid);
+                    job.killed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Where the makefile `include` names is: as written if it's there,
+/// otherwise in the first `-I` directory that has it
+fn find_include(state: &State, file: &str) -> String {
+    if file.is_empty() || file.starts_with('/') || Path::new(file).exists() {
+        return file.to_string();
+    }
+    state
+        .include_dirs
+        .iter()
+        .map(|dir| Path::new(dir).join(file))
+        .find(|path| path.exists())
+        .map_or_else(|| file.to_string(), |path| path.to_string_lossy().into_owned())
+}
+
+/// Whether `name`'s recipe can read the terminal. Others get `/dev/null` so
+/// a stray prompt fails instead of fighting other jobs for what's typed.
+fn interactive(state: &State, name: &str) -> bool {
+    state.interactive_all || matches_any(&state.interactive, name)
+}
+
+/// `dir` written the one way, without `./` or a trailing slash
+fn dir_name(dir: &str) -> String {
+    match normalize_path(dir).trim_end_matches('/') {
+        "" if dir.starts_with('/') => "/".to_string(),
+        "" => ".".to_string(),
+        dir => dir.to_string(),
+    }
+}
+
+/// The directory `name` is in if recipes there must run one at a time
+fn serial_dir(state: &State, name: &str) -> Option<String> {
+    let parent = Path::new(name.trim_end_matches('/')).parent()?.to_str()?;
+    let dir = dir_name(parent);
+    (state.serial_dirs_all || state.serial_dirs.contains(&dir)).then_some(dir)
+}
+
+/// Delete `name` if a recipe that was cut short has changed it since
+/// `before`, unless it's precious
+fn remove_unfinished(state: &State, name: &str, before: Option<SystemTime>) {
+    let precious = state.precious_all || matches_any(&state.precious, name) || state.phony.iter().any(|p| p == name);
+    // only what the recipe touched, an old target is still good
+    let now = Path::new(name).metadata().and_then(|m| m.modified()).ok();
+    if !precious && now.is_some() && now != before {
+        eprintln!("{}: *** Deleting file '{}'", state.basename, name);
+        let _ = std::fs::remove_file(name);
+    }
+}
+
+/// Whether `name`'s recipe writes `$@.tmp` for us to rename into place.
+/// A phony target has no file to guard.
+fn atomic(state: &State, name: &str) -> bool {
+    (state.atomic_all || matches_any(&state.atomic, name)) && !state.phony.iter().any(|p| p == name)
+}
+
+/// Delete what an `.ATOMIC` recipe that didn't succeed wrote so far
+fn discard_temp(run: &Run) {
+    if let Some(temp) = &run.temp {
+        let _ = std::fs::remove_file(temp);
+    }
+}
+
+/// Whether `name` only exists on the way to something else, so it needn't
+/// be there when what needs it is up to date
+fn is_intermediate(state: &State, name: &str) -> bool {
+    !(state.not_intermediate_all || matches_any(&state.not_intermediate, name))
+        && state.intermediates.iter().chain(&state.secondary).any(|i| i == name)
+}
+
+/// Whether `name` is an intermediate file that can stay missing: what
+/// first needed it is there, or is a missing intermediate file itself and
+/// so on up, and is newer than everything it's made from
+fn intermediate_unneeded(state: &State, node: &Node, name: &str) -> bool {
+    let mtime = |p: &str| Path::new(p).metadata().and_then(|m| m.modified()).ok();
+    if !is_intermediate(state, name) {
+        return false;
+    }
+    let mut time = None;
+    for t in &node.needed_by {
+        time = mtime(t).map(|time| (t, time));
+        if time.is_some() || !is_intermediate(state, t) {
+            break;
+        }
+    }
+    let Some((target, time)) = time else { return false };
+    node.prerequisites.iter().all(|p| {
+        state.skipped_intermediates.contains(p)
+            || state.assume_old.contains(p)
+            || !state.remade.contains(p)
+                && !state.phony.contains(p)
+                && !state.assume_new.contains(p)
+                && mtime(p).is_some_and(|t| !newer_than(state, target, t, time))
+    })
+}
+
+/// Delete the intermediate files made along the way, unless they're
+/// `.SECONDARY` or `.PRECIOUS`
+fn remove_intermediates(state: &mut State) {
+    let made = std::mem::take(&mut state.made_intermediates);
+    let doomed = made
+        .into_iter()
+        .filter(|i| {
+            !(state.secondary_all
+                || state.secondary.contains(i)
+                || state.precious_all
+                || matches_any(&state.precious, i)
+                || state.phony.contains(i))
+        })
+        .filter(|i| state.dryrun || Path::new(i).exists())
+        .collect::<Vec<_>>();
+    if doomed.is_empty() {
+        return;
+    }
+    if !state.silent {
+        println!("rm {}", doomed.join(" "));
+    }
+    if !state.dryrun {
+        for i in &doomed {
+            let _ = std::fs::remove_file(i);
+        }
+    }
+}
+
+/// Flags from the `@`, `-` and `+` prefixes at the start of a recipe line
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct RecipePrefix {
+    silent: bool,
+    ignore_errors: bool,
+    always: bool,
+}
+
+/// Strip any combination of `@`, `-` and `+` (in any order, blanks allowed
+/// in between) from the front of a recipe line
+fn parse_recipe_prefix(cmd: &str) -> (RecipePrefix, &str) {
+    let mut prefix = RecipePrefix::default();
+    let mut cmd = cmd;
+
+    loop {
+        match cmd.chars().next() {
+            Some('@') => prefix.silent = true,
+            Some('-') => prefix.ignore_errors = true,
+            Some('+') => prefix.always = true,
+            Some(' ' | '\t') => {}
+            _ => break,
+        }
+        cmd = &cmd[1..];
+    }
+
+    (prefix, cmd)
+}
+
+/// Whether a recipe line of `target` is printed before it runs. `-n` shows
+/// everything, otherwise `@`, a per-target `.SILENT` and `-s` (or a bare
+/// `.SILENT:`) each hide it.
+fn echoes(state: &State, target: &str, prefix: RecipePrefix) -> bool {
+    if state.question {
+        return false;
+    } else if state.dryrun {
+        return true;
+    }
+
+    !prefix.silent && !state.silent && !matches_any(&state.silent_targets, target)
+}
+
+/// What's in `IMAKE_JOB_ID` for a recipe running in `slot` under `-j`. No
+/// two recipes running at once have the same one, sub-makes included: theirs
+/// are numbered under the one of the recipe that ran them, like `2.1`.
+fn job_id(state: &State, slot: jobs::Slot) -> String {
+    match (&state.parent_job_id, slot.id()) {
+        (Some(parent), 0) => parent.clone(),
+        (Some(parent), id) => format!("{}.{}", parent, id),
+        (None, id) => id.to_string(),
+    }
+}
+
+/// Longest single `NAME=value` string the kernel will copy into a child
+/// (linux' MAX_ARG_STRLEN)
+const ENV_STRING_MAX: usize = 32 * 4096;
+
+/// Conservative total for the environment so argv still has room
+const ENV_TOTAL_MAX: usize = 1024 * 1024;
+
+/// Start `cmd` in `CURDIR` with the umask we started with, whatever has
+/// happened to this process since
+fn child_context(state: &State, cmd: &mut Command) {
+    if !state.curdir.is_empty() {
+        cmd.current_dir(&state.curdir);
+    }
+    if let Some(mask) = state.umask {
+        sys::child_umask(cmd, mask);
+    }
+}
+
+/// Bring the environment a child is about to inherit up to date and check
+/// it. Exported variables too big to be passed on get a warning and, with
+/// `--skip-oversized-exports`, are left out so the exec doesn't fail with
+/// E2BIG.
+fn guard_env(state: &State, vars: &HashMap<String, Var>, cmd: &mut Command) {
+    // everything from our own environment is in `vars` or passed through,
+    // so this is all of it
+    let env = child_env(state, vars);
+    cmd.env_clear();
+    cmd.envs(env.iter().map(|v| (&v.name, &v.value)));
+
+    let mut sizes = env
+        .iter()
+        .map(|v| (v.name.clone(), v.name.len() + v.value.len() + 2))
+        .collect::<Vec<_>>();
+    let mut total: usize = sizes.iter().map(|(_, s)| s).sum();
+
+    // biggest first so we drop as few as possible
+    sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    for (name, size) in sizes {
+        if size <= ENV_STRING_MAX && total <= ENV_TOTAL_MAX {
+            break;
+        }
+
+        let mut warned = state.oversized_warned.borrow_mut();
+        if !warned.contains(&name) {
+            eprintln!(
+                "{}: warning: exported variable '{}' is {} bytes and may not fit in the environment{}",
+                state.basename,
+                name,
+                size,
+                if state.skip_oversized_exports {
+                    "; not exporting it"
+                } else {
+                    ""
+                }
+            );
+            warned.push(name.clone());
+        }
+
+        if state.skip_oversized_exports {
+            cmd.env_remove(&name);
+            total -= size;
+        } else if size <= ENV_STRING_MAX {
+            break;
+        }
+    }
+}
+
+/// How the environment a recipe run with `vars` gets differs from the one
+/// we started with: `+NAME=value` for an addition, `~NAME=value` for a
+/// change and `-NAME` for something left out
+fn env_diff(state: &State, vars: &HashMap<String, Var>) -> Vec<String> {
+    let startup = std::env::vars_os()
+        .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+        .collect::<HashMap<_, _>>();
+    let env = child_env(state, vars);
+
+    let mut diff = Vec::new();
+    for var in &env {
+        match startup.get(&var.name) {
+            Some(value) if *value == var.value => {}
+            Some(_) => diff.push(format!("~{}={}", var.name, var.value)),
+            None => diff.push(format!("+{}={}", var.name, var.value)),
+        }
+    }
+    for name in startup.keys() {
+        if !env.iter().any(|v| v.name == *name) {
+            diff.push(format!("-{}", name));
+        }
+    }
+
+    // by name, whatever happened to it
+    diff.sort_by(|a, b| a[1..].cmp(&b[1..]));
+    diff
+}
+
+/// The variables a recipe run with `vars` gets in its environment
+fn child_env<'a>(state: &'a State, vars: &'a HashMap<String, Var>) -> Vec<&'a Var> {
+    vars.values()
+        .filter(|v| v.exported(state.export_all))
+        .chain(state.passed_env.iter().filter(|v| !vars.contains_key(&v.name)))
+        .filter(|v| !v.name.is_empty() && !v.name.contains(['=', '\0']))
+        .collect()
+}
+
+/// Could be a shell variable
+fn is_shell_name(name: &str) -> bool {
+    !name.starts_with(|c: char| c.is_ascii_digit())
+        && !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// TODO: symbol table
+// Need a proper symbol table that keeps track of variable flavors, expands only when needed,
+// and updates the environment.
+//
+// Perhaps scopes are needed
+//
+// TODO: process launching utilities
+
+/// Keep track of defined variables
+struct SymbolTable {}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn set(name: &str, value: &str) {
+        std::env::set_var(name, value)
+    }
+
+    pub fn get(name: &str) -> String {
+        std::env::var(name).unwrap_or_default()
+    }
+}
+
+fn expand_ng(
+    state: &State,
+    vars: &mut HashMap<String, Var>,
+    loc: &Location,
+    src: &mut String,
+) -> String {
+    #[derive(Debug)]
+    enum SubType {
+        Var,
+        Info,
+        Shell,
+        Subst,
+        Warn,
+        BaseName,
+        AddPrefix,
+        AddSuffix,
+        Sort,
+        #[cfg(feature = "extensions")]
+        SortNumeric,
+        #[cfg(feature = "extensions")]
+        SortVersion,
+        FirstWord,
+        LastWord,
+        Words,
+        Suffix,
+        Join,
+        Dir,
+        NotDir,
+        AbsPath,
+        FindString,
+        Error,
+        Call,
+        Flavor,
+        Origin,
+        ForEach,
+        Word,
+        WordList,
+        PatSubst,
+        SubstRef,
+        Strip,
+        WildCard,
+        Value,
+        File,
+        Let,
+        IntCmp,
+    }
+
+    // `$` should have already been consumed
+    let x = src.pop();
+    match x {
+        Some(b) if (b == '(') || (b == '{') => {
+            let mut arg = String::new();
+            let mut func = SubType::Var;
+            let mut func_name = String::new();
+            let mut had_space = false;
+
+            let mut delim_stack = b.to_string();
+
+            // keep track if we hit delimiters for substitutions X:a=b
+            let mut hit_colon = true;
+            let mut defo_subst = false;
+            while !delim_stack.is_empty() {
+                let Some(c) = src.pop() else {
+                    if matches!(func, SubType::Var) {
+                        fatal_unterm_var(state, loc);
+                    }
+                    eprintln!(
+                        "{}:{}: *** unterminated call to function '{}': missing '{}'.  Stop.",
+                        loc.file_name,
+                        loc.line,
+                        func_name,
+                        if b == '(' { ')' } else { '}' }
+                    );
+                    leave_and_exit(state, 2)
+                };
+                arg.push(c);
+                match c {
+                    ')' if delim_stack.ends_with('(') => {
+                        delim_stack.pop();
+                    }
+                    '}' if delim_stack.ends_with('{') => {
+                        delim_stack.pop();
+                    }
+                    '}' if delim_stack.ends_with('(') => fatal_unterm_var(state, loc),
+                    ')' if delim_stack.ends_with('{') => fatal_unterm_var(state, loc),
+                    '(' => delim_stack.push('('),
+                    '{' => delim_stack.push('{'),
+                    ':' if delim_stack.len() == 1 => {
+                        hit_colon = true;
+                    }
+                    '=' if delim_stack.len() == 1 && hit_colon => {
+                        defo_subst = true;
+                    }
+
+                    ' ' if delim_stack.len() == 1 && !had_space => {
+                        had_space = true;
+                        func_name = arg.trim().to_string();
+                        func = match arg.trim() {
+                            "info" => {
+                                arg = String::new();
+                                SubType::Info
+                            }
+                            "shell" => {
+                                arg = String::new();
+                                SubType::Shell
+                            }
+                            "subst" => {
+                                arg = String::new();
+                                SubType::Subst
+                            }
+                            "warning" => {
+                                arg = String::new();
+                                SubType::Warn
+                            }
+                            "basename" => {
+                                arg = String::new();
+                                SubType::BaseName
+                            }
+                            "addprefix" => {
+                                arg = String::new();
+                                SubType::AddPrefix
+                            }
+                            "addsuffix" => {
+                                arg = String::new();
+                                SubType::AddSuffix
+                            }
+                            "sort" => {
+                                arg = String::new();
+                                SubType::Sort
+                            }
+                            #[cfg(feature = "extensions")]
+                            "sort-numeric" => {
+                                arg = String::new();
+                                SubType::SortNumeric
+                            }
+                            #[cfg(feature = "extensions")]
+                            "sort-version" => {
+                                arg = String::new();
+                                SubType::SortVersion
+                            }
+                            "firstword" => {
+                                arg = String::new();
+                                SubType::FirstWord
+                            }
+                            "lastword" => {
+                                arg = String::new();
+                                SubType::LastWord
+                            }
+                            "words" => {
+                                arg = String::new();
+                                SubType::Words
+                            }
+                            "word" => {
+                                arg = String::new();
+                                SubType::Word
+                            }
+                            "wordlist" => {
+                                arg = String::new();
+                                SubType::WordList
+                            }
+                            "suffix" => {
+                                arg = String::new();
+                                SubType::Suffix
+                            }
+                            "join" => {
+                                arg = String::new();
+                                SubType::Join
+                            }
+                            "notdir" => {
+                                arg = String::new();
+                                SubType::NotDir
+                            }
+                            "dir" => {
+                                arg = String::new();
+                                SubType::Dir
+                            }
+                            "abspath" => {
+                                arg = String::new();
+                                SubType::AbsPath
+                            }
+                            "findstring" => {
+                                arg = String::new();
+                                SubType::FindString
+                            }
+                            "error" => {
+                                arg = String::new();
+                                SubType::Error
+                            }
+                            "call" => {
+                                arg = String::new();
+                                SubType::Call
+                            }
+                            "flavor" => {
+                                arg = String::new();
+                                SubType::Flavor
+                            }
+                            "origin" => {
+                                arg = String::new();
+                                SubType::Origin
+                            }
+                            "foreach" => {
+                                arg = String::new();
+                                SubType::ForEach
+                            }
+                            "patsubst" => {
+                                arg = String::new();
+                                SubType::PatSubst
+                            }
+                            "strip" => {
+                                arg = String::new();
+                                SubType::Strip
+                            }
+                            "wildcard" => {
+                                arg = String::new();
+                                SubType::WildCard
+                            }
+                            "value" => {
+                                arg = String::new();
+                                SubType::Value
+                            }
+                            "file" => {
+                                arg = String::new();
+                                SubType::File
+                            }
+                            "let" => {
+                                arg = String::new();
+                                SubType::Let
+                            }
+                            "intcmp" => {
+                                arg = String::new();
+                                SubType::IntCmp
+                            }
+                            _ => SubType::Var,
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            arg.pop(); // drop last `)` or `}`
+
+            if matches!(func, SubType::Var) && defo_subst {
+                func = SubType::SubstRef
+            }
+
+            // TODO: fill in expand stuff
+            match func {
+                SubType::Var => {
+                    let name = expand_simple_ng(state, vars, loc, arg.trim());
+                    if let Some(v) = vars.get(&name) {
+                        v.clone().eval(state, loc, vars)
+                    } else {
+                        strict::undefined_variable(state, loc, &name);
+                        String::new()
+                    }
+                }
+                SubType::Shell => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let cmd = process_for_shell(&arg);
+
+                    // WONTFIX: gnu make does internal interpreting of shell
+                    // we will not do this and let the shell handle everything
+                    //
+                    // let cnf_status = Command::new("/bin/sh")
+                    //     .arg0(&state.basename)
+                    //     .stdout(Stdio::null())
+                    //     .stderr(Stdio::null())
+                    //     .arg("-c")
+                    //     .arg(format!("command -V {}", cmd_name))
+                    //     .status()
+                    //     .expect("command failed");
+                    // if !cnf_status.success() {
+                    //     eprintln!(
+                    //         "{}: {}: No such file or directory",
+                    //         state.basename, cmd_name
+                    //     );
+                    //     let name: String = ".SHELLSTATUS".into();
+                    //     // TODO: move vars out of state
+                    //     // vars.insert(
+                    //     //     name.clone(),
+                    //     //     Var::new(Flavor::Simple, Origin::Env, name, "127".into(), false),
+                    //     // );
+                    //     String::new()
+                    // } else {
+                    // }
+                    shell_output(state, vars, loc, &cmd)
+                }
+                SubType::Info => {
+                    let msg = expand_simple_ng(state, vars, loc, &arg);
+                    println!("{}", msg);
+                    state.parse_messages.borrow_mut().push((false, msg));
+                    String::new()
+                }
+
+                SubType::Subst => {
+                    let args = split_args(state, loc, "subst", &arg, 3);
+                    let from = expand_simple_ng(state, vars, loc, args[0]);
+                    let to = expand_simple_ng(state, vars, loc, args[1]);
+                    let text = expand_simple_ng(state, vars, loc, args[2]);
+                    text.replace(&from, &to)
+                }
+                SubType::Warn => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let msg = format!("{}:{}: {}", loc.file_name, loc.line, arg);
+                    eprintln!("{}", msg);
+                    state.parse_messages.borrow_mut().push((true, msg));
+                    String::new()
+                }
+                SubType::BaseName => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let names = arg.split_whitespace().rev();
+                    let mut out = String::new();
+                    for name in names {
+                        let mut rev = name.chars().rev().peekable();
+                        let mut purged = String::new();
+                        let mut no_dot = false;
+                        while match rev.peek() {
+                            Some('.') => {
+                                rev.next();
+                                false
+                            }
+                            Some('/') => {
+                                no_dot = true;
+                                false
+                            }
+                            Some(_) => {
+                                purged.push(rev.next().unwrap_or_else(|| unreachable!()));
+                                true
+                            }
+                            None => {
+                                no_dot = true;
+                                false
+                            }
+                        } {}
+                        if no_dot {
+                            out.push_str(&purged);
+                        }
+                        out.extend(rev);
+                        out.push(' ');
+                    }
+                    out.chars().rev().collect()
+                }
+                SubType::Suffix => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let names = arg.split_whitespace().rev();
+                    let mut out = String::new();
+                    for name in names {
+                        let mut rev = name.chars().rev().peekable();
+                        let mut purged = String::new();
+                        let mut no_dot = false;
+                        while match rev.peek() {
+                            Some('/') => {
+                                no_dot = true;
+                                false
+                            }
+                            Some(&a) => {
+                                purged.push(rev.next().unwrap_or_else(|| unreachable!()));
+                                a != '.'
+                            }
+                            None => {
+                                no_dot = true;
+                                false
+                            }
+                        } {}
+                        if !no_dot {
+                            out.push_str(&purged);
+                        }
+                        out.push(' ');
+                    }
+                    out.chars().rev().collect()
+                }
+                SubType::AddPrefix => {
+                    let args = split_args(state, loc, "addprefix", &arg, 2);
+                    let prefix = expand_simple_ng(state, vars, loc, args[0]);
+                    let args = expand_simple_ng(state, vars, loc, args[1]);
+                    args.split_whitespace()
+                        .map(|x| format!("{}{}", prefix, x))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                SubType::AddSuffix => {
+                    let args = split_args(state, loc, "addsuffix", &arg, 2);
+                    let suffix = expand_simple_ng(state, vars, loc, args[0]);
+                    let args = expand_simple_ng(state, vars, loc, args[1]);
+                    args.split_whitespace()
+                        .map(|x| format!("{}{}", x, suffix))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                SubType::Sort => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let mut args = arg.split_whitespace().collect::<Vec<_>>();
+                    args.sort();
+                    args.dedup();
+                    let mut out = String::new();
+                    for arg in args.into_iter() {
+                        out.push_str(arg);
+                        out.push(' ');
+                    }
+                    out
+                }
+                #[cfg(feature = "extensions")]
+                SubType::SortNumeric => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let mut args = arg.split_whitespace().collect::<Vec<_>>();
+                    args.sort_by(|a, b| cmp_numeric(a, b));
+                    args.dedup();
+                    args.join(" ")
+                }
+                #[cfg(feature = "extensions")]
+                SubType::SortVersion => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let mut args = arg.split_whitespace().collect::<Vec<_>>();
+                    args.sort_by(|a, b| cmp_version(a, b));
+                    args.dedup();
+                    args.join(" ")
+                }
+                SubType::FirstWord => expand_simple_ng(state, vars, loc, &arg)
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                SubType::LastWord => expand_simple_ng(state, vars, loc, &arg)
+                    .split_whitespace()
+                    .last()
+                    .unwrap_or_default()
+                    .to_string(),
+                SubType::Words => expand_simple_ng(state, vars, loc, &arg)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .len()
+                    .to_string(),
+                SubType::Join => {
+                    let args = split_args(state, loc, "join", &arg, 2);
+                    let a1 = expand_simple_ng(state, vars, loc, args[0]);
+                    let a1 = a1.split_whitespace();
+                    let a2 = expand_simple_ng(state, vars, loc, args[1]);
+                    let a2 = a2.split_whitespace();
+                    let mut out = String::new();
+                    for (a, b) in a1.zip(a2) {
+                        out.push_str(a);
+                        out.push_str(b);
+                        out.push(' ');
+                    }
+                    out
+                }
+                SubType::NotDir => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let names = arg.split_whitespace().rev();
+                    let mut out = String::new();
+                    for name in names {
+                        let mut rev = name.chars().rev().peekable();
+                        let mut purged = String::new();
+                        while match rev.peek() {
+                            Some('/') => false,
+                            Some(_) => {
+                                purged.push(rev.next().unwrap());
+                                true
+                            }
+                            None => false,
+                        } {}
+                        out.push_str(&purged);
+                        out.push(' ');
+                    }
+                    out.chars().rev().collect()
+                }
+                SubType::Dir => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let names = arg.split_whitespace().rev();
+                    let mut out = String::new();
+                    for name in names {
+                        let mut rev = name.chars().rev().peekable();
+                        let mut purged = String::new();
+                        let mut no_slash = false;
+                        while match rev.peek() {
+                            Some('/') => false,
+                            Some(_) => {
+                                purged.push(rev.next().unwrap());
+                                true
+                            }
+                            None => {
+                                no_slash = true;
+                                false
+                            }
+                        } {}
+                        if no_slash {
+                            out.push('/');
+                            out.push('.');
+                        } else {
+                            out.extend(rev);
+                        }
+                        out.push(' ');
+                    }
+                    out.chars().rev().collect()
+                }
+                SubType::AbsPath => expand_simple_ng(state, vars, loc, &arg)
+                    .split_whitespace()
+                    .map(|x| {
+                        Path::new(x)
+                            .canonicalize()
+                            .map(|x| x.to_string_lossy().into_owned())
+                            .unwrap_or_default()
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                SubType::FindString => {
+                    let args = split_args(state, loc, "findstring", &arg, 2);
+                    let s = expand_simple_ng(state, vars, loc, args[0]);
+                    let rhs = expand_simple_ng(state, vars, loc, args[1]);
+                    if rhs.contains(&s) {
+                        s.into()
+                    } else {
+                        String::new()
+                    }
+                }
+                SubType::File => {
+                    // the text is everything after the first comma, commas and all
+                    let mut args = get_all_args(state, loc, "file", &arg).into_iter();
+                    let op = expand_simple_ng(state, vars, loc, &args.next().unwrap_or_default());
+                    let text = args.collect::<Vec<_>>();
+                    let text = (!text.is_empty()).then(|| expand_simple_ng(state, vars, loc, &text.join(",")));
+                    // the files are ours to change or read, not something the
+                    // parse cache could notice
+                    state.parse_ran_shell.set(true);
+                    file_function(state, loc, op.trim(), text)
+                }
+                SubType::Error => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    eprintln!("{}:{}: *** {}.  Stop.", loc.file_name, loc.line, arg.trim());
+                    leave_and_exit(state, 2);
+                }
+                SubType::Call => {
+                    let args = get_all_args(state, loc, "call", &arg);
+                    let mut args = args.into_iter();
+                    let name = args.next().unwrap_or_default();
+                    let name = expand_simple_ng(state, vars, loc, &name.trim());
+                    let mut vars = vars.clone();
+                    let mut highest = 0;
+                    for (i, arg) in args.enumerate() {
+                        let arg = expand_simple_ng(state, &mut vars, loc, &arg);
+                        highest = i + 2;
+                        let n = (i + 1).to_string();
+                        vars.insert(
+                            n.clone(),
+                            Var::new(
+                                Flavor::Simple,
+                                Origin::File,
+                                Some(loc.clone()),
+                                n,
+                                arg.to_string(),
+                                false,
+                            ),
+                        );
+                    }
+                    // TODO: hack. needs to be sorted out in a refactor.
+                    // need a better data structure for storing vars.
+                    for i in highest..100 {
+                        vars.remove(&i.to_string());
+                    }
+                    
+                    if let Some(v) = vars.get(&name) {
+                        let v = v.clone();
+                        v.clone().eval(state, loc, &mut vars)
+                    } else {
+                        String::new()
+                    }
+                }
+                SubType::Flavor => {
+                    let name = arg.trim();
+                    let name = expand_simple_ng(state, vars, loc, name);
+                    match vars.get(&name) {
+                        Some(Var {
+                            flavor: Flavor::Simple,
+                            ..
+                        }) => "simple",
+                        Some(Var {
+                            flavor: Flavor::Recursive,
+                            ..
+                        }) => "recursive",
+                        Some(Var {
+                            flavor: Flavor::Undefined,
+                            ..
+                        })
+                        | None => "undefined",
+                    }
+                    .into()
+                }
+                SubType::Origin => {
+                    let name = arg.trim();
+                    let name = expand_simple_ng(state, vars, loc, name);
+                    match vars.get(&name) {
+                        Some(Var {
+                            origin: Origin::Default,
+                            ..
+                        }) => "default".into(),
+                        Some(Var {
+                            origin: Origin::Env,
+                            ..
+                        }) => "environment".into(),
+                        Some(Var {
+                            origin: Origin::EnvOverride,
+                            ..
+                        }) => "environment override".into(),
+                        Some(Var {
+                            origin: Origin::File,
+                            ..
+                        }) => "file".into(),
+                        Some(Var {
+                            origin: Origin::CmdLine,
+                            ..
+                        }) => "command line".into(),
+                        Some(Var {
+                            origin: Origin::Override,
+                            ..
+                        }) => "override".into(),
+                        Some(Var {
+                            origin: Origin::Automatic,
+                            ..
+                        }) => "automatic".into(),
+                        Some(Var {
+                            origin: Origin::Undefined,
+                            ..
+                        })
+                        | None => "undefined".into(),
+                    }
+                }
+                SubType::ForEach => {
+                    let mut args = get_args::<3>(state, loc, "foreach", &arg);
+                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
+                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
+                    let mut vars = vars.clone();
+
+                    let mut out = String::new();
+
+                    for v in args[1].split_whitespace() {
+                        vars.insert(
+                            args[0].trim().into(),
+                            Var::new(
+                                Flavor::Simple,
+                                Origin::File,
+                                Some(loc.clone()),
+                                args[0].trim().into(),
+                                v.to_string(),
+                                false,
+                            ),
+                        );
+
+                        out.push_str(&expand_simple_ng(state, &mut vars, loc, &args[2]));
+                        out.push(' ');
+                    }
+                    out.pop();
+
+                    out
+                }
+                SubType::Let => {
+                    let args = get_all_args(state, loc, "let", &arg);
+                    if args.len() < 3 {
+                        fatal_arg_count(state, loc, args.len(), "let");
+                    }
+                    let names = expand_simple_ng(state, vars, loc, &args[0]);
+                    let list = expand_simple_ng(state, vars, loc, &args[1]);
+                    let mut vars = vars.clone();
+
+                    // one word each and the last name gets whatever's left
+                    let names = names.split_whitespace().collect::<Vec<_>>();
+                    let mut words = list.split_whitespace().collect::<Vec<_>>().into_iter();
+                    for (i, name) in names.iter().enumerate() {
+                        let value = if i + 1 == names.len() {
+                            words.by_ref().collect::<Vec<_>>().join(" ")
+                        } else {
+                            words.next().unwrap_or_default().to_string()
+                        };
+                        vars.insert(
+                            name.to_string(),
+                            Var::new(Flavor::Simple, Origin::File, Some(loc.clone()), name.to_string(), value, false),
+                        );
+                    }
+
+                    expand_simple_ng(state, &mut vars, loc, &args[2..].join(","))
+                }
+                SubType::IntCmp => {
+                    let args = get_all_args(state, loc, "intcmp", &arg);
+                    if args.len() < 2 {
+                        fatal_arg_count(state, loc, args.len(), "intcmp");
+                    }
+                    let mut int = |i: usize, which: &str| {
+                        let s = expand_simple_ng(state, vars, loc, &args[i]);
+                        s.trim().parse::<i128>().unwrap_or_else(|_| {
+                            eprintln!(
+                                "{}:{}: *** non-numeric {} argument to 'intcmp' function: '{}'.  Stop.",
+                                loc.file_name,
+                                loc.line,
+                                which,
+                                s.trim()
+                            );
+                            leave_and_exit(state, 2)
+                        })
+                    };
+                    let lhs = int(0, "first");
+                    let rhs = int(1, "second");
+
+                    if args.len() == 2 {
+                        // no parts: the number if they're equal
+                        if lhs == rhs {
+                            lhs.to_string()
+                        } else {
+                            String::new()
+                        }
+                    } else {
+                        // only the part that's picked is expanded. a missing
+                        // greater-than part is the equal one.
+                        let part = match lhs.cmp(&rhs) {
+                            std::cmp::Ordering::Less => args.get(2),
+                            std::cmp::Ordering::Equal => args.get(3),
+                            std::cmp::Ordering::Greater => args.get(4).or(args.get(3)),
+                        };
+                        part.map(|p| expand_simple_ng(state, vars, loc, p)).unwrap_or_default()
+                    }
+                }
+                SubType::Word => {
+                    let mut args = get_args::<2>(state, loc, "words", &arg);
+                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
+                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
+                    let n = args[0].trim().parse::<usize>().unwrap_or_else(|_| {
+                        println!(
+                            "{}:{}: *** non-numeric first argument to 'word' function: '{}'.  Stop.",
+                            loc.file_name, loc.line, args[0]
+                        );
+                        leave_and_exit(state, 2)
+                    });
+                    let mut words = args[1].split_whitespace();
+
+                    if n == 0 {
+                        println!("{}:{}: *** first argument to 'word' function must be greater than 0.  Stop.", loc.file_name, loc.line);
+                        leave_and_exit(state, 2)
+                    }
+
+                    words.nth(n - 1).unwrap_or_default().to_string()
+                }
+                SubType::WordList => {
+                    let mut args = get_args::<3>(state, loc, "wordlist", &arg);
+                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
+                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
+                    args[2] = expand_simple_ng(state, vars, loc, &args[2]);
+                    let mut n = args[0].trim().parse::<usize>().unwrap_or_else(|_| {
+                        println!(
+                            "{}:{}: *** non-numeric first argument to 'wordlist' function: '{}'.  Stop.",
+                            loc.file_name, loc.line, args[0]
+                        );
+                        leave_and_exit(state, 2)
+                    });
+                    let mut e = args[1].trim().parse::<usize>().unwrap_or_else(|_| {
+                        println!(
+                            "{}:{}: *** non-numeric second argument to 'wordlist' function: '{}'.  Stop.",
+                            loc.file_name, loc.line, args[1]
+                        );
+                        leave_and_exit(state, 2)
+                    });
+
+                    if n == 0 {
+                        println!(
+                            "{}:{}: *** invalid first argument to 'wordlist' function: '0'.  Stop.",
+                            loc.file_name, loc.line
+                        );
+                        leave_and_exit(state, 2)
+                    }
+                    // i was incorrect here it doesn't get reversed
+                    let rev = n > e;
+                    let rev = false;
+
+                    let words = args[2].split_whitespace().collect::<Vec<_>>();
+                    let out_words = if rev {
+                        &words[std::cmp::min(e - 1, words.len())..std::cmp::min(n, words.len())]
+                    } else {
+                        &words[std::cmp::min(n - 1, words.len())..std::cmp::min(e, words.len())]
+                    };
+                    let out_words = out_words
+                        .into_iter()
+                        .map(|x| format!("{} ", x))
+                        .collect::<Vec<_>>();
+                    if rev {
+                        out_words.into_iter().rev().collect::<String>()
+                    } else {
+                        out_words.into_iter().collect::<String>()
+                    }
+                }
+                SubType::SubstRef => {
+                    // only a reference with both `:` and `=` gets here
+                    let (var, rhs) = arg.split_once(':').unwrap_or_default();
+                    let (lhs, rhs) = rhs.split_once('=').unwrap_or_default();
+
+                    let lhs = expand_simple_ng(state, vars, loc, lhs.trim());
+                    let rhs = expand_simple_ng(state, vars, loc, rhs.trim());
+                    let var = expand_simple_ng(state, vars, loc, var.trim());
+
+                    if let Some((prefix, postfix)) = lhs.split_once('%') {
+                        let split = rhs.split_once("%");
+                        let min_len = prefix.len() + postfix.len();
+
+                        if let Some(v) = vars.get(var.trim()) {
+                            let v = v.clone().eval(state, loc, vars);
+                            let mut out = String::new();
+                            for v in v.split_whitespace() {
+                                if v.len() >= min_len && v.starts_with(prefix) && v.ends_with(postfix) {
+                                    if let Some((add_prefix, add_postfix)) = split {
+                                        out.push_str(add_prefix);
+                                        out.push_str(&v[prefix.len()..v.len() - postfix.len()]);
+                                        out.push_str(add_postfix);
+                                    } else {
+                                        out.push_str(&rhs);
+                                    }
+                                    
+                                    out.push(' ');
+                                }
+                            }
+                            out.pop(); // remove last ` `
+
+                            out
+                        } else {
+                            String::new()
+                        }
+                    } else if let Some(v) = vars.get(&var) {
+                        let v = v.clone().eval(state, loc, vars);
+                        let mut out = String::new();
+                        for v in v.split_whitespace() {
+                            if v.ends_with(&lhs) {
+                                out.push_str(&v[0..v.len() - lhs.len()]);
+                                out.push_str(&rhs);
+                                out.push(' ');
+                            }
+                        }
+                        out.pop(); // remove last ` `
+
+                        out
+                    } else {
+                        String::new()
+                    }
+                }
+                SubType::PatSubst => {
+                    let args = get_args::<3>(state, loc, "patsubst", &arg);
+
+                    let lhs = expand_simple_ng(state, vars, loc, args[0].trim());
+                    let rhs = expand_simple_ng(state, vars, loc, args[1].trim());
+                    let v = expand_simple_ng(state, vars, loc, args[2].trim());
+
+                    if let Some((prefix, postfix)) = lhs.split_once('%') {
+                        let split = rhs.split_once("%");
+                        let min_len = prefix.len() + postfix.len();
+
+                        let mut out = String::new();
+                        for v in v.split_whitespace() {
+                            if v.len() >= min_len && v.starts_with(prefix) && v.ends_with(postfix) {
+                                if let Some((add_prefix, add_postfix)) = split {
+                                    out.push_str(add_prefix);
+                                    out.push_str(&v[prefix.len()..v.len() - postfix.len()]);
+                                    out.push_str(add_postfix);
+                                } else {
+                                    out.push_str(&rhs);
+                                }
+                                
+                                out.push(' ');
+                            }
+                        }
+                        out.pop(); // remove last ` `
+
+                        out
+                    } else {
+                        let mut out = String::new();
+                        for v in v.split_whitespace() {
+                            if v == lhs {
+                                out.push_str(&rhs);
+                            } else {
+                                out.push_str(v);
+                            }
+                            out.push(' ');
+                        }
+
+                        out.pop(); // remove last ` `
+
+                        out
+                    }
+                }
+                SubType::Strip => {
                     let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let mut args = arg.split_whitespace().collect::<Vec<_>>();
-                    args.sort();
-                    args.dedup();
                     let mut out = String::new();
-                    for arg in args.into_iter() {
-                        out.extend(arg.chars());
+
+                    for a in arg.split_whitespace() {
+                        out.push_str(a);
                         out.push(' ');
                     }
-                    out
+
+                    out.pop();
+
+                    out
+                }
+                SubType::WildCard => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    let out = wildcard(&arg);
+                    state.parse_globs.borrow_mut().push((arg, out.clone()));
+                    out
+                }
+                SubType::Value => {
+                    let arg = expand_simple_ng(state, vars, loc, &arg);
+                    if let Some(v) = vars.get(arg.trim()) {
+                        v.value.clone()
+                    } else {
+                        String::new()
+                    }
+                }
+            }
+        }
+
+        None | Some('$') => '$'.to_string(),
+
+        // these special cases can be handled as variables in
+        // the var stack
+        //
+        // Some('?') => {
+        //     let mut out = String::new();
+        //     if let Some(rule) = rule {
+        //         for p in &rule.prerequisites {
+        //             out.extend(p.chars());
+        //             out.push(' ');
+        //         }
+        //         out.pop(); // remove the last pushed ` `
+        //     }
+        //     out
+        // }
+
+        // Some('@') => {
+        //     if let Some(rule) = rule {
+        //         rule.target.clone()
+        //     } else {
+        //         String::new()
+        //     }
+        // }
+        Some(v) => {
+            if let Some(v) = vars.get(&v.to_string()) {
+                v.clone().eval(state, loc, vars).to_string()
+            } else {
+                strict::undefined_variable(state, loc, &v.to_string());
+                String::new()
+            }
+        }
+    }
+}
+
+/// What `.FEATURES` says we can do, for makefiles that check before
+/// relying on something
+fn features() -> String {
+    let mut features = vec!["target-specific", "order-only", "else-if", "jobserver", "output-sync", "imake-job-id"];
+    if cfg!(feature = "extensions") {
+        features.extend(["imake-sort-numeric", "imake-sort-version", "imake-scoped-include", "imake-alias"]);
+    }
+    features.join(" ")
+}
+
+/// Quote a string for JSON output
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Orders words by their value as numbers, with words that aren't
+/// numbers after all the ones that are
+#[cfg(feature = "extensions")]
+fn cmp_numeric(a: &str, b: &str) -> std::cmp::Ordering {
+    match (a.parse::<f64>().ok(), b.parse::<f64>().ok()) {
+        (Some(x), Some(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Orders words like `sort -V`: runs of digits compare as numbers and
+/// everything between them compares as text, so `1.9` comes before `1.10`
+#[cfg(feature = "extensions")]
+fn cmp_version(a: &str, b: &str) -> std::cmp::Ordering {
+    fn runs(s: &str) -> Vec<&str> {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for (i, c) in s.char_indices().skip(1) {
+            let prev = s[..i].chars().last().unwrap();
+            if c.is_ascii_digit() != prev.is_ascii_digit() {
+                runs.push(&s[start..i]);
+                start = i;
+            }
+        }
+        if start < s.len() {
+            runs.push(&s[start..]);
+        }
+        runs
+    }
+
+    for (x, y) in runs(a).into_iter().zip(runs(b)) {
+        let ord = if x.starts_with(|c: char| c.is_ascii_digit()) && y.starts_with(|c: char| c.is_ascii_digit()) {
+            let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+            x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+        } else {
+            x.cmp(y)
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    runs(a).len().cmp(&runs(b).len()).then_with(|| a.cmp(b))
+}
+
+/// `$(file >name,text)`, `$(file >>name,text)` or `$(file <name)`. Text
+/// written gets a newline if it doesn't end in one and one is taken off
+/// what's read, so reading back what was written gives the text again.
+fn file_function(state: &State, loc: &Location, op: &str, text: Option<String>) -> String {
+    let fatal = |msg: String| -> ! {
+        eprintln!("{}:{}: *** {}.  Stop.", loc.file_name, loc.line, msg);
+        leave_and_exit(state, 2)
+    };
+
+    let (mode, name) = if let Some(name) = op.strip_prefix(">>") {
+        (">>", name)
+    } else if let Some(name) = op.strip_prefix('>') {
+        (">", name)
+    } else if let Some(name) = op.strip_prefix('<') {
+        ("<", name)
+    } else {
+        fatal(format!("file: invalid file operation: {}", op))
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        fatal("file: missing filename".into());
+    }
+
+    if mode == "<" {
+        if text.is_some() {
+            fatal("file: too many arguments".into());
+        }
+        // a file that isn't there reads as nothing
+        return match std::fs::read(name) {
+            Ok(bytes) => {
+                let s = String::from_utf8_lossy(&bytes);
+                s.strip_suffix('\n').unwrap_or(&s).to_string()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => fatal(format!("read: {}: {}", name, e)),
+        };
+    }
+
+    let file = File::options()
+        .create(true)
+        .append(mode == ">>")
+        .write(true)
+        .truncate(mode == ">")
+        .open(name)
+        .unwrap_or_else(|e| fatal(format!("open: {}: {}", name, e)));
+    if let Some(mut text) = text {
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        if let Err(e) = (&file).write_all(text.as_bytes()) {
+            fatal(format!("write: {}: {}", name, e));
+        }
+    }
+    String::new()
+}
+
+/// Run `cmd` for `$(shell)` or `!=` and return what it printed, with the
+/// trailing newlines dropped and the rest turned into spaces. Its exit
+/// status, or 128 plus the signal that killed it, goes in `.SHELLSTATUS`.
+fn shell_output(state: &State, vars: &mut HashMap<String, Var>, loc: &Location, cmd: &str) -> String {
+    if state.safe_dryrun {
+        eprintln!("{}: warning: not running '{}' (--dry-run=safe)", loc, cmd);
+        return String::new();
+    }
+
+    // either may have been undefined, which leaves them empty like any
+    // other variable. running nothing fails below like a missing shell.
+    let mut value = |name: &str| match vars.get(name) {
+        Some(v) => v.clone().eval(state, loc, vars),
+        None => String::new(),
+    };
+    let (shell, shell_flags) = (value("SHELL"), value(".SHELLFLAGS"));
+
+    let mut command = Command::new(&shell);
+    command
+        .arg0(&state.basename)
+        .args(shell_flags.split_ascii_whitespace())
+        .arg(cmd);
+    guard_env(state, vars, &mut command);
+    child_context(state, &mut command);
+
+    // `.SHELLSTDERR` says where the command's stderr goes: through to ours
+    // like GNU make, into `.SHELLERROR`, or nowhere
+    let stderr = match vars.get(".SHELLSTDERR") {
+        Some(v) => v.clone().eval(state, loc, vars).trim().to_string(),
+        None => String::new(),
+    };
+    let capture = match stderr.as_str() {
+        "" | "pass" => {
+            command.stderr(Stdio::inherit());
+            false
+        }
+        "capture" => true,
+        "discard" => {
+            command.stderr(Stdio::null());
+            false
+        }
+        _ => {
+            eprintln!(
+                "{}:{}: *** unknown .SHELLSTDERR '{}', expected pass, capture or discard.  Stop.",
+                loc.file_name, loc.line, stderr
+            );
+            leave_and_exit(state, 2)
+        }
+    };
+
+    state.parse_ran_shell.set(true);
+    let (status, out) = match command.output() {
+        Ok(out) => {
+            let status = out.status.code().or_else(|| out.status.signal().map(|sig| 128 + sig));
+            (status.unwrap_or_default(), Some(out))
+        }
+        // like GNU make it's as if the shell said it couldn't find it
+        Err(e) => {
+            eprintln!("{}: {}: {}", state.basename, shell, e);
+            (127, None)
+        }
+    };
+
+    let name: String = ".SHELLSTATUS".into();
+    vars.insert(
+        name.clone(),
+        Var::new(Flavor::Simple, Origin::Override, Some(loc.clone()), name, status.to_string(), false),
+    );
+    let Some(out) = out else {
+        return String::new();
+    };
+
+    let one_line = |out: &[u8]| {
+        let s = String::from_utf8_lossy(out);
+        s.trim_end_matches('\n').replace("\r\n", " ").replace('\n', " ")
+    };
+    if capture {
+        let name: String = ".SHELLERROR".into();
+        vars.insert(
+            name.clone(),
+            Var::new(Flavor::Simple, Origin::Override, Some(loc.clone()), name, one_line(&out.stderr), false),
+        );
+    }
+    one_line(&out.stdout)
+}
+
+/// Whether a prerequisite modified at `prereq` is newer than `target`
+/// modified at `time`. A `.LOW_RESOLUTION_TIME` target has been through
+/// something that drops fractions of a second, so only whole seconds count.
+fn newer_than(state: &State, target: &str, prereq: SystemTime, time: SystemTime) -> bool {
+    if state.low_resolution.iter().any(|t| t == target) {
+        let secs = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        secs(prereq) > secs(time)
+    } else {
+        prereq > time
+    }
+}
+
+/// The time to stamp things with: now, or `SOURCE_DATE_EPOCH` when it's set
+/// so a reproducible build gets the same times on every run. It's never
+/// older than the newest of `prerequisites` or what was stamped would still
+/// be out of date.
+fn build_time(prerequisites: &[String]) -> SystemTime {
+    let time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map_or_else(SystemTime::now, |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+    prerequisites
+        .iter()
+        .filter_map(|p| Path::new(p).metadata().and_then(|m| m.modified()).ok())
+        .fold(time, SystemTime::max)
+}
+
+/// Whether `name` is one of `targets`, which can have `%` patterns in them
+/// like the lists of special targets can
+fn matches_any(targets: &[String], name: &str) -> bool {
+    targets.iter().any(|t| pattern_stem(t, name).is_some())
+}
+
+/// `name` as the graph knows it: `.` components and doubled slashes are
+/// dropped and `dir/..` cancels out, so `./foo`, `obj/../foo` and `foo` are
+/// the same target. It's only done to the text, so with a symlink in the
+/// way `obj/..` isn't really here.
+fn normalize_path(name: &str) -> String {
+    if !name.contains('/') {
+        return name.to_string();
+    }
+    let absolute = name.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for c in name.split('/') {
+        match c {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|p| *p != "..") => {
+                parts.pop();
+            }
+            // there's nothing above the root
+            ".." if absolute => {}
+            c => parts.push(c),
+        }
+    }
+    let mut out = parts.join("/");
+    if absolute {
+        out.insert(0, '/');
+    }
+    if out.is_empty() {
+        out.push('.');
+    } else if name.ends_with('/') && !parts.is_empty() {
+        // `dir/` as a target is a directory, keep saying so
+        out.push('/');
+    }
+    out
+}
+
+/// Split a list of targets or prerequisites into [`normalize_path`]ed names
+fn path_list(list: &str) -> Vec<String> {
+    list.split_whitespace().map(normalize_path).collect()
+}
+
+/// What `%` stands for when `word` matches `pattern`
+fn pattern_stem<'a>(pattern: &str, word: &'a str) -> Option<&'a str> {
+    let Some((prefix, suffix)) = pattern.split_once('%') else {
+        return (pattern == word).then_some("");
+    };
+    if word.len() >= prefix.len() + suffix.len() && word.starts_with(prefix) && word.ends_with(suffix) {
+        Some(&word[prefix.len()..word.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+fn wildcard(pattern: &str) -> String {
+    let mut out = String::new();
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true
+    };
+    // something like `[` that isn't a pattern can still be a file name
+    let Ok(entries) = glob::glob_with(pattern, options) else {
+        return if Path::new(pattern).exists() { pattern.to_string() } else { String::new() };
+    };
+    // directories we can't read have nothing in them as far as we're concerned
+    for entry in entries.flatten() {
+        out.push_str(&entry.to_string_lossy());
+        out.push(' ');
+    }
+    out.pop();
+    out
+}
+
+fn expand_simple_ng(
+    state: &State,
+    vars: &mut HashMap<String, Var>,
+    loc: &Location,
+    input: &str,
+) -> String {
+    let since = state.timings.enter_expand();
+    let mut stack: String = input.chars().rev().collect();
+    let mut output = String::with_capacity(input.len());
+
+    while let Some(c) = stack.pop() {
+        match c {
+            '$' => {
+                output.push_str(&expand_ng(state, vars, loc, &mut stack));
+            }
+            // TODO: handle quoting properly
+            // '\'' if target_rule.is_none() => {}
+            // '"' if target_rule.is_none() => {}
+            a => {
+                output.push(a);
+            }
+        }
+    }
+
+    state.timings.leave_expand(since);
+    output
+}
+
+struct Line {
+    targets: Option<String>,
+}
+
+fn parse_line(state: &mut State, vars: &mut HashMap<String, Var>, location: &Location, src: &str) {
+    // Assume we're not gonna be in a rule
+    // correct later if we're wrong
+    state.in_rule = false;
+    let mut chars = src.chars().peekable();
+
+    let mut is_rule = false;
+    let mut double_colon = false;
+
+    let mut delim_stack = String::new();
+
+    while match chars.next() {
+        Some(')') => {
+            delim_stack.pop();
+            true
+        }
+        Some('}') => {
+            delim_stack.pop();
+            true
+        }
+
+        Some('(') => {
+            delim_stack.push('(');
+            true
+        }
+        Some('{') => {
+            delim_stack.push('{');
+            true
+        }
+
+        Some(_) if !delim_stack.is_empty() => true,
+        
+        Some(':') if matches!(chars.peek(), Some('=')) => false,
+
+        Some('=') => false,
+
+        Some(':') if matches!(chars.peek(), Some(':')) => {
+            chars.next();
+            match chars.peek() {
+                Some('=') => false,
+                _ => {
+                    is_rule = true;
+                    double_colon = true;
+                    false
+                }
+            }
+        }
+        Some(':') => {
+            is_rule = true;
+            false
+        }
+
+        Some(_) => true,
+        None => false,
+    } {}
+
+    let mut targets = None;
+    let mut src = src;
+    if let Some((t, rhs)) = src.split_once(if double_colon { "::" } else { ":" }).filter(|_| is_rule) {
+        targets = Some(t);
+        src = rhs
+    }
+
+    let unexport = directive(src, "unexport").map(|rest| rest.trim());
+    if targets.is_none() && unexport.is_some_and(|rest| !rest.is_empty()) {
+        for var in expand_simple_ng(state, vars, location, unexport.unwrap_or_default()).split_whitespace() {
+            if let Some(var) = vars.get_mut(var) {
+                var.unexport();
+            }
+        }
+    } else if targets.is_none() && unexport.is_some() {
+        // only undoes a bare `export`, anything named keeps its setting
+        state.export_all = false;
+    } else {
+        // FIXME:
+        // GNU make handles export X Y=1 as prereqs. we handle it as
+        // export the var `X Y` and set it to `1`
+        // after a rule's colon it's only `export` if a variable follows,
+        // otherwise it's a prerequisite
+        // `override` can come before or after `export`
+        let (is_override, src) = match directive(src, "override") {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, src),
+        };
+        let (export, src) = match directive(src, "export") {
+            Some(rest) if targets.is_none() || rest.contains('=') => (true, rest.trim_start()),
+            _ => (false, src),
+        };
+        let (is_override, src) = match directive(src, "override") {
+            Some(rest) if !is_override => (true, rest.trim_start()),
+            _ => (is_override, src),
+        };
+        // `t: private X = 1` is for `t` alone, not what it depends on
+        let (private, src) = match src.trim().strip_prefix("private ") {
+            Some(rest) if targets.is_some() => (true, rest),
+            _ => (false, src),
+        };
+
+        let (is_var, var_lhs, var_op, var_rhs) = {
+            let mut lhs = String::new();
+            let mut op = String::new();
+            let mut buf = String::new();
+            let mut hit_eq = false;
+            let mut delim_stack = String::new();
+            let mut chars = src.chars();
+
+            while match chars.next() {
+                Some(')') => {
+                    buf.push(')');
+                    delim_stack.pop();
+                    true
+                }
+                Some('}') => {
+                    buf.push('}');
+                    delim_stack.pop();
+                    true
+                }
+
+                Some('(') => {
+                    buf.push('(');
+                    delim_stack.push('(');
+                    true
+                }
+                Some('{') => {
+                    buf.push('{');
+                    delim_stack.push('{');
+                    true
+                }
+
+                Some(a) if !delim_stack.is_empty() => {
+                    buf.push(a);
+                    true
+                }
+
+                Some(';') if !hit_eq => {
+                    false
+                }
+
+                Some('=') if !hit_eq => {
+                    hit_eq = true;
+                    lhs = buf;
+                    buf = String::new();
+
+                    match lhs.pop() {
+                        Some(':') => {
+                            match lhs.pop() {
+                                Some(':') => op.push(':'),
+                                Some(x) => lhs.push(x),
+                                None => {}
+                            }
+                            op.push(':');
+                            op.push('=');
+                            true
+                        }
+
+                        Some(a) if matches!(a, '?' | '+' | '!') => {
+                            op.push(a);
+                            op.push('=');
+                            true
+                        }
+
+                        Some(a) => {
+                            lhs.push(a);
+                            op.push('=');
+                            true
+                        }
+
+                        // an empty name is caught once it's been expanded
+                        None => {
+                            op.push('=');
+                            true
+                        }
+                    }
                 }
-                SubType::FirstWord => expand_simple_ng(state, vars, loc, &arg)
-                    .split_whitespace()
-                    .next()
-                    .unwrap_or_default()
-                    .to_string(),
-                SubType::LastWord => expand_simple_ng(state, vars, loc, &arg)
-                    .split_whitespace()
-                    .last()
-                    .unwrap_or_default()
-                    .to_string(),
-                SubType::Words => expand_simple_ng(state, vars, loc, &arg)
-                    .split_whitespace()
-                    .collect::<Vec<_>>()
-                    .len()
-                    .to_string(),
-                SubType::Join => {
-                    let mut args = arg.split(',');
-                    let a1 = args.next().unwrap();
-                    let a1 = expand_simple_ng(state, vars, loc, &a1);
-                    let a1 = a1.split_whitespace();
-                    let a2 = args.next().unwrap();
-                    let a2 = expand_simple_ng(state, vars, loc, &a2);
-                    let a2 = a2.split_whitespace();
-                    let mut out = String::new();
-                    for (a, b) in a1.zip(a2) {
-                        out.extend(a.chars());
-                        out.extend(b.chars());
-                        out.push(' ');
+
+                Some(a) => {
+                    buf.push(a);
+                    true
+                }
+                None => false
+            } {}
+            (hit_eq, lhs, op, buf)
+        };
+
+        if is_var {
+            // let (lhs, rhs, var_op) = {
+            //     if let Some((lhs, rhs)) = src.split_once("::=") {
+            //         (lhs, rhs, VarOp::Store(true))
+            //     } else if let Some((lhs, rhs)) = src.split_once(":=") {
+            //         (lhs, rhs, VarOp::Store(true))
+            //     } else if let Some((lhs, rhs)) = src.split_once("+=") {
+            //         (lhs, rhs, VarOp::Append)
+            //     } else if let Some((lhs, rhs)) = src.split_once("!=") {
+            //         (lhs, rhs, VarOp::Shell)
+            //     } else if let Some((lhs, rhs)) = src.split_once("?=") {
+            //         (lhs, rhs, VarOp::StoreIfUndef)
+            //     } else {
+            //         let (lhs, rhs) = src.split_once('=').expect("aaaaa panic");
+            //         (lhs, rhs, VarOp::Store(false))
+            //     }
+            // };
+            //
+            let lhs = var_lhs;
+            let rhs = var_rhs;
+
+            let var_op = match var_op.as_str() {
+                "::=" | ":=" => VarOp::Store(true),
+                "+=" => VarOp::Append,
+                "!=" => VarOp::Shell,
+                "?=" => VarOp::StoreIfUndef,
+                // a plain `=`, the only other one found above
+                _ => VarOp::Store(false),
+            };
+
+            let lhs = expand_simple_ng(state, vars, location, &lhs);
+            if lhs.trim().is_empty() {
+                eprintln!("{}:{}: *** empty variable name.  Stop.", location.file_name, location.line);
+                leave_and_exit(state, 2);
+            }
+            let name = lhs.trim().to_string();
+            if targets.is_none() && !is_override && vars.get(&name).is_some_and(|v| v.overrides_file()) {
+                return;
+            }
+            let origin = if is_override { Origin::Override } else { Origin::File };
+            // we're better than GNU make here and allow `X Y=1`
+            match var_op {
+                VarOp::Store(expand) => {
+                    let lhs = lhs.trim().to_string();
+                    let rhs = if expand {
+                        expand_simple_ng(state, vars, location, &rhs)
+                    } else {
+                        rhs.to_string()
+                    };
+                    let var = vars.get_mut(lhs.trim());
+
+                    if let Some(targets) = targets {
+                        let targets = path_list(&expand_simple_ng(state, vars, location, targets));
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets,
+                            data: RuleData::Var(lhs, var_op, rhs, export, private),
+                        });
+                    } else {
+                        if let Some(var) = var {
+                            var.store(rhs.trim().to_string());
+                        } else {
+                            vars.insert(
+                                lhs.clone(),
+                                Var::new(
+                                    if expand {
+                                        Flavor::Simple
+                                    } else {
+                                        Flavor::Recursive
+                                    },
+                                    origin,
+                                    Some(location.clone()),
+                                    lhs,
+                                    rhs.trim().to_string(),
+                                    export,
+                                ),
+                            );
+                        }
                     }
-                    out
                 }
-                SubType::NotDir => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let names = arg.split_whitespace().rev();
-                    let mut out = String::new();
-                    for name in names {
-                        let mut rev = name.chars().rev().peekable();
-                        let mut purged = String::new();
-                        while match rev.peek() {
-                            Some('/') => false,
-                            Some(_) => {
-                                purged.push(rev.next().unwrap());
-                                true
-                            }
-                            None => false,
-                        } {}
-                        out.extend(purged.chars());
-                        out.push(' ');
+
+                VarOp::StoreIfUndef => {
+                    let lhs = lhs.trim().to_string();
+                    let rhs = rhs.to_string();
+                    let var = vars.get_mut(lhs.trim());
+
+                    if let Some(targets) = targets {
+                        let targets = path_list(&expand_simple_ng(state, vars, location, targets));
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets,
+                            data: RuleData::Var(lhs, var_op, rhs, export, private),
+                        });
+                    } else {
+                        if var.is_none() {
+                            vars.insert(
+                                lhs.clone(),
+                                Var::new(
+                                    Flavor::Recursive,
+                                    origin,
+                                    Some(location.clone()),
+                                    lhs,
+                                    rhs.trim().to_string(),
+                                    export,
+                                ),
+                            );
+                        }
                     }
-                    out.chars().rev().collect()
                 }
-                SubType::Dir => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let names = arg.split_whitespace().rev();
-                    let mut out = String::new();
-                    for name in names {
-                        let mut rev = name.chars().rev().peekable();
-                        let mut purged = String::new();
-                        let mut no_slash = false;
-                        while match rev.peek() {
-                            Some('/') => false,
-                            Some(_) => {
-                                purged.push(rev.next().unwrap());
-                                true
-                            }
-                            None => {
-                                no_slash = true;
-                                false
-                            }
-                        } {}
-                        if no_slash {
-                            out.push('/');
-                            out.push('.');
+
+                VarOp::Append => {
+                    let lhs = lhs.trim().to_string();
+                    let flavor = vars.get(lhs.trim()).map(|x| x.flavor);
+                    let rhs = if matches!(flavor, Some(Flavor::Recursive)) {
+                        expand_simple_ng(state, vars, location, &rhs)
+                    } else {
+                        rhs.to_string()
+                    };
+                    let var = vars.get_mut(lhs.trim());
+
+                    if let Some(targets) = targets {
+                        let targets = path_list(&expand_simple_ng(state, vars, location, targets));
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets,
+                            data: RuleData::Var(lhs, var_op, rhs, export, private),
+                        });
+                    } else {
+                        if let Some(var) = var {
+                            var.append(rhs.trim());
                         } else {
-                            out.extend(rev);
+                            vars.insert(
+                                lhs.clone(),
+                                Var::new(
+                                    Flavor::Recursive,
+                                    origin,
+                                    Some(location.clone()),
+                                    lhs,
+                                    rhs.trim().to_string(),
+                                    export,
+                                ),
+                            );
                         }
-                        out.push(' ');
                     }
-                    out.chars().rev().collect()
                 }
-                SubType::AbsPath => expand_simple_ng(state, vars, loc, &arg)
-                    .split_whitespace()
-                    .map(|x| {
-                        Path::new(x)
-                            .canonicalize()
-                            .map(|x| x.to_str().unwrap().to_string())
-                            .unwrap_or_default()
-                    })
-                    .fold(String::new(), |s, x| format!("{} {}", s, x)),
-                SubType::FindString => {
-                    let mut args = arg.split(',');
-                    let s = args.next().unwrap();
-                    let s = expand_simple_ng(state, vars, loc, &s);
-                    let rhs = args.next().unwrap();
-                    let rhs = expand_simple_ng(state, vars, loc, &rhs);
-                    if rhs.contains(&s) {
-                        s.into()
+
+                // run now and stored as if by `=`
+                VarOp::Shell => {
+                    let lhs = lhs.trim().to_string();
+                    let cmd = expand_simple_ng(state, vars, location, &rhs);
+                    let value = shell_output(state, vars, location, cmd.trim());
+
+                    if let Some(targets) = targets {
+                        let targets = path_list(&expand_simple_ng(state, vars, location, targets));
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets,
+                            data: RuleData::Var(lhs, VarOp::Store(false), value, export, private),
+                        });
+                    } else if let Some(var) = vars.get_mut(&lhs) {
+                        var.flavor = Flavor::Recursive;
+                        var.store(value);
+                        if export {
+                            var.export();
+                        }
                     } else {
-                        String::new()
-                    }
-                }
-                SubType::Error => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    eprintln!("{}:{}: *** {}.  Stop.", loc.file_name, loc.line, arg.trim());
-                    std::process::exit(2);
-                }
-                SubType::Call => {
-                    let args = get_all_args(loc, "call", &arg);
-                    let mut args = args.into_iter();
-                    let name = args.next().unwrap();
-                    let name = expand_simple_ng(state, vars, loc, &name.trim());
-                    let mut vars = vars.clone();
-                    let mut highest = 0;
-                    for (i, arg) in args.enumerate() {
-                        let arg = expand_simple_ng(state, &mut vars, loc, &arg);
-                        highest = i + 2;
-                        let n = (i + 1).to_string();
                         vars.insert(
-                            n.clone(),
-                            Var::new(
-                                Flavor::Simple,
-                                Origin::File,
-                                Some(loc.clone()),
-                                n,
-                                arg.to_string(),
-                                false,
-                            ),
+                            lhs.clone(),
+                            Var::new(Flavor::Recursive, origin, Some(location.clone()), lhs, value, export),
                         );
                     }
-                    // TODO: hack. needs to be sorted out in a refactor.
-                    // need a better data structure for storing vars.
-                    for i in highest..100 {
-                        vars.remove(&i.to_string());
-                    }
-                    
-                    if let Some(v) = vars.get(&name) {
-                        let v = v.clone();
-                        v.clone().eval(state, loc, &mut vars)
-                    } else {
-                        String::new()
-                    }
                 }
-                SubType::Flavor => {
-                    let name = arg.trim();
-                    let name = expand_simple_ng(state, vars, loc, name);
-                    match vars.get(&name) {
-                        Some(Var {
-                            flavor: Flavor::Simple,
-                            ..
-                        }) => "simple",
-                        Some(Var {
-                            flavor: Flavor::Recursive,
-                            ..
-                        }) => "recursive",
-                        Some(Var {
-                            flavor: Flavor::Undefined,
-                            ..
-                        })
-                        | None => "undefined",
-                    }
-                    .into()
+            }
+            if is_override && targets.is_none() {
+                if let Some(var) = vars.get_mut(&name) {
+                    var.origin = Origin::Override;
                 }
-                SubType::Origin => {
-                    let name = arg.trim();
-                    let name = expand_simple_ng(state, vars, loc, name);
-                    match vars.get(&name) {
-                        Some(Var {
-                            origin: Origin::Default,
-                            ..
-                        }) => "default".into(),
-                        Some(Var {
-                            origin: Origin::Env,
-                            ..
-                        }) => "environment".into(),
-                        Some(Var {
-                            origin: Origin::EnvOverride,
-                            ..
-                        }) => "environment override".into(),
-                        Some(Var {
-                            origin: Origin::File,
-                            ..
-                        }) => "file".into(),
-                        Some(Var {
-                            origin: Origin::CmdLine,
-                            ..
-                        }) => "command line".into(),
-                        Some(Var {
-                            origin: Origin::Override,
-                            ..
-                        }) => "override".into(),
-                        Some(Var {
-                            origin: Origin::Automatic,
-                            ..
-                        }) => "automatic".into(),
-                        Some(Var {
-                            origin: Origin::Undefined,
-                            ..
-                        })
-                        | None => "undefined".into(),
+            }
+        } else if let Some(targets) = targets {
+            state.in_rule = true;
+            // multiple recipies can be handled by shell `;`. this allows for `@cmd; cmd; cmd`
+            // to be handled properly
+            let (prereqs, recipie) = {
+                if let Some((prereqs, recpie)) = src.split_once(';') {
+                    (prereqs, Some(recpie))
+                } else {
+                    (src, None)
+                }
+            };
+            // automatic variables aren't set yet so `$@` in here is empty,
+            // GNU only binds them in prerequisites under .SECONDEXPANSION
+            let prereqs = expand_simple_ng(state, vars, location, prereqs);
+            // let prereqs = prereqs.trim().split_whitespace().map(|x| { x.to_string(); x.push(' '); x }).collect();
+            let targets = path_list(&expand_simple_ng(state, vars, location, targets));
+            note_default_goal(vars, &targets);
+            if let Some((pattern, prereqs)) = prereqs.split_once(':') {
+                let (prereqs, order_only) = prereqs.split_once('|').unwrap_or((prereqs, ""));
+                // static pattern rule: `targets: target-pattern: prereq-patterns`.
+                // each target gets its own prerequisites with the stem filled in
+                let pattern = pattern.trim();
+                for target in &targets {
+                    let Some(stem) = pattern_stem(pattern, target) else {
+                        eprintln!(
+                            "{}:{}: target '{}' doesn't match the target pattern",
+                            location.file_name, location.line, target
+                        );
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets: vec![target.clone()],
+                            data: RuleData::Prereq(double_colon, String::new()),
+                        });
+                        continue;
+                    };
+
+                    let with_stem = |prereqs: &str| {
+                        prereqs
+                            .split_whitespace()
+                            .map(|p| normalize_path(&p.replacen('%', stem, 1)))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    };
+                    if !order_only.trim().is_empty() {
+                        state.rules.push(Rule {
+                            location: location.clone(),
+                            targets: vec![target.clone()],
+                            data: RuleData::OrderOnly(with_stem(order_only)),
+                        });
                     }
+                    state.rules.push(Rule {
+                        location: location.clone(),
+                        targets: vec![target.clone()],
+                        data: RuleData::Prereq(double_colon, with_stem(prereqs)),
+                    });
+                    state.rules.push(Rule {
+                        location: location.clone(),
+                        targets: vec![target.clone()],
+                        data: RuleData::Stem(stem.to_string()),
+                    });
                 }
-                SubType::ForEach => {
-                    let mut args = get_args::<3>(loc, "foreach", &arg);
-                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
-                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
-                    let mut vars = vars.clone();
+                // the recipe lines that follow are for every target
+                state.rules.push(Rule {
+                    location: location.clone(),
+                    targets: targets.clone(),
+                    data: RuleData::Prereq(double_colon, String::new()),
+                });
+            } else {
+                let (prereqs, order_only) = prereqs.split_once('|').unwrap_or((&prereqs, ""));
+                // before the `Prereq` so recipe lines still follow that
+                if !order_only.trim().is_empty() {
+                    state.rules.push(Rule {
+                        location: location.clone(),
+                        targets: targets.clone(),
+                        data: RuleData::OrderOnly(path_list(order_only).join(" ")),
+                    });
+                }
+                state.rules.push(Rule {
+                    location: location.clone(),
+                    targets: targets.clone(),
+                    data: RuleData::Prereq(double_colon, path_list(prereqs).join(" ")),
+                });
+            }
+            if let Some(r) = recipie {
+                state.rules.push(Rule {
+                    location: location.clone(),
+                    targets: targets.clone(),
+                    data: RuleData::Recipie(r.into()),
+                })
+            }
+        } else if export {
+            let mut export_all = true;
+            for var in expand_simple_ng(state, vars, location, src).split_whitespace() {
+                export_all = false;
+                if let Some(var) = vars.get_mut(var) {
+                    var.export();
+                }
+            }
+            if export_all {
+                // variables defined later are exported too
+                state.export_all = true;
+            }
+        } else {
+            expand_simple_ng(state, vars, location, src);
+        }
+    }
+}
 
-                    let mut out = String::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    for v in args[1].split_whitespace() {
-                        vars.insert(
-                            args[0].trim().into(),
-                            Var::new(
-                                Flavor::Simple,
-                                Origin::File,
-                                Some(loc.clone()),
-                                args[0].trim().into(),
-                                v.to_string(),
-                                false,
-                            ),
-                        );
+    #[test]
+    fn expand_ng() {
+        // let mut state = State::default();
+        // vars.insert(
+        //     "X".into(),
+        //     Var::new(Flavor::Simple, Origin::File, "X".into(), "1".into(), false),
+        // );
+        // vars.insert(
+        //     "Y".into(),
+        //     Var::new(Flavor::Simple, Origin::File, "Y".into(), "$X".into(), false),
+        // );
+        // vars.insert(
+        //     "Z".into(),
+        //     Var::new(
+        //         Flavor::Recursive,
+        //         Origin::File,
+        //         "Y".into(),
+        //         "$X".into(),
+        //         false,
+        //     ),
+        // );
 
-                        out.extend(expand_simple_ng(state, &mut vars, loc, &args[2]).chars());
-                        out.push(' ');
-                    }
-                    out.pop();
+        // let tests = [
+        //     ("$X", "1"),
+        //     ("${X}", "1"),
+        //     ("$(X)", "1"),
+        //     ("$Y", "$X"),
+        //     ("$Y${Z}$(X)", "$X11"),
+        //     ("$Z", "1"),
+        //     ("$$", "$"),
+        // ];
+
+        // for (src, out) in tests {
+        //     eprintln!("testing expansion of `{}` to `{}`", src, out);
+        //     assert_eq!(
+        //         super::expand_simple_ng(&state,vars, l&Location::default(), None, &src),
+        //         out
+        //     );
+        // }
+    }
+
+    #[test]
+    fn parse_line_test() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+
+        super::parse_line(&mut state, &mut vars, &Location::default(), "test=1");
+        super::parse_line(&mut state, &mut vars, &Location::default(), "test+=1");
+        super::parse_line(&mut state, &mut vars, &Location::default(), "x: test+=1");
+        super::parse_line(&mut state, &mut vars, &Location::default(), "x: a b");
+        assert_eq!(super::expand_simple_ng(&state, &mut vars, &Location::default(), "$(test)"), "1 1");
+
+        // the target-specific one is kept for `x` and leaves the global alone
+        assert_eq!(state.rules.len(), 2);
+        assert!(state.rules.iter().all(|r| r.targets == ["x"]));
+        assert!(matches!(
+            &state.rules[0].data,
+            RuleData::Var(name, VarOp::Append, value, false, false) if name.trim() == "test" && value.trim() == "1"
+        ));
+        assert!(matches!(&state.rules[1].data, RuleData::Prereq(false, p) if p.trim() == "a b"));
+        assert_eq!(vars["test"].value, "1 1");
+    }
+
+    #[test]
+    fn recipe_prefixes() {
+        let none = RecipePrefix::default();
+        let all = RecipePrefix {
+            silent: true,
+            ignore_errors: true,
+            always: true,
+        };
+
+        assert_eq!(parse_recipe_prefix("echo hi"), (none, "echo hi"));
+        assert_eq!(parse_recipe_prefix("@-+echo hi"), (all, "echo hi"));
+        assert_eq!(parse_recipe_prefix("+ - @echo hi"), (all, "echo hi"));
+        assert_eq!(
+            parse_recipe_prefix("-@rm -f x"),
+            (
+                RecipePrefix {
+                    silent: true,
+                    ignore_errors: true,
+                    ..none
+                },
+                "rm -f x"
+            )
+        );
+        assert_eq!(
+            parse_recipe_prefix("@echo -n"),
+            (
+                RecipePrefix {
+                    silent: true,
+                    ..none
+                },
+                "echo -n"
+            )
+        );
+    }
+
+    #[test]
+    fn prerequisite_vars() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        super::parse_line(&mut state, &mut vars, &loc, "t: b a b");
+
+        let mut t = prepare_target(&mut state, &vars, "t").vars.view(&vars);
+        let mut expand = |src| expand_simple_ng(&state, &mut t, &loc, src);
+        assert_eq!(expand("$<"), "b");
+        assert_eq!(expand("$^"), "b a");
+        assert_eq!(expand("$+"), "b a b");
+        // narrowed down to the newer ones once the prerequisites are made
+        assert_eq!(expand("$?"), "b a");
+    }
+
+    #[test]
+    fn order_only() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["t: a | dir a", "t: | log", "x.o y.o: %.o: %.c | %.d"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+
+        let node = prepare_target(&mut state, &vars, "t");
+        assert_eq!(node.prerequisites, ["a"]);
+        assert_eq!(node.waiting, ["a", "dir", "log"]);
+        let mut t = node.vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$^ [$|]"), "a [dir log]");
+
+        let mut y = prepare_target(&mut state, &vars, "y.o").vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut y, &loc, "$< [$|]"), "y.c [y.d]");
+    }
+
+    #[test]
+    fn pattern_rules() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["%.x: %.y ; cp $< $@", "a.y:", "main.c:", "%.o: %.s"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+
+        let a = prepare_target(&mut state, &vars, "a.x");
+        assert_eq!(a.prerequisites, ["a.y"]);
+        assert_eq!(a.recipies.len(), 1);
+        assert_eq!(expand_simple_ng(&state, &mut a.vars.view(&vars), &loc, "$<"), "a.y");
+        assert!(prepare_target(&mut state, &vars, "b.x").prerequisites.is_empty());
+
+        let main = prepare_target(&mut state, &vars, "main.o");
+        assert_eq!(main.prerequisites, ["main.c"]);
+        assert_eq!(main.recipies[0].1, "$(COMPILE.c) $(OUTPUT_OPTION) $<");
+        assert_eq!(expand_simple_ng(&state, &mut main.vars.view(&vars), &loc, "$<"), "main.c");
+
+        // `%.o: %.s` has no recipe so it only cancels, it isn't a rule
+        let x = format!("{}: %.x: %.y", loc);
+        assert_eq!(implicit::written_rules(&state), std::slice::from_ref(&x));
+        assert_eq!(implicit::find(&state, "a.x").unwrap().rule, x);
+        assert_eq!(implicit::find(&state, "main.o").unwrap().rule, "<builtin>: %.o: %.c");
+
+        state.no_builtin_rules = true;
+        assert!(!prepare_target(&mut state, &vars, "main.o").found_rules);
+    }
+
+    #[test]
+    fn export_model() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        vars.insert(
+            "HOME".into(),
+            Var::new(Flavor::Simple, Origin::Env, None, "HOME".into(), "/root".into(), false),
+        );
+        vars.insert(
+            "CC".into(),
+            Var::new(Flavor::Recursive, Origin::Default, None, "CC".into(), "cc".into(), false),
+        );
+
+        let exported = |state: &State, vars: &HashMap<String, Var>| {
+            let mut names = child_env(state, vars).iter().map(|v| v.name.clone()).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        for line in ["A = a", "export", "B = b", "unexport A", "t: export D = d"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        // defined after the bare `export` but still exported
+        assert_eq!(exported(&state, &vars), ["B", "HOME"]);
+        let t = prepare_target(&mut state, &vars, "t").vars.view(&vars);
+        assert_eq!(exported(&state, &t), ["B", "D", "HOME"]);
+
+        for line in ["unexport", "unexport HOME", "export E = e"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        assert_eq!(exported(&state, &vars), ["E"]);
+    }
+
+    #[test]
+    fn builtin_vars() {
+        let loc = Location::default();
+        let mut vars = BUILTIN_VARS
+            .iter()
+            .map(|(name, value)| {
+                let name = name.to_string();
+                let var = Var::new(Flavor::Recursive, Origin::Default, None, name.clone(), value.to_string(), false);
+                (name, var)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(vars.len(), BUILTIN_VARS.len());
+
+        let state = State::default();
+        let mut expand = |src| {
+            let out = expand_simple_ng(&state, &mut vars, &loc, src);
+            out.split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(expand("$(COMPILE.c)"), "cc -c");
+        assert_eq!(expand("$(LINK.cpp)"), "g++");
+        assert_eq!(expand("$(RM) $(ARFLAGS)"), "rm -f rv");
+    }
+
+    #[test]
+    fn goal_overlap() {
+        let mut state = State::default();
+        state.phony.push("clean".into());
+        assert!(!goals_overlap(&state, "a", "b"));
+
+        state.jobs = 4;
+        assert!(goals_overlap(&state, "a", "b"));
+        assert!(!goals_overlap(&state, "clean", "all"));
+        assert!(!goals_overlap(&state, "all", "clean"));
+
+        state.not_parallel = true;
+        assert!(!goals_overlap(&state, "a", "b"));
+    }
+
+    #[test]
+    fn stem_var() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in [
+            "x.o: %.o: %.c",
+            "%.w: ; true",
+            "t.c:",
+            "lib.tar.gz:",
+            ".SUFFIXES: .q .r",
+            ".q.r: ; true",
+            "b.q:",
+        ] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+
+        let mut stem = |name| {
+            let mut vars = prepare_target(&mut state, &vars, name).vars.view(&vars);
+            expand_simple_ng(&state, &mut vars, &loc, "$*")
+        };
+        assert_eq!(stem("x.o"), "x");
+        assert_eq!(stem("sub/q.w"), "sub/q");
+        assert_eq!(stem("b.r"), "b");
+        assert_eq!(stem("t.c"), "t");
+        assert_eq!(stem("lib.tar.gz"), "");
+    }
+
+    #[test]
+    fn rule_context() {
+        let dir = std::env::temp_dir().join(format!("imake-rule-context-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inc = dir.join("rule.inc");
+        let main = dir.join("main.mk");
+        std::fs::write(&inc, "r2:\n\techo r2\n").unwrap();
+        std::fs::write(
+            &main,
+            format!(
+                "all:\n\techo a\nifdef V\n\techo v\nelse\n\techo no-v\nendif\n\n# c\n\techo b\n\
+                 x.o y.o: %.o: %.c\n\techo s\ninclude {}\n\t# c\n",
+                inc.display()
+            ),
+        )
+        .unwrap();
+
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        process_lines(&mut state, &mut vars, main.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let recipe = |target: &str| {
+            state
+                .rules
+                .iter()
+                .filter(|r| r.targets == [target])
+                .filter_map(|r| match &r.data {
+                    RuleData::Recipie(r) => Some(r.trim().to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        // conditionals and blank lines stay inside the recipe
+        assert_eq!(recipe("all"), ["echo a", "echo no-v", "echo b"]);
+        // and the included rule doesn't pick up the comment after it
+        assert_eq!(recipe("r2"), ["echo r2"]);
+        // a static pattern rule's recipe is for all of its targets
+        assert!(state
+            .rules
+            .iter()
+            .any(|r| r.targets == ["x.o", "y.o"] && matches!(&r.data, RuleData::Recipie(r) if r.trim() == "echo s")));
+        assert!(!state.in_rule);
+    }
+
+    #[test]
+    fn echo_matrix() {
+        let plain = RecipePrefix::default();
+        let at = RecipePrefix {
+            silent: true,
+            ..plain
+        };
+
+        let mut state = State::default();
+        state.silent_targets.push("quiet".into());
+        state.silent_targets.push("%.gen".into());
+        assert!(echoes(&state, "t", plain));
+        assert!(!echoes(&state, "t", at));
+        assert!(!echoes(&state, "quiet", plain));
+        assert!(!echoes(&state, "x.gen", plain));
+        assert!(echoes(&state, "x.gent", plain));
+
+        state.silent = true;
+        assert!(!echoes(&state, "t", plain));
+
+        // `-n` prints what would run whatever asked for quiet
+        state.dryrun = true;
+        for (target, prefix) in [("t", plain), ("t", at), ("quiet", plain), ("quiet", at)] {
+            assert!(echoes(&state, target, prefix));
+        }
+    }
+
+    #[test]
+    fn ifdef_needs_a_value() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        for line in ["E =", "F = $(E)", "S = x"] {
+            super::parse_line(&mut state, &mut vars, &Location::default(), line);
+        }
+
+        assert!(!is_defined(&vars, "E"));
+        assert!(is_defined(&vars, "F"));
+        assert!(is_defined(&vars, "S"));
+        assert!(!is_defined(&vars, "NOPE"));
+    }
+
+    #[test]
+    fn let_and_intcmp() {
+        let state = State::default();
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        let mut expand = |src| expand_simple_ng(&state, &mut vars, &loc, src);
+        assert_eq!(expand("$(let a b,1 2 3,$(a)-$(b),x)"), "1-2 3,x");
+        assert_eq!(expand("$(let a b c,1,[$(a)][$(b)][$(c)])"), "[1][][]");
+        assert_eq!(expand("[$(a)]"), "[]");
+
+        assert_eq!(expand("$(intcmp 007,7)"), "7");
+        assert_eq!(expand("[$(intcmp 1,2)]"), "[]");
+        assert_eq!(expand("$(intcmp -3,2,lt,eq,gt)"), "lt");
+        assert_eq!(expand("$(intcmp 2,2,lt,eq,gt)"), "eq");
+        assert_eq!(expand("$(intcmp 3,2,lt,ge)"), "ge");
+        assert_eq!(expand("[$(intcmp 3,2,lt)]"), "[]");
+        // the parts not picked aren't expanded
+        assert_eq!(expand("$(intcmp 1,2,ok,$(error no))"), "ok");
+    }
+
+    #[cfg(feature = "extensions")]
+    #[test]
+    fn extension_sorts() {
+        let loc = Location::default();
+        let state = State::default();
+        let mut vars = HashMap::new();
+        let mut expand = |src| expand_simple_ng(&state, &mut vars, &loc, src);
+        assert_eq!(expand("$(sort-numeric 10 9 x 1.5 -2 9)"), "-2 1.5 9 10 x");
+        assert_eq!(expand("$(sort-version 1.10 1.9 1.9.1 1.2rc1 1.02)"), "1.02 1.2rc1 1.9 1.9.1 1.10");
+        assert_eq!(expand("$(sort-version foo-2.0 foo-10.0 bar)"), "bar foo-2.0 foo-10.0");
+    }
+
+    #[cfg(feature = "extensions")]
+    #[test]
+    fn scoped_include() {
+        let dir = std::env::temp_dir().join(format!("imake-scoped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inc = dir.join("foo.mk");
+        let main = dir.join("main.mk");
+        std::fs::write(&inc, "CFLAGS += -Wall\nSRC := a.c\nSEEN := $(SRC)\nexport PREFIX = /usr\n").unwrap();
+        std::fs::write(&main, format!("CFLAGS = -O2\nUNTOUCHED = x\ninclude --scoped {}\n", inc.display())).unwrap();
+
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        process_lines(&mut state, &mut vars, main.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let loc = Location::default();
+        let expand = |vars: &mut _, src| expand_simple_ng(&state, vars, &loc, src);
+        assert_eq!(expand(&mut vars, "$(CFLAGS)|$(foo.CFLAGS)"), "-O2|-O2 -Wall");
+        assert_eq!(expand(&mut vars, "$(SRC)|$(foo.SRC)|$(foo.SEEN)"), "|a.c|a.c");
+        assert_eq!(expand(&mut vars, "$(PREFIX)|$(foo.PREFIX)"), "/usr|");
+        assert!(!vars.contains_key("foo.UNTOUCHED"));
+    }
+
+    #[test]
+    fn shell_status() {
+        let mut state = State::default();
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        for (name, value) in [("SHELL", "/bin/sh"), (".SHELLFLAGS", "-c")] {
+            vars.insert(name.into(), Var::new(Flavor::Simple, Origin::Default, None, name.into(), value.into(), false));
+        }
+        assert!(!vars.contains_key(".SHELLSTATUS"));
+
+        super::parse_line(&mut state, &mut vars, &loc, "X != printf 'a\\nb\\n\\n'; exit 3");
+        assert_eq!(vars["X"].value, "a b");
+        assert!(matches!(vars["X"].flavor, Flavor::Recursive));
+        let status = &vars[".SHELLSTATUS"];
+        assert_eq!(status.value, "3");
+        assert!(matches!(status.origin, Origin::Override));
+
+        expand_simple_ng(&state, &mut vars, &loc, "$(shell kill -TERM $$$$)");
+        assert_eq!(vars[".SHELLSTATUS"].value, "143");
+
+        super::parse_line(&mut state, &mut vars, &loc, ".SHELLSTDERR = capture");
+        let out = expand_simple_ng(&state, &mut vars, &loc, "$(shell echo out; printf 'e1\\ne2\\n' >&2)");
+        assert_eq!(out, "out");
+        assert_eq!(vars[".SHELLERROR"].value, "e1 e2");
+
+        state.safe_dryrun = true;
+        super::parse_line(&mut state, &mut vars, &loc, "Y != echo ran; exit 4");
+        assert_eq!(vars["Y"].value, "");
+        assert_eq!(expand_simple_ng(&state, &mut vars, &loc, "[$(shell echo ran)]"), "[]");
+        assert_eq!(vars[".SHELLSTATUS"].value, "0");
+
+        // nothing to run once it's been undefined
+        state.safe_dryrun = false;
+        vars.remove("SHELL");
+        assert_eq!(expand_simple_ng(&state, &mut vars, &loc, "[$(shell echo ran)]"), "[]");
+        assert_eq!(vars[".SHELLSTATUS"].value, "127");
+    }
+
+    #[test]
+    fn command_context() {
+        let state = State {
+            curdir: "/".into(),
+            umask: Some(0o077),
+            ..Default::default()
+        };
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        for (name, value) in [("SHELL", "/bin/sh"), (".SHELLFLAGS", "-c")] {
+            vars.insert(name.into(), Var::new(Flavor::Simple, Origin::Default, None, name.into(), value.into(), false));
+        }
+        assert_eq!(shell_output(&state, &mut vars, &loc, "pwd; umask"), "/ 0077");
+    }
+
+    #[test]
+    fn directory_markers() {
+        let state = State {
+            basename: "imake".into(),
+            ..Default::default()
+        };
+        assert_eq!(directory_messages(&state, 0, "/src").0, "imake: Entering directory '/src'");
+        assert_eq!(directory_messages(&state, 2, "/src").1, "imake[2]: Leaving directory '/src'");
+    }
+
+    #[test]
+    fn job_ids() {
+        let (r, w) = jobs::Slots::create(3).unwrap();
+        let mut state = State {
+            slots: jobs::Slots::new(&[r, w]),
+            ..Default::default()
+        };
+        let slots = [(); 3].map(|_| state.slots.acquire().unwrap());
+        assert_eq!(slots.map(|s| job_id(&state, s)), ["0", "1", "2"]);
+
+        state.slots.release(slots[1]);
+        let again = state.slots.acquire().unwrap();
+        assert_eq!(job_id(&state, again), "1");
+
+        state.parent_job_id = Some("2".into());
+        assert_eq!(job_id(&state, slots[0]), "2");
+        assert_eq!(job_id(&state, again), "2.1");
+    }
+
+    #[test]
+    fn unlimited_jobs() {
+        let mut slots = jobs::Slots::unlimited();
+        let taken = [(); 50].map(|_| slots.acquire().unwrap());
+        assert_eq!(taken[49].id(), 49);
+        assert!(!slots.has_jobserver());
+
+        let state = State {
+            jobs: jobs::UNLIMITED,
+            ..Default::default()
+        };
+        assert!(goals_overlap(&state, "a", "b"));
+    }
+
+    #[test]
+    fn fifo_jobserver() {
+        let (path, fd) = jobs::Slots::create_fifo(2).unwrap();
+        let mut ours = jobs::Slots::new(&[fd, fd]);
+        // a sub-make opens it by name and shares the one token
+        let theirs = jobs::open_fifo(&path).unwrap();
+        let mut theirs = jobs::Slots::new(&[theirs, theirs]);
+
+        assert!(matches!(ours.acquire(), Some(jobs::Slot::Implicit)));
+        assert!(matches!(theirs.acquire(), Some(jobs::Slot::Implicit)));
+        let token = theirs.acquire().unwrap();
+        assert!(ours.acquire().is_none());
+        theirs.release(token);
+        assert!(matches!(ours.acquire(), Some(jobs::Slot::Token(b'+', 1))));
+
+        assert_eq!(jobs::Style::parse("fifo"), Some(jobs::Style::Fifo));
+        assert_eq!(jobs::Style::parse("sem"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn halt_now() {
+        let mut state = State {
+            halt: Halt::Now,
+            ..Default::default()
+        };
+        let mut sched = Schedule::default();
+        let mut node = prepare_target(&mut state, &HashMap::new(), "t");
+        let pid = sys::spawn(Command::new("sleep").arg("10")).unwrap();
+        node.run = Some(Run {
+            mtime: None,
+            reason: plan::Reason::Phony,
+            temp: None,
+            output: None,
+            slot: None,
+            lines: VecDeque::new(),
+            has_recipies: true,
+            executed: Vec::new(),
+            job: Some(Job {
+                pid,
+                loc: Location::default(),
+                cmd: "sleep 10".into(),
+                ignore_errors: false,
+                since: None,
+                killed: false,
+            }),
+            failed: false,
+        });
+        sched.nodes.insert("t".into(), node);
+        sched.running = 1;
+
+        halt_jobs(&state, &mut sched);
+        assert!(sched.stopping);
+        assert!(sched.nodes["t"].run.as_ref().unwrap().job.as_ref().unwrap().killed);
+        // only wait for ours, other tests have children too
+        let mut status = 0;
+        assert_eq!(unsafe { sys::waitpid(pid, &mut status, 0) }, pid);
+        assert_eq!(std::process::ExitStatus::from_raw(status).signal(), Some(15));
+    }
+
+    #[test]
+    fn private_vars() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["X = global", "t: private X = mine", "t: private Y = only", "t: private Y += more"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+
+        let node = prepare_target(&mut state, &vars, "t");
+        let mut t = node.vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$(X) $(Y)"), "mine only more");
+        // what the prerequisites get back instead
+        assert!(matches!(&node.hidden[..], [(x, Some(old)), (y, None)] if x == "X" && old.value == "global" && y == "Y"));
+    }
+
+    #[test]
+    fn file_function() {
+        let dir = std::env::temp_dir().join(format!("imake-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rsp");
+        let path = path.to_str().unwrap();
+
+        let state = State::default();
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        let mut expand = |src: &str| expand_simple_ng(&state, &mut vars, &loc, src);
+        assert_eq!(expand(&format!("$(file >{},a b,c)", path)), "");
+        expand(&format!("$(file >> {},d\n)", path));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "a b,c\nd\n");
+        assert_eq!(expand(&format!("$(file <{})", path)), "a b,c\nd");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(expand(&format!("$(file <{})", path)), "");
+    }
 
-                    out
-                }
-                SubType::Word => {
-                    let mut args = get_args::<2>(loc, "words", &arg);
-                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
-                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
-                    let n = args[0].trim().parse::<usize>().unwrap_or_else(|_| {
-                        println!(
-                            "{}:{}: *** non-numeric first argument to 'word' function: '{}'.  Stop.",
-                            loc.file_name, loc.line, args[0]
-                        );
-                        std::process::exit(2)
-                    });
-                    let mut words = args[1].split_whitespace();
+    #[test]
+    fn missing_prerequisites() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["t: Cargo.toml made p | dir", "made:", "dir:", "%.x: %.nope"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        state.phony.push("p".into());
+        assert_eq!(report_missing(&state), Ok(()));
 
-                    if n == 0 {
-                        println!("{}:{}: *** first argument to 'word' function must be greater than 0.  Stop.", loc.file_name, loc.line);
-                        std::process::exit(2)
-                    }
+        super::parse_line(&mut state, &mut vars, &loc, "u: t not-there.nope");
+        assert_eq!(report_missing(&state), Err(1));
+    }
 
-                    words.nth(n - 1).unwrap_or_default().to_string()
-                }
-                SubType::WordList => {
-                    let mut args = get_args::<3>(loc, "wordlist", &arg);
-                    args[0] = expand_simple_ng(state, vars, loc, &args[0]);
-                    args[1] = expand_simple_ng(state, vars, loc, &args[1]);
-                    args[2] = expand_simple_ng(state, vars, loc, &args[2]);
-                    let mut n = args[0].trim().parse::<usize>().unwrap_or_else(|_| {
-                        println!(
-                            "{}:{}: *** non-numeric first argument to 'wordlist' function: '{}'.  Stop.",
-                            loc.file_name, loc.line, args[0]
-                        );
-                        std::process::exit(2)
-                    });
-                    let mut e = args[1].trim().parse::<usize>().unwrap_or_else(|_| {
-                        println!(
-                            "{}:{}: *** non-numeric second argument to 'wordlist' function: '{}'.  Stop.",
-                            loc.file_name, loc.line, args[1]
-                        );
-                        std::process::exit(2)
-                    });
+    #[test]
+    fn strict_unrequested() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: a | b", "a:", "b:", "clean:", "stray:", "%.o: %.c"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        for t in ["a", "b", "clean", "stray", "%.o", ".DEFAULT"] {
+            state.rules.push(Rule {
+                location: loc.clone(),
+                targets: vec![t.into()],
+                data: RuleData::Recipie("echo".into()),
+            });
+        }
+        state.phony.push("clean".into());
 
-                    if n == 0 {
-                        println!(
-                            "{}:{}: *** invalid first argument to 'wordlist' function: '0'.  Stop.",
-                            loc.file_name, loc.line
-                        );
-                        std::process::exit(2)
-                    }
-                    // i was incorrect here it doesn't get reversed
-                    let rev = n > e;
-                    let rev = false;
+        let unrequested = strict::unrequested(&state, &["all".into()]);
+        assert_eq!(unrequested.iter().map(|(_, t)| t.as_str()).collect::<Vec<_>>(), ["stray"]);
+        assert!(strict::unrequested(&state, &["stray".into()]).is_empty());
+    }
 
-                    let words = args[2].split_whitespace().collect::<Vec<_>>();
-                    let out_words = if rev {
-                        &words[std::cmp::min(e - 1, words.len())..std::cmp::min(n, words.len())]
-                    } else {
-                        &words[std::cmp::min(n - 1, words.len())..std::cmp::min(e, words.len())]
-                    };
-                    let out_words = out_words
-                        .into_iter()
-                        .map(|x| format!("{} ", x))
-                        .collect::<Vec<_>>();
-                    if rev {
-                        out_words.into_iter().rev().collect::<String>()
-                    } else {
-                        out_words.into_iter().collect::<String>()
-                    }
-                }
-                SubType::SubstRef => {
-                    let (var, rhs) = arg.split_once(':').unwrap();
-                    let (lhs, rhs) = rhs.split_once('=').unwrap();
+    #[test]
+    fn environment_diff() {
+        let state = State::default();
+        let mut vars = HashMap::new();
+        let path = std::env::var("PATH").unwrap();
+        for (name, value, origin) in [("PATH", "/nowhere", Origin::Env), ("IMAKE_TEST_NEW", "1", Origin::File)] {
+            vars.insert(name.to_string(), Var::new(Flavor::Simple, origin, None, name.into(), value.into(), true));
+        }
+        let diff = env_diff(&state, &vars);
+        assert!(diff.contains(&"~PATH=/nowhere".to_string()));
+        assert!(diff.contains(&"+IMAKE_TEST_NEW=1".to_string()));
+        // everything else we started with is left out
+        assert_eq!(diff.iter().filter(|d| d.starts_with('-')).count(), std::env::vars_os().count() - 1);
+
+        vars.get_mut("PATH").unwrap().store(path);
+        assert!(!env_diff(&state, &vars).iter().any(|d| d[1..].split('=').next() == Some("PATH")));
+    }
 
-                    let lhs = expand_simple_ng(state, vars, loc, lhs.trim());
-                    let rhs = expand_simple_ng(state, vars, loc, rhs.trim());
-                    let var = expand_simple_ng(state, vars, loc, var.trim());
+    #[test]
+    fn shuffled_order() {
+        use shuffle::Shuffle;
+        let prereqs = (0..20).map(|i| i.to_string()).collect::<Vec<_>>();
+        let order = |mode, target| {
+            let mut order = prereqs.clone();
+            shuffle::apply(mode, target, &mut order);
+            order
+        };
 
-                    if lhs.contains("%") {
-                        let (prefix, postfix) = lhs.split_once("%").unwrap();
-                        let split = rhs.split_once("%");
-                        let min_len = prefix.len() + postfix.len();
+        assert_eq!(order(Shuffle::None, "t"), prereqs);
+        assert_eq!(order(Shuffle::Reverse, "t")[0], "19");
+        let shuffled = order(Shuffle::Random(42), "t");
+        assert_ne!(shuffled, prereqs);
+        assert_eq!(shuffled, order(Shuffle::Random(42), "t"));
+        assert_ne!(shuffled, order(Shuffle::Random(43), "t"));
+        let mut sorted = shuffled.clone();
+        sorted.sort_by_key(|p| p.parse::<u32>().unwrap());
+        assert_eq!(sorted, prereqs);
+
+        assert_eq!(Shuffle::parse("random:42"), Some(Shuffle::Random(42)));
+        assert_eq!(Shuffle::parse("42"), Some(Shuffle::Random(42)));
+        assert_eq!(Shuffle::Random(42).flag().as_deref(), Some("--shuffle=random:42"));
+        assert_eq!(Shuffle::parse("sideways"), None);
+    }
 
-                        if let Some(v) = vars.get(var.trim()) {
-                            let v = v.clone().eval(state, loc, vars);
-                            let mut out = String::new();
-                            for v in v.split_whitespace() {
-                                if v.len() >= min_len && v.starts_with(prefix) && v.ends_with(postfix) {
-                                    if let Some((add_prefix, add_postfix)) = split {
-                                        out.extend(add_prefix.chars());
-                                        out.extend(v[prefix.len()..v.len() - postfix.len()].chars());
-                                        out.extend(add_postfix.chars());
-                                    } else {
-                                        out.extend(rhs.chars());
-                                    }
-                                    
-                                    out.push(' ');
-                                }
-                            }
-                            out.pop(); // remove last ` `
+    #[test]
+    fn intermediate_files() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["%.b: %.a ; cp $< $@", "%.c: %.b ; cp $< $@", "%.a: %.c", "x.a:", ".SECONDARY: y.b"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        process_specials(&mut state, &mut vars);
+
+        let x = implicit::find(&state, "x.c").unwrap();
+        assert_eq!(x.prereqs, ["x.b"]);
+        assert_eq!(x.intermediate, ["x.b"]);
+        assert!(implicit::find(&state, "x.b").unwrap().intermediate.is_empty());
+        // `%.a: %.c` would need `%.c: %.b` again
+        assert!(implicit::find(&state, "z.c").is_none());
+
+        prepare_target(&mut state, &vars, "x.c");
+        assert_eq!(state.intermediates, ["x.b"]);
+        assert_eq!(state.secondary, ["y.b"]);
+        assert!(!state.secondary_all);
+        assert!(is_intermediate(&state, "x.b") && is_intermediate(&state, "y.b"));
+
+        for line in [".INTERMEDIATE: ./w.o", ".NOTINTERMEDIATE: x.%"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        process_specials(&mut state, &mut vars);
+        assert!(is_intermediate(&state, "w.o"));
+        assert!(!is_intermediate(&state, "x.b"));
+    }
 
-                            out
-                        } else {
-                            String::new()
-                        }
-                    } else if let Some(v) = vars.get(&var) {
-                        let v = v.clone().eval(state, loc, vars);
-                        let mut out = String::new();
-                        for v in v.split_whitespace() {
-                            if v.ends_with(&lhs) {
-                                out.extend(v[0..v.len() - lhs.len()].chars());
-                                out.extend(rhs.chars());
-                                out.push(' ');
-                            }
-                        }
-                        out.pop(); // remove last ` `
+    #[test]
+    fn gnu_plan_diff() {
+        let gnu = compat::normalize(
+            "gmake[1]: Entering directory '/src/lib'\ncc -c a.c  \n\ngmake[1]: Leaving directory '/src/lib'\n\
+             gmake: Nothing to be done for 'docs'.\ngmake: 'b.o' is up to date.\n\
+             gmake[2]: *** [x] Error 1\nar rc lib.a a.o\n",
+        );
+        assert_eq!(gnu, ["cc -c a.c", "make: *** [x] Error 1", "ar rc lib.a a.o"]);
+        let ours = compat::normalize("imake: *** [x] Error 1\ncc -c a.c\nranlib lib.a\n");
+        assert_eq!(ours, ["make: *** [x] Error 1", "cc -c a.c", "ranlib lib.a"]);
+        // a command that happens to have a colon isn't a program name
+        assert_eq!(compat::normalize("echo make: done"), ["echo make: done"]);
+
+        assert_eq!(
+            compat::diff(&gnu, &ours),
+            [
+                ('-', "cc -c a.c"),
+                (' ', "make: *** [x] Error 1"),
+                ('-', "ar rc lib.a a.o"),
+                ('+', "cc -c a.c"),
+                ('+', "ranlib lib.a"),
+            ]
+        );
+        assert!(compat::diff(&gnu, &gnu).iter().all(|(c, _)| *c == ' '));
+    }
 
-                        out
-                    } else {
-                        String::new()
-                    }
-                }
-                SubType::PatSubst => {
-                    let args = get_args::<3>(loc, "patsubst", &arg);
+    #[test]
+    fn goal_suggestions() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["install install-strip: all", "all:", "%.o: %.c", ".PHONY: check", ".ALIAS: tests = all"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        process_specials(&mut state, &mut vars);
+
+        let closest = |goal| suggest::closest(&state, goal);
+        assert_eq!(closest("instal").as_deref(), Some("install"));
+        assert_eq!(closest("install-strp").as_deref(), Some("install-strip"));
+        #[cfg(feature = "extensions")]
+        assert_eq!(closest("test").as_deref(), Some("tests"));
+        assert_eq!(closest("al").as_deref(), Some("all"));
+        assert_eq!(closest("x.o"), None);
+        assert_eq!(closest("deploy"), None);
+    }
 
-                    let lhs = expand_simple_ng(state, vars, loc, args[0].trim());
-                    let rhs = expand_simple_ng(state, vars, loc, args[1].trim());
-                    let v = expand_simple_ng(state, vars, loc, args[2].trim());
+    #[test]
+    fn low_resolution_time() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        super::parse_line(&mut state, &mut vars, &Location::default(), ".LOW_RESOLUTION_TIME: ./copied");
+        process_specials(&mut state, &mut vars);
+
+        let at = |ms| SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(ms);
+        // the copy lost the .5 of its mtime
+        assert!(newer_than(&state, "exact", at(10_500), at(10_000)));
+        assert!(!newer_than(&state, "copied", at(10_500), at(10_000)));
+        assert!(newer_than(&state, "copied", at(11_000), at(10_000)));
+    }
 
-                    if lhs.contains("%") {
-                        let (prefix, postfix) = lhs.split_once("%").unwrap();
-                        let split = rhs.split_once("%");
-                        let min_len = prefix.len() + postfix.len();
+    #[test]
+    fn wait_prerequisites() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["t: .WAIT a b .WAIT .WAIT c | d .WAIT", "t: a .WAIT e"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
 
-                        let mut out = String::new();
-                        for v in v.split_whitespace() {
-                            if v.len() >= min_len && v.starts_with(prefix) && v.ends_with(postfix) {
-                                if let Some((add_prefix, add_postfix)) = split {
-                                    out.extend(add_prefix.chars());
-                                    out.extend(v[prefix.len()..v.len() - postfix.len()].chars());
-                                    out.extend(add_postfix.chars());
-                                } else {
-                                    out.extend(rhs.chars());
-                                }
-                                
-                                out.push(' ');
-                            }
-                        }
-                        out.pop(); // remove last ` `
+        let node = prepare_target(&mut state, &vars, "t");
+        assert_eq!(node.waiting, ["a", "b", ".WAIT", "c", ".WAIT", "e", "d"]);
+        assert_eq!(node.prerequisites, ["a", "b", "c", "a", "e"]);
+        let mut t = node.vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$^ [$|]"), "a b c e [d]");
+    }
 
-                        out
-                    } else {
-                        let mut out = String::new();
-                        for v in v.split_whitespace() {
-                            if v == lhs {
-                                out.extend(rhs.chars());
-                            } else {
-                                out.extend(v.chars());
-                            }
-                            out.push(' ');
-                        }
+    #[test]
+    fn interactive_targets() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        super::parse_line(&mut state, &mut vars, &Location::default(), ".INTERACTIVE: menuconfig %.edit");
+        process_specials(&mut state, &mut vars);
+        assert!(interactive(&state, "menuconfig") && interactive(&state, "notes.edit"));
+        assert!(!interactive(&state, "all"));
+
+        super::parse_line(&mut state, &mut vars, &Location::default(), ".INTERACTIVE:");
+        process_specials(&mut state, &mut vars);
+        assert!(interactive(&state, "all"));
+    }
 
-                        out.pop(); // remove last ` `
+    #[test]
+    fn conditional_arguments() {
+        let state = State::default();
+        let loc = Location::default();
+        assert_eq!(conditional_args(&state, &loc, " (a, b)"), ("a", " b"));
+        assert_eq!(conditional_args(&state, &loc, "($(subst x,y,$(X)),${Y})"), ("$(subst x,y,$(X))", "${Y}"));
+        assert_eq!(conditional_args(&state, &loc, "\"a\" 'b'"), ("\"a\"", "'b'"));
+        assert_eq!(conditional_args(&state, &loc, "(,)"), ("", ""));
+    }
 
-                        out
-                    }
-                }
-                SubType::Strip => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let mut out = String::new();
+    #[test]
+    fn override_precedence() {
+        let dir = std::env::temp_dir().join(format!("imake-toolchain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let toolchain = dir.join("arm.mk");
+        std::fs::write(&toolchain, "CC = arm-cc\nSYSROOT := /sysroot\n").unwrap();
+
+        let mut state = State {
+            toolchain: Some(toolchain.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        vars.insert("X".into(), Var::new(Flavor::Simple, Origin::CmdLine, None, "X".into(), "cmd".into(), false));
+        load_toolchain(&mut state, &mut vars);
+        std::fs::remove_dir_all(&dir).unwrap();
 
-                    for a in arg.split_whitespace() {
-                        out.extend(a.chars());
-                        out.push(' ');
-                    }
+        for line in ["X = file", "CC = cc", "SYSROOT += /usr", "Y = file", "override Y = over", "Y = again"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        assert_eq!(vars["X"].value, "cmd");
+        assert_eq!(vars["CC"].value, "arm-cc");
+        assert_eq!(vars["SYSROOT"].value, "/sysroot");
+        assert_eq!(vars["Y"].value, "over");
+        assert!(matches!(vars["CC"].origin, Origin::Override));
+
+        super::parse_line(&mut state, &mut vars, &loc, "override X = mine");
+        assert_eq!(vars["X"].value, "mine");
+    }
 
-                    out.pop();
+    #[test]
+    fn clean_outputs() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: app", "app: main.o util.o ; cc -o $@ $^", "util.o: util.h", "%.o: %.c ; cc -c $<"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        for line in ["clean: ; rm -f app", ".PHONY: all clean", "main.c util.c util.h:"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        process_specials(&mut state, &mut vars);
+        assert_eq!(clean::outputs(&mut state, &vars), ["app", "util.o", "main.o"]);
+    }
 
-                    out
-                }
-                SubType::WildCard => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    let mut out = String::new();
-                    let options = glob::MatchOptions {
-                        case_sensitive: true,
-                        require_literal_separator: true,
-                        require_literal_leading_dot: true
-                    };
-                    for entry in glob::glob_with(&arg, options).unwrap() {
-                        out.extend(entry.unwrap().to_str().unwrap().chars());
-                        out.push(' ');
-                    }
-                    out.pop();
-                    out
-                }
-                SubType::Value => {
-                    let arg = expand_simple_ng(state, vars, loc, &arg);
-                    if let Some(v) = vars.get(arg.trim()) {
-                        v.value.clone()
-                    } else {
-                        String::new()
-                    }
-                }
-                _ => todo!(),
-            }
+    #[test]
+    fn assume_new_and_old() {
+        let dir = std::env::temp_dir().join(format!("imake-assume-new-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        for (i, name) in ["lib.h", "lib.o", "app"].iter().enumerate() {
+            let f = std::fs::File::create(file(name)).unwrap();
+            f.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000 * (i as u64 + 1))).unwrap();
         }
 
-        None | Some('$') => '$'.to_string(),
+        let remade = |assume_new: &[&str], assume_old: &[&str]| {
+            let mut state = State {
+                dryrun: true,
+                assume_new: assume_new.iter().map(|f| file(f)).collect(),
+                assume_old: assume_old.iter().map(|f| file(f)).collect(),
+                ..Default::default()
+            };
+            let mut vars = HashMap::new();
+            let loc = Location::default();
+            for (target, prereq) in [("app", "lib.o"), ("lib.o", "lib.h")] {
+                super::parse_line(&mut state, &mut vars, &loc, &format!("{}: {} ; @:", file(target), file(prereq)));
+            }
+            process_goals(&mut state, &vars, &[file("app")], |_, _, _| false);
+            state.remade.iter().map(|t| t[dir.to_str().unwrap().len() + 1..].to_string()).collect::<Vec<_>>()
+        };
+        assert!(remade(&[], &[]).is_empty());
+        assert_eq!(remade(&["lib.h"], &[]), ["lib.o", "app"]);
+        // the file itself is as new as it gets
+        assert_eq!(remade(&["lib.o"], &[]), ["app"]);
+        assert!(remade(&["lib.h"], &["lib.o"]).is_empty());
+        std::fs::remove_file(file("lib.o")).unwrap();
+        assert!(remade(&[], &["lib.o"]).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // these special cases can be handled as variables in
-        // the var stack
-        //
-        // Some('?') => {
-        //     let mut out = String::new();
-        //     if let Some(rule) = rule {
-        //         for p in &rule.prerequisites {
-        //             out.extend(p.chars());
-        //             out.push(' ');
-        //         }
-        //         out.pop(); // remove the last pushed ` `
-        //     }
-        //     out
-        // }
+    #[test]
+    fn touch_before_epoch() {
+        let dir = std::env::temp_dir().join(format!("imake-touch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        for (name, secs) in [("out", 1000), ("in.c", 2000)] {
+            let f = std::fs::File::create(file(name)).unwrap();
+            f.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)).unwrap();
+        }
+        // a stamp from before the source was changed
+        std::env::set_var("SOURCE_DATE_EPOCH", "500");
+
+        let remade = |touch: bool| {
+            let mut state = State {
+                touch,
+                silent: true,
+                ..Default::default()
+            };
+            let mut vars = HashMap::new();
+            let rule = format!("{}: {} ; false", file("out"), file("in.c"));
+            super::parse_line(&mut state, &mut vars, &Location::default(), &rule);
+            process_goals(&mut state, &vars, &[file("out")], |_, _, _| false);
+            state.remade.len()
+        };
+        assert_eq!(remade(true), 1);
+        assert_eq!(remade(false), 0);
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // Some('@') => {
-        //     if let Some(rule) = rule {
-        //         rule.target.clone()
-        //     } else {
-        //         String::new()
-        //     }
-        // }
-        Some(v) => {
-            if let Some(v) = vars.get(&v.to_string()) {
-                v.clone().eval(state, loc, vars).to_string()
-            } else {
-                String::new()
+    #[test]
+    fn parallel_job_order() {
+        let dir = std::env::temp_dir().join(format!("imake-parallel-{}", std::process::id()));
+        // the copy of this test logs to the same place as the original
+        let log = std::env::var("IMAKE_TEST_FAILING_JOB").unwrap_or_else(|_| dir.join("log").display().to_string());
+        let rule = |target: &str, recipe: &str| format!("{}: ; {}", target, recipe.replace("LOG", &log));
+        let run = |lines: &[String]| {
+            let (r, w) = jobs::Slots::create(2).unwrap();
+            let mut state = State {
+                jobs: 2,
+                slots: jobs::Slots::new(&[r, w]),
+                silent: true,
+                ..Default::default()
+            };
+            let mut vars = HashMap::new();
+            for (name, value) in [("SHELL", "/bin/sh"), (".SHELLFLAGS", "-c")] {
+                let var = Var::new(Flavor::Simple, Origin::Default, None, name.into(), value.into(), false);
+                vars.insert(name.into(), var);
             }
+            for line in lines {
+                super::parse_line(&mut state, &mut vars, &Location::default(), line);
+            }
+            process_goals(&mut state, &vars, &["all".to_string()], |_, _, _| false);
+        };
+
+        // a failure stops make, so that half is run in a copy of this test
+        let failing = [
+            "all: bad slow next".to_string(),
+            rule("bad", "exit 1"),
+            rule("slow", "sleep 0.3; echo slow >> LOG"),
+            rule("next", "echo next >> LOG"),
+            rule("all", "echo all >> LOG"),
+        ];
+        if std::env::var_os("IMAKE_TEST_FAILING_JOB").is_some() {
+            run(&failing);
+            return;
         }
-    }
-}
 
-fn expand_simple_ng(
-    state: &State,
-    vars: &mut HashMap<String, Var>,
-    loc: &Location,
-    input: &str,
-) -> String {
-    let mut stack: String = input.chars().rev().collect();
-    let mut output = String::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        let logged = || std::fs::read_to_string(&log).unwrap().lines().map(String::from).collect::<Vec<_>>();
+
+        // two at a time in prerequisite order, the next one as soon as a
+        // slot is free and the goal only once they're all done
+        run(&[
+            "all: a b c".to_string(),
+            rule("a", "echo a >> LOG; sleep 0.5; echo a done >> LOG"),
+            rule("b", "sleep 0.1; echo b >> LOG"),
+            rule("c", "echo c >> LOG"),
+            rule("all", "echo all >> LOG"),
+        ]);
+        assert_eq!(logged(), ["a", "b", "c", "a done", "all"]);
+
+        // what's already running is let finish but nothing new starts
+        std::fs::remove_file(&log).unwrap();
+        let child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::parallel_job_order", "--nocapture"])
+            .env("IMAKE_TEST_FAILING_JOB", &log)
+            .output()
+            .unwrap();
+        assert_eq!(child.status.code(), Some(2));
+        assert_eq!(logged(), ["slow"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    while let Some(c) = stack.pop() {
-        match c {
-            '$' => {
-                output.extend(expand_ng(state, vars, loc, &mut stack).chars());
+    #[test]
+    fn keep_going_status() {
+        let status = |goals: &[&str]| {
+            let mut state = State {
+                keep_going: true,
+                silent: true,
+                targets_to_make: goals.iter().map(|g| g.to_string()).collect(),
+                ..Default::default()
+            };
+            let mut vars = HashMap::new();
+            for (name, value) in [("SHELL", "/bin/sh"), (".SHELLFLAGS", "-c")] {
+                let var = Var::new(Flavor::Simple, Origin::Default, None, name.into(), value.into(), false);
+                vars.insert(name.into(), var);
             }
-            // TODO: handle quoting properly
-            // '\'' if target_rule.is_none() => {}
-            // '"' if target_rule.is_none() => {}
-            a => {
-                output.push(a);
+            for line in [".PHONY: good bad", "good: ; @:", "bad: ; @exit 3"] {
+                super::parse_line(&mut state, &mut vars, &Location::default(), line);
             }
-        }
+            make_goals(state, vars)
+        };
+        assert_eq!(status(&["good"]), Ok(()));
+        // carrying on doesn't make it a success, whatever the recipe said
+        assert_eq!(status(&["bad", "good"]), Err(2));
+        assert_eq!(status(&["good", "no-such-goal"]), Err(2));
     }
 
-    output
-}
+    #[test]
+    fn failed_prerequisites_skip_dependents() {
+        let mut state = State {
+            keep_going: true,
+            silent: true,
+            ..Default::default()
+        };
+        let mut vars = HashMap::new();
+        for (name, value) in [("SHELL", "/bin/sh"), (".SHELLFLAGS", "-c")] {
+            let var = Var::new(Flavor::Simple, Origin::Default, None, name.into(), value.into(), false);
+            vars.insert(name.into(), var);
+        }
+        let lines = [
+            ".PHONY: top app lib.o main.o",
+            "top: app",
+            "app: lib.o main.o ; @:",
+            "lib.o: ; @exit 1",
+            "main.o: ; @:",
+        ];
+        for line in lines {
+            super::parse_line(&mut state, &mut vars, &Location::default(), line);
+        }
+        let mut outcome = Vec::new();
+        process_goals(&mut state, &vars, &["top".to_string()], |_, goal, progress| {
+            outcome.push((goal.to_string(), matches!(progress, Progress::Failed)));
+            false
+        });
+
+        // the other prerequisite is still made but nothing above the
+        // failure is attempted
+        assert_eq!(outcome, [("top".to_string(), true)]);
+        assert_eq!(state.failed, ["lib.o"]);
+        assert_eq!(state.remade, ["main.o"]);
+        assert!(matches!(state.processed["app"], Progress::Failed));
+    }
 
-struct Line {
-    targets: Option<String>,
-}
+    #[test]
+    fn question_runs_nothing() {
+        let dir = std::env::temp_dir().join(format!("imake-question-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(file("in"), "").unwrap();
+
+        let out_of_date = || {
+            let mut state = State {
+                question: true,
+                dryrun: true,
+                ..Default::default()
+            };
+            let mut vars = HashMap::new();
+            let rule = format!("{}: {} ; touch $@", file("out"), file("in"));
+            super::parse_line(&mut state, &mut vars, &Location::default(), &rule);
+            process_goals(&mut state, &vars, &[file("out")], |_, _, _| false);
+            state.out_of_date
+        };
+        assert!(out_of_date());
+        assert!(!Path::new(&file("out")).exists());
+        std::fs::write(file("out"), "").unwrap();
+        assert!(!out_of_date());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-fn parse_line(state: &mut State, vars: &mut HashMap<String, Var>, location: &Location, src: &str) {
-    // Assume we're not gonna be in a rule
-    // correct later if we're wrong
-    state.in_rule = false;
-    let mut chars = src.chars().peekable();
+    #[test]
+    fn serial_dirs() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        super::parse_line(&mut state, &mut vars, &loc, ".SERIAL_DIRS: ./obj/ . /tmp/");
+        process_specials(&mut state, &mut vars);
+        assert_eq!(state.serial_dirs, ["obj", ".", "/tmp"]);
+
+        assert_eq!(serial_dir(&state, "obj/a.o").as_deref(), Some("obj"));
+        assert_eq!(serial_dir(&state, "./obj/sub/../b.o").as_deref(), Some("obj"));
+        assert_eq!(serial_dir(&state, "a.o").as_deref(), Some("."));
+        assert_eq!(serial_dir(&state, "/tmp/x").as_deref(), Some("/tmp"));
+        assert_eq!(serial_dir(&state, "lib/a.o"), None);
+
+        state.serial_dirs_all = true;
+        assert_eq!(serial_dir(&state, "lib/a.o").as_deref(), Some("lib"));
+    }
 
-    let mut is_rule = false;
-    let mut double_colon = false;
+    #[test]
+    fn dump_ast() {
+        let src = "X := a \\\n  b # c\nifdef X\nt: p | o ; one\n\ttwo\nelse ifeq (1,2)\nt: Y = 1\nendif\n";
+        let json = ast::to_json("mk", src).unwrap();
+        let lines = json.lines().map(str::trim).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                r#"{"file":"mk","nodes":["#,
+                r#"{"kind":"assignment","span":{"start":0,"end":16,"line":1},"name":"X","op":":=","value":"a b","export":false,"override":false},"#,
+                r#"{"kind":"conditional","span":{"start":17,"end":75,"line":3},"directive":"ifdef","args":"X","then":["#,
+                concat!(
+                    r#"{"kind":"rule","span":{"start":25,"end":39,"line":4},"targets":["t"],"double_colon":false,"#,
+                    r#""prerequisites":["p"],"order_only":["o"],"recipe":[{"span":{"start":25,"end":39,"line":4},"text":"one"},"#,
+                    r#"{"span":{"start":40,"end":44,"line":5},"text":"two"}]}"#
+                ),
+                r#"],"else":["#,
+                r#"{"kind":"conditional","span":{"start":45,"end":75,"line":6},"directive":"ifeq","args":"(1,2)","then":["#,
+                r#"{"kind":"target_variable","span":{"start":61,"end":69,"line":7},"targets":["t"],"name":"Y","op":"=","value":"1","export":false,"override":false}"#,
+                r#"],"else":[]}"#,
+                r#"]}"#,
+                r#"]}"#,
+            ]
+        );
+        assert_eq!(ast::to_json("mk", "else\n").unwrap_err(), "mk:1: *** extraneous 'else'.  Stop.");
+        assert_eq!(ast::to_json("mk", "ifdef X\n").unwrap_err(), "mk:1: *** missing 'endif'.  Stop.");
+    }
 
-    let mut delim_stack = String::new();
+    #[test]
+    fn infer_phony() {
+        let dir = std::env::temp_dir().join(format!("imake-infer-phony-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut state = State {
+            infer_phony: Some(Default::default()),
+            phony: vec![file("declared")],
+            ..Default::default()
+        };
 
-    while match chars.next() {
-        Some(')') => {
-            delim_stack.pop();
-            true
-        }
-        Some('}') => {
-            delim_stack.pop();
-            true
+        std::fs::write(file("made"), "").unwrap();
+        std::fs::write(file("old"), "").unwrap();
+        let old = Path::new(&file("old")).metadata().and_then(|m| m.modified()).ok();
+        for (target, before) in [("missing", None), ("declared", None), ("made", None), ("old", old)] {
+            phony::observe(&mut state, &file(target), before);
         }
+        assert_eq!(state.infer_phony.as_ref().unwrap().targets, [file("missing")]);
 
-        Some('(') => {
-            delim_stack.push('(');
-            true
-        }
-        Some('{') => {
-            delim_stack.push('{');
-            true
+        // once it does make its file it's a file again
+        std::fs::write(file("missing"), "").unwrap();
+        phony::observe(&mut state, &file("missing"), None);
+        assert!(state.infer_phony.as_ref().unwrap().targets.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_targets() {
+        let mut state = State {
+            dryrun: true,
+            plan: Some(Vec::new()),
+            ..Default::default()
+        };
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: out.o run", "out.o run all: ; cc -o $@", ".ATOMIC: ./out.o run", ".PHONY: run"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
         }
+        process_specials(&mut state, &mut vars);
+        assert!(atomic(&state, "out.o"));
+        assert!(!atomic(&state, "run") && !atomic(&state, "all"));
 
-        Some(_) if !delim_stack.is_empty() => true,
-        
-        Some(':') if matches!(chars.peek(), Some('=')) => false,
+        process_goals(&mut state, &vars, &["all".to_string()], |_, _, _| false);
+        let recipes = state.plan.unwrap().into_iter().map(|s| s.recipe.join("\n")).collect::<Vec<_>>();
+        assert_eq!(recipes, ["cc -o out.o.tmp", "cc -o run", "cc -o all"]);
+    }
 
-        Some('=') => false,
+    #[test]
+    fn doctor_report() {
+        let dir = std::env::temp_dir().join(format!("imake-doctor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = State {
+            curdir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let shell = |value: &str| {
+            let var = Var::new(Flavor::Simple, Origin::File, None, "SHELL".into(), value.into(), false);
+            HashMap::from([("SHELL".to_string(), var)])
+        };
+        assert_eq!(doctor::report(&state, &mut shell("/nonexistent/sh"), None), Err(1));
+        // the probe doesn't stay behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        Some(':') if matches!(chars.peek(), Some(':')) => {
-            chars.next();
-            match chars.peek() {
-                Some('=') => false,
-                _ => {
-                    is_rule = true;
-                    double_colon = true;
-                    false
-                }
-            }
+    #[test]
+    fn environment_overrides() {
+        let mut state = State::default();
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        for (name, origin) in [("CC", Origin::EnvOverride), ("LD", Origin::Env), ("AR", Origin::EnvOverride)] {
+            vars.insert(name.into(), Var::new(Flavor::Simple, origin, None, name.into(), "env".into(), true));
         }
-        Some(':') => {
-            is_rule = true;
-            false
+        for line in ["CC = file", "CC += -O2", "LD = file", "override AR = file"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
         }
-
-        Some(_) => true,
-        None => false,
-    } {}
-
-    let mut targets = None;
-    let mut src = src;
-    if is_rule {
-        let (t, rhs) = src
-            .split_once(if double_colon { "::" } else { ":" })
-            .expect("aaaaaaa panic");
-        targets = Some(t);
-        src = rhs
+        assert_eq!(vars["CC"].value, "env");
+        assert_eq!(vars["LD"].value, "file");
+        assert_eq!(vars["AR"].value, "file");
     }
 
-    if targets.is_none() && src.trim().starts_with("unexport ") {
-        for var in expand_simple_ng(state, vars, location, &src.trim()[9..]).split_whitespace() {
-            if let Some(var) = vars.get_mut(var) {
-                var.unexport();
-            }
+    #[test]
+    fn keep_going_failures() {
+        let mut state = State {
+            dryrun: true,
+            keep_going: true,
+            ..Default::default()
+        };
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: app other", "app: missing.c lib ; @:", "lib other: ; @:"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
         }
-    } else if targets.is_none() && src.trim().starts_with("unexport") {
-        for var in vars.values_mut() {
-            // Don't implicitly unexport if explicitly exported
-            // TODO: check soundness of exporting and unexporting
-            if !var.exported && !matches!(var.origin, Origin::Env) {
-                var.unexport();
+        let mut failed = Vec::new();
+        process_goals(&mut state, &vars, &["all".to_string()], |_, goal, progress| {
+            if matches!(progress, Progress::Failed) {
+                failed.push(goal.to_string());
             }
-        }
-    } else {
-        // FIXME:
-        // GNU make handles export X Y=1 as prereqs. we handle it as
-        // export the var `X Y` and set it to `1`
-        let (export, src) = if src.trim().starts_with("export ") {
-            (true, &src.trim()[7..])
-        } else if src.trim().starts_with("export") {
-            (true, "")
-        } else {
-            (false, src)
+            false
+        });
+        // everything else is still made, but not what needed the missing file
+        assert_eq!(failed, ["all"]);
+        assert_eq!(state.remade, ["lib", "other"]);
+        assert_eq!(state.failed, ["missing.c"]);
+    }
+
+    #[test]
+    fn debug_categories() {
+        use debug::{Categories, Category};
+
+        let some = Categories::default().parse("b,j").unwrap();
+        assert!(some.has(Category::Basic) && some.has(Category::Jobs));
+        assert!(!some.has(Category::Verbose) && !some.has(Category::Internal));
+
+        let verbose = Categories::default().parse("verbose").unwrap();
+        assert!(verbose.has(Category::Basic) && verbose.has(Category::Verbose));
+
+        assert_eq!(Categories::ALL.parse("none,implicit"), Categories::default().parse("i"));
+        assert!(!Categories::ALL.has(Category::Env));
+        assert_eq!(Categories::default().parse("basic,bogus"), Err("bogus".to_string()));
+    }
+
+    #[test]
+    fn max_load() {
+        let state = State {
+            max_load: Some(0.5),
+            ..Default::default()
         };
+        let mut sched = Schedule::default();
+        // nothing running, so one job goes whatever the load
+        sched.started.push_back(Instant::now());
+        assert!(load_allows(&state, &mut sched));
+
+        // the job just started counts as a whole one
+        sched.running = 1;
+        assert!(!load_allows(&state, &mut sched));
+
+        sched.started.clear();
+        sched.started.push_back(Instant::now() - Duration::from_secs(2));
+        let unloaded = sys::load_average().is_some_and(|load| load < 0.5);
+        assert_eq!(load_allows(&state, &mut sched), unloaded);
+        assert!(sched.started.is_empty());
+    }
 
-        let (is_var, var_lhs, var_op, var_rhs) = {
-            let mut lhs = String::new();
-            let mut op = String::new();
-            let mut buf = String::new();
-            let mut hit_eq = false;
-            let mut delim_stack = String::new();
-            let mut chars = src.chars();
+    #[test]
+    fn output_sync_modes() {
+        use output::Mode;
+
+        assert_eq!(Mode::parse("recurse"), Some(Mode::Recurse));
+        assert_eq!(Mode::parse("lines"), None);
+        assert_eq!(Mode::Line.flag().as_deref(), Some("-Oline"));
+        assert_eq!(Mode::None.flag(), None);
+
+        // sub-makes sync their own unless the whole of them is wanted
+        assert!(Mode::Target.captures(false) && !Mode::Target.captures(true));
+        assert!(Mode::Recurse.captures(true));
+        assert!(!Mode::None.captures(false));
+    }
 
-            while match chars.next() {
-                Some(')') => {
-                    buf.push(')');
-                    delim_stack.pop();
-                    true
-                }
-                Some('}') => {
-                    buf.push('}');
-                    delim_stack.pop();
-                    true
-                }
+    #[test]
+    fn option_spellings() {
+        use options::{parse, Arg};
+
+        let parse = |args: &[&str]| parse(args.iter().map(|a| a.to_string()));
+        let opt = |opt, value: Option<&str>| Arg::Option(opt, value.map(str::to_string));
+
+        assert_eq!(
+            parse(&["-kCdir", "--file=mk", "-sfother", "--include-dir", "inc", "--keep", "X=1", "all"]).unwrap(),
+            [
+                opt(Opt::KeepGoing, None),
+                opt(Opt::Directory, Some("dir")),
+                opt(Opt::File, Some("mk")),
+                opt(Opt::Silent, None),
+                opt(Opt::File, Some("other")),
+                opt(Opt::IncludeDir, Some("inc")),
+                opt(Opt::KeepGoing, None),
+                Arg::Assignment("X=1".into()),
+                Arg::Goal("all".into()),
+            ]
+        );
 
-                Some('(') => {
-                    buf.push('(');
-                    delim_stack.push('(');
-                    true
-                }
-                Some('{') => {
-                    buf.push('{');
-                    delim_stack.push('{');
-                    true
-                }
+        // numbers can follow `-j`, anything else is the next option. the
+        // rest of a cluster is all its argument.
+        assert_eq!(
+            parse(&["-j4k", "-lfoo", "-j", "2", "--jobs", "-k", "-Oline", "-O", "x"]).unwrap(),
+            [
+                opt(Opt::Jobs, Some("4k")),
+                opt(Opt::LoadAverage, Some("foo")),
+                opt(Opt::Jobs, Some("2")),
+                opt(Opt::Jobs, None),
+                opt(Opt::KeepGoing, None),
+                opt(Opt::OutputSync, Some("line")),
+                opt(Opt::OutputSync, None),
+                Arg::Goal("x".into()),
+            ]
+        );
+        assert_eq!(parse(&["--", "-k"]).unwrap(), [Arg::Goal("-k".into())]);
+
+        // what a GNU make parent might pass down
+        assert_eq!(
+            parse(&["-pqL", "--trace", "--warn-undefined-variables", "-E", "X = 1"]).unwrap(),
+            [
+                opt(Opt::PrintDataBase, None),
+                opt(Opt::Question, None),
+                opt(Opt::CheckSymlinkTimes, None),
+                opt(Opt::Trace, None),
+                opt(Opt::WarnUndefinedVariables, None),
+                opt(Opt::Eval, Some("X = 1")),
+            ]
+        );
+        assert_eq!(parse(&["--environment-overr"]).unwrap(), [opt(Opt::EnvironmentOverrides, None)]);
 
-                Some(a) if !delim_stack.is_empty() => {
-                    buf.push(a);
-                    true
-                }
+        assert_eq!(parse(&["-X"]).unwrap_err(), "invalid option -- 'X'");
+        assert_eq!(parse(&["-kf"]).unwrap_err(), "option requires an argument -- 'f'");
+        assert_eq!(parse(&["--silent=3"]).unwrap_err(), "option '--silent' doesn't allow an argument");
+        assert!(parse(&["--assume"]).unwrap_err().starts_with("option '--assume' is ambiguous"));
+    }
 
-                Some(';') if !hit_eq => {
-                    false
-                }
+    #[test]
+    fn else_conditionals() {
+        // a makefile that's meant to stop make is read in a copy of this
+        // test so it can
+        if let Ok(makefile) = std::env::var("IMAKE_TEST_MAKEFILE") {
+            process_lines(&mut State::default(), &mut HashMap::new(), &makefile);
+            return;
+        }
 
-                Some('=') if !hit_eq => {
-                    hit_eq = true;
-                    lhs = buf;
-                    buf = String::new();
+        let dir = std::env::temp_dir().join(format!("imake-else-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let makefile = dir.join("Makefile");
+        let read = |branch: &str| {
+            std::fs::write(&makefile, format!("X = 1\nifeq (a,b)\n{}\nPICKED = 1\nendif\n", branch)).unwrap();
+            let mut state = State::default();
+            let mut vars = HashMap::new();
+            process_lines(&mut state, &mut vars, makefile.to_str().unwrap());
+            vars.contains_key("PICKED")
+        };
+        assert!(read("else ifeq ($(X),1)") && !read("else ifeq (a, b)"));
+        assert!(read("else ifneq 'a' \"b\"") && !read("else ifneq (1,$(X))"));
+        assert!(read("else ifdef X") && !read("else ifdef Y") && !read("else ifdef"));
+        assert!(read("else ifndef Y") && !read("else ifndef X") && read("else ifndef"));
+
+        for branch in ["else ifeq", "else ifeq (a)", "else ifeq ()", "else ifneq", "else ifneq x"] {
+            std::fs::write(&makefile, format!("ifeq (a,b)\n{}\nendif\n", branch)).unwrap();
+            let child = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::else_conditionals", "--nocapture"])
+                .env("IMAKE_TEST_MAKEFILE", &makefile)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&child.stderr);
+            assert_eq!(child.status.code(), Some(2), "{}", branch);
+            assert!(stderr.contains(":2: *** invalid syntax in conditional.  Stop."), "{}: {}", branch, stderr);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-                    match lhs.pop() {
-                        Some(':') => {
-                            let x = lhs.pop().expect("better errror message");
-                            if x == ':' {
-                                op.push(':');
-                            } else {
-                                lhs.push(x)
-                            }
-                            op.push(':');
-                            op.push('=');
-                            true
-                        }
+    #[cfg(feature = "extensions")]
+    #[test]
+    fn goal_aliases() {
+        if let Ok(makefile) = std::env::var("IMAKE_TEST_ALIASES") {
+            let mut state = State::default();
+            let mut vars = HashMap::new();
+            process_lines(&mut state, &mut vars, &makefile);
+            process_specials(&mut state, &mut vars);
+            return;
+        }
 
-                        Some(a) if matches!(a, '?' | '+' | '!') => {
-                            op.push(a);
-                            op.push('=');
-                            true
-                        }
+        let dir = std::env::temp_dir().join(format!("imake-alias-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let makefile = dir.join("Makefile");
+        std::fs::write(&makefile, "check: ; @echo real\n.ALIAS: check tests = unit lint\nunit lint:\n").unwrap();
 
-                        Some(a) => {
-                            lhs.push(a);
-                            op.push('=');
-                            true
-                        }
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        process_lines(&mut state, &mut vars, makefile.to_str().unwrap());
+        process_specials(&mut state, &mut vars);
+        assert_eq!(state.aliases["check"], ["unit", "lint"]);
+        assert_eq!(state.aliases["tests"], ["unit", "lint"]);
+        assert!(features().split(' ').any(|f| f == "imake-alias"));
+
+        let child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::goal_aliases", "--nocapture"])
+            .env("IMAKE_TEST_ALIASES", &makefile)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&child.stderr);
+        assert!(stderr.contains(":2: warning: 'check' is both a target and an alias"), "{}", stderr);
+        assert!(!stderr.contains("'tests'"), "{}", stderr);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-                        None => todo!("better error message for empty var name")
-                    }
-                }
+    #[test]
+    fn daemon_requests() {
+        use std::os::unix::net::UnixStream;
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(daemon::request(&["-k".into(), "all".into()]).as_bytes()).unwrap();
+        let (env, args) = daemon::read_request(&server).unwrap();
+        assert_eq!(env, daemon::env_hash(std::env::vars_os()));
+        assert_eq!(args, ["-k", "all"]);
+
+        // another shell's bookkeeping doesn't matter, anything else does
+        let hash = |env: &[(&str, &str)]| daemon::env_hash(env.iter().map(|(k, v)| (k.into(), v.into())));
+        let base = hash(&[("PATH", "/bin"), ("CC", "cc"), ("SHLVL", "1"), ("_", "/bin/imake")]);
+        assert_eq!(base, hash(&[("SHLVL", "3"), ("CC", "cc"), ("PATH", "/bin"), ("OLDPWD", "/tmp")]));
+        assert_ne!(base, hash(&[("PATH", "/bin"), ("CC", "clang")]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"imake-daemon 1\nall\n\n").unwrap();
+        assert!(daemon::read_request(&server).is_none());
+    }
 
-                Some(a) => {
-                    buf.push(a);
-                    true
-                }
-                None => false
-            } {}
-            (hit_eq, lhs, op, buf)
+    #[test]
+    fn shebang_scripts() {
+        let dir = std::env::temp_dir().join(format!("imake-shebang-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, first: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("{}\nall:\n", first)).unwrap();
+            path.to_string_lossy().into_owned()
         };
+        let direct = script("direct", "#!/usr/bin/imake -s -f");
+        let env = script("env", "#!/usr/bin/env imake");
+        let cmake = script("cmake", "#!/usr/bin/env cmake -P");
+        let args = |argv: &[&str]| shebang_args(argv.iter().map(|a| a.to_string()).collect());
+
+        assert_eq!(args(&["/usr/bin/imake", "-s -f", &direct]), ["/usr/bin/imake", "-s", "-f", &direct]);
+        assert_eq!(args(&["imake", &env]), ["imake", "-f", &env]);
+
+        // goals that happen to be scripts, even ones for us, are left alone
+        assert_eq!(args(&["imake", &cmake]), ["imake", &cmake]);
+        assert_eq!(args(&["imake", &direct]), ["imake", &direct]);
+        assert_eq!(args(&["/usr/bin/imake", "-k", &env]), ["/usr/bin/imake", "-k", &env]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        if is_var {
-            // let (lhs, rhs, var_op) = {
-            //     if let Some((lhs, rhs)) = src.split_once("::=") {
-            //         (lhs, rhs, VarOp::Store(true))
-            //     } else if let Some((lhs, rhs)) = src.split_once(":=") {
-            //         (lhs, rhs, VarOp::Store(true))
-            //     } else if let Some((lhs, rhs)) = src.split_once("+=") {
-            //         (lhs, rhs, VarOp::Append)
-            //     } else if let Some((lhs, rhs)) = src.split_once("!=") {
-            //         (lhs, rhs, VarOp::Shell)
-            //     } else if let Some((lhs, rhs)) = src.split_once("?=") {
-            //         (lhs, rhs, VarOp::StoreIfUndef)
-            //     } else {
-            //         let (lhs, rhs) = src.split_once('=').expect("aaaaa panic");
-            //         (lhs, rhs, VarOp::Store(false))
-            //     }
-            // };
-            //
-            let lhs = var_lhs;
-            let rhs = var_rhs;
+    #[test]
+    fn daemon_staleness() {
+        let dir = std::env::temp_dir().join(format!("imake-daemon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.src"), "").unwrap();
+        let makefile = dir.join("Makefile");
+        let parse = |text: String| {
+            std::fs::write(&makefile, text).unwrap();
+            let mut state = State::default();
+            parse_makefiles(&mut state, &mut HashMap::new(), makefile.to_str().unwrap());
+            let stamps = state.makefiles.iter().map(|f| cache::file_stamp(f)).collect::<Vec<_>>();
+            (state, stamps)
+        };
 
-            let var_op = match var_op.as_str() {
-                "::=" | ":=" => VarOp::Store(true),
-                "=" => VarOp::Store(false),
-                "+=" => VarOp::Append,
-                "!=" => VarOp::Shell,
-                "?=" => VarOp::StoreIfUndef,
-                _ => panic!()
-            };
+        let (state, stamps) = parse(format!("SRCS := $(wildcard {}/*.src)\n", dir.display()));
+        assert!(!daemon::stale(&state, &stamps));
+        std::fs::write(dir.join("b.src"), "").unwrap();
+        assert!(daemon::stale(&state, &stamps));
+
+        // like `$(shell)`, there's no telling what it would read next time
+        let (state, stamps) = parse(format!("A := $(file <{}/a.src)\n", dir.display()));
+        assert!(daemon::stale(&state, &stamps));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unterminated_last_line() {
+        let dir = std::env::temp_dir().join(format!("imake-last-line-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let makefile = dir.join("Makefile");
+        // a quoted backslash with no newline after it
+        std::fs::write(&makefile, "all:\n\techo 'abc\\").unwrap();
+        let mut state = State::default();
+        process_lines(&mut state, &mut HashMap::new(), makefile.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(&state.rules[1].data, RuleData::Recipie(r) if r == "\techo 'abc\\"));
+    }
 
-            let lhs = expand_simple_ng(state, vars, location, &lhs);
-            // we're better than GNU make here and allow `X Y=1`
-            match var_op {
-                VarOp::Store(expand) => {
-                    let lhs = lhs.trim().to_string();
-                    let rhs = if expand {
-                        expand_simple_ng(state, vars, location, &rhs)
-                    } else {
-                        rhs.to_string()
-                    };
-                    let var = vars.get_mut(lhs.trim());
+    #[test]
+    fn stray_closing_delimiters() {
+        let state = State::default();
+        let loc = Location::default();
+        let mut vars = HashMap::new();
+        // nothing open for them to close, so they're just text
+        assert_eq!(get_all_args(&state, &loc, "subst", "a),b}"), ["a)", "b}"]);
+        assert_eq!(expand_simple_ng(&state, &mut vars, &loc, "$(subst a,b,a))"), "b)");
+    }
 
-                    if let Some(targets) = targets {
-                        let targets = expand_simple_ng(state, vars, location, targets)
-                            .split_whitespace()
-                            .map(|x| x.to_string())
-                            .collect();
-                        state.rules.push(Rule {
-                            location: location.clone(),
-                            targets,
-                            data: RuleData::Var(lhs, var_op, rhs),
-                        });
-                    } else {
-                        if let Some(var) = var {
-                            var.store(rhs.trim().to_string());
-                        } else {
-                            vars.insert(
-                                lhs.clone(),
-                                Var::new(
-                                    if expand {
-                                        Flavor::Simple
-                                    } else {
-                                        Flavor::Recursive
-                                    },
-                                    Origin::File,
-                                    Some(location.clone()),
-                                    lhs,
-                                    rhs.trim().to_string(),
-                                    export,
-                                ),
-                            );
-                        }
-                    }
-                }
+    #[test]
+    fn parse_cache_messages() {
+        let dir = std::env::temp_dir().join(format!("imake-cache-messages-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let makefile = dir.join("Makefile");
+        std::fs::write(&makefile, "$(info hello)\n$(warning careful)\nall:\n").unwrap();
+        let cache = dir.join("cache").to_string_lossy().into_owned();
 
-                VarOp::StoreIfUndef => {
-                    let lhs = lhs.trim().to_string();
-                    let rhs = rhs.to_string();
-                    let var = vars.get_mut(lhs.trim());
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        process_lines(&mut state, &mut vars, makefile.to_str().unwrap());
+        cache::save_as(&cache, &state, &vars, 1);
+
+        // a hit has them to print again, in the order they came
+        let mut cached = State::default();
+        assert!(cache::load_from(&cache, &mut cached, &mut HashMap::new(), 1));
+        let warning = (true, format!("{}:2: careful", makefile.display()));
+        assert_eq!(*cached.parse_messages.borrow(), [(false, "hello".to_string()), warning]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-                    if let Some(targets) = targets {
-                        let targets = expand_simple_ng(state, vars, location, targets)
-                            .split_whitespace()
-                            .map(|x| x.to_string())
-                            .collect();
-                        state.rules.push(Rule {
-                            location: location.clone(),
-                            targets,
-                            data: RuleData::Var(lhs, var_op, rhs),
-                        });
-                    } else {
-                        if var.is_none() {
-                            vars.insert(
-                                lhs.clone(),
-                                Var::new(
-                                    Flavor::Recursive,
-                                    Origin::File,
-                                    Some(location.clone()),
-                                    lhs,
-                                    rhs.trim().to_string(),
-                                    export,
-                                ),
-                            );
-                        }
-                    }
-                }
+    #[test]
+    fn passed_environment() {
+        let env = |name: &str, value: &str| Var::new(Flavor::Simple, Origin::Env, None, name.into(), value.into(), true);
+        let state = State {
+            no_env_vars: true,
+            passed_env: vec![env("IMAKE_KEPT", "1"), env("IMAKE_SHADOWED", "2"), env("IMAKE_CHANGED", "3")],
+            ..Default::default()
+        };
+        let mut vars = HashMap::new();
+        for (name, value, exported) in [("IMAKE_SHADOWED", "x", false), ("IMAKE_CHANGED", "y", true)] {
+            vars.insert(name.to_string(), Var::new(Flavor::Simple, Origin::File, None, name.into(), value.into(), exported));
+        }
 
-                VarOp::Append => {
-                    let lhs = lhs.trim().to_string();
-                    let flavor = vars.get(lhs.trim()).map(|x| x.flavor);
-                    let rhs = if matches!(flavor, Some(Flavor::Recursive)) {
-                        expand_simple_ng(state, vars, location, &rhs)
-                    } else {
-                        rhs.to_string()
-                    };
-                    let var = vars.get_mut(lhs.trim());
+        let mut env = child_env(&state, &vars)
+            .iter()
+            .map(|v| format!("{}={}", v.name, v.value))
+            .collect::<Vec<_>>();
+        env.sort();
+        assert_eq!(env, ["IMAKE_CHANGED=y", "IMAKE_KEPT=1"]);
+    }
 
-                    if let Some(targets) = targets {
-                        let targets = expand_simple_ng(state, vars, location, targets)
-                            .split_whitespace()
-                            .map(|x| x.to_string())
-                            .collect();
-                        state.rules.push(Rule {
-                            location: location.clone(),
-                            targets,
-                            data: RuleData::Var(lhs, var_op, rhs),
-                        });
-                    } else {
-                        if let Some(var) = var {
-                            var.append(rhs.trim());
-                        } else {
-                            vars.insert(
-                                lhs.clone(),
-                                Var::new(
-                                    Flavor::Recursive,
-                                    Origin::File,
-                                    Some(location.clone()),
-                                    lhs,
-                                    rhs.trim().to_string(),
-                                    export,
-                                ),
-                            );
-                        }
-                    }
-                }
+    #[test]
+    fn rebuild_reasons() {
+        let reasons = [
+            plan::Reason::Missing,
+            plan::Reason::Newer(vec!["a.c".into()]),
+            plan::Reason::Newer(vec!["a.c".into(), "b.h".into()]),
+            plan::Reason::Always,
+        ];
+        assert_eq!(
+            reasons.map(|r| r.to_string()),
+            ["target missing", "prerequisite a.c newer", "prerequisites a.c b.h newer", "forced by -B"]
+        );
+    }
 
-                _ => todo!(),
-            }
-        } else if let Some(targets) = targets {
-            state.in_rule = true;
-            // multiple recipies can be handled by shell `;`. this allows for `@cmd; cmd; cmd`
-            // to be handled properly
-            let (prereqs, recipie) = {
-                if let Some((prereqs, recpie)) = src.split_once(';') {
-                    (prereqs, Some(recpie))
-                } else {
-                    (src, None)
-                }
-            };
-            let prereqs = expand_simple_ng(state, vars, location, prereqs);
-            // let prereqs = prereqs.trim().split_whitespace().map(|x| { x.to_string(); x.push(' '); x }).collect();
-            let targets = expand_simple_ng(state, vars, location, targets)
-                .split_whitespace()
-                .map(|x| x.to_string())
-                .collect::<Vec<_>>();
-            state.rules.push(Rule {
-                location: location.clone(),
-                targets: targets.clone(),
-                data: RuleData::Prereq(double_colon, prereqs),
-            });
-            if let Some(r) = recipie {
-                state.rules.push(Rule {
-                    location: location.clone(),
-                    targets: targets.clone(),
-                    data: RuleData::Recipie(r.into()),
-                })
-            }
-        } else if export {
-            let mut export_all = true;
-            for var in expand_simple_ng(state, vars, location, src).split_whitespace() {
-                export_all = false;
-                if let Some(var) = vars.get_mut(var) {
-                    var.export();
-                }
-            }
-            if export_all {
-                for var in vars.values_mut() {
-                    // Don't implicitly export if explicitly unexported
-                    if !var.unexported {
-                        var.export();
-                    }
-                }
-            }
-        } else {
-            expand_simple_ng(state, vars, location, src);
+    #[test]
+    fn overlay_rollback() {
+        let var = |name: &str, value: &str| Var::new(Flavor::Simple, Origin::File, None, name.into(), value.into(), false);
+        let mut globals = HashMap::new();
+        globals.insert("X".to_string(), var("X", "global"));
+
+        let mut scope = overlay::Overlay::default();
+        scope.set("X".into(), var("X", "target"));
+        scope.set("Y".into(), var("Y", "new"));
+        assert_eq!(scope.get(&globals, "X").unwrap().value, "target");
+
+        let loc = Location::default();
+        let seen = scope.with(&mut globals, |vars| {
+            let seen = expand_simple_ng(&State::default(), vars, &loc, "$(X) $(Y)");
+            vars.get_mut("X").unwrap().store("changed".into());
+            seen
+        });
+        assert_eq!(seen, "target new");
+        assert_eq!(globals["X"].value, "global");
+        assert!(!globals.contains_key("Y"));
+    }
+
+    #[test]
+    fn keyword_targets() {
+        assert_eq!(directive("  include a.mk", "include"), Some(" a.mk"));
+        assert_eq!(directive("else", "else"), Some(""));
+        for line in ["include: x", "include : x", "ifeq = 1", "export := x", "elsewhere: x", "endiff:"] {
+            assert!(DIRECTIVES.iter().all(|d| directive(line, d).is_none()), "{}", line);
         }
+
+        let dir = std::env::temp_dir().join(format!("imake-keywords-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let makefile = dir.join("Makefile");
+        std::fs::write(
+            &makefile,
+            "include: ; @true\nexport = 1\nelsewhere: export\n\\ifeq (a,b): y\nifeq (a,b)\nNO = 1\nendif\n",
+        )
+        .unwrap();
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        process_lines(&mut state, &mut vars, makefile.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let targets = state.rules.iter().flat_map(|r| r.targets.clone()).collect::<Vec<_>>();
+        assert_eq!(targets, ["include", "include", "elsewhere", "ifeq", "(a,b)"]);
+        assert!(matches!(&state.rules[2].data, RuleData::Prereq(_, p) if p.trim() == "export"));
+        assert_eq!(vars["export"].value, "1");
+        assert!(!vars.contains_key("NO"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn vpath_search() {
+        let dir = std::env::temp_dir().join(format!("imake-vpath-{}", std::process::id()));
+        let (inc, src) = (dir.join("inc"), dir.join("src"));
+        std::fs::create_dir_all(&inc).unwrap();
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(inc.join("imake-x.h"), "").unwrap();
+        std::fs::write(src.join("imake-x.h"), "").unwrap();
+        std::fs::write(src.join("imake-main.c"), "").unwrap();
+
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        assert!(directive("vpath %.h inc", "vpath").is_some() && directive("vpath", "vpath").is_some());
+        assert!(directive("vpath := x", "vpath").is_none() && directive("vpathx", "vpath").is_none());
+        state.vpath.directive(&format!("%.h {}", inc.display()));
+        state.vpath.set_general(&format!("/nowhere:{}", src.display()));
+        super::parse_line(&mut state, &mut vars, &loc, "imake-main.out: imake-main.c imake-x.h imake-gen.c");
+        super::parse_line(&mut state, &mut vars, &loc, "imake-gen.c:");
+        std::fs::write(src.join("imake-gen.c"), "").unwrap();
+
+        let node = prepare_target(&mut state, &vars, "imake-main.out");
+        let found = [src.join("imake-main.c"), inc.join("imake-x.h")].map(|p| p.display().to_string());
+        // something with a rule of its own is made here
+        assert_eq!(node.prerequisites, [&found[0], &found[1], "imake-gen.c"]);
+
+        state.vpath.directive("%.h");
+        assert_eq!(state.vpath.search("imake-x.h"), Some(src.join("imake-x.h").display().to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
-    fn expand_ng() {
-        // let mut state = State::default();
-        // vars.insert(
-        //     "X".into(),
-        //     Var::new(Flavor::Simple, Origin::File, "X".into(), "1".into(), false),
-        // );
-        // vars.insert(
-        //     "Y".into(),
-        //     Var::new(Flavor::Simple, Origin::File, "Y".into(), "$X".into(), false),
-        // );
-        // vars.insert(
-        //     "Z".into(),
-        //     Var::new(
-        //         Flavor::Recursive,
-        //         Origin::File,
-        //         "Y".into(),
-        //         "$X".into(),
-        //         false,
-        //     ),
-        // );
+    fn path_keys() {
+        let names = ["./foo", ".//a/./b", "/tmp//x/", "../up", "/", "./", "plain"];
+        assert_eq!(names.map(normalize_path), ["foo", "a/b", "/tmp/x/", "../up", "/", ".", "plain"]);
+        let names = ["obj/../foo.o", "a/b/../../../c", "/../x", "../../y", "a/.."];
+        assert_eq!(names.map(normalize_path), ["foo.o", "../c", "/x", "../../y", "."]);
 
-        // let tests = [
-        //     ("$X", "1"),
-        //     ("${X}", "1"),
-        //     ("$(X)", "1"),
-        //     ("$Y", "$X"),
-        //     ("$Y${Z}$(X)", "$X11"),
-        //     ("$Z", "1"),
-        //     ("$$", "$"),
-        // ];
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        super::parse_line(&mut state, &mut vars, &loc, "./out /tmp/stamp: ./src/a.c b.c");
+        let node = prepare_target(&mut state, &vars, "out");
+        assert_eq!(node.prerequisites, ["src/a.c", "b.c"]);
+        let mut t = node.vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$(@D) $(@F) | $(^D) | $(<F)"), ". out | src . | a.c");
+        let mut t = prepare_target(&mut state, &vars, "/tmp/stamp").vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$(@D) $(@F)"), "/tmp stamp");
+    }
 
-        // for (src, out) in tests {
-        //     eprintln!("testing expansion of `{}` to `{}`", src, out);
-        //     assert_eq!(
-        //         super::expand_simple_ng(&state,vars, l&Location::default(), None, &src),
-        //         out
-        //     );
-        // }
+    #[test]
+    fn needed_by_chain() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: a", "a: b", "b: imake-not-there"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        let mut sched = Schedule::default();
+        let node = prepare_target(&mut state, &vars, "all");
+        state.processed.insert("all".into(), Progress::InFlight);
+        sched.nodes.insert("all".into(), node);
+
+        // stops at the missing file with everything above it still waiting
+        assert!(advance(&mut state, &mut sched, "all", &mut Vec::new()).is_none());
+        assert!(sched.stopping);
+        assert!(sched.nodes["all"].needed_by.is_empty());
+        assert_eq!(sched.nodes["a"].needed_by, ["all"]);
+        assert_eq!(sched.nodes["b"].needed_by, ["a", "all"]);
     }
 
     #[test]
-    fn parse_line_test() {
+    fn goal_turns() {
+        let active = ["x", "y", "z"].map(String::from);
+        assert_eq!(take_turns(&active, 0), ["x", "y", "z"]);
+        assert_eq!(take_turns(&active, 1), ["y", "z", "x"]);
+        assert_eq!(take_turns(&active, 5), ["z", "x", "y"]);
+        assert!(take_turns(&[], 3).is_empty());
+
+        // goals sharing a prerequisite only make it once
+        let mut state = State {
+            dryrun: true,
+            jobs: 2,
+            ..Default::default()
+        };
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["x: a s", "y: s b", "a b s x y: ; @:"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        let goals = ["x", "y"].map(String::from);
+        let mut finished = Vec::new();
+        process_goals(&mut state, &vars, &goals, |_, goal, progress| {
+            assert!(matches!(progress, Progress::Done(Some(_))));
+            finished.push(goal.to_string());
+            false
+        });
+        finished.sort();
+        assert_eq!(finished, goals);
+        let mut remade = state.remade.clone();
+        remade.sort();
+        assert_eq!(remade, ["a", "b", "s", "x", "y"]);
+    }
+
+    #[test]
+    fn default_goal() {
+        let loc = Location::default();
+        let goal = |lines: &[&str]| {
+            let mut state = State::default();
+            let mut vars = HashMap::new();
+            for line in lines {
+                super::parse_line(&mut state, &mut vars, &loc, line);
+            }
+            select_targets(&mut state, &mut vars)
+        };
+        assert_eq!(goal(&[".PHONY: a", "%.o: %.c", "t: X = 1", "a:", "b:"]), ["a"]);
+        assert_eq!(goal(&["a:", ".DEFAULT_GOAL := b", "b:"]), ["b"]);
+        assert_eq!(goal(&["a:", ".DEFAULT_GOAL :=", "b:"]), ["b"]);
+        assert_eq!(goal(&["a:", ".DEFAULT: c"]), ["c"]);
+        assert_eq!(goal(&[".DEFAULT_GOAL = b", "a:", ".DEFAULT: c"]), ["b"]);
+        assert!(goal(&["X = 1"]).is_empty());
+    }
+
+    #[test]
+    fn special_variables() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+        vars.insert(
+            ".VARIABLES".into(),
+            Var::new(Flavor::Simple, Origin::Default, None, ".VARIABLES".into(), String::new(), false),
+        );
+        for line in ["B = 1", "A := $(B)", "t: T = 1"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        assert_eq!(expand_simple_ng(&state, &mut vars, &loc, "$(.VARIABLES)"), ".VARIABLES A B");
+        super::parse_line(&mut state, &mut vars, &loc, "C = $(.VARIABLES)");
+        assert_eq!(expand_simple_ng(&state, &mut vars, &loc, "$(C)"), ".VARIABLES A B C");
+        assert!(features().split(' ').any(|f| f == "order-only"));
+        assert!(features().split(' ').any(|f| f == "output-sync"));
+    }
+
+    #[test]
+    fn graph_queries() {
         let mut state = State::default();
         let mut vars = HashMap::new();
+        let loc = Location::default();
+        for line in ["all: imake-x imake-y | imake-dir", "imake-y: imake-x", ".PHONY: all"] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+        state.phony.push("all".into());
 
-        super::parse_line(&mut state, &Location::default(), "test=1");
-        super::parse_line(&mut state, &Location::default(), "test+=1");
-        super::parse_line(&mut state, &Location::default(), "x: test+=1");
-        super::parse_line(&mut state, &Location::default(), "x: a b");
-        eprintln!(
-            "{} = {}",
-            super::expand_simple_ng(&state, &mut vars, &Location::default(), "$(test)"),
-            "1"
+        let mut ask = |request: &str| graph::answer(&mut state, &vars, request).0;
+        assert_eq!(ask(r#"{"op":"targets"}"#), r#"{"ok":true,"targets":["all","imake-y"]}"#);
+        assert_eq!(
+            ask(r#"{ "op" : "deps", "target": "./all" }"#),
+            r#"{"ok":true,"target":"all","deps":["imake-x","imake-y"],"order_only":["imake-dir"]}"#
+        );
+        assert_eq!(ask(r#"{"op":"rdeps","target":"imake-x"}"#), r#"{"ok":true,"target":"imake-x","rdeps":["all","imake-y"]}"#);
+        assert_eq!(
+            ask(r#"{"op":"outdated","target":"imake-y"}"#),
+            r#"{"ok":true,"target":"imake-y","outdated":true,"reason":"target missing"}"#
+        );
+        assert_eq!(ask(r#"{"op":"deps"}"#), r#"{"ok":false,"error":"no target given"}"#);
+        assert_eq!(ask("deps all"), r#"{"ok":false,"error":"bad request"}"#);
+        assert!(graph::answer(&mut state, &vars, r#"{"op":"stop"}"#).1);
+    }
+
+    #[test]
+    fn temp_files() {
+        let (a, _) = temp::create("test").unwrap();
+        let (b, _) = temp::create("test").unwrap();
+        assert_ne!(a, b);
+        assert!(a.starts_with(temp::dir()) && a.exists() && b.exists());
+        temp::remove_all();
+        assert!(!a.exists() && !b.exists());
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_str("plain"), "\"plain\"");
+        assert_eq!(json_str("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_str("l1\nl2\t\u{1}"), "\"l1\\nl2\\t\\u0001\"");
+    }
+
+    #[test]
+    fn plan_json() {
+        assert_eq!(plan::to_json(&[]), "[\n]");
+        let steps = [
+            plan::Step {
+                target: "a.o".into(),
+                reason: plan::Reason::Newer(vec!["a.h".into()]),
+                prerequisites: vec!["a.c".into(), "a.h".into()],
+                recipe: vec!["cc -c \"a.c\"".into()],
+            },
+            plan::Step {
+                target: "all".into(),
+                reason: plan::Reason::Phony,
+                prerequisites: vec!["a.o".into()],
+                recipe: Vec::new(),
+            },
+        ];
+        assert_eq!(
+            plan::to_json(&steps),
+            "[\n{\"target\":\"a.o\",\"reason\":\"newer\",\"newer\":[\"a.h\"],\"prerequisites\":[\"a.c\",\"a.h\"],\
+             \"recipe\":[\"cc -c \\\"a.c\\\"\"]},\n\
+             {\"target\":\"all\",\"reason\":\"phony\",\"newer\":[],\"prerequisites\":[\"a.o\"],\"recipe\":[]}\n]"
+        );
+    }
+
+    #[test]
+    fn makeflags_round_trip() {
+        let overrides = ["DIR=/tmp/with space", "X=a\\b", "Y=1"];
+        let flags = format!(
+            "ks -- {}",
+            overrides.iter().map(|o| makeflags_quote(o)).collect::<Vec<_>>().join(" ")
         );
 
-        eprintln!("{:#?}", state);
-        assert!(false)
+        let mut expected = vec!["-ks".to_string()];
+        expected.extend(overrides.iter().map(|o| o.to_string()));
+        assert_eq!(makeflags_args(&flags), expected);
+
+        assert_eq!(makeflags_args(" --no-print-directory"), ["--no-print-directory"]);
+        assert_eq!(makeflags_args(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn makeflags_print_directory() {
+        let overrides = ["X=1".to_string()];
+        assert_eq!(makeflags_value("", true, vec![], &overrides), "w -- X=1");
+        assert_eq!(makeflags_value("kw", true, vec![], &[]), "kw");
+        assert_eq!(makeflags_value("s", false, vec![], &overrides), "s -- X=1");
+        let long_flags = vec!["--no-print-directory".to_string()];
+        assert_eq!(makeflags_value("", false, long_flags, &overrides), "--no-print-directory -- X=1");
+    }
+
+    #[test]
+    fn automatic_var_binding() {
+        let mut state = State::default();
+        let mut vars = HashMap::new();
+        let loc = Location::default();
+
+        for line in [
+            "X = global",
+            "t: X = at-$@",
+            "t: Y := simple-$@",
+            "t: Z += $(X)",
+            "x.o y.o: %.o: %.c",
+        ] {
+            super::parse_line(&mut state, &mut vars, &loc, line);
+        }
+
+        // recursive target vars see the target, `:=` ones were expanded
+        // while reading the makefile when `$@` was still empty
+        let mut t = prepare_target(&mut state, &vars, "t").vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$(X) $(Y)"), "at-t simple-");
+        assert_eq!(expand_simple_ng(&state, &mut t, &loc, "$(Z)"), "at-t");
+        // and are inherited by its prerequisites
+        let mut p = prepare_target(&mut state, &t, "p").vars.view(&t);
+        assert_eq!(expand_simple_ng(&state, &mut p, &loc, "$(X)"), "at-p");
+        let mut other = prepare_target(&mut state, &vars, "other").vars.view(&vars);
+        assert_eq!(expand_simple_ng(&state, &mut other, &loc, "$(X)"), "global");
+
+        assert_eq!(prepare_target(&mut state, &vars, "x.o").prerequisites, ["x.c"]);
+        assert_eq!(prepare_target(&mut state, &vars, "y.o").prerequisites, ["y.c"]);
+        assert_eq!(pattern_stem("lib%.a", "libfoo.a"), Some("foo"));
+        assert_eq!(pattern_stem("%.o", "x.c"), None);
     }
 
     // #[test]
@@ -0,0 +1,359 @@
+//! The command line, and the flags a parent make passes down in
+//! `MAKEFLAGS`, read the way GNU make reads them.
+//!
+//! Every option is in [`OPTIONS`], by letter if it has one and by each of
+//! its long names. Letters can be run together (`-ks`), and one that needs
+//! an argument takes the rest of the cluster or else the next word (`-Cdir`,
+//! `-kC dir`). Long options take theirs after `=` or as the next word and
+//! can be shortened to anything that isn't ambiguous, like `--keep`. An
+//! optional argument has to be attached (`-Oline`, `--debug=b`), except for
+//! `-j` and `-l`, which also take a number in the next word. Nothing after
+//! `--` is an option.
+
+use Takes::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opt {
+    Ignored,
+    AlwaysMake,
+    Directory,
+    DebugAll,
+    Debug,
+    EnvironmentOverrides,
+    Eval,
+    File,
+    Help,
+    IncludeDir,
+    IgnoreErrors,
+    Jobs,
+    KeepGoing,
+    LoadAverage,
+    CheckSymlinkTimes,
+    DryRun,
+    OldFile,
+    OutputSync,
+    PrintDataBase,
+    Question,
+    NoBuiltinRules,
+    NoBuiltinVariables,
+    Silent,
+    NoSilent,
+    Stop,
+    Touch,
+    Trace,
+    Version,
+    PrintDirectory,
+    NoPrintDirectory,
+    NewFile,
+    WarnUndefinedVariables,
+    JobserverAuth,
+    JobserverStyle,
+    TimeReport,
+    ParseCache,
+    Audit,
+    Only,
+    Missing,
+    CleanOutputs,
+    InferPhony,
+    Doctor,
+    Coverage,
+    Shuffle,
+    Strict,
+    PlanJson,
+    Daemon,
+    ServeGraph,
+    DaemonStop,
+    SkipOversizedExports,
+    MaxExpansionDepth,
+    MaxRecursion,
+    Halt,
+    SerializeDir,
+    NoEnvVars,
+    Toolchain,
+}
+
+/// What an option takes, and what it's called in `--help`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Takes {
+    Nothing,
+    Required(&'static str),
+    Optional(&'static str),
+    /// Optional, but a number in the next word counts as well
+    Number(&'static str),
+}
+
+struct Spec {
+    opt: Opt,
+    short: Option<char>,
+    long: &'static [&'static str],
+    takes: Takes,
+    help: &'static str,
+}
+
+const fn spec(opt: Opt, short: Option<char>, long: &'static [&'static str], takes: Takes, help: &'static str) -> Spec {
+    Spec { opt, short, long, takes, help }
+}
+
+const OPTIONS: &[Spec] = &[
+    spec(Opt::Ignored, Some('b'), &[], Nothing, "Ignored for compatibility."),
+    spec(Opt::Ignored, Some('m'), &[], Nothing, "Ignored for compatibility."),
+    spec(Opt::AlwaysMake, Some('B'), &["always-make"], Nothing, "Unconditionally make all targets."),
+    spec(
+        Opt::Directory,
+        Some('C'),
+        &["directory"],
+        Required("DIRECTORY"),
+        "Change to DIRECTORY before doing anything.",
+    ),
+    spec(Opt::DebugAll, Some('d'), &[], Nothing, "Print lots of debugging information."),
+    spec(Opt::Debug, None, &["debug"], Optional("FLAGS"), "Print various types of debugging information."),
+    spec(
+        Opt::EnvironmentOverrides,
+        Some('e'),
+        &["environment-overrides", "environment-override"],
+        Nothing,
+        "Environment variables override makefiles.",
+    ),
+    spec(Opt::Eval, Some('E'), &["eval"], Required("STRING"), "Evaluate STRING as a makefile statement."),
+    spec(Opt::File, Some('f'), &["file", "makefile"], Required("FILE"), "Read FILE as a makefile."),
+    spec(Opt::Help, Some('h'), &["help"], Nothing, "Print this message and exit."),
+    spec(Opt::IgnoreErrors, Some('i'), &["ignore-errors"], Nothing, "Ignore errors from recipes."),
+    spec(
+        Opt::IncludeDir,
+        Some('I'),
+        &["include-dir"],
+        Required("DIRECTORY"),
+        "Search DIRECTORY for included makefiles.",
+    ),
+    spec(Opt::Jobs, Some('j'), &["jobs"], Number("N"), "Allow N jobs at once; infinite jobs with no arg."),
+    spec(Opt::KeepGoing, Some('k'), &["keep-going"], Nothing, "Keep going when some targets can't be made."),
+    spec(
+        Opt::LoadAverage,
+        Some('l'),
+        &["load-average", "max-load"],
+        Number("N"),
+        "Don't start multiple jobs unless load is below N.",
+    ),
+    spec(
+        Opt::CheckSymlinkTimes,
+        Some('L'),
+        &["check-symlink-times"],
+        Nothing,
+        "Use the latest mtime between symlinks and target.",
+    ),
+    spec(
+        Opt::DryRun,
+        Some('n'),
+        &["just-print", "dry-run", "recon"],
+        Optional("safe"),
+        "Don't actually run any recipe; just print them.",
+    ),
+    spec(
+        Opt::OldFile,
+        Some('o'),
+        &["old-file", "assume-old"],
+        Required("FILE"),
+        "Consider FILE to be very old and don't remake it.",
+    ),
+    spec(
+        Opt::OutputSync,
+        Some('O'),
+        &["output-sync"],
+        Optional("TYPE"),
+        "Synchronize output of parallel jobs by TYPE.",
+    ),
+    spec(Opt::PrintDataBase, Some('p'), &["print-data-base"], Nothing, "Print make's internal database."),
+    spec(Opt::Question, Some('q'), &["question"], Nothing, "Run no recipe; exit status says if up to date."),
+    spec(Opt::NoBuiltinRules, Some('r'), &["no-builtin-rules"], Nothing, "Disable the built-in implicit rules."),
+    spec(
+        Opt::NoBuiltinVariables,
+        Some('R'),
+        &["no-builtin-variables"],
+        Nothing,
+        "Disable the built-in variable settings.",
+    ),
+    spec(Opt::Silent, Some('s'), &["silent", "quiet"], Nothing, "Don't echo recipes."),
+    spec(Opt::NoSilent, None, &["no-silent"], Nothing, "Echo recipes (disable --silent mode)."),
+    spec(Opt::Stop, Some('S'), &["no-keep-going", "stop"], Nothing, "Turns off -k."),
+    spec(Opt::Touch, Some('t'), &["touch"], Nothing, "Touch targets instead of remaking them."),
+    spec(Opt::Trace, None, &["trace"], Nothing, "Print tracing information."),
+    spec(Opt::Version, Some('v'), &["version"], Nothing, "Print the version number of make and exit."),
+    spec(Opt::PrintDirectory, Some('w'), &["print-directory"], Nothing, "Print the current directory."),
+    spec(
+        Opt::NoPrintDirectory,
+        None,
+        &["no-print-directory"],
+        Nothing,
+        "Turn off -w, even if it was turned on implicitly.",
+    ),
+    spec(
+        Opt::NewFile,
+        Some('W'),
+        &["what-if", "new-file", "assume-new"],
+        Required("FILE"),
+        "Consider FILE to be infinitely new.",
+    ),
+    spec(
+        Opt::WarnUndefinedVariables,
+        None,
+        &["warn-undefined-variables"],
+        Nothing,
+        "Warn when an undefined variable is referenced.",
+    ),
+    spec(Opt::JobserverAuth, None, &["jobserver-auth", "jobserver-fds"], Required("R,W"), "Set by the parent make."),
+    spec(Opt::JobserverStyle, None, &["jobserver-style"], Required("STYLE"), "Make a 'pipe' or 'fifo' jobserver."),
+    spec(Opt::TimeReport, None, &["time-report"], Nothing, "Say where the time went at the end."),
+    spec(Opt::ParseCache, None, &["parse-cache"], Nothing, "Reuse the parsed makefiles while they're unchanged."),
+    spec(Opt::Audit, None, &["audit"], Required("FILE"), "Write a manifest of every recipe run to FILE."),
+    spec(Opt::Only, None, &["only"], Required("TARGET"), "Run TARGET's recipe without its prerequisites."),
+    spec(Opt::Missing, None, &["missing"], Nothing, "List the prerequisites that can't be had."),
+    spec(Opt::CleanOutputs, None, &["clean-outputs"], Nothing, "Delete every file a recipe makes."),
+    spec(Opt::InferPhony, None, &["infer-phony"], Nothing, "Treat targets whose recipe never makes them as phony."),
+    spec(Opt::Doctor, None, &["doctor"], Nothing, "Check the build environment for known problems."),
+    spec(Opt::Coverage, None, &["coverage"], Nothing, "Report what of the makefiles the build never used."),
+    spec(Opt::Shuffle, None, &["shuffle"], Optional("MODE"), "Make prerequisites in a different order."),
+    spec(Opt::Strict, None, &["strict"], Nothing, "Treat what make normally lets go as errors."),
+    spec(Opt::PlanJson, None, &["plan-json"], Nothing, "Print what would be run as JSON."),
+    spec(Opt::Daemon, None, &["daemon"], Nothing, "Keep the makefiles parsed and serve builds."),
+    spec(Opt::ServeGraph, None, &["serve-graph"], Nothing, "Answer questions about the graph."),
+    spec(Opt::DaemonStop, None, &["daemon-stop"], Nothing, "Stop the running daemon."),
+    spec(
+        Opt::SkipOversizedExports,
+        None,
+        &["skip-oversized-exports"],
+        Nothing,
+        "Don't export variables too big to pass on.",
+    ),
+    spec(Opt::MaxExpansionDepth, None, &["max-expansion-depth"], Required("N"), "Give up expanding N deep."),
+    spec(Opt::MaxRecursion, None, &["max-recursion"], Required("N"), "Refuse to run N recursive makes deep."),
+    spec(Opt::Halt, None, &["halt"], Required("HOW"), "On failure 'drain' running jobs or stop them 'now'."),
+    spec(Opt::SerializeDir, None, &["serialize-dir"], Nothing, "Run one recipe at a time per directory."),
+    spec(Opt::NoEnvVars, None, &["no-env-vars"], Nothing, "Don't import the environment as variables."),
+    spec(Opt::Toolchain, None, &["toolchain"], Required("FILE"), "Read FILE's settings ahead of the makefile."),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    /// An option, and its argument if it was given one
+    Option(Opt, Option<String>),
+    /// `NAME=value`
+    Assignment(String),
+    Goal(String),
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// The long option `name` is short for
+fn find_long(name: &str) -> Result<&'static Spec, String> {
+    if let Some(spec) = OPTIONS.iter().find(|s| s.long.contains(&name)) {
+        return Ok(spec);
+    }
+
+    let mut found: Vec<(&Spec, &str)> = Vec::new();
+    for spec in OPTIONS {
+        for long in spec.long.iter().filter(|l| !name.is_empty() && l.starts_with(name)) {
+            if !found.iter().any(|(s, _)| s.opt == spec.opt) {
+                found.push((spec, long));
+            }
+        }
+    }
+    match found[..] {
+        [(spec, _)] => Ok(spec),
+        [] => Err(format!("unrecognized option '--{}'", name)),
+        _ => {
+            let names = found.iter().map(|(_, l)| format!("'--{}'", l)).collect::<Vec<_>>();
+            Err(format!("option '--{}' is ambiguous; possibilities: {}", name, names.join(" ")))
+        }
+    }
+}
+
+/// Sort out which words are options, with their arguments, which are
+/// variable assignments and which are goals
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Vec<Arg>, String> {
+    let mut args = args.into_iter().peekable();
+    let mut parsed = Vec::new();
+    let mut options_done = false;
+
+    while let Some(arg) = args.next() {
+        if arg.is_empty() || arg == "-" {
+            continue;
+        } else if options_done || !arg.starts_with('-') {
+            // a bare word is never an option, whatever letter it is
+            parsed.push(if arg.contains('=') { Arg::Assignment(arg) } else { Arg::Goal(arg) });
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, attached) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let spec = find_long(name)?;
+            let value = match spec.takes {
+                Nothing if attached.is_some() => {
+                    return Err(format!("option '--{}' doesn't allow an argument", name));
+                }
+                Nothing | Optional(_) => attached,
+                Required(_) => match attached.or_else(|| args.next()) {
+                    Some(value) => Some(value),
+                    None => return Err(format!("option '--{}' requires an argument", name)),
+                },
+                Number(_) => attached.or_else(|| args.next_if(|a| is_number(a))),
+            };
+            parsed.push(Arg::Option(spec.opt, value));
+        } else {
+            let mut rest = &arg[1..];
+            while let Some(c) = rest.chars().next() {
+                rest = &rest[c.len_utf8()..];
+                let Some(spec) = OPTIONS.iter().find(|s| s.short == Some(c)) else {
+                    return Err(format!("invalid option -- '{}'", c));
+                };
+                let value = match spec.takes {
+                    Nothing => None,
+                    Required(_) | Optional(_) if !rest.is_empty() => Some(std::mem::take(&mut rest).to_string()),
+                    Required(_) => match args.next() {
+                        Some(value) => Some(value),
+                        None => return Err(format!("option requires an argument -- '{}'", c)),
+                    },
+                    Optional(_) => None,
+                    // like GNU's, `-j4k` is asking for `4k` jobs
+                    Number(_) if !rest.is_empty() => Some(std::mem::take(&mut rest).to_string()),
+                    Number(_) => args.next_if(|a| is_number(a)),
+                };
+                parsed.push(Arg::Option(spec.opt, value));
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// What `--help` prints
+pub fn usage(program: &str) -> String {
+    let mut usage = format!("Usage: {} [options] [target] ...\nOptions:\n", program);
+    for spec in OPTIONS {
+        let mut names = Vec::new();
+        if let Some(c) = spec.short {
+            names.push(match spec.takes {
+                Nothing => format!("-{}", c),
+                Required(value) => format!("-{} {}", c, value),
+                Optional(value) => format!("-{}[{}]", c, value),
+                Number(value) => format!("-{} [{}]", c, value),
+            });
+        }
+        for long in spec.long {
+            names.push(match spec.takes {
+                Nothing => format!("--{}", long),
+                Required(value) => format!("--{}={}", long, value),
+                Optional(value) | Number(value) => format!("--{}[={}]", long, value),
+            });
+        }
+        let names = names.join(", ");
+        if names.len() < 28 {
+            usage.push_str(&format!("  {:<28}{}\n", names, spec.help));
+        } else {
+            usage.push_str(&format!("  {}\n{:30}{}\n", names, "", spec.help));
+        }
+    }
+    usage
+}
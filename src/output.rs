@@ -0,0 +1,145 @@
+//! `-O`/`--output-sync`: keep what parallel recipes print from getting
+//! interleaved.
+//!
+//! A recipe's stdout and stderr go to scratch files instead of the
+//! terminal and are copied out in one piece, after each line with `line`
+//! and once the whole recipe is done with `target`. Recursive makes are
+//! left to sync their own output unless it's `recurse`, which holds on to
+//! everything a sub-make prints until it's finished. The echoed command
+//! goes in with its output, and when stdout and stderr are the same file
+//! they're kept together so they still come out in the order written.
+//! Like GNU make's, this only happens with more than one job, and under
+//! `-w` each piece of output is wrapped in its own directory messages
+//! rather than the whole run.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, Write},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::fs::MetadataExt,
+    },
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::{directory_messages, temp, State};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Mode {
+    #[default]
+    None,
+    Line,
+    Target,
+    Recurse,
+}
+
+impl Mode {
+    pub fn parse(kind: &str) -> Option<Mode> {
+        match kind {
+            "none" => Some(Mode::None),
+            "line" => Some(Mode::Line),
+            "target" => Some(Mode::Target),
+            "recurse" => Some(Mode::Recurse),
+            _ => None,
+        }
+    }
+
+    /// How sub-makes are told
+    pub fn flag(self) -> Option<String> {
+        let kind = match self {
+            Mode::None => return None,
+            Mode::Line => "line",
+            Mode::Target => "target",
+            Mode::Recurse => "recurse",
+        };
+        Some(format!("-O{}", kind))
+    }
+
+    /// Whether a recipe line's output is held back
+    pub fn captures(self, recursive: bool) -> bool {
+        match self {
+            Mode::None => false,
+            Mode::Recurse => true,
+            _ => !recursive,
+        }
+    }
+}
+
+/// Where a running recipe's output is kept until it can be shown. Once
+/// flushed it's reused by the next one.
+#[derive(Debug)]
+pub struct Captured {
+    out: (PathBuf, File),
+    /// `None` when stderr goes in with stdout
+    err: Option<(PathBuf, File)>,
+}
+
+/// Whether our stdout and stderr are the same file, like after `2>&1`
+fn combined() -> bool {
+    let file = |fd: BorrowedFd| fd.try_clone_to_owned().map(File::from).and_then(|f| f.metadata());
+    match (file(io::stdout().as_fd()), file(io::stderr().as_fd())) {
+        (Ok(out), Ok(err)) => out.dev() == err.dev() && out.ino() == err.ino(),
+        _ => false,
+    }
+}
+
+impl Captured {
+    pub fn new() -> io::Result<Captured> {
+        Ok(Captured {
+            out: temp::create("out")?,
+            err: if combined() { None } else { Some(temp::create("err")?) },
+        })
+    }
+
+    /// Send `cmd`'s stdout and stderr here
+    pub fn redirect(&self, cmd: &mut Command) -> io::Result<()> {
+        let err = self.err.as_ref().unwrap_or(&self.out);
+        cmd.stdout(Stdio::from(self.out.1.try_clone()?))
+            .stderr(Stdio::from(err.1.try_clone()?));
+        Ok(())
+    }
+
+    /// Add an echoed recipe line
+    pub fn echo(&mut self, line: &str) {
+        let _ = writeln!(self.out.1, "{}", line);
+    }
+
+    /// Show what's been kept so far, all at once, and start again empty
+    pub fn flush(&mut self, state: &State) {
+        let (out, err) = (take(&mut self.out), self.err.as_mut().map(take).unwrap_or_default());
+        if out.is_empty() && err.is_empty() {
+            return;
+        }
+
+        // one write each so a sub-make's output arriving through the same
+        // pipe can't end up in the middle of ours
+        let mut stdout = io::stdout().lock();
+        if state.print_directory {
+            let (entering, leaving) = directory_messages(state, state.level, &state.curdir);
+            let mut wrapped = format!("{}\n", entering).into_bytes();
+            wrapped.extend(out);
+            let _ = stdout.write_all(&wrapped);
+            let _ = stdout.flush();
+            let _ = io::stderr().write_all(&err);
+            let _ = writeln!(stdout, "{}", leaving);
+        } else {
+            let _ = stdout.write_all(&out);
+            let _ = stdout.flush();
+            let _ = io::stderr().write_all(&err);
+        }
+        let _ = stdout.flush();
+    }
+}
+
+/// Everything written to `file` so far, leaving it empty. The children
+/// share its offset so it's wound back to the start as well.
+fn take((path, file): &mut (PathBuf, File)) -> Vec<u8> {
+    let mut contents = Vec::new();
+    if let Ok(mut reader) = File::open(&*path) {
+        let _ = reader.read_to_end(&mut contents);
+    }
+    let _ = file.set_len(0);
+    let _ = file.rewind();
+    contents
+}
@@ -0,0 +1,74 @@
+//! Target-specific variables without a copy of every variable per target.
+//!
+//! A target sees the global variables with its own laid over them: the
+//! automatic ones, any `t: X = 1` and whatever it inherited from the
+//! target that needed it. Only that overlay is kept for each target. To
+//! expand something for it the overlay is written into the global table,
+//! remembering what it replaced, and undone afterwards, so a target costs
+//! as much as the variables it sets rather than all of them.
+
+use std::collections::HashMap;
+
+use crate::Var;
+
+#[derive(Debug, Clone, Default)]
+pub struct Overlay {
+    vars: HashMap<String, Var>,
+}
+
+/// What applying an overlay replaced, to put back
+#[must_use]
+pub struct Undo(Vec<(String, Option<Var>)>);
+
+impl Overlay {
+    pub fn set(&mut self, name: String, var: Var) {
+        self.vars.insert(name, var);
+    }
+
+    /// Stop overriding `name`, so the global one shows through
+    pub fn remove(&mut self, name: &str) {
+        self.vars.remove(name);
+    }
+
+    /// `name` as the target sees it
+    pub fn get<'a>(&'a self, globals: &'a HashMap<String, Var>, name: &str) -> Option<&'a Var> {
+        self.vars.get(name).or_else(|| globals.get(name))
+    }
+
+    pub fn apply(&self, globals: &mut HashMap<String, Var>) -> Undo {
+        Undo(
+            self.vars
+                .iter()
+                .map(|(name, var)| (name.clone(), globals.insert(name.clone(), var.clone())))
+                .collect(),
+        )
+    }
+
+    /// Run `f` with the variables as the target sees them. Anything `f`
+    /// does to a variable the overlay sets is forgotten afterwards.
+    pub fn with<T>(&self, globals: &mut HashMap<String, Var>, f: impl FnOnce(&mut HashMap<String, Var>) -> T) -> T {
+        let undo = self.apply(globals);
+        let out = f(globals);
+        undo.rollback(globals);
+        out
+    }
+
+    /// Every variable as the target sees it, for when a copy is wanted
+    #[cfg(test)]
+    pub fn view(&self, globals: &HashMap<String, Var>) -> HashMap<String, Var> {
+        let mut view = globals.clone();
+        view.extend(self.vars.clone());
+        view
+    }
+}
+
+impl Undo {
+    pub fn rollback(self, globals: &mut HashMap<String, Var>) {
+        for (name, old) in self.0.into_iter().rev() {
+            match old {
+                Some(old) => globals.insert(name, old),
+                None => globals.remove(&name),
+            };
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! `--infer-phony`: remember targets whose recipe ran but didn't create
+//! them, and treat them as `.PHONY` from then on.
+//!
+//! They're kept in `.imake.phony`, one to a line. Once a file of the same
+//! name turns up it's ignored, where an undeclared phony target would stop
+//! being remade. A target drops out again if a run of its recipe does
+//! create or update the file. Targets the makefiles declare `.PHONY` are never
+//! recorded. Nothing is learned under `-n` or `-t`.
+
+use std::{path::Path, time::SystemTime};
+
+use crate::State;
+
+const PHONY_FILE: &str = ".imake.phony";
+
+#[derive(Debug, Clone, Default)]
+pub struct Inferred {
+    /// Targets found to be phony, this run or before
+    pub targets: Vec<String>,
+    changed: bool,
+}
+
+/// Add what earlier runs found to `.PHONY`
+pub fn load(state: &mut State) {
+    let Some(inferred) = &mut state.infer_phony else { return };
+    let Ok(contents) = std::fs::read_to_string(PHONY_FILE) else {
+        return;
+    };
+    for t in contents.lines().map(str::trim).filter(|t| !t.is_empty()) {
+        if state.phony.iter().any(|p| p == t) {
+            // the makefile says so itself now
+            inferred.changed = true;
+        } else {
+            inferred.targets.push(t.to_string());
+            state.phony.push(t.to_string());
+        }
+    }
+}
+
+/// Note whether `target`'s recipe, which just ran, made it. `before` is
+/// its modification time from before the recipe started.
+pub fn observe(state: &mut State, target: &str, before: Option<SystemTime>) {
+    let Some(inferred) = &mut state.infer_phony else { return };
+    let known = inferred.targets.iter().any(|t| t == target);
+    if state.phony.iter().any(|p| p == target) && !known {
+        return;
+    }
+
+    let after = Path::new(target).metadata().and_then(|m| m.modified()).ok();
+    if after.is_some() && after != before {
+        if known {
+            inferred.targets.retain(|t| t != target);
+            state.phony.retain(|p| p != target);
+            inferred.changed = true;
+        }
+    } else if after.is_none() && !known {
+        inferred.targets.push(target.to_string());
+        inferred.changed = true;
+    }
+}
+
+pub fn save(state: &State) {
+    let Some(inferred) = state.infer_phony.as_ref().filter(|i| i.changed) else {
+        return;
+    };
+    let written = if inferred.targets.is_empty() {
+        std::fs::remove_file(PHONY_FILE).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+    } else {
+        std::fs::write(PHONY_FILE, inferred.targets.join("\n") + "\n")
+    };
+    if let Err(e) = written {
+        eprintln!("{}: warning: can't update {}: {}", state.basename, PHONY_FILE, e);
+    }
+}
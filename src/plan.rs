@@ -0,0 +1,77 @@
+//! `--plan-json`: what a build would do, for tools rather than people.
+//!
+//! It's a dry run that prints a JSON array instead of the recipe lines.
+//! Each element is a target that would be remade, in the order it would
+//! be, with why, its prerequisites and its recipe expanded as it would
+//! run. Recipes aren't run at all, not even sub-makes, so nothing below a
+//! `$(MAKE)` line is in the plan.
+
+use std::fmt;
+
+use crate::json_str;
+
+/// Why a target needs remaking
+#[derive(Debug, Clone)]
+pub enum Reason {
+    Phony,
+    /// There's no such file
+    Missing,
+    /// These prerequisites are newer than it
+    Newer(Vec<String>),
+    /// Asked for with `--only`
+    Only,
+    /// `-B` says everything is out of date
+    Always,
+}
+
+/// How `IMAKE_REBUILD_REASON` puts it
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Phony => write!(f, "phony target"),
+            Reason::Missing => write!(f, "target missing"),
+            Reason::Newer(newer) if newer.len() == 1 => write!(f, "prerequisite {} newer", newer[0]),
+            Reason::Newer(newer) => write!(f, "prerequisites {} newer", newer.join(" ")),
+            Reason::Only => write!(f, "requested by --only"),
+            Reason::Always => write!(f, "forced by -B"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub target: String,
+    pub reason: Reason,
+    pub prerequisites: Vec<String>,
+    pub recipe: Vec<String>,
+}
+
+pub fn json_list(items: &[String]) -> String {
+    let items = items.iter().map(|i| json_str(i)).collect::<Vec<_>>().join(",");
+    format!("[{}]", items)
+}
+
+/// The plan as a JSON array, one step to a line
+pub fn to_json(steps: &[Step]) -> String {
+    let mut out = String::from("[");
+    for (i, step) in steps.iter().enumerate() {
+        let (reason, newer) = match &step.reason {
+            Reason::Phony => ("phony", &[][..]),
+            Reason::Missing => ("missing", &[][..]),
+            Reason::Newer(newer) => ("newer", &newer[..]),
+            Reason::Only => ("only", &[][..]),
+            Reason::Always => ("always", &[][..]),
+        };
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str(&format!(
+            "{{\"target\":{},\"reason\":\"{}\",\"newer\":{},\"prerequisites\":{},\"recipe\":{}}}",
+            json_str(&step.target),
+            reason,
+            json_list(newer),
+            json_list(&step.prerequisites),
+            json_list(&step.recipe),
+        ));
+    }
+    out.push_str("\n]");
+    out
+}
@@ -0,0 +1,97 @@
+//! `--shuffle`: make prerequisites in some other order than they were
+//! written, to flush out missing dependencies that a serial build happens
+//! to get right.
+//!
+//! - `--shuffle=reverse`: the last prerequisite first
+//! - `--shuffle` or `--shuffle=random`: a random order, with the seed
+//!   printed when make starts and again if the build fails
+//! - `--shuffle=random:SEED` (or just `--shuffle=SEED`): the order that
+//!   seed gave before
+//! - `--shuffle=none`: the order they were written in
+//!
+//! Each target's prerequisites are shuffled by the seed and its name, so an
+//! order doesn't depend on which targets happened to be looked at first.
+//! Only the order things are made in changes, `$^` and `$<` are as written.
+//! Sub-makes are handed the same seed.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    cache::{fnv1a, FNV_OFFSET},
+    State,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Shuffle {
+    #[default]
+    None,
+    Reverse,
+    Random(u64),
+}
+
+impl Shuffle {
+    /// What follows `--shuffle=`, if it's a mode we know
+    pub fn parse(mode: &str) -> Option<Shuffle> {
+        match mode {
+            "none" => Some(Shuffle::None),
+            "reverse" => Some(Shuffle::Reverse),
+            "random" => Some(Shuffle::Random(fresh_seed())),
+            mode => mode
+                .strip_prefix("random:")
+                .unwrap_or(mode)
+                .parse()
+                .ok()
+                .map(Shuffle::Random),
+        }
+    }
+
+    /// How a sub-make is told to do the same
+    pub fn flag(self) -> Option<String> {
+        match self {
+            Shuffle::None => None,
+            Shuffle::Reverse => Some("--shuffle=reverse".into()),
+            Shuffle::Random(seed) => Some(format!("--shuffle=random:{}", seed)),
+        }
+    }
+}
+
+fn fresh_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let bytes = [now.as_nanos().to_le_bytes().as_slice(), &std::process::id().to_le_bytes()].concat();
+    // small enough to type back in
+    fnv1a(&bytes, FNV_OFFSET) % 1_000_000_000
+}
+
+/// splitmix64, which is plenty for picking an order
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Put `target`'s prerequisites in the order they'll be made
+pub fn apply(shuffle: Shuffle, target: &str, prereqs: &mut [String]) {
+    match shuffle {
+        Shuffle::None => {}
+        Shuffle::Reverse => prereqs.reverse(),
+        Shuffle::Random(seed) => {
+            let mut rng = fnv1a(target.as_bytes(), FNV_OFFSET ^ seed);
+            for i in (1..prereqs.len()).rev() {
+                let j = (next(&mut rng) % (i as u64 + 1)) as usize;
+                prereqs.swap(i, j);
+            }
+        }
+    }
+}
+
+/// Say how to get the same order again once a shuffled build has failed
+pub fn report_failure(state: &State) {
+    if let Shuffle::Random(seed) = state.shuffle {
+        eprintln!(
+            "{}: *** the build order was shuffled, replay it with --shuffle=random:{}",
+            state.basename, seed
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! `--strict`: things make normally lets go that are treated as errors.
+//!
+//! - a recipe using a variable that isn't defined
+//! - a recipe line indented with spaces before its tab
+//! - a recipe that didn't create its target, which isn't `.PHONY`
+//! - a target with a recipe that nothing asks for: it isn't a goal, the
+//!   default goal, a prerequisite or `.PHONY`
+//!
+//! Sloppy makefile text stops make like a syntax error would. A target
+//! that isn't created fails as though its recipe had.
+
+use crate::{leave_and_exit, Location, RuleData, State};
+
+/// Stop on a recipe referring to `name` when it isn't defined. Automatic
+/// variables are left alone, `$<` and friends are empty for some targets.
+pub fn undefined_variable(state: &State, loc: &Location, name: &str) {
+    if !state.strict || !state.in_recipe.get() {
+        return;
+    }
+    if name.starts_with(['@', '%', '<', '?', '^', '+', '|', '*']) {
+        return;
+    }
+    eprintln!(
+        "{}:{}: *** undefined variable '{}' in recipe (--strict).  Stop.",
+        loc.file_name, loc.line, name
+    );
+    leave_and_exit(state, 2)
+}
+
+/// Targets with recipes that aren't in `requested`, aren't a prerequisite
+/// of anything and aren't `.PHONY`, with where their recipes are
+pub fn unrequested(state: &State, requested: &[String]) -> Vec<(Location, String)> {
+    let mut wanted = requested.to_vec();
+    wanted.extend(state.phony.iter().cloned());
+    for rule in &state.rules {
+        if let RuleData::Prereq(_, prereqs) | RuleData::OrderOnly(prereqs) = &rule.data {
+            wanted.extend(prereqs.split_whitespace().map(|p| p.to_string()));
+        }
+    }
+
+    let mut unwanted: Vec<(Location, String)> = Vec::new();
+    for rule in &state.rules {
+        let RuleData::Recipie(_) = rule.data else { continue };
+        for t in &rule.targets {
+            if t.contains('%') || t.starts_with('.') || wanted.contains(t) || unwanted.iter().any(|(_, u)| u == t) {
+                continue;
+            }
+            unwanted.push((rule.location.clone(), t.clone()));
+        }
+    }
+    unwanted
+}
@@ -0,0 +1,46 @@
+//! "did you mean" for a goal there's no rule to make.
+//!
+//! Every target with an explicit rule and every `.ALIAS` name is a
+//! candidate. The closest by edit distance is suggested if it's within a
+//! third of the goal's length (but always allowing one typo), so a short
+//! goal doesn't get matched to something that only shares a letter.
+
+use crate::State;
+
+/// Levenshtein distance, counting in chars
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The known target `goal` was most likely meant to be, if one is close
+pub fn closest(state: &State, goal: &str) -> Option<String> {
+    let mut aliases = state.aliases.keys().collect::<Vec<_>>();
+    aliases.sort();
+    let targets = state
+        .rules
+        .iter()
+        .flat_map(|r| &r.targets)
+        .filter(|t| !t.contains('%') && !t.starts_with('.'))
+        .chain(aliases);
+
+    let limit = (goal.chars().count() / 3).max(1);
+    let mut best: Option<(usize, &String)> = None;
+    for t in targets {
+        let d = distance(goal, t);
+        if d > 0 && d <= limit && best.is_none_or(|(b, _)| d < b) {
+            best = Some((d, t));
+        }
+    }
+    best.map(|(_, t)| t.clone())
+}
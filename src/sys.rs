@@ -0,0 +1,242 @@
+//! The handful of libc calls std doesn't wrap for us.
+
+use std::{
+    ffi::CString,
+    os::unix::{
+        ffi::OsStrExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+extern "C" {
+    pub fn fork() -> i32;
+    pub fn dup2(old: i32, new: i32) -> i32;
+    pub fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    fn signal(sig: i32, handler: usize) -> usize;
+    fn raise(sig: i32) -> i32;
+    fn kill(pid: i32, sig: i32) -> i32;
+    fn umask(mask: u32) -> u32;
+    fn pipe(fds: *mut i32) -> i32;
+    fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+    fn poll(fds: *mut PollFd, nfds: u64, timeout: i32) -> i32;
+    fn getloadavg(loadavg: *mut f64, nelem: i32) -> i32;
+    fn mkfifo(path: *const std::ffi::c_char, mode: u32) -> i32;
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+const POLLIN: i16 = 1;
+const WNOHANG: i32 = 1;
+const EINTR: i32 = 4;
+
+const F_GETFD: i32 = 1;
+const F_SETFD: i32 = 2;
+const FD_CLOEXEC: i32 = 1;
+
+const SIG_IGN: usize = 1;
+const SIG_DFL: usize = 0;
+
+/// SIGHUP, SIGINT and SIGTERM: the ones that mean someone wants us to stop
+pub const INTERRUPTS: [i32; 3] = [1, 2, 15];
+
+/// Descriptors open in this process other than stdin, stdout and stderr
+fn open_fds() -> Vec<i32> {
+    if let Ok(dir) = std::fs::read_dir("/proc/self/fd") {
+        // the listing itself holds a descriptor which is gone by the time
+        // we look at it; `fcntl` just fails on that one
+        dir.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|&fd| fd > 2)
+            .collect()
+    } else {
+        (3..1024)
+            .filter(|&fd| unsafe { fcntl(fd, F_GETFD) } != -1)
+            .collect()
+    }
+}
+
+/// Mark everything we inherited close-on-exec so recipes don't get our
+/// parent's descriptors. Anything std opens is close-on-exec already. The
+/// ones that should be passed on (the jobserver) are handed back out with
+/// [`inherit_fds`] to the children allowed them.
+pub fn cloexec_inherited() {
+    for fd in open_fds() {
+        unsafe {
+            let flags = fcntl(fd, F_GETFD);
+            if flags != -1 {
+                fcntl(fd, F_SETFD, flags | FD_CLOEXEC);
+            }
+        }
+    }
+}
+
+/// Let `cmd` inherit `fds` even though they're close-on-exec for everyone
+/// else
+pub fn inherit_fds(cmd: &mut Command, fds: Vec<i32>) {
+    if fds.is_empty() {
+        return;
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            for &fd in &fds {
+                let flags = fcntl(fd, F_GETFD);
+                if flags != -1 {
+                    fcntl(fd, F_SETFD, flags & !FD_CLOEXEC);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Stop interrupts from killing make while jobs are running so it can clean
+/// up after them. Returns the old handlers for [`restore_interrupts`] and
+/// [`child_interrupts`].
+pub fn ignore_interrupts() -> [usize; 3] {
+    INTERRUPTS.map(|sig| unsafe { signal(sig, SIG_IGN) })
+}
+
+pub fn restore_interrupts(old: [usize; 3]) {
+    for (sig, handler) in INTERRUPTS.iter().zip(old) {
+        unsafe {
+            signal(*sig, handler);
+        }
+    }
+}
+
+/// Give `cmd` the interrupt handlers we had before ignoring them
+pub fn child_interrupts(cmd: &mut Command, old: [usize; 3]) {
+    unsafe {
+        cmd.pre_exec(move || {
+            restore_interrupts(old);
+            Ok(())
+        });
+    }
+}
+
+/// Our umask. There's no reading it without setting it, so it's put back
+/// straight away.
+pub fn get_umask() -> u32 {
+    unsafe {
+        let mask = umask(0o022);
+        umask(mask);
+        mask
+    }
+}
+
+/// Start `cmd` with `mask` as its umask
+pub fn child_umask(cmd: &mut Command, mask: u32) {
+    unsafe {
+        cmd.pre_exec(move || {
+            umask(mask);
+            Ok(())
+        });
+    }
+}
+
+/// Go down with `sig` like we would have if we hadn't been ignoring it
+pub fn die_of(sig: i32) -> ! {
+    unsafe {
+        signal(sig, SIG_DFL);
+        raise(sig);
+    }
+    std::process::exit(128 + sig)
+}
+
+/// Send SIGTERM to `pid`. Only the process itself gets it, anything it
+/// started is left to notice its parent has gone.
+pub fn terminate(pid: i32) {
+    unsafe {
+        kill(pid, 15);
+    }
+}
+
+/// A pipe with both ends close-on-exec
+pub fn make_pipe() -> Option<(i32, i32)> {
+    let mut fds = [0; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } == -1 {
+        return None;
+    }
+    for fd in fds {
+        unsafe {
+            fcntl(fd, F_SETFD, FD_CLOEXEC);
+        }
+    }
+    Some((fds[0], fds[1]))
+}
+
+/// A named pipe at `path` only we can use
+pub fn make_fifo(path: &Path) -> std::io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { mkfifo(path.as_ptr(), 0o600) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn is_open(fd: i32) -> bool {
+    unsafe { fcntl(fd, F_GETFD) != -1 }
+}
+
+/// Whether `fd` has something to read within `timeout` milliseconds
+pub fn readable(fd: i32, timeout: i32) -> bool {
+    let mut pfd = PollFd {
+        fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { poll(&mut pfd, 1, timeout) > 0 && pfd.revents & POLLIN != 0 }
+}
+
+pub fn read_byte(fd: i32) -> Option<u8> {
+    let mut b = 0;
+    loop {
+        match unsafe { read(fd, &mut b, 1) } {
+            1 => return Some(b),
+            -1 if std::io::Error::last_os_error().raw_os_error() == Some(EINTR) => continue,
+            _ => return None,
+        }
+    }
+}
+
+pub fn write_byte(fd: i32, b: u8) {
+    while unsafe { write(fd, &b, 1) } == -1
+        && std::io::Error::last_os_error().raw_os_error() == Some(EINTR)
+    {}
+}
+
+/// The one minute load average, if the system will say
+pub fn load_average() -> Option<f64> {
+    let mut load = 0.0;
+    (unsafe { getloadavg(&mut load, 1) } == 1).then_some(load)
+}
+
+/// Start `cmd` and hand back its pid. It's reaped with [`wait_child`]
+/// along with everything else, not through std.
+#[allow(clippy::zombie_processes)]
+pub fn spawn(cmd: &mut Command) -> std::io::Result<i32> {
+    cmd.spawn().map(|child| child.id() as i32)
+}
+
+/// Reap any child. `None` if there isn't one, or with `block` unset if
+/// none has finished yet.
+pub fn wait_child(block: bool) -> Option<(i32, ExitStatus)> {
+    let mut status = 0;
+    loop {
+        let pid = unsafe { waitpid(-1, &mut status, if block { 0 } else { WNOHANG }) };
+        match pid {
+            -1 if std::io::Error::last_os_error().raw_os_error() == Some(EINTR) => continue,
+            -1 | 0 => return None,
+            pid => return Some((pid, ExitStatus::from_raw(status))),
+        }
+    }
+}
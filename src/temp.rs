@@ -0,0 +1,72 @@
+//! Scratch files make needs for itself, like a makefile read from stdin
+//! with `-f -`. They go in `$TMPDIR`, or `/tmp` without one, and are
+//! removed on the way out: a normal exit, an error, or dying of an
+//! interrupt that arrived while recipes were running.
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use crate::sys;
+
+/// Everything [`create`] has handed out that's still to be cleaned up
+static FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Where scratch files go
+pub fn dir() -> PathBuf {
+    match std::env::var_os("TMPDIR") {
+        Some(dir) if !dir.is_empty() => dir.into(),
+        _ => PathBuf::from("/tmp"),
+    }
+}
+
+/// A new file only we can read, named after `what` it's for. It's removed
+/// by [`remove_all`].
+pub fn create(what: &str) -> io::Result<(PathBuf, File)> {
+    let dir = dir();
+    let mut files = FILES.lock().unwrap();
+    for n in files.len().. {
+        let path = dir.join(format!("imake-{}-{}-{}", what, std::process::id(), n));
+        match File::options().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => {
+                files.push(path.clone());
+                return Ok((path, file));
+            }
+            // left behind by an earlier make with our pid
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// A new named pipe, like [`create`] but left for the caller to open
+pub fn fifo(what: &str) -> io::Result<PathBuf> {
+    let dir = dir();
+    let mut files = FILES.lock().unwrap();
+    for n in files.len().. {
+        let path = dir.join(format!("imake-{}-{}-{}", what, std::process::id(), n));
+        match sys::make_fifo(&path) {
+            Ok(()) => {
+                files.push(path.clone());
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Remove every scratch file. Called whichever way make exits.
+pub fn remove_all() {
+    // a poisoned lock still has the list in it
+    let mut files = FILES.lock().unwrap_or_else(|e| e.into_inner());
+    for path in files.drain(..) {
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,147 @@
+//! `--time-report`: where the time went.
+//!
+//! Each makefile is charged for reading and parsing it, not counting the
+//! makefiles it includes. Expansion is only timed at the outermost call so
+//! nested references aren't counted twice. Expansion that happens while a
+//! makefile is being parsed shows up both in that makefile's time and in
+//! the expansion total.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Every makefile read, with its own parse time and how much of that
+    /// went on expansion
+    makefiles: RefCell<Vec<(String, Duration, Duration)>>,
+    /// Makefiles being read right now: name, start, time spent in includes
+    /// and time spent expanding
+    reading: RefCell<Vec<(String, Instant, Duration, Duration)>>,
+    expand: Cell<Duration>,
+    expand_depth: Cell<usize>,
+    stat: Cell<Duration>,
+    stats: Cell<usize>,
+    recipes: Cell<Duration>,
+    commands: Cell<usize>,
+}
+
+/// Shared between clones of `State` so the report sees everything
+#[derive(Debug, Clone, Default)]
+pub struct Timings(Option<Rc<Inner>>);
+
+fn add(cell: &Cell<Duration>, since: Instant) {
+    cell.set(cell.get() + since.elapsed());
+}
+
+impl Timings {
+    pub fn enabled() -> Self {
+        Self(Some(Rc::default()))
+    }
+
+    /// Now, if anyone is interested
+    pub fn start(&self) -> Option<Instant> {
+        self.0.as_ref().map(|_| Instant::now())
+    }
+
+    pub fn enter_makefile(&self, name: &str) {
+        if let Some(t) = &self.0 {
+            t.reading
+                .borrow_mut()
+                .push((name.to_string(), Instant::now(), Duration::ZERO, Duration::ZERO));
+        }
+    }
+
+    pub fn leave_makefile(&self) {
+        let Some(t) = &self.0 else { return };
+        let mut reading = t.reading.borrow_mut();
+        let Some((name, start, included, expanding)) = reading.pop() else {
+            return;
+        };
+
+        let elapsed = start.elapsed();
+        if let Some(parent) = reading.last_mut() {
+            parent.2 += elapsed;
+        }
+
+        let mut makefiles = t.makefiles.borrow_mut();
+        match makefiles.iter_mut().find(|(n, ..)| *n == name) {
+            Some(m) => {
+                m.1 += elapsed - included;
+                m.2 += expanding;
+            }
+            None => makefiles.push((name, elapsed - included, expanding)),
+        }
+    }
+
+    pub fn enter_expand(&self) -> Option<Instant> {
+        let t = self.0.as_ref()?;
+        let depth = t.expand_depth.get();
+        t.expand_depth.set(depth + 1);
+        (depth == 0).then(Instant::now)
+    }
+
+    pub fn leave_expand(&self, since: Option<Instant>) {
+        let Some(t) = &self.0 else { return };
+        t.expand_depth.set(t.expand_depth.get() - 1);
+        if let Some(since) = since {
+            let elapsed = since.elapsed();
+            t.expand.set(t.expand.get() + elapsed);
+            if let Some(current) = t.reading.borrow_mut().last_mut() {
+                current.3 += elapsed;
+            }
+        }
+    }
+
+    pub fn stat(&self, since: Option<Instant>) {
+        if let (Some(t), Some(since)) = (&self.0, since) {
+            add(&t.stat, since);
+            t.stats.set(t.stats.get() + 1);
+        }
+    }
+
+    pub fn recipe(&self, since: Option<Instant>) {
+        if let (Some(t), Some(since)) = (&self.0, since) {
+            add(&t.recipes, since);
+            t.commands.set(t.commands.get() + 1);
+        }
+    }
+
+    /// Print the breakdown to stderr
+    pub fn report(&self, basename: &str, total: Duration) {
+        let Some(t) = &self.0 else { return };
+        let makefiles = t.makefiles.borrow();
+        let parsing = makefiles.iter().map(|(_, d, _)| *d).sum::<Duration>();
+        let width = makefiles.iter().map(|(n, ..)| n.len()).max().unwrap_or(0).max(10);
+
+        eprintln!("{}: time report", basename);
+        eprintln!("  {:<w$} {:>9.3}s", "parsing", parsing.as_secs_f64(), w = width + 2);
+        for (name, own, expanding) in makefiles.iter() {
+            eprintln!(
+                "    {:<w$} {:>9.3}s (expanding {:.3}s)",
+                name,
+                own.as_secs_f64(),
+                expanding.as_secs_f64(),
+                w = width
+            );
+        }
+        eprintln!("  {:<w$} {:>9.3}s", "expanding", t.expand.get().as_secs_f64(), w = width + 2);
+        eprintln!(
+            "  {:<w$} {:>9.3}s ({} calls)",
+            "stat",
+            t.stat.get().as_secs_f64(),
+            t.stats.get(),
+            w = width + 2
+        );
+        eprintln!(
+            "  {:<w$} {:>9.3}s ({} commands)",
+            "recipes",
+            t.recipes.get().as_secs_f64(),
+            t.commands.get(),
+            w = width + 2
+        );
+        eprintln!("  {:<w$} {:>9.3}s", "total", total.as_secs_f64(), w = width + 2);
+    }
+}
@@ -0,0 +1,76 @@
+//! Looking for prerequisites in other directories, with `vpath` directives
+//! and the `VPATH` variable.
+//!
+//! A prerequisite that isn't in the current directory and isn't the target
+//! of an explicit rule is looked for in the directories of each `vpath`
+//! whose pattern it matches, in the order they were written, and then in
+//! those of `VPATH`. The first one it's found in is the name the target
+//! sees it by, in `$<`, `$^` and the rest. Targets themselves aren't
+//! searched for, they're always made where they're named.
+
+use std::path::Path;
+
+use crate::{normalize_path, pattern_stem, State};
+
+#[derive(Debug, Clone, Default)]
+pub struct Vpath {
+    /// `vpath pattern dirs...`, in the order they were written
+    pub directives: Vec<(String, Vec<String>)>,
+    /// `VPATH`
+    pub dirs: Vec<String>,
+}
+
+/// Directory lists are separated by colons or blanks
+fn split_dirs(dirs: &str) -> Vec<String> {
+    dirs.split(|c: char| c == ':' || c.is_ascii_whitespace())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_string())
+        .collect()
+}
+
+impl Vpath {
+    /// The rest of a `vpath` line, expanded. With no directories it forgets
+    /// those for the pattern, and with nothing at all every pattern's.
+    pub fn directive(&mut self, args: &str) {
+        let args = args.trim();
+        let (pattern, dirs) = args.split_once(|c: char| c.is_ascii_whitespace()).unwrap_or((args, ""));
+        let dirs = split_dirs(dirs);
+        if pattern.is_empty() {
+            self.directives.clear();
+        } else if dirs.is_empty() {
+            self.directives.retain(|(p, _)| p != pattern);
+        } else {
+            self.directives.push((pattern.to_string(), dirs));
+        }
+    }
+
+    pub fn set_general(&mut self, dirs: &str) {
+        self.dirs = split_dirs(dirs);
+    }
+
+    /// Where `name` is if it isn't here but is somewhere we've been told to
+    /// look
+    pub fn search(&self, name: &str) -> Option<String> {
+        if name.starts_with('/') || Path::new(name).exists() {
+            return None;
+        }
+        self.directives
+            .iter()
+            .filter(|(pattern, _)| pattern_stem(pattern, name).is_some())
+            .flat_map(|(_, dirs)| dirs)
+            .chain(&self.dirs)
+            .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), name))
+            .find(|path| Path::new(path).exists())
+    }
+}
+
+/// `name`, or where it was found if it's a file we should look for
+pub fn resolve(state: &State, name: &str) -> String {
+    if state.rules.iter().any(|r| r.targets.iter().any(|t| t == name)) {
+        return name.to_string();
+    }
+    state
+        .vpath
+        .search(name)
+        .map_or_else(|| name.to_string(), |path| normalize_path(&path))
+}